@@ -0,0 +1,75 @@
+//! Bearer-token check for the admin endpoints (`/keys*`, `PUT|DELETE /devices/{device}`,
+//! `POST|PUT|DELETE /anchors*`).
+//!
+//! The token is `KEYS_ADMIN_TOKEN` (see `secrets`), held by the key store so a secrets reload swaps it;
+//! handlers call `KeyStore::authorize`. While it is unset every admin endpoint answers 403.
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use crate::secrets::Secret;
+
+/// Why an admin request was refused. `code()` values are stable (HTTP error bodies).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminError {
+    /// `KEYS_ADMIN_TOKEN` is not set.
+    Disabled,
+    /// Missing or wrong `Authorization: Bearer` header.
+    Unauthorized,
+}
+
+impl AdminError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AdminError::Disabled => "keys_admin_disabled",
+            AdminError::Unauthorized => "unauthorized",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+
+    pub(crate) fn response(&self) -> HttpResponse {
+        let mut r = match self {
+            AdminError::Disabled => HttpResponse::Forbidden(),
+            AdminError::Unauthorized => HttpResponse::Unauthorized(),
+        };
+        r.json(self.to_json())
+    }
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::Disabled => f.write_str("admin API disabled (KEYS_ADMIN_TOKEN not set)"),
+            AdminError::Unauthorized => f.write_str("missing or wrong bearer token"),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+/// Check the `Authorization: Bearer` header against `expected` (`None`: admin API disabled).
+pub(crate) fn authorize(req: &HttpRequest, expected: Option<&Secret>) -> Result<(), AdminError> {
+    let Some(expected) = expected.map(Secret::expose) else { return Err(AdminError::Disabled) };
+    let given = req.headers().get("authorization").and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer ")).unwrap_or("");
+    // Compare every byte so the time taken does not reveal the matching prefix
+    let same = given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0;
+    if same { Ok(()) } else { Err(AdminError::Unauthorized) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn bearer_token_must_match_exactly() {
+        let token = Secret::from("s3cret");
+        let with = |header: &str| TestRequest::default().insert_header(("authorization", header)).to_http_request();
+        assert_eq!(authorize(&with("Bearer s3cret"), Some(&token)), Ok(()));
+        assert_eq!(authorize(&with("Bearer s3cre"), Some(&token)), Err(AdminError::Unauthorized));
+        assert_eq!(authorize(&with("s3cret"), Some(&token)), Err(AdminError::Unauthorized));
+        assert_eq!(authorize(&TestRequest::default().to_http_request(), Some(&token)), Err(AdminError::Unauthorized));
+        assert_eq!(authorize(&with("Bearer s3cret"), None), Err(AdminError::Disabled));
+    }
+}
@@ -0,0 +1,452 @@
+//! Anchor registry: the single source of truth for anchor (fixed beacon) positions.
+//!
+//! Anchors are keyed by `beaconId`, the `major||minor` hex string `as_uwb_update` emits, and persisted
+//! as a JSON array to `positioning.anchors_file` (default `data/anchors.json`) after every change. When
+//! the file does not exist yet the registry is seeded from `positioning.anchors` (see `config`).
+//!
+//! - `GET /anchors`: list, sorted by `beaconId`.
+//! - `GET /anchors/{beaconId}`: one anchor (404 `anchor_not_found`).
+//! - `POST /anchors`: create (201; 409 `anchor_exists`).
+//! - `PUT /anchors/{beaconId}`: create or replace (200 / 201).
+//! - `DELETE /anchors/{beaconId}`: remove (204; 404 `anchor_not_found`).
+//!
+//! `POST`, `PUT` and `DELETE` need the admin token (see `admin_auth`); `GET` is open.
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::{fs, io};
+use tracing::{error, info};
+use crate::lorawan_stream::IngestState;
+use crate::positioning::Anchor;
+
+/// Stored anchor. Coordinates are meters on the floor plan, `height` above the floor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnchorRecord {
+    pub beacon_id: String,
+    pub x: f64,
+    pub y: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub floor: i32,
+    #[serde(default)]
+    pub label: String,
+}
+
+impl AnchorRecord {
+    /// Normalize `beaconId` to lower case and check it and the coordinates.
+    fn validated(mut self) -> Result<Self, AnchorError> {
+        self.beacon_id = normalize_id(&self.beacon_id)?;
+        for (field, v) in [("x", self.x), ("y", self.y), ("height", self.height)] {
+            if !v.is_finite() { return Err(AnchorError::InvalidCoordinate(field)); }
+        }
+        Ok(self)
+    }
+
+    /// Solver view of this anchor.
+    pub fn to_anchor(&self) -> Anchor {
+        Anchor { beacon_id: self.beacon_id.clone(), x: self.x, y: self.y, z: self.height }
+    }
+}
+
+/// Why a registry operation failed. `code()` values are stable (returned in HTTP error bodies).
+#[derive(Debug)]
+pub enum AnchorError {
+    /// `beaconId` is not 8 hex characters.
+    InvalidBeaconId(String),
+    /// Coordinate is NaN or infinite.
+    InvalidCoordinate(&'static str),
+    /// `POST` for an existing `beaconId`.
+    Exists(String),
+    NotFound(String),
+    /// Path `beaconId` and body `beaconId` differ on `PUT`.
+    IdMismatch { path: String, body: String },
+    /// Reading / writing `ANCHORS_FILE` failed.
+    Storage(io::Error),
+}
+
+impl AnchorError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AnchorError::InvalidBeaconId(_) => "invalid_beacon_id",
+            AnchorError::InvalidCoordinate(_) => "invalid_coordinate",
+            AnchorError::Exists(_) => "anchor_exists",
+            AnchorError::NotFound(_) => "anchor_not_found",
+            AnchorError::IdMismatch { .. } => "beacon_id_mismatch",
+            AnchorError::Storage(_) => "storage_error",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+
+    fn response(&self) -> HttpResponse {
+        let mut r = match self {
+            AnchorError::InvalidBeaconId(_) | AnchorError::InvalidCoordinate(_) | AnchorError::IdMismatch { .. } => HttpResponse::BadRequest(),
+            AnchorError::Exists(_) => HttpResponse::Conflict(),
+            AnchorError::NotFound(_) => HttpResponse::NotFound(),
+            AnchorError::Storage(_) => HttpResponse::InternalServerError(),
+        };
+        r.json(self.to_json())
+    }
+}
+
+impl std::fmt::Display for AnchorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnchorError::InvalidBeaconId(id) => write!(f, "beaconId must be 8 hex chars (major||minor), got {id:?}"),
+            AnchorError::InvalidCoordinate(field) => write!(f, "{field} must be a finite number"),
+            AnchorError::Exists(id) => write!(f, "anchor {id} already exists"),
+            AnchorError::NotFound(id) => write!(f, "no anchor {id}"),
+            AnchorError::IdMismatch { path, body } => write!(f, "beaconId in path ({path}) and body ({body}) differ"),
+            AnchorError::Storage(e) => write!(f, "anchor storage: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AnchorError {}
+
+fn normalize_id(id: &str) -> Result<String, AnchorError> {
+    let id = id.trim();
+    if id.len() != 8 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AnchorError::InvalidBeaconId(id.to_string()));
+    }
+    Ok(id.to_ascii_lowercase())
+}
+
+/// In-memory anchors backed by a JSON file; shared by all workers.
+pub struct AnchorRegistry {
+    path: Option<PathBuf>,
+    anchors: RwLock<BTreeMap<String, AnchorRecord>>,
+}
+
+impl AnchorRegistry {
+    /// Registry that is never written to disk (tests, tools).
+    pub fn in_memory(seed: Vec<AnchorRecord>) -> Self {
+        let anchors = seed.into_iter().map(|a| (a.beacon_id.clone(), a)).collect();
+        AnchorRegistry { path: None, anchors: RwLock::new(anchors) }
+    }
+
+    /// Load `path`, or seed from `seed` (and write the file) when it does not exist yet.
+    pub fn open(path: impl Into<PathBuf>, seed: &[Anchor]) -> Result<Self, AnchorError> {
+        let path = path.into();
+        let records = match fs::read(&path) {
+            Ok(bytes) => {
+                let list: Vec<AnchorRecord> = serde_json::from_slice(&bytes)
+                    .map_err(|e| AnchorError::Storage(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                list.into_iter().map(AnchorRecord::validated).collect::<Result<Vec<_>, _>>()?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => seed.iter()
+                .map(|a| AnchorRecord { beacon_id: a.beacon_id.clone(), x: a.x, y: a.y, height: a.z, floor: 0, label: String::new() })
+                .collect(),
+            Err(e) => return Err(AnchorError::Storage(e)),
+        };
+        let reg = AnchorRegistry { path: Some(path), ..Self::in_memory(records) };
+        reg.persist(&reg.anchors.read().unwrap_or_else(|p| p.into_inner()))?;
+        Ok(reg)
+    }
+
+    /// `open`, falling back to an in-memory registry if the file cannot be read or written.
+    pub fn open_or_in_memory(path: &str, seed: &[Anchor]) -> Self {
+        match Self::open(path, seed) {
+            Ok(reg) => {
+                info!(path = %path, anchors = reg.len(), "anchor registry loaded");
+                reg
+            }
+            Err(e) => {
+                error!(path = %path, error = %e, "anchor registry not persisted; changes will be lost on restart");
+                Self::in_memory(seed.iter().map(|a| AnchorRecord { beacon_id: a.beacon_id.clone(), x: a.x, y: a.y, height: a.z, floor: 0, label: String::new() }).collect())
+            }
+        }
+    }
+
+    fn persist(&self, anchors: &BTreeMap<String, AnchorRecord>) -> Result<(), AnchorError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        write_atomic(path, &serde_json::to_vec_pretty(&anchors.values().collect::<Vec<_>>()).expect("anchors serialize"), None)
+            .map_err(AnchorError::Storage)
+    }
+
+    pub fn list(&self) -> Vec<AnchorRecord> {
+        self.anchors.read().unwrap_or_else(|p| p.into_inner()).values().cloned().collect()
+    }
+
+    pub fn get(&self, beacon_id: &str) -> Result<AnchorRecord, AnchorError> {
+        let id = normalize_id(beacon_id)?;
+        self.anchors.read().unwrap_or_else(|p| p.into_inner()).get(&id).cloned().ok_or(AnchorError::NotFound(id))
+    }
+
+    pub fn len(&self) -> usize { self.anchors.read().unwrap_or_else(|p| p.into_inner()).len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Add a new anchor; fails if the `beaconId` is taken.
+    pub fn create(&self, record: AnchorRecord) -> Result<AnchorRecord, AnchorError> {
+        let record = record.validated()?;
+        let mut anchors = self.anchors.write().unwrap_or_else(|p| p.into_inner());
+        if anchors.contains_key(&record.beacon_id) {
+            return Err(AnchorError::Exists(record.beacon_id));
+        }
+        anchors.insert(record.beacon_id.clone(), record.clone());
+        if let Err(e) = self.persist(&anchors) {
+            anchors.remove(&record.beacon_id);
+            return Err(e);
+        }
+        Ok(record)
+    }
+
+    /// Create or replace; returns the stored record and whether it was newly created.
+    pub fn upsert(&self, record: AnchorRecord) -> Result<(AnchorRecord, bool), AnchorError> {
+        let record = record.validated()?;
+        let mut anchors = self.anchors.write().unwrap_or_else(|p| p.into_inner());
+        let previous = anchors.insert(record.beacon_id.clone(), record.clone());
+        if let Err(e) = self.persist(&anchors) {
+            match &previous {
+                Some(p) => { anchors.insert(p.beacon_id.clone(), p.clone()); }
+                None => { anchors.remove(&record.beacon_id); }
+            }
+            return Err(e);
+        }
+        Ok((record, previous.is_none()))
+    }
+
+    pub fn remove(&self, beacon_id: &str) -> Result<AnchorRecord, AnchorError> {
+        let id = normalize_id(beacon_id)?;
+        let mut anchors = self.anchors.write().unwrap_or_else(|p| p.into_inner());
+        let removed = anchors.remove(&id).ok_or(AnchorError::NotFound(id))?;
+        if let Err(e) = self.persist(&anchors) {
+            anchors.insert(removed.beacon_id.clone(), removed);
+            return Err(e);
+        }
+        Ok(removed)
+    }
+
+    /// Solver view of every anchor.
+    pub fn anchors(&self) -> Vec<Anchor> {
+        self.anchors.read().unwrap_or_else(|p| p.into_inner()).values().map(AnchorRecord::to_anchor).collect()
+    }
+}
+
+/// Write to a sibling temp file (`<name>.tmp`), sync it, then rename over `path` so readers never see a
+/// partial file. `mode` sets the Unix permissions of the file (`0o600` for key material); `None` leaves
+/// them to the umask.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    use std::io::Write;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(mode);
+    }
+    let mut file = opts.open(&tmp)?;
+    // The open mode only applies when the file is created; a temp file left by a crash keeps its own
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// `PUT` body: `beaconId` optional (taken from the path).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnchorBody {
+    pub beacon_id: Option<String>,
+    pub x: f64,
+    pub y: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub floor: i32,
+    #[serde(default)]
+    pub label: String,
+}
+
+#[get("/anchors")]
+pub async fn list_anchors(reg: web::Data<AnchorRegistry>) -> impl Responder {
+    HttpResponse::Ok().json(json!({ "anchors": reg.list() }))
+}
+
+#[get("/anchors/{beacon_id}")]
+pub async fn get_anchor(reg: web::Data<AnchorRegistry>, path: web::Path<String>) -> impl Responder {
+    match reg.get(&path.into_inner()) {
+        Ok(a) => HttpResponse::Ok().json(a),
+        Err(e) => e.response(),
+    }
+}
+
+#[post("/anchors")]
+pub async fn create_anchor(req: HttpRequest, state: web::Data<IngestState>, reg: web::Data<AnchorRegistry>, body: web::Json<AnchorRecord>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match reg.create(body.into_inner()) {
+        Ok(a) => {
+            info!(beacon_id = %a.beacon_id, "anchor created");
+            HttpResponse::Created().json(a)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[put("/anchors/{beacon_id}")]
+pub async fn put_anchor(req: HttpRequest, state: web::Data<IngestState>, reg: web::Data<AnchorRegistry>, path: web::Path<String>, body: web::Json<AnchorBody>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    let path_id = path.into_inner();
+    let b = body.into_inner();
+    if let Some(body_id) = b.beacon_id.filter(|id| !id.eq_ignore_ascii_case(path_id.trim())) {
+        return AnchorError::IdMismatch { path: path_id, body: body_id }.response();
+    }
+    let record = AnchorRecord { beacon_id: path_id, x: b.x, y: b.y, height: b.height, floor: b.floor, label: b.label };
+    match reg.upsert(record) {
+        Ok((a, true)) => HttpResponse::Created().json(a),
+        Ok((a, false)) => {
+            info!(beacon_id = %a.beacon_id, "anchor updated");
+            HttpResponse::Ok().json(a)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[delete("/anchors/{beacon_id}")]
+pub async fn delete_anchor(req: HttpRequest, state: web::Data<IngestState>, reg: web::Data<AnchorRegistry>, path: web::Path<String>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match reg.remove(&path.into_inner()) {
+        Ok(a) => {
+            info!(beacon_id = %a.beacon_id, "anchor deleted");
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.response(),
+    }
+}
+
+/// Register the anchor endpoints on the registry `state.positioner` solves with. Registers `state` too,
+/// as writes check its key store even when local ingestion is off.
+pub fn config(cfg: &mut web::ServiceConfig, state: web::Data<IngestState>) {
+    cfg.app_data(web::Data::from(state.positioner.anchors.clone()));
+    cfg.app_data(state);
+    cfg.service(list_anchors);
+    cfg.service(get_anchor);
+    cfg.service(create_anchor);
+    cfg.service(put_anchor);
+    cfg.service(delete_anchor);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::secrets::Secrets;
+    use crate::test_support::{self, Server, ADMIN_TOKEN};
+
+    fn bearer() -> (&'static str, String) {
+        ("authorization", format!("Bearer {ADMIN_TOKEN}"))
+    }
+
+    fn rec(id: &str, x: f64) -> AnchorRecord {
+        AnchorRecord { beacon_id: id.into(), x, y: 0.0, height: 2.0, floor: 1, label: "dock".into() }
+    }
+
+    fn seed() -> [Anchor; 1] {
+        [Anchor { beacon_id: "020000b3".into(), x: 0.0, y: 0.0, z: 1.5 }]
+    }
+
+    #[test]
+    fn seeds_an_empty_file_and_rejects_bad_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let reg = AnchorRegistry::open(dir.path().join("anchors.json"), &seed()).unwrap();
+        assert_eq!(reg.get("020000B3").unwrap().height, 1.5);
+        assert!(matches!(reg.create(rec("020000b3", 1.0)), Err(AnchorError::Exists(_))));
+        assert!(matches!(reg.create(rec("xyz", 1.0)), Err(AnchorError::InvalidBeaconId(_))));
+    }
+
+    #[test]
+    fn changes_survive_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("anchors.json");
+        let reg = AnchorRegistry::open(&path, &seed()).unwrap();
+        reg.create(rec("02000053", 20.0)).unwrap();
+        assert!(!reg.upsert(rec("02000053", 19.5)).unwrap().1);
+        reg.remove("020000b3").unwrap();
+        assert!(matches!(reg.remove("020000b3"), Err(AnchorError::NotFound(_))));
+
+        // Reopening reads the file, not the seed
+        let reopened = AnchorRegistry::open(&path, &seed()).unwrap();
+        assert_eq!(reopened.list(), vec![rec("02000053", 19.5)]);
+        assert_eq!(reopened.anchors()[0].z, 2.0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_applies_the_mode_to_a_stale_temp_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        fs::write(dir.path().join("keys.tmp"), b"left by a crash").unwrap();
+        fs::set_permissions(dir.path().join("keys.tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomic(&path, b"[]", Some(0o600)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"[]");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!dir.path().join("keys.tmp").exists());
+    }
+
+    #[actix_web::test]
+    async fn endpoints_answer_404_and_400() {
+        let server = Server::new(|_| {});
+        let reg = server.state.positioner.anchors.clone();
+        reg.create(rec("020000b3", 1.0)).unwrap();
+        let app = init_service(App::new().configure(|cfg| config(cfg, server.state.clone()))).await;
+
+        let missing = call_service(&app, TestRequest::get().uri("/anchors/02000053").to_request()).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let delete = call_service(&app, TestRequest::delete().uri("/anchors/02000053").insert_header(bearer()).to_request()).await;
+        assert_eq!(delete.status(), StatusCode::NOT_FOUND);
+
+        let bad_id = TestRequest::post().uri("/anchors").insert_header(bearer()).set_json(rec("xyz", 1.0)).to_request();
+        let bad_id: Value = call_and_read_body_json(&app, bad_id).await;
+        assert_eq!(bad_id["code"], "invalid_beacon_id");
+        let mismatch = TestRequest::put().uri("/anchors/020000b3").insert_header(bearer()).set_json(json!({ "beaconId": "02000053", "x": 1.0, "y": 0.0, "height": 2.0 })).to_request();
+        let mismatch: Value = call_and_read_body_json(&app, mismatch).await;
+        assert_eq!(mismatch["code"], "beacon_id_mismatch");
+        let exists = call_service(&app, TestRequest::post().uri("/anchors").insert_header(bearer()).set_json(rec("020000B3", 1.0)).to_request()).await;
+        assert_eq!(exists.status(), StatusCode::CONFLICT);
+        assert_eq!(reg.list(), vec![rec("020000b3", 1.0)]);
+    }
+
+    #[actix_web::test]
+    async fn writes_need_the_admin_token() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| config(cfg, server.state.clone()))).await;
+        let put = || TestRequest::put().uri("/anchors/020000b3").set_json(json!({ "x": 1.0, "y": 0.0, "height": 2.0 }));
+        assert_eq!(call_service(&app, put().to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, put().insert_header(("authorization", "Bearer wrong")).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, TestRequest::post().uri("/anchors").set_json(rec("020000b3", 1.0)).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(server.state.positioner.anchors.list().is_empty());
+
+        assert_eq!(call_service(&app, put().insert_header(bearer()).to_request()).await.status(), StatusCode::CREATED);
+        let delete = || TestRequest::delete().uri("/anchors/020000b3");
+        assert_eq!(call_service(&app, delete().to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, delete().insert_header(bearer()).to_request()).await.status(), StatusCode::NO_CONTENT);
+        // reads stay open
+        assert_eq!(call_service(&app, TestRequest::get().uri("/anchors").to_request()).await.status(), StatusCode::OK);
+
+        // 403 while no token is configured
+        server.state.keys.set_secrets(&Secrets { admin_token: None, ..test_support::secrets() });
+        let disabled: Value = call_and_read_body_json(&app, put().insert_header(bearer()).to_request()).await;
+        assert_eq!(disabled["code"], "keys_admin_disabled");
+    }
+}
@@ -1,31 +1,361 @@
+use std::collections::BTreeMap;
 use std::env;
-#[path = "../lorawan_codec.rs"]
-mod lorawan_codec;
-use lorawan_codec::decode_frame;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use pinpoint_backend::config::Config;
+use pinpoint_backend::key_store::{KeyError, KeySource, KeyStore};
+use pinpoint_backend::lorawan_codec::{decode_frame_traced, DecodeFailure, DecodeOptions, DecodedFrame, Frame, RejectedCandidate};
+use pinpoint_backend::lorawan_stream::{content_timestamp_ms, decode_with_keys};
 
 // Small CLI to help debug uplink decode issues on a server.
 // Usage:
-//   cargo run --bin decode_uplink -- <base64_ciphertext>
-// Reads env vars LORA_SECRET_KEY and LORA_SIGN_TOKEN (hex) or uses the same defaults as the server.
-fn main() {
-    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
-    if args.is_empty() {
-        eprintln!("Usage: decode_uplink <base64_ciphertext>\n\nEnvironment:\n  LORA_SECRET_KEY   32-hex AES-128 key\n  LORA_SIGN_TOKEN   16-hex HMAC key (first 8 bytes)\n");
-        std::process::exit(2);
+//   cargo run --bin decode_uplink -- <base64_ciphertext>             one frame, field-by-field breakdown
+//   cargo run --bin decode_uplink -- [--format json|csv] [FILE|-]    batch: one result per input frame
+//   --timestamp MS   content.timestamp the HMAC is bound to, for inputs that do not carry one (raw base64)
+// Batch input (FILE, or stdin when omitted / `-`), one frame per line; blank lines and `#` comments skipped:
+//   - raw base64 ciphertext
+//   - a `/v1/uwb` JSON body `{ "content": { "data", "devEui", "timestamp" } }` or a capture line (`{ "body": ... }`)
+//   - a server log line with `data_b64=...` (`LORA_LOG_RAW=1`, or `"data_b64":"..."`); a `raw_json={...}` on the same line
+//     supplies devEui and the timestamp the HMAC is bound to
+// Output goes to stdout (JSON lines by default, or CSV with a header row); the summary of counts per
+// message type and per error code goes to stderr. Exits 1 if any frame failed to decode.
+// Keys and decode options: the server's, loaded the same way (pinpoint.toml / CONFIG_FILE and the
+// environment, e.g. LORA_DECODE_FALLBACK; keys from SECRETS_FILE / SECRETS_DIR, secrets/demo.env outside
+// APP_ENV=production). Inputs with a devEui use its keys from the device key store (KEYS_FILE), as the
+// server does; the others, and a single base64 argument, use the default keys.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format { Json, Csv }
+
+fn usage() -> ! {
+    eprintln!("Usage: decode_uplink [--timestamp MS] <base64_ciphertext>\n       decode_uplink [--timestamp MS] [--format json|csv] [FILE|-]\n\nEnvironment:\n  LORA_UPLINK_SECRET_KEY   32-hex AES-128 key (fallback LORA_SECRET_KEY)\n  LORA_UPLINK_SIGN_TOKEN   hex HMAC key (fallback LORA_SIGN_TOKEN)\n  SECRETS_FILE / SECRETS_DIR   read these from a secrets file / directory instead\n  KEYS_FILE                per-device keys (device key store), default data/keys.json\n  CONFIG_FILE              decode options ([decode]), default pinpoint.toml\n");
+    std::process::exit(2);
+}
+
+/// One frame found in the input.
+struct Input {
+    line: usize,
+    /// `b64`, `body`, `capture` or `log`.
+    source: &'static str,
+    data: String,
+    dev_eui: Option<String>,
+    timestamp: Option<u128>,
+}
+
+fn is_b64(s: &str) -> bool {
+    s.len() >= 16 && s.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+}
+
+/// `data`, `devEui` and `timestamp` from a `/v1/uwb` body.
+fn from_body(line: usize, source: &'static str, body: &Value) -> Option<Input> {
+    let content = body.get("content")?;
+    Some(Input {
+        line,
+        source,
+        data: content.get("data")?.as_str()?.to_string(),
+        dev_eui: content.get("devEui").and_then(Value::as_str).map(str::to_string),
+        timestamp: content_timestamp_ms(content),
+    })
+}
+
+/// Drop ANSI colour sequences (`ESC [ ... m`) that tracing adds on a terminal.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() { if c.is_ascii_alphabetic() { break; } }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Classify one input line; `None` for blank lines, comments and lines without a frame.
+fn parse_line(line: usize, text: &str) -> Option<Input> {
+    let text = strip_ansi(text);
+    let t = text.trim();
+    if t.is_empty() || t.starts_with('#') { return None; }
+    // a JSON line that is neither a body nor a capture may still be a JSON-formatted log line
+    if t.starts_with('{') {
+        let input = serde_json::from_str::<Value>(t).ok().and_then(|v| match v.get("body") {
+            Some(body) => from_body(line, "capture", body),
+            None => from_body(line, "body", &v),
+        });
+        if input.is_some() { return input; }
+    }
+    if let Some(at) = t.find("data_b64=").map(|i| i + "data_b64=".len()).or_else(|| t.find("\"data_b64\":\"").map(|i| i + "\"data_b64\":\"".len())) {
+        // tracing prints `&str` fields quoted: data_b64="..."
+        let data: String = t[at..].trim_start_matches('"').chars().take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=')).collect();
+        // the request body logged next to it carries devEui and the HMAC timestamp
+        let body = t.find("raw_json=").and_then(|i| t[i..].find('{').map(|j| i + j)).and_then(|start| {
+            serde_json::Deserializer::from_str(&t[start..]).into_iter::<Value>().next()?.ok()
+        });
+        let mut input = body.as_ref().and_then(|b| from_body(line, "log", b)).unwrap_or(Input { line, source: "log", data: String::new(), dev_eui: None, timestamp: None });
+        input.data = data;
+        return Some(input);
+    }
+    is_b64(t).then(|| Input { line, source: "b64", data: t.to_string(), dev_eui: None, timestamp: None })
+}
+
+/// Why an input did not decode.
+enum Failure {
+    /// The key store refused its devEui (revoked keys).
+    Keys(KeyError),
+    Decode(DecodeFailure),
+}
+
+impl Failure {
+    fn code(&self) -> &'static str {
+        match self {
+            Failure::Keys(e) => e.code(),
+            Failure::Decode(f) => f.error.code(),
+        }
+    }
+
+    fn rejected(&self) -> &[RejectedCandidate] {
+        match self {
+            Failure::Keys(_) => &[],
+            Failure::Decode(f) => &f.rejected,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Keys(e) => e.fmt(f),
+            Failure::Decode(d) => d.error.fmt(f),
+        }
     }
-    let b64 = args.remove(0);
-    let secret_key = env::var("LORA_SECRET_KEY").unwrap_or_else(|_| "A60C3263B832E551EEBDDDB93D8B05EA".to_string());
-    let sign_token = env::var("LORA_SIGN_TOKEN").unwrap_or_else(|_| "3E3D4BEE7FE182D8".to_string());
+}
+
+/// Decode with the key sets the server would try for the input's devEui.
+fn decode(keys: &KeyStore, input: &Input, timestamp: Option<u128>, opts: &DecodeOptions, now: u128) -> Result<(DecodedFrame, KeySource), Failure> {
+    let sets = keys.resolve(input.dev_eui.as_deref().unwrap_or(""), now).map_err(Failure::Keys)?;
+    decode_with_keys(&input.data, input.timestamp.or(timestamp), sets, opts)
+        .map(|(df, keys)| (df, keys.source))
+        .map_err(Failure::Decode)
+}
+
+/// Per-frame result as a JSON object (also the source of the CSV columns).
+fn result_json(input: &Input, result: &Result<(DecodedFrame, KeySource), Failure>) -> Value {
+    let mut out = json!({ "line": input.line, "source": input.source, "devEui": input.dev_eui, "ok": result.is_ok() });
+    match result {
+        Ok((df, key_source)) => {
+            out["messageType"] = json!(format!("0x{:02x}", df.message_type()));
+            out["messageNumber"] = json!(df.header.message_number);
+            out["deviceId"] = json!(df.device_id().map(|d| d.hex()));
+            if let Frame::LocationReport(r) = &df.frame {
+                out["beacons"] = r.beacons.iter().map(|b| json!({ "beaconId": b.beacon_id(), "distanceCm": b.distance_cm })).collect();
+            }
+            out["mode"] = json!(df.diagnostics.mode);
+            out["layout"] = json!(df.diagnostics.layout);
+            out["score"] = json!(df.diagnostics.score);
+            out["hmac"] = serde_json::to_value(df.hmac).unwrap_or(Value::Null);
+            out["keySource"] = json!(key_source);
+        }
+        Err(f) => {
+            out["error"] = json!({ "code": f.code(), "message": f.to_string() });
+            out["rejected"] = json!(f.rejected());
+        }
+    }
+    out
+}
+
+const CSV_HEADER: &str = "line,source,dev_eui,ok,message_type,message_number,device_id,beacons,mode,layout,error_code";
 
-    match decode_frame(&b64, &secret_key, &sign_token) {
+fn csv_row(v: &Value) -> String {
+    let s = |k: &str| match &v[k] {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    // beacons as `id:cm` separated by `;` so the row stays one CSV field without quoting
+    let beacons = v["beacons"].as_array().map(|bs| bs.iter()
+        .map(|b| format!("{}:{}", b["beaconId"].as_str().unwrap_or(""), b["distanceCm"]))
+        .collect::<Vec<_>>().join(";")).unwrap_or_default();
+    let error = v["error"]["code"].as_str().unwrap_or("");
+    [s("line"), s("source"), s("devEui"), s("ok"), s("messageType"), s("messageNumber"), s("deviceId"), beacons, s("mode"), s("layout"), error.to_string()].join(",")
+}
+
+fn single(b64: &str, timestamp: Option<u128>, secret_key: &str, sign_token: &str, opts: &DecodeOptions) {
+    match decode_frame_traced(b64, secret_key, sign_token, timestamp, opts) {
         Ok(df) => {
-            println!("decode: OK  message_type=0x{:02x}", df.message_type);
-            println!("explained: {}", df.buffer_explained);
+            let d = &df.diagnostics;
+            println!("decode: OK  message_type=0x{:02x}  mode={}  layout={}  score={}", df.message_type(), d.mode, d.layout, d.score);
+            for r in &d.rejected { println!("rejected: {}", json!(r)); }
+            println!("explained: {}", df.explain());
         }
-        Err(e) => {
+        Err(f) => {
             // Print a clear error reason to match server logs
-            println!("decode: ERR  {}", e);
+            println!("decode: ERR  code={}  {}", f.error.code(), f.error);
+            for r in &f.rejected { println!("rejected: {}", json!(r)); }
             std::process::exit(1);
         }
     }
 }
+
+fn main() {
+    let mut format = Format::Json;
+    let mut timestamp: Option<u128> = None;
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--help" | "-h" => usage(),
+            "--format" => format = match args.next().as_deref() {
+                Some("json") => Format::Json,
+                Some("csv") => Format::Csv,
+                _ => usage(),
+            },
+            "--timestamp" => timestamp = Some(args.next().and_then(|t| t.parse().ok()).unwrap_or_else(|| usage())),
+            _ if a.starts_with("--timestamp=") => timestamp = Some(a["--timestamp=".len()..].parse().unwrap_or_else(|_| usage())),
+            _ if a.starts_with("--format=") => format = match &a["--format=".len()..] {
+                "json" => Format::Json,
+                "csv" => Format::Csv,
+                _ => usage(),
+            },
+            _ => positional.push(a),
+        }
+    }
+    if positional.len() > 1 { usage() }
+    let config = Config::load().unwrap_or_else(|e| { eprintln!("config: {e}"); std::process::exit(2) });
+    let secrets = config.load_secrets().unwrap_or_else(|e| { eprintln!("keys: {e}"); std::process::exit(2) });
+    let keys = KeyStore::from_config(&config.keys, &secrets);
+    let opts = config.decode;
+    let (secret_key, sign_token) = (secrets.uplink.secret_key.expose(), secrets.uplink.sign_token.expose());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+
+    let reader: Box<dyn BufRead> = match positional.first().map(String::as_str) {
+        None | Some("-") => {
+            if positional.is_empty() && io::stdin().is_terminal() { usage() }
+            Box::new(BufReader::new(io::stdin()))
+        }
+        Some(path) if std::path::Path::new(path).is_file() => Box::new(BufReader::new(File::open(path).unwrap_or_else(|e| { eprintln!("{path}: {e}"); std::process::exit(2) }))),
+        Some(b64) => return single(b64, timestamp, secret_key, sign_token, &opts),
+    };
+
+    let mut out = io::stdout().lock();
+    if format == Format::Csv { let _ = writeln!(out, "{CSV_HEADER}"); }
+    let (mut ok, mut failed) = (0u64, 0u64);
+    let mut per_type: BTreeMap<String, u64> = BTreeMap::new();
+    let mut per_error: BTreeMap<&'static str, u64> = BTreeMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let Ok(line) = line else { break };
+        let Some(input) = parse_line(i + 1, &line) else { continue };
+        let result = decode(&keys, &input, timestamp, &opts, now);
+        match &result {
+            Ok((df, _)) => { ok += 1; *per_type.entry(format!("0x{:02x}", df.message_type())).or_default() += 1; }
+            Err(f) => { failed += 1; *per_error.entry(f.code()).or_default() += 1; }
+        }
+        let v = result_json(&input, &result);
+        let row = match format { Format::Json => v.to_string(), Format::Csv => csv_row(&v) };
+        // stop quietly when piped into `head`
+        if writeln!(out, "{row}").is_err() { break; }
+    }
+
+    eprintln!("summary: frames={} ok={} failed={}", ok + failed, ok, failed);
+    for (t, n) in &per_type { eprintln!("  type  {t:<24} {n}"); }
+    for (code, n) in &per_error { eprintln!("  error {code:<24} {n}"); }
+    if failed > 0 { std::process::exit(1); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pinpoint_backend::key_store::{DeviceKeys, KeyPair};
+    use pinpoint_backend::lorawan_codec::{Battery, Beacon, DeviceId, HmacBinding, LocationReport, Motion, Uplink};
+
+    const SECRET_KEY: &str = "3BA16CA4D2BE9EB96147779B32182750";
+    const SIGN_TOKEN: &str = "7AE4AF8AAD3BD554";
+    const TS: u128 = 1_718_000_000_123;
+
+    fn ciphertext() -> String {
+        ciphertext_with(SIGN_TOKEN, SECRET_KEY)
+    }
+
+    fn ciphertext_with(sign_token: &str, secret_key: &str) -> String {
+        let report = LocationReport::new(DeviceId(0xa0ba3e29), Motion::Moving, vec![Beacon { major: 0x0200, minor: 0x00b3, distance_cm: 945, battery: Battery(90) }]);
+        Uplink::new(42, Frame::LocationReport(report)).encrypt(HmacBinding::Timestamp(TS), sign_token, secret_key).unwrap()
+    }
+
+    fn pair(secret_key: &str, sign_token: &str) -> KeyPair {
+        KeyPair { secret_key: secret_key.into(), sign_token: sign_token.into() }
+    }
+
+    fn demo_keys() -> KeyStore {
+        KeyStore::in_memory(pair(SECRET_KEY, SIGN_TOKEN), pair(SECRET_KEY, SIGN_TOKEN))
+    }
+
+    fn fields(input: &Input) -> (&str, &str, Option<&str>, Option<u128>) {
+        (input.source, input.data.as_str(), input.dev_eui.as_deref(), input.timestamp)
+    }
+
+    #[test]
+    fn raw_base64_lines() {
+        let data = ciphertext();
+        assert_eq!(fields(&parse_line(3, &format!("  {data}  ")).unwrap()), ("b64", data.as_str(), None, None));
+        assert!(parse_line(1, "").is_none());
+        assert!(parse_line(2, "# comment").is_none());
+        assert!(parse_line(4, "not a frame").is_none());
+    }
+
+    #[test]
+    fn uplink_bodies_and_capture_lines() {
+        let data = ciphertext();
+        let body = json!({ "content": { "data": data, "devEui": "00956900A0BA3E29", "timestamp": TS.to_string() } });
+        assert_eq!(fields(&parse_line(1, &body.to_string()).unwrap()), ("body", data.as_str(), Some("00956900A0BA3E29"), Some(TS)));
+        let capture = json!({ "receivedAt": 1, "peer": "10.0.0.1", "body": body });
+        assert_eq!(fields(&parse_line(2, &capture.to_string()).unwrap()).0, "capture");
+        assert!(parse_line(3, r#"{"content":{"devEui":"x"}}"#).is_none());
+    }
+
+    #[test]
+    fn server_log_lines_with_ansi_codes() {
+        let data = ciphertext();
+        let raw_json = json!({ "content": { "data": data, "devEui": "00956900A0BA3E29", "timestamp": TS } });
+        let line = format!("\x1b[2m2026-10-17T00:00:00Z\x1b[0m \x1b[32m INFO\x1b[0m lorawan raw body \x1b[3mraw_json\x1b[0m\x1b[2m=\x1b[0m{raw_json} \x1b[3mdata_b64\x1b[0m\x1b[2m=\x1b[0m\"{data}\"");
+        assert_eq!(fields(&parse_line(7, &line).unwrap()), ("log", data.as_str(), Some("00956900A0BA3E29"), Some(TS)));
+        // JSON log format, without a body next to it
+        let json_line = format!(r#"{{"level":"INFO","fields":{{"message":"lorawan raw body","data_b64":"{data}"}}}}"#);
+        assert_eq!(fields(&parse_line(8, &json_line).unwrap()), ("log", data.as_str(), None, None));
+    }
+
+    #[test]
+    fn csv_row_matches_header() {
+        let mut input = parse_line(5, &json!({ "content": { "data": ciphertext(), "devEui": "00956900A0BA3E29", "timestamp": TS } }).to_string()).unwrap();
+        let decoded = decode(&demo_keys(), &input, None, &DecodeOptions::default(), TS);
+        let row = csv_row(&result_json(&input, &decoded));
+        assert_eq!(row.split(',').count(), CSV_HEADER.split(',').count());
+        assert!(row.starts_with("5,body,00956900A0BA3E29,true,0x05,42,a0ba3e29,020000b3:945,"), "{row}");
+        assert!(row.ends_with(','), "no error code: {row}");
+
+        input.data = "%%%%".into();
+        let failed = decode(&demo_keys(), &input, None, &DecodeOptions::default(), TS);
+        assert!(csv_row(&result_json(&input, &failed)).ends_with(",false,,,,,,,bad_base64"));
+    }
+
+    #[test]
+    fn inputs_with_a_dev_eui_use_its_keys() {
+        const DEVICE_KEY: &str = "00112233445566778899AABBCCDDEEFF";
+        const DEVICE_TOKEN: &str = "0123456789ABCDEF";
+        let keys = demo_keys();
+        keys.put("00956900A0BA3E29", DeviceKeys { uplink: pair(DEVICE_KEY, DEVICE_TOKEN), downlink: None }, None, TS).unwrap();
+        let body = json!({ "content": { "data": ciphertext_with(DEVICE_TOKEN, DEVICE_KEY), "devEui": "00956900A0BA3E29", "timestamp": TS } });
+        let mut input = parse_line(1, &body.to_string()).unwrap();
+        let result = result_json(&input, &decode(&keys, &input, None, &DecodeOptions::default(), TS));
+        assert_eq!((&result["ok"], &result["keySource"]), (&json!(true), &json!("device")));
+
+        // without a devEui only the default keys are tried
+        input.dev_eui = None;
+        assert!(decode(&keys, &input, None, &DecodeOptions::default(), TS).is_err());
+        keys.revoke("00956900A0BA3E29", TS).unwrap();
+        input.dev_eui = Some("00956900A0BA3E29".into());
+        let revoked = decode(&keys, &input, None, &DecodeOptions::default(), TS).map(|_| ()).unwrap_err();
+        assert_eq!(revoked.code(), "device_key_revoked");
+    }
+}
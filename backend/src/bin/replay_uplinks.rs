@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+use pinpoint_backend::capture::{read_records, CaptureRecord};
+use pinpoint_backend::config::Config;
+use pinpoint_backend::key_store::KeyStore;
+use pinpoint_backend::lorawan_codec::DecodeOptions;
+use pinpoint_backend::lorawan_stream::{content_timestamp_ms, decode_with_keys};
+
+// Replays a raw uplink capture (CAPTURE_FILE, see capture.rs) either against a server or straight
+// into the decoder, so field traffic can be reproduced offline.
+// Usage:
+//   cargo run --bin replay_uplinks -- data/capture/uplinks.jsonl.1 data/capture/uplinks.jsonl [--url URL] ...
+// Options:
+//   --url          re-POST each captured body to this endpoint (default http://localhost:8080/v1/uwb)
+//   --decode       decode locally with the server's decoder instead of POSTing
+//   --timing       original | compressed | none: gaps between records (default compressed when POSTing,
+//                  none with --decode)
+//   --factor       compressed timing: divide each gap by this (default 10)
+//   --maxGapMs     compressed timing: cap each gap at this many ms (default 2000)
+//   --quiet        only print errors and the final summary
+// Records from all files are replayed in receive order. The captured content.timestamp is sent as is,
+// so a server receiving an old capture needs LORA_TS_SKEW_MS=0 (and a restart to clear its replay guard
+// if it already saw the frames).
+// Keys and decode options (--decode): the server's, loaded the same way (pinpoint.toml / CONFIG_FILE and
+// the environment; keys from SECRETS_FILE / SECRETS_DIR, secrets/demo.env outside APP_ENV=production).
+// Each record is decoded with the keys the device key store (KEYS_FILE) held for its devEui when it was
+// received, falling back to the default keys, as the server does.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Timing { Original, Compressed, None }
+
+struct Opts {
+    files: Vec<String>,
+    url: Option<String>,
+    timing: Timing,
+    factor: f64,
+    max_gap: Duration,
+    quiet: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: replay_uplinks <capture.jsonl>... [--url URL | --decode] [--timing original|compressed|none]\n                      [--factor N] [--maxGapMs MS] [--quiet]\n\nEnvironment (--decode):\n  LORA_UPLINK_SECRET_KEY   32-hex AES-128 key (fallback LORA_SECRET_KEY)\n  LORA_UPLINK_SIGN_TOKEN   hex HMAC key (fallback LORA_SIGN_TOKEN)\n  SECRETS_FILE / SECRETS_DIR   read these from a secrets file / directory instead\n  KEYS_FILE                per-device keys (device key store), default data/keys.json\n  CONFIG_FILE              decode options ([decode]), default pinpoint.toml\n");
+    std::process::exit(2);
+}
+
+/// Positional file names plus `--key value` / `--key=value` pairs; bare `--flag` maps to "1".
+fn parse_args() -> (Vec<String>, HashMap<String, String>) {
+    let mut files = Vec::new();
+    let mut out = HashMap::new();
+    let mut args = env::args().skip(1).peekable();
+    while let Some(a) = args.next() {
+        let Some(key) = a.strip_prefix("--") else { files.push(a); continue };
+        if key == "help" { usage() }
+        if let Some((k, v)) = key.split_once('=') {
+            out.insert(k.to_string(), v.to_string());
+        } else if key != "decode" && key != "quiet" && args.peek().is_some_and(|n| !n.starts_with("--")) {
+            out.insert(key.to_string(), args.next().unwrap_or_default());
+        } else {
+            out.insert(key.to_string(), "1".to_string());
+        }
+    }
+    (files, out)
+}
+
+fn opts_from_args() -> Opts {
+    let (files, q) = parse_args();
+    if files.is_empty() { usage() }
+    let f = |k: &str, d: f64| q.get(k).map(|s| s.parse::<f64>().unwrap_or_else(|_| { eprintln!("--{k}: not a number: {s}"); usage() })).unwrap_or(d);
+    let decode = q.contains_key("decode");
+    if decode && q.contains_key("url") {
+        eprintln!("--url and --decode are exclusive");
+        usage()
+    }
+    let timing = match q.get("timing").map(String::as_str) {
+        None if decode => Timing::None,
+        None | Some("compressed") => Timing::Compressed,
+        Some("original") => Timing::Original,
+        Some("none") => Timing::None,
+        Some(other) => { eprintln!("--timing: expected original|compressed|none, got {other}"); usage() }
+    };
+    let factor = f("factor", 10.0);
+    if factor <= 0.0 { eprintln!("--factor must be > 0"); usage() }
+    Opts {
+        files,
+        url: (!decode).then(|| q.get("url").cloned().unwrap_or_else(|| "http://localhost:8080/v1/uwb".to_string())),
+        timing,
+        factor,
+        max_gap: Duration::from_millis(f("maxGapMs", 2000.0).max(0.0) as u64),
+        quiet: q.contains_key("quiet"),
+    }
+}
+
+/// Wait before the next record, given the receive-time gap to the previous one.
+fn gap(o: &Opts, prev: Option<u128>, at: u128) -> Duration {
+    let Some(prev) = prev else { return Duration::ZERO };
+    let ms = at.saturating_sub(prev) as f64;
+    match o.timing {
+        Timing::None => Duration::ZERO,
+        Timing::Original => Duration::from_millis(ms as u64),
+        Timing::Compressed => Duration::from_millis((ms / o.factor) as u64).min(o.max_gap),
+    }
+}
+
+/// POST one captured body; `Err(reason)` when the request fails or the server reports an error.
+async fn post(client: &reqwest::Client, url: &str, rec: &CaptureRecord) -> Result<String, String> {
+    let resp = client.post(url).json(&rec.body).send().await.map_err(|e| format!("http error: {e}"))?;
+    let status = resp.status();
+    let reply: serde_json::Value = resp.json().await.unwrap_or(serde_json::Value::Null);
+    match reply.get("error").filter(|e| !e.is_null()) {
+        Some(err) => Err(format!("{} {}", status.as_u16(), err)),
+        None if !status.is_success() => Err(status.as_u16().to_string()),
+        None if reply["duplicate"] == true => Ok(format!("{} duplicate", status.as_u16())),
+        None => Ok(status.as_u16().to_string()),
+    }
+}
+
+/// Decode one captured body locally; `Ok` carries the message type, `Err` the key store or codec error code.
+fn decode(rec: &CaptureRecord, keys: &KeyStore, opts: &DecodeOptions) -> Result<String, String> {
+    let content = rec.body.get("content").cloned().unwrap_or(serde_json::Value::Null);
+    let data = rec.data_b64().ok_or_else(|| "missing_data".to_string())?;
+    let dev_eui = content.get("devEui").and_then(|v| v.as_str()).unwrap_or("");
+    let sets = keys.resolve(dev_eui, rec.received_at).map_err(|e| e.code().to_string())?;
+    decode_with_keys(data, content_timestamp_ms(&content), sets, opts)
+        .map(|(df, _)| format!("0x{:02x}", df.message_type()))
+        .map_err(|f| f.error.code().to_string())
+}
+
+#[tokio::main]
+async fn main() {
+    let o = opts_from_args();
+    let mut records = Vec::new();
+    let mut unreadable = 0u64;
+    for path in &o.files {
+        let file = File::open(path).unwrap_or_else(|e| { eprintln!("{path}: {e}"); std::process::exit(2) });
+        for r in read_records(BufReader::new(file)) {
+            match r {
+                Ok(rec) => records.push(rec),
+                Err(e) => { eprintln!("{path}: {e}"); unreadable += 1 }
+            }
+        }
+    }
+    // stable: records with equal receive times keep file order
+    records.sort_by_key(|r| r.received_at);
+    let target = o.url.as_deref().unwrap_or("decode_frame");
+    println!("replaying {} record(s) from {} file(s) -> {}", records.len(), o.files.len(), target);
+
+    let client = reqwest::Client::new();
+    // only --decode needs the configuration (keys, decode options); a plain re-POST runs without it
+    let decoder = o.url.is_none().then(|| {
+        let config = Config::load().unwrap_or_else(|e| { eprintln!("config: {e}"); std::process::exit(2) });
+        let secrets = config.load_secrets().unwrap_or_else(|e| { eprintln!("keys: {e}"); std::process::exit(2) });
+        (KeyStore::from_config(&config.keys, &secrets), config.decode)
+    });
+    let (mut ok, mut failed) = (0u64, 0u64);
+    let mut outcomes: BTreeMap<String, u64> = BTreeMap::new();
+    let mut prev = None;
+    for rec in &records {
+        let wait = gap(&o, prev, rec.received_at);
+        prev = Some(rec.received_at);
+        if !wait.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+        let dev_eui = rec.body.pointer("/content/devEui").and_then(|v| v.as_str()).unwrap_or("-");
+        let result = match &o.url {
+            Some(url) => post(&client, url, rec).await,
+            None => {
+                let (keys, opts) = decoder.as_ref().expect("config loaded for --decode");
+                decode(rec, keys, opts)
+            }
+        };
+        match result {
+            Ok(detail) => {
+                ok += 1;
+                if !o.quiet { println!("{} {} -> {}", rec.received_at, dev_eui, detail); }
+                if o.url.is_none() { *outcomes.entry(detail).or_default() += 1; }
+            }
+            Err(reason) => {
+                failed += 1;
+                eprintln!("{} {} -> {}", rec.received_at, dev_eui, reason);
+                if o.url.is_none() { *outcomes.entry(reason).or_default() += 1; }
+            }
+        }
+    }
+    let breakdown = outcomes.iter().map(|(k, n)| format!(" {k}={n}")).collect::<String>();
+    println!("done: ok={ok} failed={failed} unreadable={unreadable}{breakdown}");
+    if failed > 0 || unreadable > 0 { std::process::exit(1); }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::prelude::*;
+use serde_json::json;
+use pinpoint_backend::lorawan_codec::{
+    Battery, Beacon, DeviceId, Frame, HmacBinding, LocationReport, Motion, Registration, StatusReport, Uplink,
+};
+use pinpoint_backend::config::Config;
+use pinpoint_backend::key_store::KeyPair;
+use pinpoint_backend::positioning::Anchor;
+
+// Device simulator: N virtual tags walk a trajectory, range to the configured anchors and POST
+// real encrypted uplinks (0x01 at start / periodically, 0x05 every tick, 0x03 periodically) to /v1/uwb,
+// so the full decrypt -> parse -> broadcast path runs in demos.
+// Usage:
+//   cargo run --bin simulate_tags -- [--url http://localhost:8080/v1/uwb] [--tags 3] [--intervalMs 600] ...
+// Options (same names as the /mock/stream query where they overlap):
+//   --url          ingestion endpoint (default http://localhost:8080/v1/uwb)
+//   --tags         number of virtual tags (default 1)
+//   --intervalMs   time between location reports per tag (default 600)
+//   --count        reports per tag before exiting, 0 = forever (default 0)
+//   --trajectory   path | circle | random (default path, the /mock/stream waypoints)
+//   --speed        tag speed in m/s (default 1.0; 0 = stand still, reported as "No Movement")
+//   --w --h        room size in meters (default 20 x 10)
+//   --anchors      id:x:y[:z],... beacon IDs are major||minor hex (default the three /mock/stream corners)
+//   --az --tz      anchor / tag height in meters (default 1.5 / 1.0)
+//   --noise --outlierRate --outlierScale --dropRate --zeroRate   range perturbations, as in /mock/stream
+//   --statusEvery  send 0x03 every N reports (default 20, 0 = never)
+//   --registerEvery  resend 0x01 every N reports (default 0 = only at start)
+//   --sign         timestamp | payload: HMAC input (default timestamp, the downlink scheme)
+//   --quiet        only print errors and the final summary
+// Keys: the server's default uplink keys, loaded the same way (pinpoint.toml / CONFIG_FILE, environment,
+// SECRETS_FILE / SECRETS_DIR, secrets/demo.env outside APP_ENV=production).
+
+#[derive(Clone, Copy, PartialEq)]
+enum Trajectory { Path, Circle, Random }
+
+struct Opts {
+    url: String,
+    tags: u32,
+    interval: Duration,
+    count: u64,
+    trajectory: Trajectory,
+    speed: f64,
+    width: f64,
+    height: f64,
+    anchors: Vec<Anchor>,
+    tag_z: f64,
+    noise: f64,
+    outlier_rate: f64,
+    outlier_scale: f64,
+    drop_rate: f64,
+    zero_rate: f64,
+    status_every: u64,
+    register_every: u64,
+    sign_with_timestamp: bool,
+    quiet: bool,
+    uplink: KeyPair,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: simulate_tags [--url URL] [--tags N] [--intervalMs MS] [--count N] [--trajectory path|circle|random] [--speed M_S]\n                     [--w M] [--h M] [--anchors id:x:y[:z],...] [--az M] [--tz M]\n                     [--noise M] [--outlierRate P] [--outlierScale F] [--dropRate P] [--zeroRate P]\n                     [--statusEvery N] [--registerEvery N] [--sign timestamp|payload] [--quiet]\n\nEnvironment:\n  LORA_UPLINK_SECRET_KEY   32-hex AES-128 key (fallback LORA_SECRET_KEY)\n  LORA_UPLINK_SIGN_TOKEN   hex HMAC key (fallback LORA_SIGN_TOKEN)\n  SECRETS_FILE / SECRETS_DIR   read these from a secrets file / directory instead\n  CONFIG_FILE              configuration file, default pinpoint.toml\n");
+    std::process::exit(2);
+}
+
+/// `--key value` / `--key=value` pairs; bare `--flag` maps to "1".
+fn parse_args() -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let mut args = env::args().skip(1).peekable();
+    while let Some(a) = args.next() {
+        let Some(key) = a.strip_prefix("--") else { usage() };
+        if key == "help" { usage() }
+        if let Some((k, v)) = key.split_once('=') {
+            out.insert(k.to_string(), v.to_string());
+        } else if args.peek().is_some_and(|n| !n.starts_with("--")) {
+            out.insert(key.to_string(), args.next().unwrap_or_default());
+        } else {
+            out.insert(key.to_string(), "1".to_string());
+        }
+    }
+    out
+}
+
+fn opts_from_args() -> Opts {
+    let q = parse_args();
+    let f = |k: &str, d: f64| q.get(k).map(|s| s.parse::<f64>().unwrap_or_else(|_| { eprintln!("--{k}: not a number: {s}"); usage() })).unwrap_or(d);
+    let n = |k: &str, d: u64| q.get(k).map(|s| s.parse::<u64>().unwrap_or_else(|_| { eprintln!("--{k}: not an integer: {s}"); usage() })).unwrap_or(d);
+    let width = f("w", 20.0);
+    let height = f("h", 10.0);
+    let az = f("az", 1.5);
+    let anchors = match q.get("anchors") {
+        Some(list) => Anchor::parse_list(list, az).unwrap_or_else(|bad| { eprintln!("--anchors: bad entry {bad:?}"); usage() }),
+        None => [("020000b3", 0.0, 0.0), ("02000053", width, 0.0), ("020000e6", 0.0, height)]
+            .iter()
+            .filter_map(|(id, x, y)| Anchor::parse_spec(&format!("{id}:{x}:{y}"), az))
+            .collect(),
+    };
+    let trajectory = match q.get("trajectory").map(String::as_str) {
+        None | Some("path") => Trajectory::Path,
+        Some("circle") => Trajectory::Circle,
+        Some("random") => Trajectory::Random,
+        Some(other) => { eprintln!("--trajectory: unknown {other:?}"); usage() }
+    };
+    let sign_with_timestamp = match q.get("sign").map(String::as_str) {
+        None | Some("timestamp") => true,
+        Some("payload") => false,
+        Some(other) => { eprintln!("--sign: unknown {other:?}"); usage() }
+    };
+    Opts {
+        url: q.get("url").cloned().unwrap_or_else(|| "http://localhost:8080/v1/uwb".to_string()),
+        tags: n("tags", 1).max(1) as u32,
+        interval: Duration::from_millis(n("intervalMs", 600).max(1)),
+        count: n("count", 0),
+        trajectory,
+        speed: f("speed", 1.0),
+        width,
+        height,
+        anchors,
+        tag_z: f("tz", 1.0),
+        noise: f("noise", 0.0),
+        outlier_rate: f("outlierRate", 0.0),
+        outlier_scale: f("outlierScale", 1.8),
+        drop_rate: f("dropRate", 0.0),
+        zero_rate: f("zeroRate", 0.0),
+        status_every: n("statusEvery", 20),
+        register_every: n("registerEvery", 0),
+        sign_with_timestamp,
+        quiet: q.contains_key("quiet"),
+        uplink: Config::load().unwrap_or_else(|e| { eprintln!("config: {e}"); std::process::exit(2) })
+            .load_secrets().unwrap_or_else(|e| { eprintln!("keys: {e}"); std::process::exit(2) }).uplink,
+    }
+}
+
+/// Same waypoints as `/mock/stream`.
+fn path_waypoints(width: f64, height: f64) -> Vec<(f64, f64)> {
+    let (cx, cy) = (width / 2.0, height / 2.0);
+    vec![
+        (cx, cy), (0.0, cy), (width, cy), (cx, cy), (cx, height), (cx, 0.0), (cx, cy),
+        (0.0, cy), (0.0, height), (width, height), (width, 0.0), (0.0, 0.0), (cx, cy),
+    ]
+}
+
+/// One virtual tag: position along its trajectory plus its radio state.
+struct Tag {
+    device_id: DeviceId,
+    dev_eui: String,
+    message_number: u16,
+    battery: f64,
+    pos: (f64, f64),
+    // polyline targets (path / random) or angle (circle)
+    waypoints: Vec<(f64, f64)>,
+    next: usize,
+    angle: f64,
+}
+
+impl Tag {
+    fn new(i: u32, o: &Opts, rng: &mut impl Rng) -> Self {
+        let device_id = DeviceId(0xA0BA_3E29u32.wrapping_add(i));
+        let waypoints = match o.trajectory {
+            Trajectory::Path => path_waypoints(o.width, o.height),
+            _ => Vec::new(),
+        };
+        // spread tags along the path / circle so they don't overlap
+        let next = if waypoints.is_empty() { 0 } else { (i as usize * 3) % waypoints.len() };
+        let pos = match o.trajectory {
+            Trajectory::Path => waypoints[next],
+            Trajectory::Random => (rng.gen_range(0.0..=o.width), rng.gen_range(0.0..=o.height)),
+            Trajectory::Circle => (o.width / 2.0, o.height / 2.0),
+        };
+        Tag {
+            device_id,
+            dev_eui: format!("00956900{:08X}", device_id.0),
+            message_number: rng.gen(),
+            battery: 100.0,
+            pos,
+            waypoints,
+            next,
+            angle: std::f64::consts::TAU * f64::from(i) / f64::from(o.tags),
+        }
+    }
+
+    /// Advance `dist` meters along the trajectory.
+    fn step(&mut self, o: &Opts, dist: f64, rng: &mut impl Rng) {
+        match o.trajectory {
+            Trajectory::Circle => {
+                let r = (o.width.min(o.height) / 3.0).max(0.1);
+                self.angle += dist / r;
+                self.pos = (o.width / 2.0 + r * self.angle.cos(), o.height / 2.0 + r * self.angle.sin());
+            }
+            Trajectory::Path | Trajectory::Random => {
+                let mut left = dist;
+                // bail out on degenerate (zero-size) rooms where every hop has length 0
+                let mut zero_hops = 0;
+                while left > 0.0 && zero_hops <= self.waypoints.len() + 1 {
+                    if self.waypoints.is_empty() || self.next >= self.waypoints.len() {
+                        // random waypoint model: pick a new target once the previous one is reached
+                        self.waypoints = vec![(rng.gen_range(0.0..=o.width), rng.gen_range(0.0..=o.height))];
+                        self.next = 0;
+                    }
+                    let target = self.waypoints[self.next];
+                    let (dx, dy) = (target.0 - self.pos.0, target.1 - self.pos.1);
+                    let d = (dx * dx + dy * dy).sqrt();
+                    if d <= left {
+                        if d == 0.0 { zero_hops += 1 } else { zero_hops = 0 }
+                        self.pos = target;
+                        left -= d;
+                        self.next += 1;
+                        if o.trajectory == Trajectory::Path { self.next %= self.waypoints.len(); }
+                    } else {
+                        self.pos = (self.pos.0 + dx / d * left, self.pos.1 + dy / d * left);
+                        left = 0.0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ranges to every anchor with the `/mock/stream` perturbations; `None` if all were dropped.
+    fn location_report(&self, o: &Opts, rng: &mut impl Rng) -> Option<LocationReport> {
+        let mut beacons = Vec::with_capacity(o.anchors.len());
+        for a in &o.anchors {
+            if o.drop_rate > 0.0 && rng.gen::<f64>() < o.drop_rate { continue; }
+            let mut d_m = ((a.x - self.pos.0).powi(2) + (a.y - self.pos.1).powi(2) + (a.z - o.tag_z).powi(2)).sqrt();
+            if o.zero_rate > 0.0 && rng.gen::<f64>() < o.zero_rate { d_m = rng.gen_range(0.0..0.10); }
+            if o.noise > 0.0 { d_m += rng.gen_range(-o.noise..o.noise); }
+            if o.outlier_rate > 0.0 && rng.gen::<f64>() < o.outlier_rate { d_m *= o.outlier_scale; }
+            let cm = (d_m.max(0.0) * 100.0).round().min(f64::from(u16::MAX)) as u16;
+            // beacon IDs are validated hex by Anchor::parse_spec
+            let id = u32::from_str_radix(&a.beacon_id, 16).unwrap_or_default();
+            beacons.push(Beacon { major: (id >> 16) as u16, minor: (id & 0xFFFF) as u16, distance_cm: cm, battery: Battery(100) });
+        }
+        if beacons.is_empty() { return None; }
+        let motion = if o.speed > 0.0 { Motion::Moving } else { Motion::Still };
+        Some(LocationReport::new(self.device_id, motion, beacons))
+    }
+
+    fn registration(&self, o: &Opts) -> Registration {
+        Registration {
+            device_id: self.device_id,
+            version_type: 0x0201,
+            min_tx_period: (o.interval.as_secs().clamp(1, 255)) as u8,
+            motion_assist: 1,
+            beacon_search_timeout: 1,
+            beacon_search_quantity: o.anchors.len().min(255) as u8,
+        }
+    }
+
+    fn status(&self) -> StatusReport {
+        StatusReport {
+            rfid_uid: self.device_id.0.to_be_bytes(),
+            abnormal: 0,
+            battery: Battery(self.battery.round() as u8),
+            config_version: 0,
+            reserved: vec![0x00],
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals { sent: u64, failed: u64, skipped: u64 }
+
+async fn post(client: &reqwest::Client, o: &Opts, tag: &mut Tag, frame: Frame) -> bool {
+    tag.message_number = tag.message_number.wrapping_add(1);
+    let up = Uplink::new(tag.message_number, frame);
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let binding = if o.sign_with_timestamp { HmacBinding::Timestamp(ts) } else { HmacBinding::Payload };
+    let data = match up.encrypt(binding, o.uplink.sign_token.expose(), o.uplink.secret_key.expose()) {
+        Ok(d) => d,
+        Err(e) => { eprintln!("encode: ERR  code={}  {}", e.code(), e); std::process::exit(1); }
+    };
+    let body = json!({ "content": { "data": data, "devEui": tag.dev_eui, "fPort": 10, "timestamp": ts } });
+    let msg_type = up.frame.message_type().unwrap_or(0);
+    match client.post(&o.url).json(&body).send().await {
+        Ok(resp) => {
+            let status = resp.status();
+            let reply: serde_json::Value = resp.json().await.unwrap_or(serde_json::Value::Null);
+            let err = reply.get("error").filter(|e| !e.is_null());
+            if !status.is_success() || err.is_some() {
+                eprintln!("{} #{} 0x{:02x} -> {} {}", tag.device_id, tag.message_number, msg_type, status.as_u16(), err.map(|e| e.to_string()).unwrap_or_default());
+                return false;
+            }
+            if !o.quiet {
+                println!("{} #{} 0x{:02x} ({:.2}, {:.2}) -> {}", tag.device_id, tag.message_number, msg_type, tag.pos.0, tag.pos.1, status.as_u16());
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("{} #{} 0x{:02x} -> http error: {}", tag.device_id, tag.message_number, msg_type, e);
+            false
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let o = opts_from_args();
+    let client = reqwest::Client::new();
+    let mut rng = StdRng::from_entropy();
+    let mut tags: Vec<Tag> = (0..o.tags).map(|i| Tag::new(i, &o, &mut rng)).collect();
+    let mut totals = Totals::default();
+    println!("simulating {} tag(s), {} anchor(s), every {:?} -> {}", o.tags, o.anchors.len(), o.interval, o.url);
+
+    let mut tick = tokio::time::interval(o.interval);
+    let step_m = o.speed * o.interval.as_secs_f64();
+    let mut n: u64 = 0;
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        for tag in tags.iter_mut() {
+            let mut frames = Vec::new();
+            if n == 0 || (o.register_every > 0 && n.is_multiple_of(o.register_every)) {
+                frames.push(Frame::Registration(tag.registration(&o)));
+            }
+            if o.status_every > 0 && n.is_multiple_of(o.status_every) {
+                frames.push(Frame::Status(tag.status()));
+            }
+            tag.step(&o, step_m, &mut rng);
+            // ~1% per 100 reports so long runs show a falling battery
+            tag.battery = (tag.battery - 0.01).max(0.0);
+            match tag.location_report(&o, &mut rng) {
+                Some(report) => frames.push(Frame::LocationReport(report)),
+                None => totals.skipped += 1,
+            }
+            for frame in frames {
+                if post(&client, &o, tag, frame).await { totals.sent += 1 } else { totals.failed += 1 }
+            }
+        }
+        n += 1;
+        if o.count > 0 && n >= o.count { break; }
+    }
+    println!("done: sent={} failed={} skipped(all beacons dropped)={}", totals.sent, totals.failed, totals.skipped);
+    if totals.failed > 0 { std::process::exit(1); }
+}
@@ -0,0 +1,211 @@
+//! Raw uplink capture: every `POST /v1/uwb` request as one JSON line, for replay with `replay_uplinks`.
+//!
+//! Enabled by `capture.file` (`CAPTURE_FILE`, e.g. `data/capture/uplinks.jsonl`). When the file would
+//! grow past `capture.max_bytes` (default 64 MiB) it is rotated to `<file>.1`, older rotations shift up,
+//! and at most `capture.keep` (default 5) rotated files are kept.
+//!
+//! Each line is a `CaptureRecord`: receive time, peer address, request headers (credentials redacted)
+//! and the JSON body exactly as received, including requests that later fail to decode.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info};
+use crate::config::CaptureConfig;
+
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+/// Header values replaced by `<redacted>` in captures.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "x-api-key", "proxy-authorization"];
+
+/// One captured request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureRecord {
+    /// Server receive time, epoch ms.
+    pub received_at: u128,
+    pub peer: String,
+    /// Lower-case header names; credentials are redacted.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: Value,
+}
+
+impl CaptureRecord {
+    pub fn new<'a>(received_at: u128, peer: &str, headers: impl IntoIterator<Item = (&'a str, &'a [u8])>, body: Value) -> Self {
+        let headers = headers.into_iter()
+            .map(|(k, v)| {
+                let k = k.to_ascii_lowercase();
+                let v = if REDACTED_HEADERS.contains(&k.as_str()) { "<redacted>".to_string() } else { String::from_utf8_lossy(v).into_owned() };
+                (k, v)
+            })
+            .collect();
+        CaptureRecord { received_at, peer: peer.to_string(), headers, body }
+    }
+
+    /// `body.content.data`, the base64 ciphertext.
+    pub fn data_b64(&self) -> Option<&str> {
+        self.body.pointer("/content/data").and_then(Value::as_str)
+    }
+}
+
+/// Read capture lines from `reader`; blank lines are skipped, bad lines are returned as errors
+/// with their 1-based line number.
+pub fn read_records(reader: impl BufRead) -> impl Iterator<Item = Result<CaptureRecord, String>> {
+    reader.lines().enumerate().filter_map(|(i, line)| match line {
+        Ok(l) if l.trim().is_empty() => None,
+        Ok(l) => Some(serde_json::from_str(&l).map_err(|e| format!("line {}: {e}", i + 1))),
+        Err(e) => Some(Err(format!("line {}: {e}", i + 1))),
+    })
+}
+
+struct Current {
+    file: File,
+    len: u64,
+}
+
+/// Rotating JSONL writer shared by all workers; `path == None` means disabled.
+pub struct CaptureWriter {
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    keep: usize,
+    current: Mutex<Option<Current>>,
+}
+
+impl CaptureWriter {
+    pub fn disabled() -> Self {
+        CaptureWriter { path: None, max_bytes: DEFAULT_MAX_BYTES, keep: DEFAULT_KEEP, current: Mutex::new(None) }
+    }
+
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> Self {
+        CaptureWriter { path: Some(path.into()), max_bytes: max_bytes.max(1), keep, current: Mutex::new(None) }
+    }
+
+    /// Writer described by `[capture]`; an empty `file` disables it.
+    pub fn from_config(config: &CaptureConfig) -> Self {
+        let path = config.file.trim();
+        if path.is_empty() { return Self::disabled() }
+        info!(path = %path, max_bytes = config.max_bytes, keep = config.keep, "raw uplink capture enabled");
+        Self::new(path, config.max_bytes, config.keep)
+    }
+
+    pub fn is_enabled(&self) -> bool { self.path.is_some() }
+
+    /// Append one record, rotating first if it would not fit.
+    pub fn write(&self, record: &CaptureRecord) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+        if current.as_ref().is_some_and(|c| c.len > 0 && c.len + line.len() as u64 > self.max_bytes) {
+            *current = None;
+            rotate(path, self.keep)?;
+        }
+        if current.is_none() {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let len = file.metadata()?.len();
+            *current = Some(Current { file, len });
+        }
+        let c = current.as_mut().expect("capture file just opened");
+        c.file.write_all(&line)?;
+        c.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// `write`, logging instead of failing: capture must never break ingestion.
+    pub fn record(&self, record: &CaptureRecord) {
+        if let Err(e) = self.write(record) {
+            metrics::counter!("uwb.capture.write_err").increment(1);
+            error!(error = %e, "raw capture write failed");
+        }
+    }
+}
+
+/// `<path>.(keep-1)` -> `<path>.keep`, ..., `<path>` -> `<path>.1`; the oldest beyond `keep` is deleted.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    match fs::remove_file(rotated(keep)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for n in (1..keep).rev() {
+        match fs::rename(rotated(n), rotated(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::json;
+    use crate::test_support::{self, Server, DEVICE, DEV_EUI};
+
+    fn record(n: u128) -> CaptureRecord {
+        CaptureRecord::new(n, "10.0.0.7:5000", [("Content-Type", &b"application/json"[..]), ("Authorization", &b"Bearer x"[..])],
+            json!({ "content": { "data": "AAAA", "devEui": "009569000004C21E", "timestamp": n } }))
+    }
+
+    fn read(path: &Path) -> Vec<CaptureRecord> {
+        read_records(io::BufReader::new(File::open(path).unwrap())).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uplinks.jsonl");
+        let line_len = serde_json::to_vec(&record(1)).unwrap().len() as u64 + 1;
+        let w = CaptureWriter::new(&path, line_len * 2, 2);
+        for n in 1..=7 { w.write(&record(n)).unwrap(); }
+        // 7 lines, 2 per file: current holds 7, .1 holds 5-6, .2 holds 3-4, 1-2 dropped
+        let times = |p: &Path| read(p).iter().map(|r| r.received_at).collect::<Vec<_>>();
+        assert_eq!(times(&path), vec![7]);
+        assert_eq!(times(&dir.path().join("uplinks.jsonl.1")), vec![5, 6]);
+        assert_eq!(times(&dir.path().join("uplinks.jsonl.2")), vec![3, 4]);
+        assert!(!dir.path().join("uplinks.jsonl.3").exists());
+    }
+
+    #[test]
+    fn records_read_back_with_credentials_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uplinks.jsonl");
+        CaptureWriter::new(&path, DEFAULT_MAX_BYTES, DEFAULT_KEEP).write(&record(1)).unwrap();
+        let back = &read(&path)[0];
+        assert_eq!(back.headers["authorization"], "<redacted>");
+        assert_eq!(back.headers["content-type"], "application/json");
+        assert_eq!(back.data_b64(), Some("AAAA"));
+    }
+
+    #[test]
+    fn unreadable_lines_are_reported_by_number() {
+        assert!(read_records(io::Cursor::new("\n{bad\n")).next().unwrap().unwrap_err().starts_with("line 2"));
+    }
+
+    #[actix_web::test]
+    async fn post_uwb_captures_the_raw_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uplinks.jsonl");
+        let server = Server::new(|c| c.capture.file = path.to_string_lossy().into_owned());
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let body = test_support::uplink_body(DEV_EUI, 9, test_support::location_report(DEVICE), crate::lorawan_stream::now_ms());
+        let req = TestRequest::post().uri("/v1/uwb").insert_header(("authorization", "Bearer forwarder")).set_json(&body).to_request();
+        call_service(&app, req).await;
+
+        let captured = read(&path);
+        assert_eq!(captured.len(), 1);
+        assert_eq!((&captured[0].body, captured[0].headers["authorization"].as_str()), (&body, "<redacted>"));
+    }
+}
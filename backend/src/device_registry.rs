@@ -0,0 +1,546 @@
+//! Device registry: which tags may send frames, what they reported when they registered, and the
+//! operator's name / assignment for them.
+//!
+//! Entries are keyed by devEui (16 hex, stored upper case) or, for uplinks without one, the in-frame
+//! device ID (8 hex, lower case), and persisted as a JSON array to `devices.registry_file` (default
+//! `data/devices.json`) after every change. `post_uwb` checks every decoded frame (see `admit`):
+//! - a 0x01 registration creates the entry when the device is new and records its "Device version and
+//!   type", shortest transmission period, motion assist switch and beacon search parameters;
+//! - frames from a disabled device are rejected (`device_disabled`), the 0x01 included (no downlink);
+//! - other frames from a device without an entry are rejected (`device_not_registered`) once
+//!   `devices.reject_unknown` (`DEVICE_REJECT_UNKNOWN`) is turned on.
+//!
+//! Rejecting unregistered devices is opt-in, deliberately: with it on by default, tags deployed before
+//! the registry existed would go silent on upgrade until each one re-sent a 0x01. A warning is logged at
+//! start-up while it is off.
+//!
+//! `onboarding` is derived: `pending` (created through the API, no 0x01 yet), `registered` or `disabled`.
+//!
+//! - `GET /devices`: every entry, optional `?enabled=true|false` and `?onboarding=pending|registered|disabled`.
+//! - `GET /devices/{device}`: one entry by devEui or device ID (404 `device_not_found`).
+//! - `PUT /devices/{device}`: `{ name?, assignee?, enabled? }`; creates a `pending` entry (201) or
+//!   changes only the given fields (200).
+//! - `DELETE /devices/{device}`: remove the entry (204); the device is unknown until it registers again.
+//!
+//! `PUT` and `DELETE` need `Authorization: Bearer $KEYS_ADMIN_TOKEN`, like `/keys` (see `admin_auth`).
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::{fs, io};
+use tracing::{error, info, warn};
+use crate::anchor_registry::write_atomic;
+use crate::config::DevicesConfig;
+use crate::lorawan_codec::{DecodedFrame, Frame, Registration};
+use crate::lorawan_stream::{now_ms, IngestState};
+
+/// Where a device is in onboarding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Onboarding {
+    /// Created through the API; no 0x01 received yet.
+    #[default]
+    Pending,
+    Registered,
+    Disabled,
+}
+
+/// What the device reported in its latest 0x01.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationInfo {
+    /// "Device version and type", 4 hex.
+    pub version_type: String,
+    /// Shortest position transmission period, as reported.
+    pub min_tx_period: u8,
+    /// Motion assist ("sports assistance") switch.
+    pub motion_assist: u8,
+    pub beacon_search_timeout: u8,
+    pub beacon_search_quantity: u8,
+    pub message_number: u16,
+}
+
+impl RegistrationInfo {
+    fn new(reg: &Registration, message_number: u16) -> Self {
+        RegistrationInfo {
+            version_type: format!("{:04x}", reg.version_type),
+            min_tx_period: reg.min_tx_period,
+            motion_assist: reg.motion_assist,
+            beacon_search_timeout: reg.beacon_search_timeout,
+            beacon_search_quantity: reg.beacon_search_quantity,
+            message_number,
+        }
+    }
+}
+
+/// Stored device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRecord {
+    /// Registry key: devEui, or device ID hex.
+    pub device: String,
+    /// Empty until known (an entry created by devEui before the device registered).
+    #[serde(default)]
+    pub device_id_hex: String,
+    #[serde(default)]
+    pub dev_eui: Option<String>,
+    /// Friendly name shown in the UI.
+    #[serde(default)]
+    pub name: String,
+    /// Person or asset the tag is assigned to.
+    #[serde(default)]
+    pub assignee: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub registration: Option<RegistrationInfo>,
+    /// Server time (epoch ms) of the first and the latest 0x01.
+    #[serde(default)]
+    pub registered_at: Option<u128>,
+    #[serde(default)]
+    pub last_registration_at: Option<u128>,
+    pub created_at: u128,
+    pub updated_at: u128,
+    /// Derived from `enabled` and `registration`; kept in the file for readers.
+    #[serde(default)]
+    pub onboarding: Onboarding,
+}
+
+impl DeviceRecord {
+    fn new(device: String, now: u128) -> Self {
+        DeviceRecord {
+            device, device_id_hex: String::new(), dev_eui: None, name: String::new(), assignee: String::new(), enabled: true,
+            registration: None, registered_at: None, last_registration_at: None, created_at: now, updated_at: now, onboarding: Onboarding::Pending,
+        }
+    }
+
+    fn settle(mut self) -> Self {
+        self.onboarding = match (self.enabled, &self.registration) {
+            (false, _) => Onboarding::Disabled,
+            (true, Some(_)) => Onboarding::Registered,
+            (true, None) => Onboarding::Pending,
+        };
+        self
+    }
+
+    fn matches(&self, dev_eui: &str, device_id_hex: &str) -> bool {
+        (!dev_eui.is_empty() && (self.device == dev_eui || self.dev_eui.as_deref() == Some(dev_eui)))
+            || (!device_id_hex.is_empty() && self.device_id_hex == device_id_hex)
+    }
+}
+
+/// Why a frame or a registry operation was refused. `code()` values are stable (HTTP bodies, `decode_error`).
+#[derive(Debug)]
+pub enum RegistryError {
+    /// Not a 16 hex devEui or 8 hex device ID.
+    InvalidDevice(String),
+    NotFound(String),
+    /// Frame (other than 0x01) from a device without an entry.
+    NotRegistered(String),
+    /// Frame from a device whose entry is disabled.
+    Disabled(String),
+    /// Reading / writing `devices.registry_file` failed.
+    Storage(io::Error),
+}
+
+impl RegistryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RegistryError::InvalidDevice(_) => "invalid_device",
+            RegistryError::NotFound(_) => "device_not_found",
+            RegistryError::NotRegistered(_) => "device_not_registered",
+            RegistryError::Disabled(_) => "device_disabled",
+            RegistryError::Storage(_) => "storage_error",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+
+    fn response(&self) -> HttpResponse {
+        let mut r = match self {
+            RegistryError::InvalidDevice(_) => HttpResponse::BadRequest(),
+            RegistryError::NotFound(_) => HttpResponse::NotFound(),
+            RegistryError::NotRegistered(_) | RegistryError::Disabled(_) => HttpResponse::Forbidden(),
+            RegistryError::Storage(_) => HttpResponse::InternalServerError(),
+        };
+        r.json(self.to_json())
+    }
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::InvalidDevice(id) => write!(f, "device must be a 16 hex devEui or 8 hex device ID, got {id:?}"),
+            RegistryError::NotFound(id) => write!(f, "no device {id}"),
+            RegistryError::NotRegistered(id) => write!(f, "device {id} has not registered (no 0x01 frame)"),
+            RegistryError::Disabled(id) => write!(f, "device {id} is disabled"),
+            RegistryError::Storage(e) => write!(f, "device registry storage: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// A 16 hex devEui (upper case) or 8 hex device ID (lower case), the forms the registry and key store
+/// are keyed by; `None` for anything else.
+pub(crate) fn normalize_device(id: &str) -> Option<String> {
+    let id = id.trim();
+    if !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    match id.len() {
+        16 => Some(id.to_ascii_uppercase()),
+        8 => Some(id.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+fn registry_key(id: &str) -> Result<String, RegistryError> {
+    normalize_device(id).ok_or_else(|| RegistryError::InvalidDevice(id.trim().to_string()))
+}
+
+/// `PUT` body; absent fields are left as they are.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DeviceEdit {
+    pub name: Option<String>,
+    pub assignee: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+/// Registered devices backed by a JSON file; shared by all workers.
+pub struct DeviceRegistry {
+    path: Option<PathBuf>,
+    reject_unknown: bool,
+    devices: RwLock<BTreeMap<String, DeviceRecord>>,
+}
+
+impl DeviceRegistry {
+    /// Registry that is never written to disk (tests, tools).
+    pub fn in_memory(reject_unknown: bool) -> Self {
+        DeviceRegistry { path: None, reject_unknown, devices: RwLock::new(BTreeMap::new()) }
+    }
+
+    /// Load `path` (an empty registry when it does not exist yet).
+    pub fn open(path: impl Into<PathBuf>, reject_unknown: bool) -> Result<Self, RegistryError> {
+        let path = path.into();
+        let records = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<DeviceRecord>>(&bytes)
+                .map_err(|e| RegistryError::Storage(io::Error::new(io::ErrorKind::InvalidData, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(RegistryError::Storage(e)),
+        };
+        let devices = records.into_iter().map(|r| (r.device.clone(), r.settle())).collect();
+        Ok(DeviceRegistry { path: Some(path), reject_unknown, devices: RwLock::new(devices) })
+    }
+
+    /// `open` the `[devices]` file, falling back to an in-memory registry if it cannot be read.
+    pub fn from_config(config: &DevicesConfig) -> Self {
+        if !config.reject_unknown {
+            warn!("devices.reject_unknown is off: frames from unregistered devices are accepted; set DEVICE_REJECT_UNKNOWN=1 once every tag has registered");
+        }
+        let path = &config.registry_file;
+        match Self::open(path, config.reject_unknown) {
+            Ok(reg) => {
+                info!(path = %path, devices = reg.len(), reject_unknown = config.reject_unknown, "device registry loaded");
+                reg
+            }
+            Err(e) => {
+                error!(path = %path, error = %e, "device registry not loaded; changes will be lost on restart");
+                Self::in_memory(config.reject_unknown)
+            }
+        }
+    }
+
+    fn persist(&self, devices: &BTreeMap<String, DeviceRecord>) -> Result<(), RegistryError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        write_atomic(path, &serde_json::to_vec_pretty(&devices.values().collect::<Vec<_>>()).expect("devices serialize"), None)
+            .map_err(RegistryError::Storage)
+    }
+
+    pub fn len(&self) -> usize { self.devices.read().unwrap_or_else(|p| p.into_inner()).len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn list(&self) -> Vec<DeviceRecord> {
+        self.devices.read().unwrap_or_else(|p| p.into_inner()).values().cloned().collect()
+    }
+
+    /// Look up by registry key, devEui or device ID.
+    pub fn get(&self, device: &str) -> Result<DeviceRecord, RegistryError> {
+        let id = registry_key(device)?;
+        let devices = self.devices.read().unwrap_or_else(|p| p.into_inner());
+        devices.get(&id).or_else(|| devices.values().find(|r| r.matches(&id, &id))).cloned().ok_or(RegistryError::NotFound(id))
+    }
+
+    /// Whether a decoded frame from `dev_eui` may be processed; a 0x01 registers the device.
+    pub fn admit(&self, dev_eui: &str, df: &DecodedFrame, now: u128) -> Result<(), RegistryError> {
+        let dev_eui = dev_eui.trim().to_ascii_uppercase();
+        let id_hex = df.device_id().map(|d| d.hex()).unwrap_or_default();
+        let shown = if dev_eui.is_empty() { id_hex.clone() } else { dev_eui.clone() };
+        let Frame::Registration(reg) = &df.frame else {
+            let devices = self.devices.read().unwrap_or_else(|p| p.into_inner());
+            return match devices.values().find(|r| r.matches(&dev_eui, &id_hex)) {
+                Some(r) if !r.enabled => Err(RegistryError::Disabled(shown)),
+                Some(_) => Ok(()),
+                None if self.reject_unknown => Err(RegistryError::NotRegistered(shown)),
+                None => Ok(()),
+            };
+        };
+        let mut devices = self.devices.write().unwrap_or_else(|p| p.into_inner());
+        let previous = devices.values().find(|r| r.matches(&dev_eui, &id_hex)).cloned();
+        if previous.as_ref().is_some_and(|r| !r.enabled) {
+            return Err(RegistryError::Disabled(shown));
+        }
+        let mut record = previous.clone().unwrap_or_else(|| DeviceRecord::new(shown.clone(), now));
+        if !id_hex.is_empty() { record.device_id_hex = id_hex; }
+        if !dev_eui.is_empty() { record.dev_eui = Some(dev_eui); }
+        record.registration = Some(RegistrationInfo::new(reg, df.header.message_number));
+        record.registered_at = record.registered_at.or(Some(now));
+        record.last_registration_at = Some(now);
+        record.updated_at = now;
+        let record = record.settle();
+        devices.insert(record.device.clone(), record.clone());
+        if let Err(e) = self.persist(&devices) {
+            // Registration is still honoured; the entry is lost on restart
+            error!(device = %record.device, error = %e, "device registry not saved");
+        }
+        match previous {
+            None => info!(device = %record.device, version_type = %reg.version_type, "device registered"),
+            Some(p) if p.registration != record.registration => info!(device = %record.device, "device re-registered with new parameters"),
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Create (pending) or edit an entry; returns it and whether it was created.
+    pub fn edit(&self, device: &str, edit: DeviceEdit, now: u128) -> Result<(DeviceRecord, bool), RegistryError> {
+        let id = registry_key(device)?;
+        let mut devices = self.devices.write().unwrap_or_else(|p| p.into_inner());
+        let previous = devices.get(&id).or_else(|| devices.values().find(|r| r.matches(&id, &id))).cloned();
+        let mut record = previous.clone().unwrap_or_else(|| {
+            let mut r = DeviceRecord::new(id.clone(), now);
+            if id.len() == 8 { r.device_id_hex = id.clone() } else { r.dev_eui = Some(id.clone()) }
+            r
+        });
+        if let Some(name) = edit.name { record.name = name.trim().to_string(); }
+        if let Some(assignee) = edit.assignee { record.assignee = assignee.trim().to_string(); }
+        if let Some(enabled) = edit.enabled { record.enabled = enabled; }
+        record.updated_at = now;
+        let record = record.settle();
+        devices.insert(record.device.clone(), record.clone());
+        if let Err(e) = self.persist(&devices) {
+            match &previous {
+                Some(p) => { devices.insert(p.device.clone(), p.clone()); }
+                None => { devices.remove(&record.device); }
+            }
+            return Err(e);
+        }
+        Ok((record, previous.is_none()))
+    }
+
+    pub fn remove(&self, device: &str) -> Result<DeviceRecord, RegistryError> {
+        let key = self.get(device)?.device;
+        let mut devices = self.devices.write().unwrap_or_else(|p| p.into_inner());
+        let removed = devices.remove(&key).ok_or(RegistryError::NotFound(key))?;
+        if let Err(e) = self.persist(&devices) {
+            devices.insert(removed.device.clone(), removed);
+            return Err(e);
+        }
+        Ok(removed)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceQuery {
+    pub enabled: Option<bool>,
+    pub onboarding: Option<Onboarding>,
+}
+
+#[get("/devices")]
+pub async fn list_devices(state: web::Data<IngestState>, q: web::Query<DeviceQuery>) -> impl Responder {
+    let devices: Vec<DeviceRecord> = state.registry.list().into_iter()
+        .filter(|d| q.enabled.is_none_or(|e| d.enabled == e))
+        .filter(|d| q.onboarding.is_none_or(|o| d.onboarding == o))
+        .collect();
+    HttpResponse::Ok().json(json!({ "devices": devices }))
+}
+
+#[get("/devices/{device}")]
+pub async fn get_device(state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    match state.registry.get(&path.into_inner()) {
+        Ok(d) => HttpResponse::Ok().json(d),
+        Err(e) => e.response(),
+    }
+}
+
+#[put("/devices/{device}")]
+pub async fn put_device(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>, body: web::Json<DeviceEdit>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match state.registry.edit(&path.into_inner(), body.into_inner(), now_ms()) {
+        Ok((d, true)) => {
+            info!(device = %d.device, "device added");
+            HttpResponse::Created().json(d)
+        }
+        Ok((d, false)) => {
+            info!(device = %d.device, enabled = d.enabled, "device updated");
+            HttpResponse::Ok().json(d)
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[delete("/devices/{device}")]
+pub async fn delete_device(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match state.registry.remove(&path.into_inner()) {
+        Ok(d) => {
+            info!(device = %d.device, "device removed");
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::lorawan_codec::{decode_frame_with, Battery, Beacon, DecodeOptions, DeviceId, HmacBinding, LocationReport, Motion, Uplink};
+    use crate::secrets::Secrets;
+    use crate::test_support::{self, Server, ADMIN_TOKEN, SECRET_KEY, SIGN_TOKEN};
+
+    const DEV: DeviceId = DeviceId(0xa0ba3e29);
+
+    fn decoded(frame: Frame, message_number: u16) -> DecodedFrame {
+        let b64 = Uplink::new(message_number, frame).encrypt(HmacBinding::Payload, SIGN_TOKEN, SECRET_KEY).unwrap();
+        decode_frame_with(&b64, SECRET_KEY, SIGN_TOKEN, None, &DecodeOptions::default()).unwrap()
+    }
+
+    fn location_report(dev: DeviceId) -> DecodedFrame {
+        decoded(Frame::LocationReport(LocationReport::new(dev, Motion::Moving, vec![Beacon { major: 1, minor: 2, distance_cm: 150, battery: Battery(90) }])), 2)
+    }
+
+    fn registration(dev: DeviceId) -> DecodedFrame {
+        decoded(Frame::Registration(Registration { device_id: dev, version_type: 0x0201, min_tx_period: 5, motion_assist: 1, beacon_search_timeout: 1, beacon_search_quantity: 4 }), 1)
+    }
+
+    #[test]
+    fn a_0x01_registers_the_device() {
+        let reg = DeviceRegistry::in_memory(true);
+        assert!(matches!(reg.admit("00956900A0BA3E29", &location_report(DEV), 1), Err(RegistryError::NotRegistered(_))));
+        reg.admit("00956900a0ba3e29", &registration(DEV), 2).unwrap();
+        reg.admit("00956900A0BA3E29", &location_report(DEV), 3).unwrap();
+        // forwarders that omit the devEui are matched by device ID
+        reg.admit("", &location_report(DEV), 3).unwrap();
+        let d = reg.get("a0ba3e29").unwrap();
+        assert_eq!((d.device.as_str(), d.onboarding, d.registered_at), ("00956900A0BA3E29", Onboarding::Registered, Some(2)));
+        assert_eq!(d.registration.as_ref().map(|r| (r.version_type.as_str(), r.min_tx_period, r.beacon_search_quantity)), Some(("0201", 5, 4)));
+    }
+
+    #[test]
+    fn unknown_devices_pass_unless_rejection_is_on() {
+        let reg = DeviceRegistry::in_memory(false);
+        reg.admit("0011223344556677", &location_report(DeviceId(1)), 1).unwrap();
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn disabled_devices_are_refused_even_their_0x01() {
+        let reg = DeviceRegistry::in_memory(true);
+        reg.admit("00956900A0BA3E29", &registration(DEV), 1).unwrap();
+        let edit = DeviceEdit { name: Some(" Forklift 3 ".into()), enabled: Some(false), ..DeviceEdit::default() };
+        let (d, created) = reg.edit("00956900A0BA3E29", edit, 2).unwrap();
+        assert_eq!((d.name.as_str(), d.onboarding, created), ("Forklift 3", Onboarding::Disabled, false));
+        assert!(matches!(reg.admit("00956900A0BA3E29", &location_report(DEV), 3), Err(RegistryError::Disabled(_))));
+        assert!(matches!(reg.admit("00956900A0BA3E29", &registration(DEV), 3), Err(RegistryError::Disabled(_))));
+    }
+
+    #[test]
+    fn edits_create_pending_entries() {
+        let reg = DeviceRegistry::in_memory(true);
+        let (p, created) = reg.edit("B0BA3E29", DeviceEdit { assignee: Some("J. Doe".into()), ..DeviceEdit::default() }, 1).unwrap();
+        assert_eq!((p.device.as_str(), p.onboarding, created), ("b0ba3e29", Onboarding::Pending, true));
+        assert!(matches!(reg.edit("xyz", DeviceEdit::default(), 1), Err(RegistryError::InvalidDevice(_))));
+    }
+
+    #[test]
+    fn entries_survive_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.json");
+        let reg = DeviceRegistry::open(&path, true).unwrap();
+        reg.admit("00956900A0BA3E29", &registration(DEV), 1).unwrap();
+        reg.edit("00956900A0BA3E29", DeviceEdit { name: Some("Forklift 3".into()), ..DeviceEdit::default() }, 2).unwrap();
+        reg.edit("b0ba3e29", DeviceEdit::default(), 3).unwrap();
+
+        let reopened = DeviceRegistry::open(&path, true).unwrap();
+        assert_eq!(reopened.list(), reg.list());
+        assert_eq!(reopened.get("a0ba3e29").unwrap().name, "Forklift 3");
+        reopened.remove("b0ba3e29").unwrap();
+        assert!(matches!(DeviceRegistry::open(&path, true).unwrap().get("b0ba3e29"), Err(RegistryError::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn writes_need_the_admin_token() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let put = |auth: Option<&str>| {
+            let req = TestRequest::put().uri("/devices/a0ba3e29").set_json(json!({ "name": "Forklift 3" }));
+            match auth { Some(a) => req.insert_header(("authorization", a)), None => req }.to_request()
+        };
+        assert_eq!(call_service(&app, put(None)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, put(Some("Bearer wrong"))).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, TestRequest::delete().uri("/devices/a0ba3e29").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(server.state.registry.is_empty());
+
+        let bearer = format!("Bearer {ADMIN_TOKEN}");
+        assert_eq!(call_service(&app, put(Some(&bearer))).await.status(), StatusCode::CREATED);
+        let delete = TestRequest::delete().uri("/devices/a0ba3e29").insert_header(("authorization", bearer.as_str())).to_request();
+        assert_eq!(call_service(&app, delete).await.status(), StatusCode::NO_CONTENT);
+        // reads stay open
+        assert_eq!(call_service(&app, TestRequest::get().uri("/devices").to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn writes_are_refused_without_a_configured_token() {
+        let server = Server::new(|_| {});
+        server.state.keys.set_secrets(&Secrets { admin_token: None, ..test_support::secrets() });
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let put = TestRequest::put().uri("/devices/a0ba3e29").insert_header(("authorization", "Bearer ")).set_json(json!({})).to_request();
+        let res = call_service(&app, put).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn endpoints_answer_404_and_400() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let bearer = format!("Bearer {ADMIN_TOKEN}");
+        assert_eq!(call_service(&app, TestRequest::get().uri("/devices/a0ba3e29").to_request()).await.status(), StatusCode::NOT_FOUND);
+        let delete = TestRequest::delete().uri("/devices/a0ba3e29").insert_header(("authorization", bearer.as_str())).to_request();
+        assert_eq!(call_service(&app, delete).await.status(), StatusCode::NOT_FOUND);
+
+        let invalid = TestRequest::put().uri("/devices/xyz").insert_header(("authorization", bearer.as_str())).set_json(json!({})).to_request();
+        let invalid: Value = call_and_read_body_json(&app, invalid).await;
+        assert_eq!(invalid["code"], "invalid_device");
+        let unknown_field = TestRequest::put().uri("/devices/a0ba3e29").insert_header(("authorization", bearer.as_str())).set_json(json!({ "nmae": "x" })).to_request();
+        assert_eq!(call_service(&app, unknown_field).await.status(), StatusCode::BAD_REQUEST);
+        assert!(server.state.registry.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn list_filters_by_onboarding_state() {
+        let server = Server::new(|_| {});
+        server.state.registry.edit("a0ba3e29", DeviceEdit::default(), 1).unwrap();
+        server.state.registry.edit("b0ba3e29", DeviceEdit { enabled: Some(false), ..DeviceEdit::default() }, 1).unwrap();
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let pending: Value = call_and_read_body_json(&app, TestRequest::get().uri("/devices?onboarding=pending").to_request()).await;
+        assert_eq!(pending["devices"].as_array().map(|d| d.iter().map(|d| d["device"].clone()).collect::<Vec<_>>()), Some(vec![json!("a0ba3e29")]));
+        let bad = call_service(&app, TestRequest::get().uri("/devices?onboarding=lost").to_request()).await;
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+    }
+}
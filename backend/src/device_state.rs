@@ -0,0 +1,204 @@
+//! Last known state of every device, so a client that connects between frames sees idle tags at once.
+//!
+//! `post_uwb` updates the store on every accepted, non-duplicate frame: `lastSeen` on any frame, the
+//! beacons / motion / floor and the solved position and track from `uwb_update` (a report that does not
+//! solve keeps the previous position), battery and fault flag from `device_status`. Keys are the same as
+//! the status store (devEui, else the device ID from the frame). `stale` is set when read: no frame for
+//! `devices.stale_after_ms` (`DEVICE_STALE_MS`). The store is in memory; after a restart devices
+//! reappear with their next frame (`/history` keeps older data).
+//!
+//! - `GET /devices/state`: all devices, optional `?stale=true|false`.
+//! - `GET /devices/{device}/state`: one device (devEui or device ID hex), 404 if never seen.
+//!
+//! New `/proxy/uwbStream` and `/ws` clients (and clients whose resume ended in `resync`) first get the
+//! same list as a `snapshot` event, limited to their filter's devices and floor / zone:
+//! `{ type: "snapshot", latestId, ts, devices: [..] }`.
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::device_status::DeviceStatus;
+use crate::lorawan_stream::{now_ms, IngestState};
+use crate::stream_filter::StreamFilter;
+
+pub const DEFAULT_STALE_AFTER_MS: u64 = 10 * 60 * 1000;
+
+/// What is known about one device.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceState {
+    /// Store key: devEui, or device ID hex when the uplink had no devEui.
+    pub device: String,
+    pub device_id_hex: String,
+    pub dev_eui: Option<String>,
+    /// Beacons of the latest location report, as in `uwb_update`.
+    pub beacons: Vec<Value>,
+    pub motion: Option<String>,
+    pub floor: Option<i64>,
+    /// Latest solved position and track; kept when later reports do not solve.
+    pub position: Option<Value>,
+    pub track: Option<Value>,
+    /// Server time (epoch ms) `position` was solved.
+    pub position_at: Option<u128>,
+    pub battery: Option<u8>,
+    pub abnormal: Option<bool>,
+    /// Id of the latest `uwb_update` event.
+    pub last_update_id: Option<u64>,
+    /// Server time (epoch ms) of the latest accepted frame of any type.
+    pub last_seen: u128,
+    /// No frame for `devices.stale_after_ms`.
+    pub stale: bool,
+}
+
+/// Per-device last known state, shared by all workers.
+pub struct DeviceStateStore {
+    devices: Mutex<HashMap<String, DeviceState>>,
+    stale_after_ms: u128,
+}
+
+impl DeviceStateStore {
+    pub fn new(stale_after_ms: u128) -> Self {
+        DeviceStateStore { devices: Mutex::new(HashMap::new()), stale_after_ms }
+    }
+
+    /// An accepted frame from `device`.
+    pub fn seen(&self, device: &str, device_id_hex: &str, dev_eui: &str, now: u128) {
+        let mut devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        let state = devices.entry(device.to_string()).or_insert_with(|| DeviceState {
+            device: device.to_string(),
+            device_id_hex: String::new(),
+            dev_eui: None,
+            beacons: Vec::new(),
+            motion: None,
+            floor: None,
+            position: None,
+            track: None,
+            position_at: None,
+            battery: None,
+            abnormal: None,
+            last_update_id: None,
+            last_seen: now,
+            stale: false,
+        });
+        if !device_id_hex.is_empty() { state.device_id_hex = device_id_hex.to_string(); }
+        if !dev_eui.is_empty() { state.dev_eui = Some(dev_eui.to_string()); }
+        state.last_seen = state.last_seen.max(now);
+    }
+
+    /// A broadcast `uwb_update` (event `id`) for a device already `seen`.
+    pub fn record_update(&self, device: &str, update: &Value, id: u64, now: u128) {
+        let mut devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        let Some(state) = devices.get_mut(device) else { return };
+        let payload = &update["payload"];
+        state.beacons = payload["beacons"].as_array().cloned().unwrap_or_default();
+        state.motion = payload["motion"].as_str().map(str::to_string);
+        state.floor = payload["floor"].as_i64();
+        if payload["position"].is_object() {
+            state.position = Some(payload["position"].clone());
+            state.track = Some(payload["track"].clone()).filter(Value::is_object);
+            state.position_at = Some(now);
+        }
+        state.last_update_id = Some(id);
+    }
+
+    /// A recorded 0x03 status for a device already `seen`.
+    pub fn record_status(&self, status: &DeviceStatus) {
+        let mut devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        let Some(state) = devices.get_mut(&status.device) else { return };
+        state.battery = Some(status.battery);
+        state.abnormal = Some(status.abnormal);
+    }
+
+    fn with_stale(&self, state: &DeviceState, now: u128) -> DeviceState {
+        DeviceState { stale: now.saturating_sub(state.last_seen) > self.stale_after_ms, ..state.clone() }
+    }
+
+    /// Look up by store key, or by devEui / device ID hex (case-insensitive).
+    pub fn get(&self, device: &str, now: u128) -> Option<DeviceState> {
+        let devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        devices.get(device).or_else(|| devices.values().find(|s| {
+            s.device.eq_ignore_ascii_case(device) || s.device_id_hex.eq_ignore_ascii_case(device)
+                || s.dev_eui.as_deref().is_some_and(|e| e.eq_ignore_ascii_case(device))
+        })).map(|s| self.with_stale(s, now))
+    }
+
+    /// All devices, sorted by device key.
+    pub fn all(&self, now: u128) -> Vec<DeviceState> {
+        let mut out: Vec<DeviceState> = self.devices.lock().unwrap_or_else(|p| p.into_inner()).values().map(|s| self.with_stale(s, now)).collect();
+        out.sort_by(|a, b| a.device.cmp(&b.device));
+        out
+    }
+
+    /// The `snapshot` event for a stream client: the devices its filter wants, as of event `latest_id`.
+    pub fn snapshot(&self, filter: &StreamFilter, latest_id: u64, now: u128) -> Value {
+        let devices: Vec<Value> = self.all(now).iter().map(|s| json!(s)).filter(|s| filter.wants_state(s)).collect();
+        json!({ "type": "snapshot", "latestId": latest_id, "ts": now, "devices": devices })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StateQuery {
+    /// Only stale (`true`) or live (`false`) devices.
+    pub stale: Option<bool>,
+}
+
+/// Last known state of every device that has sent an accepted frame.
+#[get("/devices/state")]
+pub async fn list_state(state: web::Data<IngestState>, q: web::Query<StateQuery>) -> impl Responder {
+    let devices: Vec<DeviceState> = state.devices.all(now_ms()).into_iter()
+        .filter(|s| q.stale.is_none_or(|stale| s.stale == stale))
+        .collect();
+    HttpResponse::Ok().json(json!({ "devices": devices }))
+}
+
+/// Last known state of a single device.
+#[get("/devices/{device}/state")]
+pub async fn get_state(state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    let device = path.into_inner();
+    match state.devices.get(&device, now_ms()) {
+        Some(s) => HttpResponse::Ok().json(s),
+        None => HttpResponse::NotFound().json(json!({ "code": "unknown_device", "message": format!("no state for {}", device) })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_filter::FilterSpec;
+
+    fn update(x: Option<f64>) -> Value {
+        let position = x.map(|x| json!({ "x": x, "y": 1.0 }));
+        json!({ "type": "uwb_update", "payload": {
+            "deviceIdHex": "a0ba3e29", "motion": "Moving", "floor": 1, "position": position,
+            "beacons": [{ "beaconId": "020000b3", "distance": 945 }],
+        } })
+    }
+
+    #[test]
+    fn keeps_last_known_state_and_flags_stale() {
+        let store = DeviceStateStore::new(60_000);
+        store.record_update("AA01", &update(Some(2.0)), 1, 0);
+        assert!(store.get("AA01", 0).is_none(), "updates for unseen devices are ignored");
+
+        store.seen("AA01", "a0ba3e29", "AA01", 1_000);
+        store.record_update("AA01", &update(Some(2.0)), 7, 1_000);
+        store.seen("AA01", "a0ba3e29", "AA01", 5_000);
+        store.record_update("AA01", &update(None), 8, 5_000);
+        store.seen("BB02", "b0ba3e29", "", 2_000);
+
+        let s = store.get("a0ba3e29", 10_000).unwrap();
+        assert_eq!((s.device.as_str(), s.last_seen, s.last_update_id, s.stale), ("AA01", 5_000, Some(8), false));
+        // the unsolved report keeps the last position
+        assert_eq!((s.position.as_ref().map(|p| &p["x"]), s.position_at), (Some(&json!(2.0)), Some(1_000)));
+        assert_eq!((s.beacons.len(), s.motion.as_deref(), s.floor), (1, Some("Moving"), Some(1)));
+        assert!(store.get("bb02", 62_001).unwrap().stale);
+        assert_eq!(store.all(10_000).iter().map(|s| s.device.as_str()).collect::<Vec<_>>(), ["AA01", "BB02"]);
+
+        let filter = FilterSpec::from_query("floor=1&zone=0,0,5,5").unwrap().build().unwrap();
+        let snap = store.snapshot(&filter, 8, 10_000);
+        assert_eq!((snap["type"].as_str(), snap["latestId"].as_u64()), (Some("snapshot"), Some(8)));
+        let devices = snap["devices"].as_array().unwrap();
+        assert_eq!((devices.len(), devices[0]["device"].as_str()), (1, Some("AA01")));
+    }
+}
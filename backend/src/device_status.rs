@@ -0,0 +1,120 @@
+//! Latest 0x03 status per device and the REST endpoints that expose it.
+//!
+//! `post_uwb` records every accepted status frame in `IngestState::statuses`; the store only keeps the
+//! most recent report per device (devEui, else the device ID from the frame).
+//!
+//! - `GET /devices/status`: all devices, optional `?abnormal=true` and `?batteryBelow=<n>` filters.
+//! - `GET /devices/{device}/status`: one device (devEui or device ID hex), 404 if never seen.
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::lorawan_codec::StatusReport;
+use crate::lorawan_stream::IngestState;
+
+/// Latest status reported by one device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStatus {
+    /// Store key: devEui, or device ID hex when the uplink had no devEui.
+    pub device: String,
+    pub device_id_hex: String,
+    pub battery: u8,
+    pub abnormal: bool,
+    pub abnormal_code: u8,
+    pub config_version: u8,
+    pub message_number: u16,
+    /// Server time (epoch ms) the report was accepted.
+    pub updated_at: u128,
+}
+
+/// Per-device latest status, shared by all workers.
+#[derive(Default)]
+pub struct StatusStore {
+    devices: Mutex<HashMap<String, DeviceStatus>>,
+}
+
+impl StatusStore {
+    pub fn new() -> Self { Self::default() }
+
+    /// Replace the stored status for `device` with `report`.
+    pub fn record(&self, device: &str, report: &StatusReport, message_number: u16, now: u128) -> DeviceStatus {
+        let status = DeviceStatus {
+            device: device.to_string(),
+            device_id_hex: report.device_id().hex(),
+            battery: report.battery.0,
+            abnormal: report.is_abnormal(),
+            abnormal_code: report.abnormal,
+            config_version: report.config_version,
+            message_number,
+            updated_at: now,
+        };
+        self.devices.lock().unwrap_or_else(|p| p.into_inner()).insert(device.to_string(), status.clone());
+        status
+    }
+
+    /// Look up by store key, or by device ID hex (case-insensitive).
+    pub fn get(&self, device: &str) -> Option<DeviceStatus> {
+        let devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        devices.get(device).cloned().or_else(|| {
+            devices.values().find(|s| s.device.eq_ignore_ascii_case(device) || s.device_id_hex.eq_ignore_ascii_case(device)).cloned()
+        })
+    }
+
+    /// All statuses, sorted by device key.
+    pub fn all(&self) -> Vec<DeviceStatus> {
+        let mut out: Vec<DeviceStatus> = self.devices.lock().unwrap_or_else(|p| p.into_inner()).values().cloned().collect();
+        out.sort_by(|a, b| a.device.cmp(&b.device));
+        out
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusQuery {
+    /// Only faulted (`true`) or healthy (`false`) devices.
+    pub abnormal: Option<bool>,
+    /// Only devices whose battery level is strictly below this value.
+    pub battery_below: Option<u8>,
+}
+
+/// List the latest status of every device that has sent a 0x03 frame.
+#[get("/devices/status")]
+pub async fn list_status(state: web::Data<IngestState>, q: web::Query<StatusQuery>) -> impl Responder {
+    let devices: Vec<DeviceStatus> = state.statuses.all().into_iter()
+        .filter(|s| q.abnormal.is_none_or(|a| s.abnormal == a))
+        .filter(|s| q.battery_below.is_none_or(|b| s.battery < b))
+        .collect();
+    HttpResponse::Ok().json(json!({ "devices": devices }))
+}
+
+/// Latest status of a single device.
+#[get("/devices/{device}/status")]
+pub async fn get_status(state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    let device = path.into_inner();
+    match state.statuses.get(&device) {
+        Some(s) => HttpResponse::Ok().json(s),
+        None => HttpResponse::NotFound().json(json!({ "code": "unknown_device", "message": format!("no status for {}", device) })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan_codec::Battery;
+
+    #[test]
+    fn keeps_latest_status_per_device() {
+        let store = StatusStore::new();
+        let mut report = StatusReport { rfid_uid: [0xA0, 0xBA, 0x3E, 0x29], abnormal: 0, battery: Battery(42), config_version: 1, reserved: vec![0] };
+        store.record("009569000004C21E", &report, 1, 10);
+        report.abnormal = 2;
+        report.battery = Battery(9);
+        store.record("009569000004C21E", &report, 2, 20);
+        let s = store.get("a0ba3e29").expect("lookup by device id");
+        assert_eq!((s.battery, s.abnormal, s.abnormal_code, s.message_number, s.updated_at), (9, true, 2, 2, 20));
+        assert_eq!(store.all().len(), 1);
+        assert!(store.get("ffffffff").is_none());
+    }
+}
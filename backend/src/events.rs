@@ -0,0 +1,192 @@
+//! Live event fan-out with ids and a replay buffer, behind `/proxy/uwbStream`.
+//!
+//! Every event `post_uwb` broadcasts (`uwb_update`, `device_status`, `decode_error`, `sequence_reset`)
+//! goes through `EventBus::publish`, which gives it the next id and keeps it in a ring of the last
+//! `stream.replay_buffer` events (`SSE_REPLAY_BUFFER`, default 1024). Ids are monotonic and start at the
+//! server's start-up time in epoch ms, so ids handed out before a restart are lower than any after it.
+//!
+//! SSE frames carry the id (`id: 42`), so a browser `EventSource` reconnects with `Last-Event-ID` and
+//! receives every event after it from the ring before the live ones. A client that falls behind the
+//! broadcast channel is caught up from the ring the same way. When the ring no longer reaches back far
+//! enough (or the id is from another server run) the client gets one `resync` event instead:
+//! `{ type: "resync", reason, lastEventId, oldestId, latestId, ts }`, with `id: latestId`, meaning
+//! "events were lost, reload state (`snapshot`, `/history`) and continue from here".
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::config::StreamConfig;
+use crate::lorawan_stream::sse_block_from_value;
+
+/// One published event.
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    pub value: Value,
+    /// The SSE frame, rendered once for all clients.
+    pub sse: Bytes,
+}
+
+impl Event {
+    /// Render `value` with `id`; events that are not published (`resync`, `snapshot`) reuse an id.
+    pub fn new(id: u64, value: Value) -> Self {
+        let sse = Bytes::from(format!("id: {id}\n{}", sse_block_from_value(&value)));
+        Event { id, value, sse }
+    }
+}
+
+/// Why a client cannot be caught up event by event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResyncReason {
+    /// The events after `Last-Event-ID` have already left the ring.
+    Expired,
+    /// `Last-Event-ID` is newer than anything published: ids from before a restart.
+    UnknownId,
+}
+
+impl ResyncReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResyncReason::Expired => "expired",
+            ResyncReason::UnknownId => "unknown_id",
+        }
+    }
+}
+
+/// The gap a client has to recover from by reloading state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resync {
+    pub reason: ResyncReason,
+    pub last_event_id: u64,
+    /// Oldest id still in the ring, if any.
+    pub oldest_id: Option<u64>,
+    pub latest_id: u64,
+}
+
+impl Resync {
+    /// The `resync` event; its id is `latest_id` so the client's next resume starts after it.
+    pub fn event(&self, now: u128) -> Event {
+        Event::new(self.latest_id, json!({
+            "type": "resync", "reason": self.reason.as_str(), "lastEventId": self.last_event_id,
+            "oldestId": self.oldest_id, "latestId": self.latest_id, "ts": now,
+        }))
+    }
+}
+
+/// A new client: what to send before the live events.
+pub struct Subscription {
+    pub rx: broadcast::Receiver<Arc<Event>>,
+    /// Events after `Last-Event-ID`, oldest first.
+    pub backlog: Vec<Arc<Event>>,
+    pub resync: Option<Resync>,
+    /// Newest id the client has (or will have after `backlog` / `resync`); live events up to it are skipped.
+    pub cursor: u64,
+}
+
+struct Ring {
+    events: VecDeque<Arc<Event>>,
+    /// Id of the last published event.
+    latest: u64,
+}
+
+impl Ring {
+    /// Events after `last_id`, or the gap when the ring does not reach back to it.
+    fn since(&self, last_id: u64) -> Result<Vec<Arc<Event>>, Resync> {
+        let oldest_id = self.events.front().map(|e| e.id);
+        let resync = |reason| Resync { reason, last_event_id: last_id, oldest_id, latest_id: self.latest };
+        if last_id > self.latest {
+            return Err(resync(ResyncReason::UnknownId));
+        }
+        if last_id < self.latest && oldest_id.is_none_or(|oldest| oldest > last_id + 1) {
+            return Err(resync(ResyncReason::Expired));
+        }
+        let skip = self.events.partition_point(|e| e.id <= last_id);
+        Ok(self.events.range(skip..).cloned().collect())
+    }
+}
+
+/// Shared (via `web::Data`) publisher for all live clients.
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<Event>>,
+    ring: Mutex<Ring>,
+    capacity: usize,
+}
+
+impl EventBus {
+    pub fn new(config: &StreamConfig) -> Self {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        Self::starting_at(start, config)
+    }
+
+    fn starting_at(latest: u64, config: &StreamConfig) -> Self {
+        let (tx, _) = broadcast::channel(config.channel_capacity.max(1));
+        EventBus { tx, ring: Mutex::new(Ring { events: VecDeque::new(), latest }), capacity: config.replay_buffer }
+    }
+
+    /// Assign the next id, keep the event for resuming clients and send it to the live ones.
+    pub fn publish(&self, value: Value) -> u64 {
+        // Under the ring lock, so ids reach the channel in order and subscribe() sees a consistent cursor
+        let mut ring = self.ring.lock().unwrap_or_else(|p| p.into_inner());
+        ring.latest += 1;
+        let event = Arc::new(Event::new(ring.latest, value));
+        if self.capacity > 0 {
+            if ring.events.len() == self.capacity { ring.events.pop_front(); }
+            ring.events.push_back(event.clone());
+        }
+        // No receivers is not an error: the event is still kept for resuming clients
+        let _ = self.tx.send(event);
+        ring.latest
+    }
+
+    /// Live clients right now.
+    pub fn subscribers(&self) -> usize { self.tx.receiver_count() }
+
+    /// Subscribe, resuming after `last_event_id` when given.
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Subscription {
+        let ring = self.ring.lock().unwrap_or_else(|p| p.into_inner());
+        let rx = self.tx.subscribe();
+        let (backlog, resync) = match last_event_id.map(|id| ring.since(id)) {
+            None => (Vec::new(), None),
+            Some(Ok(backlog)) => (backlog, None),
+            Some(Err(resync)) => (Vec::new(), Some(resync)),
+        };
+        Subscription { rx, backlog, resync, cursor: ring.latest }
+    }
+
+    /// Events after `last_id` for a client that lagged behind the channel.
+    pub fn since(&self, last_id: u64) -> Result<Vec<Arc<Event>>, Resync> {
+        self.ring.lock().unwrap_or_else(|p| p.into_inner()).since(last_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_from_ring_and_resync_on_gap() {
+        let bus = EventBus::starting_at(100, &StreamConfig { replay_buffer: 3, channel_capacity: 8 });
+        for n in 0..5 { assert_eq!(bus.publish(json!({ "type": "uwb_update", "n": n })), 101 + n); }
+        // ring keeps 103..=105
+        let ids = |v: Vec<Arc<Event>>| v.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(bus.since(103).unwrap()), vec![104, 105]);
+        assert_eq!(ids(bus.since(102).unwrap()), vec![103, 104, 105]);
+        assert!(bus.since(105).unwrap().is_empty());
+        let gap = bus.since(101).unwrap_err();
+        assert_eq!((gap.reason, gap.oldest_id, gap.latest_id), (ResyncReason::Expired, Some(103), 105));
+        assert_eq!(bus.since(900).unwrap_err().reason, ResyncReason::UnknownId);
+
+        let e = bus.since(104).unwrap().remove(0);
+        assert_eq!(&e.sse[..], b"id: 105\nevent: uwb_update\ndata: {\"n\":4,\"type\":\"uwb_update\"}\n\n");
+        let resync = gap.event(7);
+        assert_eq!((resync.id, resync.value["type"].as_str(), resync.value["lastEventId"].as_u64()), (105, Some("resync"), Some(101)));
+
+        // a subscriber sees the backlog, then only newer live events
+        let mut sub = bus.subscribe(Some(104));
+        assert_eq!((ids(sub.backlog.clone()), sub.cursor, sub.resync.is_none()), (vec![105], 105, true));
+        bus.publish(json!({ "type": "device_status" }));
+        assert_eq!(sub.rx.try_recv().unwrap().id, 106);
+    }
+}
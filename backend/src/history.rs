@@ -0,0 +1,308 @@
+//! Append-only history of accepted uplinks: decoded frame, computed position and the event broadcast.
+//!
+//! Records are JSON lines in time segments under `history.dir` (default `data/history`): one file per
+//! `history.segment_ms` (default 1 h) named after the segment start, e.g. `1718000000000.jsonl`.
+//! Lines are only ever appended; whole segments older than `history.retention_days` (default 30,
+//! 0 keeps everything) are deleted at start-up and whenever writing moves to a new segment.
+//! An empty `history.dir` disables the store.
+//!
+//! - `GET /history?device=&from=&to=&limit=`: records with `from <= ts <= to` (epoch ms, default the
+//!   last hour), optionally for one device (`deviceIdHex` or devEui), oldest first.
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+use crate::config::HistoryConfig;
+use crate::lorawan_codec::DecodedFrame;
+use crate::lorawan_stream::{now_ms, IngestState};
+
+pub const DEFAULT_SEGMENT_MS: u128 = 60 * 60 * 1000;
+pub const DEFAULT_RETENTION_DAYS: u128 = 30;
+/// Window a query covers when it gives no `from`: the hour before `to`.
+pub const DEFAULT_WINDOW_MS: u128 = 60 * 60 * 1000;
+/// Most records one `/history` call returns.
+pub const MAX_QUERY_LIMIT: usize = 100_000;
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+/// One stored uplink.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecord {
+    /// Server receive time, epoch ms.
+    pub ts: u128,
+    pub device_id_hex: String,
+    #[serde(default)]
+    pub dev_eui: String,
+    pub message_number: u16,
+    pub message_type: u8,
+    /// Typed frame content (`Frame` as JSON).
+    pub frame: Value,
+    /// `payload.position` of a 0x05 update, when one was solved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<Value>,
+    /// Event broadcast on the SSE stream for this frame (`uwb_update`, `device_status`); none for 0x01.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<Value>,
+}
+
+impl HistoryRecord {
+    pub fn new(ts: u128, dev_eui: &str, df: &DecodedFrame, event: Option<Value>) -> Self {
+        HistoryRecord {
+            ts,
+            device_id_hex: df.device_id().map(|d| d.hex()).unwrap_or_default(),
+            dev_eui: dev_eui.to_string(),
+            message_number: df.header.message_number,
+            message_type: df.message_type(),
+            frame: serde_json::to_value(&df.frame).unwrap_or(Value::Null),
+            position: event.as_ref().and_then(|e| e.pointer("/payload/position")).cloned(),
+            event,
+        }
+    }
+
+    fn matches_device(&self, device: &str) -> bool {
+        self.device_id_hex.eq_ignore_ascii_case(device) || self.dev_eui.eq_ignore_ascii_case(device)
+    }
+}
+
+struct Segment {
+    start: u128,
+    file: File,
+}
+
+/// Segmented JSONL store shared by all workers; `dir == None` means disabled.
+pub struct HistoryStore {
+    dir: Option<PathBuf>,
+    segment_ms: u128,
+    /// 0 keeps everything.
+    retention_ms: u128,
+    current: Mutex<Option<Segment>>,
+}
+
+impl HistoryStore {
+    pub fn disabled() -> Self {
+        HistoryStore { dir: None, segment_ms: DEFAULT_SEGMENT_MS, retention_ms: 0, current: Mutex::new(None) }
+    }
+
+    pub fn open(dir: impl Into<PathBuf>, segment_ms: u128, retention_ms: u128) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(HistoryStore { dir: Some(dir), segment_ms: segment_ms.max(1), retention_ms, current: Mutex::new(None) })
+    }
+
+    /// Store described by `[history]`; prunes expired segments once.
+    /// A directory that cannot be created disables the store (logged).
+    pub fn from_config(config: &HistoryConfig) -> Self {
+        let dir = config.dir.trim();
+        if dir.is_empty() {
+            info!("history store disabled (history.dir empty)");
+            return Self::disabled();
+        }
+        let (segment_ms, retention_days) = (config.segment_ms, config.retention_days);
+        match Self::open(dir, u128::from(segment_ms), u128::from(retention_days) * DAY_MS) {
+            Ok(store) => {
+                let now = now_ms();
+                match store.prune(now) {
+                    Ok(removed) => info!(dir = %dir, segment_ms, retention_days, removed, "history store ready"),
+                    Err(e) => warn!(dir = %dir, error = %e, "history prune failed"),
+                }
+                store
+            }
+            Err(e) => {
+                error!(dir = %dir, error = %e, "history store disabled: cannot create directory");
+                Self::disabled()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool { self.dir.is_some() }
+
+    fn segment_start(&self, ts: u128) -> u128 { ts - ts % self.segment_ms }
+
+    /// Append one record to the segment covering `record.ts`.
+    pub fn append(&self, record: &HistoryRecord) -> io::Result<()> {
+        let Some(dir) = &self.dir else { return Ok(()) };
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        let start = self.segment_start(record.ts);
+        let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+        if current.as_ref().is_none_or(|s| s.start != start) {
+            let file = OpenOptions::new().create(true).append(true).open(dir.join(format!("{start:013}.jsonl")))?;
+            let rolled = current.is_some();
+            *current = Some(Segment { start, file });
+            if rolled {
+                if let Err(e) = self.prune(record.ts) { warn!(error = %e, "history prune failed"); }
+            }
+        }
+        let seg = current.as_mut().expect("segment just opened");
+        // One write per line so concurrent readers never see a partial record from this writer
+        seg.file.write_all(&line)
+    }
+
+    /// Segment start times on disk, ascending.
+    fn segments(&self) -> io::Result<Vec<u128>> {
+        let Some(dir) = &self.dir else { return Ok(Vec::new()) };
+        let mut starts: Vec<u128> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str()?.strip_suffix(".jsonl")?.parse().ok())
+            .collect();
+        starts.sort_unstable();
+        Ok(starts)
+    }
+
+    /// Delete segments that ended before `now - retention`; returns how many were removed.
+    pub fn prune(&self, now: u128) -> io::Result<usize> {
+        let Some(dir) = &self.dir else { return Ok(0) };
+        if self.retention_ms == 0 { return Ok(0); }
+        let horizon = now.saturating_sub(self.retention_ms);
+        let mut removed = 0;
+        for start in self.segments()? {
+            if start + self.segment_ms <= horizon {
+                fs::remove_file(dir.join(format!("{start:013}.jsonl")))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Records with `from <= ts <= to`, oldest first, at most `limit`. The flag is true when more matched.
+    pub fn query(&self, device: Option<&str>, from: u128, to: u128, limit: usize) -> io::Result<(Vec<HistoryRecord>, bool)> {
+        let Some(dir) = &self.dir else { return Ok((Vec::new(), false)) };
+        let mut out = Vec::new();
+        for start in self.segments()?.into_iter().filter(|s| *s <= to && s + self.segment_ms > from) {
+            let file = match File::open(dir.join(format!("{start:013}.jsonl"))) {
+                Ok(f) => f,
+                // pruned since listing
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut records: Vec<HistoryRecord> = BufReader::new(file).lines()
+                .map_while(Result::ok)
+                // a torn last line (crash mid-write) is skipped
+                .filter_map(|l| serde_json::from_str::<HistoryRecord>(&l).ok())
+                .filter(|r| r.ts >= from && r.ts <= to && device.is_none_or(|d| r.matches_device(d)))
+                .collect();
+            records.sort_by_key(|r| r.ts);
+            for r in records {
+                if out.len() == limit { return Ok((out, true)); }
+                out.push(r);
+            }
+        }
+        Ok((out, false))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub device: Option<String>,
+    /// Epoch ms (query strings cannot carry u128).
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Stored records for a time window (and optionally one device).
+#[get("/history")]
+pub async fn get_history(state: web::Data<IngestState>, q: web::Query<HistoryQuery>) -> impl Responder {
+    if !state.history.is_enabled() {
+        return HttpResponse::ServiceUnavailable().json(json!({ "code": "history_disabled", "message": "history.dir is empty (HISTORY_DIR)" }));
+    }
+    let now = now_ms();
+    let to = q.to.map_or(now, u128::from);
+    let from = q.from.map_or_else(|| to.saturating_sub(DEFAULT_WINDOW_MS), u128::from);
+    if from > to {
+        return HttpResponse::BadRequest().json(json!({ "code": "bad_range", "message": format!("from ({from}) is after to ({to})") }));
+    }
+    let limit = q.limit.unwrap_or(10_000).min(MAX_QUERY_LIMIT);
+    let device = q.device.clone().filter(|d| !d.is_empty());
+    let st = state.clone();
+    match web::block(move || st.history.query(device.as_deref(), from, to, limit)).await {
+        Ok(Ok((records, truncated))) => HttpResponse::Ok().json(json!({ "from": from, "to": to, "count": records.len(), "truncated": truncated, "records": records })),
+        Ok(Err(e)) => {
+            error!(error = %e, "history query failed");
+            HttpResponse::InternalServerError().json(json!({ "code": "storage_error", "message": e.to_string() }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "code": "storage_error", "message": e.to_string() })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::test_support::{self, Server, DEVICE, DEV_EUI};
+
+    fn record(ts: u128, device: &str) -> HistoryRecord {
+        HistoryRecord { ts, device_id_hex: device.into(), dev_eui: String::new(), message_number: (ts % 1000) as u16, message_type: 0x05, frame: json!({}), position: None, event: None }
+    }
+
+    /// Store with 1 s segments and 10 s retention holding records at 0.5 s to 2.5 s (0.9 s from "b").
+    fn filled(dir: &tempfile::TempDir) -> HistoryStore {
+        let store = HistoryStore::open(dir.path(), 1_000, 10_000).unwrap();
+        for ts in [500, 900, 1_200, 2_100, 2_500] {
+            store.append(&record(ts, if ts == 900 { "b" } else { "a" })).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn appends_go_to_the_segment_of_their_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(filled(&dir).segments().unwrap(), vec![0, 1_000, 2_000]);
+    }
+
+    #[test]
+    fn queries_filter_by_range_device_and_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = filled(&dir);
+        let (all, truncated) = store.query(None, 0, 3_000, 100).unwrap();
+        assert_eq!((all.iter().map(|r| r.ts).collect::<Vec<_>>(), truncated), (vec![500, 900, 1_200, 2_100, 2_500], false));
+        let (a, _) = store.query(Some("A"), 600, 2_200, 100).unwrap();
+        assert_eq!(a.iter().map(|r| r.ts).collect::<Vec<_>>(), vec![1_200, 2_100]);
+        assert!(store.query(None, 0, 3_000, 2).unwrap().1);
+    }
+
+    #[test]
+    fn prune_drops_segments_past_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = filled(&dir);
+        // Segment [0, 1000) ends at the 11s horizon, [1000, 2000) does not yet
+        assert_eq!(store.prune(11_500).unwrap(), 1);
+        assert_eq!(store.query(None, 0, 3_000, 100).unwrap().0.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn endpoint_returns_accepted_uplinks() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let now = now_ms();
+        let body = test_support::uplink_body(DEV_EUI, 7, test_support::location_report(DEVICE), now);
+        call_service(&app, TestRequest::post().uri("/v1/uwb").set_json(&body).to_request()).await;
+
+        let uri = format!("/history?device={}&from={}", DEVICE.hex(), now - 1_000);
+        let history: Value = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!((&history["count"], &history["records"][0]["messageNumber"]), (&json!(1), &json!(7)));
+    }
+
+    #[actix_web::test]
+    async fn endpoint_rejects_inverted_ranges() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, TestRequest::get().uri("/history?from=2000&to=1000").to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["code"], "bad_range");
+    }
+
+    #[actix_web::test]
+    async fn endpoint_is_unavailable_without_a_dir() {
+        let server = Server::new(|c| c.history.dir = String::new());
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, TestRequest::get().uri("/history").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,608 @@
+//! Per-device LoRaWAN keys with a fleet-wide default.
+//!
+//! Entries are keyed by devEui (16 hex, stored upper case) or in-frame device ID (8 hex, lower case) and
+//! persisted as a JSON array to `keys.file` (default `data/keys.json`, mode 0600) after every change.
+//! For an uplink, `resolve` returns the key sets to try in order:
+//! - the devEui entry, else the entry for the device ID in the last 8 hex digits of the devEui (tags are
+//!   provisioned as `00956900<deviceId>`);
+//! - while a rotation's overlap window is open, the entry's previous keys after its current ones;
+//! - without an entry, the default keys (see `secrets`; replaced when the secrets are reloaded). A revoked
+//!   entry never falls back: its frames fail with `device_key_revoked`.
+//!
+//! The devEui is whatever the request says, so after decoding `check_device_id` also rejects a frame
+//! whose in-frame device ID matches a revoked entry (by ID, or by the devEui suffix).
+//!
+//! Admin endpoints (all require `Authorization: Bearer $KEYS_ADMIN_TOKEN`, see `admin_auth`; 403
+//! `keys_admin_disabled` when unset).
+//! Responses only ever show key fingerprints (`sha256:1a2b3c4d`):
+//! - `GET /keys`: default keys and every device entry.
+//! - `GET /keys/{device}`: one entry (404 `device_keys_not_found`).
+//! - `PUT /keys/{device}`: add (201) or rotate (200); on rotation the old keys stay valid for `overlapMs`
+//!   (default `keys.rotation_overlap_ms`, 24 h).
+//! - `POST /keys/{device}/revoke`: drop the device's keys (current and previous) and reject its frames.
+//! - `DELETE /keys/{device}`: remove the entry; the device falls back to the default keys (204).
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::{env, fs, io};
+use tracing::{error, info, warn};
+use crate::admin_auth::{self, AdminError};
+use crate::anchor_registry::write_atomic;
+use crate::config::KeysConfig;
+use crate::device_registry::normalize_device;
+use crate::lorawan_stream::{now_ms, IngestState};
+use crate::secrets::{Secret, Secrets};
+
+pub const DEFAULT_OVERLAP_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// AES-128 key and HMAC sign token, both hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPair {
+    pub secret_key: Secret,
+    pub sign_token: Secret,
+}
+
+impl KeyPair {
+    pub(crate) fn validated(self, which: &'static str) -> Result<Self, KeyError> {
+        let secret_key = self.secret_key.expose().trim().to_ascii_uppercase();
+        let sign_token = self.sign_token.expose().trim().to_ascii_uppercase();
+        if secret_key.len() != 32 || !secret_key.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(KeyError::InvalidKey { field: which, reason: "secretKey must be 32 hex chars (AES-128)" });
+        }
+        if sign_token.is_empty() || !sign_token.len().is_multiple_of(2) || !sign_token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(KeyError::InvalidKey { field: which, reason: "signToken must be a non-empty, even-length hex string" });
+        }
+        Ok(KeyPair { secret_key: secret_key.into(), sign_token: sign_token.into() })
+    }
+
+    /// `{ secretKey, signToken }` as fingerprints.
+    pub fn masked(&self) -> Value {
+        json!({ "secretKey": self.secret_key.fingerprint(), "signToken": self.sign_token.fingerprint() })
+    }
+}
+
+/// Keys for one device; `downlink: None` encrypts 0x01 responses with the default downlink keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceKeys {
+    pub uplink: KeyPair,
+    #[serde(default)]
+    pub downlink: Option<KeyPair>,
+}
+
+impl DeviceKeys {
+    fn validated(self) -> Result<Self, KeyError> {
+        Ok(DeviceKeys { uplink: self.uplink.validated("uplink")?, downlink: self.downlink.map(|d| d.validated("downlink")).transpose()? })
+    }
+
+    fn masked(&self) -> Value {
+        json!({ "uplink": self.uplink.masked(), "downlink": self.downlink.as_ref().map(KeyPair::masked) })
+    }
+}
+
+/// Stored entry. `keys: None` means revoked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRecord {
+    pub device: String,
+    pub keys: Option<DeviceKeys>,
+    /// Keys replaced by the last rotation, still tried until `previous_until`.
+    #[serde(default)]
+    pub previous: Option<DeviceKeys>,
+    #[serde(default)]
+    pub previous_until: Option<u128>,
+    pub updated_at: u128,
+}
+
+impl KeyRecord {
+    pub fn is_revoked(&self) -> bool { self.keys.is_none() }
+
+    fn previous_at(&self, now: u128) -> Option<&DeviceKeys> {
+        self.previous.as_ref().filter(|_| self.previous_until.is_some_and(|until| now < until))
+    }
+
+    /// API view: masked keys, previous keys only while the overlap window is open.
+    pub fn summary(&self, now: u128) -> Value {
+        let previous = self.previous_at(now);
+        json!({
+            "device": self.device,
+            "revoked": self.is_revoked(),
+            "keys": self.keys.as_ref().map(DeviceKeys::masked),
+            "previous": previous.map(DeviceKeys::masked),
+            "previousUntil": previous.and(self.previous_until),
+            "updatedAt": self.updated_at,
+        })
+    }
+}
+
+/// Which entry a resolved key set came from (`uwb.keys.used{source}`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    Device,
+    /// Keys replaced by a rotation, inside the overlap window.
+    Previous,
+    Default,
+}
+
+impl KeySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeySource::Device => "device",
+            KeySource::Previous => "previous",
+            KeySource::Default => "default",
+        }
+    }
+}
+
+/// One uplink / downlink key combination to try for a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedKeys {
+    pub source: KeySource,
+    pub uplink: KeyPair,
+    pub downlink: KeyPair,
+}
+
+/// Why a key operation failed. `code()` values are stable (HTTP error bodies, `uwb.decode.err`).
+#[derive(Debug)]
+pub enum KeyError {
+    /// Not a 16-hex devEui or 8-hex device ID.
+    InvalidDevice(String),
+    InvalidKey { field: &'static str, reason: &'static str },
+    NotFound(String),
+    /// The device's keys were revoked; its frames are rejected.
+    Revoked(String),
+    /// Reading / writing `keys.file` failed.
+    Storage(io::Error),
+}
+
+impl KeyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            KeyError::InvalidDevice(_) => "invalid_device",
+            KeyError::InvalidKey { .. } => "invalid_key",
+            KeyError::NotFound(_) => "device_keys_not_found",
+            KeyError::Revoked(_) => "device_key_revoked",
+            KeyError::Storage(_) => "storage_error",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+
+    fn response(&self) -> HttpResponse {
+        let mut r = match self {
+            KeyError::InvalidDevice(_) | KeyError::InvalidKey { .. } => HttpResponse::BadRequest(),
+            KeyError::NotFound(_) => HttpResponse::NotFound(),
+            KeyError::Revoked(_) => HttpResponse::Conflict(),
+            KeyError::Storage(_) => HttpResponse::InternalServerError(),
+        };
+        r.json(self.to_json())
+    }
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::InvalidDevice(id) => write!(f, "device must be a 16-hex devEui or 8-hex device ID, got {id:?}"),
+            KeyError::InvalidKey { field, reason } => write!(f, "{field}: {reason}"),
+            KeyError::NotFound(id) => write!(f, "no keys stored for {id}"),
+            KeyError::Revoked(id) => write!(f, "keys for {id} are revoked"),
+            KeyError::Storage(e) => write!(f, "key storage: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+fn store_key(id: &str) -> Result<String, KeyError> {
+    normalize_device(id).ok_or_else(|| KeyError::InvalidDevice(id.trim().to_string()))
+}
+
+/// Device keys backed by a JSON file, plus the default keys; shared by all workers.
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    /// Default uplink / downlink keys; replaced on a secrets reload.
+    default: RwLock<(KeyPair, KeyPair)>,
+    overlap_ms: u64,
+    admin_token: RwLock<Option<Secret>>,
+    records: RwLock<BTreeMap<String, KeyRecord>>,
+}
+
+impl KeyStore {
+    /// Store that is never written to disk (tests, tools); admin API disabled.
+    pub fn in_memory(default_uplink: KeyPair, default_downlink: KeyPair) -> Self {
+        KeyStore { path: None, default: RwLock::new((default_uplink, default_downlink)), overlap_ms: DEFAULT_OVERLAP_MS, admin_token: RwLock::new(None), records: RwLock::new(BTreeMap::new()) }
+    }
+
+    /// Load `path` (an empty store when it does not exist yet).
+    pub fn open(path: impl Into<PathBuf>, default_uplink: KeyPair, default_downlink: KeyPair) -> Result<Self, KeyError> {
+        let path = path.into();
+        let records = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<KeyRecord>>(&bytes)
+                .map_err(|e| KeyError::Storage(io::Error::new(io::ErrorKind::InvalidData, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(KeyError::Storage(e)),
+        };
+        let records = records.into_iter().map(|r| (r.device.clone(), r)).collect();
+        Ok(KeyStore { path: Some(path), records: RwLock::new(records), ..Self::in_memory(default_uplink, default_downlink) })
+    }
+
+    /// Default keys and admin token from `secrets`, plus the `[keys]` file and rotation overlap.
+    /// Falls back to an in-memory store if the file cannot be read.
+    pub fn from_config(config: &KeysConfig, secrets: &Secrets) -> Self {
+        let (uplink, downlink) = (secrets.uplink.clone(), secrets.downlink.clone());
+        let path = &config.file;
+        let mut store = match Self::open(path, uplink.clone(), downlink.clone()) {
+            Ok(store) => {
+                info!(path = %path, devices = store.len(), "device key store loaded");
+                store
+            }
+            Err(e) => {
+                error!(path = %path, error = %e, "device key store not loaded; using default keys only, changes will be lost on restart");
+                Self::in_memory(uplink, downlink)
+            }
+        };
+        store.overlap_ms = config.rotation_overlap_ms;
+        store.set_secrets(secrets);
+        if secrets.admin_token.is_none() {
+            info!("admin API disabled (KEYS_ADMIN_TOKEN not set)");
+        }
+        if env::var("LOG_KEYS_FULL").is_ok() {
+            warn!("LOG_KEYS_FULL is no longer supported; keys are only ever logged as fingerprints");
+        }
+        store
+    }
+
+    /// Enable the admin API with this bearer token.
+    pub fn with_admin_token(self, token: impl Into<Secret>) -> Self {
+        *self.admin_token.write().unwrap_or_else(|p| p.into_inner()) = Some(token.into());
+        self
+    }
+
+    /// Swap in reloaded default keys and admin token; device entries are untouched.
+    pub fn set_secrets(&self, secrets: &Secrets) {
+        *self.default.write().unwrap_or_else(|p| p.into_inner()) = (secrets.uplink.clone(), secrets.downlink.clone());
+        *self.admin_token.write().unwrap_or_else(|p| p.into_inner()) = secrets.admin_token.clone();
+    }
+
+    fn defaults(&self) -> (KeyPair, KeyPair) {
+        self.default.read().unwrap_or_else(|p| p.into_inner()).clone()
+    }
+
+    fn persist(&self, records: &BTreeMap<String, KeyRecord>) -> Result<(), KeyError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        // readable by the owner only
+        write_atomic(path, &serde_json::to_vec_pretty(&records.values().collect::<Vec<_>>()).expect("keys serialize"), Some(0o600))
+            .map_err(KeyError::Storage)
+    }
+
+    pub fn len(&self) -> usize { self.records.read().unwrap_or_else(|p| p.into_inner()).len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn list(&self) -> Vec<KeyRecord> {
+        self.records.read().unwrap_or_else(|p| p.into_inner()).values().cloned().collect()
+    }
+
+    pub fn get(&self, device: &str) -> Result<KeyRecord, KeyError> {
+        let id = store_key(device)?;
+        self.records.read().unwrap_or_else(|p| p.into_inner()).get(&id).cloned().ok_or(KeyError::NotFound(id))
+    }
+
+    /// Key sets to try for an uplink from `dev_eui`, most specific first. `Err(Revoked)` when the
+    /// device's entry is revoked.
+    pub fn resolve(&self, dev_eui: &str, now: u128) -> Result<Vec<ResolvedKeys>, KeyError> {
+        let records = self.records.read().unwrap_or_else(|p| p.into_inner());
+        let by_eui = normalize_device(dev_eui).filter(|id| id.len() == 16);
+        let record = by_eui.as_deref()
+            .and_then(|eui| records.get(eui).or_else(|| records.get(&eui[8..].to_ascii_lowercase())));
+        let (default_uplink, default_downlink) = self.defaults();
+        let Some(record) = record else {
+            return Ok(vec![ResolvedKeys { source: KeySource::Default, uplink: default_uplink, downlink: default_downlink }]);
+        };
+        let Some(keys) = &record.keys else { return Err(KeyError::Revoked(record.device.clone())) };
+        let resolved = |source, k: &DeviceKeys| ResolvedKeys { source, uplink: k.uplink.clone(), downlink: k.downlink.clone().unwrap_or_else(|| default_downlink.clone()) };
+        let mut sets = vec![resolved(KeySource::Device, keys)];
+        if let Some(previous) = record.previous_at(now) {
+            sets.push(resolved(KeySource::Previous, previous));
+        }
+        Ok(sets)
+    }
+
+    /// `Err(Revoked)` when a revoked entry is keyed by `device_id_hex` (the decoded frame's device ID) or by
+    /// a devEui ending in it, whatever devEui the request carried.
+    pub fn check_device_id(&self, device_id_hex: &str) -> Result<(), KeyError> {
+        let records = self.records.read().unwrap_or_else(|p| p.into_inner());
+        let revoked = records.values().find(|r| r.keys.is_none() && match r.device.len() {
+            16 => r.device[8..].eq_ignore_ascii_case(device_id_hex),
+            _ => r.device.eq_ignore_ascii_case(device_id_hex),
+        });
+        match revoked {
+            Some(r) => Err(KeyError::Revoked(r.device.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Add keys for a device, or rotate them: the replaced keys stay valid for `overlap_ms`
+    /// (default `keys.rotation_overlap_ms`). Returns the stored record and whether it was newly created.
+    pub fn put(&self, device: &str, keys: DeviceKeys, overlap_ms: Option<u64>, now: u128) -> Result<(KeyRecord, bool), KeyError> {
+        let id = store_key(device)?;
+        let keys = keys.validated()?;
+        let mut records = self.records.write().unwrap_or_else(|p| p.into_inner());
+        let old = records.get(&id).cloned();
+        let mut record = KeyRecord { device: id.clone(), keys: Some(keys.clone()), previous: None, previous_until: None, updated_at: now };
+        if let Some(old) = &old {
+            match &old.keys {
+                // Same keys again: keep any overlap already running
+                Some(current) if *current == keys => {
+                    record.previous = old.previous.clone();
+                    record.previous_until = old.previous_until;
+                }
+                Some(current) => {
+                    record.previous = Some(current.clone());
+                    record.previous_until = Some(now + u128::from(overlap_ms.unwrap_or(self.overlap_ms)));
+                }
+                // Revoked keys are never tried again
+                None => {}
+            }
+        }
+        records.insert(id.clone(), record.clone());
+        if let Err(e) = self.persist(&records) {
+            match old {
+                Some(o) => { records.insert(id, o); }
+                None => { records.remove(&id); }
+            }
+            return Err(e);
+        }
+        Ok((record, old.is_none()))
+    }
+
+    /// Drop a device's keys and reject its frames from now on (also for devices on the default keys).
+    pub fn revoke(&self, device: &str, now: u128) -> Result<KeyRecord, KeyError> {
+        let id = store_key(device)?;
+        let mut records = self.records.write().unwrap_or_else(|p| p.into_inner());
+        let record = KeyRecord { device: id.clone(), keys: None, previous: None, previous_until: None, updated_at: now };
+        let old = records.insert(id.clone(), record.clone());
+        if let Err(e) = self.persist(&records) {
+            match old {
+                Some(o) => { records.insert(id, o); }
+                None => { records.remove(&id); }
+            }
+            return Err(e);
+        }
+        Ok(record)
+    }
+
+    /// Remove the entry; the device goes back to the default keys.
+    pub fn remove(&self, device: &str) -> Result<KeyRecord, KeyError> {
+        let id = store_key(device)?;
+        let mut records = self.records.write().unwrap_or_else(|p| p.into_inner());
+        let removed = records.remove(&id).ok_or(KeyError::NotFound(id))?;
+        if let Err(e) = self.persist(&records) {
+            records.insert(removed.device.clone(), removed);
+            return Err(e);
+        }
+        Ok(removed)
+    }
+
+    /// Check the `Authorization: Bearer` header against `KEYS_ADMIN_TOKEN`; also guards the registry writes.
+    pub(crate) fn authorize(&self, req: &HttpRequest) -> Result<(), AdminError> {
+        admin_auth::authorize(req, self.admin_token.read().unwrap_or_else(|p| p.into_inner()).as_ref())
+    }
+}
+
+/// `PUT /keys/{device}` body.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutKeysBody {
+    pub uplink: KeyPair,
+    #[serde(default)]
+    pub downlink: Option<KeyPair>,
+    /// Overlap window for the replaced keys; `keys.rotation_overlap_ms` when omitted.
+    pub overlap_ms: Option<u64>,
+}
+
+#[get("/keys")]
+pub async fn list_keys(req: HttpRequest, state: web::Data<IngestState>) -> impl Responder {
+    let keys = &state.keys;
+    if let Err(e) = keys.authorize(&req) { return e.response(); }
+    let now = now_ms();
+    let (uplink, downlink) = keys.defaults();
+    HttpResponse::Ok().json(json!({
+        "default": { "uplink": uplink.masked(), "downlink": downlink.masked() },
+        "overlapMs": keys.overlap_ms,
+        "devices": keys.list().iter().map(|r| r.summary(now)).collect::<Vec<_>>(),
+    }))
+}
+
+#[get("/keys/{device}")]
+pub async fn get_keys(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match state.keys.get(&path.into_inner()) {
+        Ok(r) => HttpResponse::Ok().json(r.summary(now_ms())),
+        Err(e) => e.response(),
+    }
+}
+
+#[put("/keys/{device}")]
+pub async fn put_keys(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>, body: web::Json<PutKeysBody>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    let b = body.into_inner();
+    let now = now_ms();
+    match state.keys.put(&path.into_inner(), DeviceKeys { uplink: b.uplink, downlink: b.downlink }, b.overlap_ms, now) {
+        Ok((r, true)) => {
+            info!(device = %r.device, "device keys added");
+            HttpResponse::Created().json(r.summary(now))
+        }
+        Ok((r, false)) => {
+            info!(device = %r.device, previous_until = ?r.previous_until, "device keys rotated");
+            HttpResponse::Ok().json(r.summary(now))
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[post("/keys/{device}/revoke")]
+pub async fn revoke_keys(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    let now = now_ms();
+    match state.keys.revoke(&path.into_inner(), now) {
+        Ok(r) => {
+            warn!(device = %r.device, "device keys revoked");
+            HttpResponse::Ok().json(r.summary(now))
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[delete("/keys/{device}")]
+pub async fn delete_keys(req: HttpRequest, state: web::Data<IngestState>, path: web::Path<String>) -> impl Responder {
+    if let Err(e) = state.keys.authorize(&req) { return e.response(); }
+    match state.keys.remove(&path.into_inner()) {
+        Ok(r) => {
+            info!(device = %r.device, "device keys removed; default keys apply");
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::test_support::{self, Server, ADMIN_TOKEN, DEVICE, DEV_EUI};
+
+    fn pair(k: char, t: char) -> KeyPair {
+        KeyPair { secret_key: k.to_string().repeat(32).into(), sign_token: t.to_string().repeat(16).into() }
+    }
+
+    fn keys(k: char, t: char) -> DeviceKeys {
+        DeviceKeys { uplink: pair(k, t), downlink: None }
+    }
+
+    /// (source, first hex digit of the uplink key) for each key set `resolve` returns.
+    fn sources(store: &KeyStore, eui: &str, now: u128) -> Vec<(KeySource, String)> {
+        store.resolve(eui, now).unwrap().iter().map(|k| (k.source, k.uplink.secret_key.expose()[..1].to_string())).collect()
+    }
+
+    #[test]
+    fn device_id_entries_match_the_dev_eui_suffix() {
+        let store = KeyStore::in_memory(pair('1', '2'), pair('3', '4'));
+        assert_eq!(sources(&store, "00956900A0BA3E29", 0), vec![(KeySource::Default, "1".into())]);
+        assert!(store.put("A0BA3E29", keys('a', 'b'), None, 0).unwrap().1);
+        // downlink falls back to the default
+        let resolved = store.resolve("00956900a0ba3e29", 0).unwrap();
+        assert_eq!((resolved[0].source, resolved[0].downlink.clone()), (KeySource::Device, pair('3', '4')));
+    }
+
+    #[test]
+    fn rejects_bad_devices_and_keys() {
+        let store = KeyStore::in_memory(pair('1', '2'), pair('3', '4'));
+        assert!(matches!(store.put("xyz", keys('a', 'b'), None, 0), Err(KeyError::InvalidDevice(_))));
+        assert!(matches!(store.put("a0ba3e29", DeviceKeys { uplink: KeyPair { secret_key: "00".into(), sign_token: "00".into() }, downlink: None }, None, 0), Err(KeyError::InvalidKey { .. })));
+    }
+
+    #[test]
+    fn rotation_keeps_the_old_keys_for_the_overlap() {
+        let store = KeyStore::in_memory(pair('1', '2'), pair('3', '4'));
+        store.put("a0ba3e29", keys('a', 'b'), None, 0).unwrap();
+        assert!(!store.put("a0ba3e29", keys('c', 'd'), Some(1000), 10).unwrap().1);
+        assert_eq!(sources(&store, "00956900A0BA3E29", 500), vec![(KeySource::Device, "C".into()), (KeySource::Previous, "A".into())]);
+        assert_eq!(sources(&store, "00956900A0BA3E29", 1010), vec![(KeySource::Device, "C".into())]);
+        // keys are never returned in full
+        assert!(!store.get("a0ba3e29").unwrap().summary(500).to_string().contains(&"C".repeat(32)));
+    }
+
+    #[test]
+    fn revoked_devices_never_fall_back_to_the_defaults() {
+        let store = KeyStore::in_memory(pair('1', '2'), pair('3', '4'));
+        store.put("a0ba3e29", keys('a', 'b'), None, 0).unwrap();
+        store.revoke("00956900A0BA3E29", 20).unwrap();
+        assert!(matches!(store.resolve("00956900A0BA3E29", 20), Err(KeyError::Revoked(_))));
+        // removing the devEui entry (the revoke) uncovers the device-ID entry again
+        store.remove("00956900A0BA3E29").unwrap();
+        assert_eq!(sources(&store, "00956900A0BA3E29", 30), vec![(KeySource::Device, "A".into())]);
+    }
+
+    #[test]
+    fn entries_persist_readable_by_the_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let store = KeyStore::open(&path, pair('1', '2'), pair('3', '4')).unwrap();
+        store.put("a0ba3e29", keys('a', 'b'), None, 0).unwrap();
+        store.revoke("00956900B0BA3E29", 1).unwrap();
+        let reopened = KeyStore::open(&path, pair('1', '2'), pair('3', '4')).unwrap();
+        assert_eq!(reopened.list(), store.list());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn revocation_follows_the_in_frame_device_id() {
+        let store = KeyStore::in_memory(pair('1', '2'), pair('3', '4'));
+        store.put("a0ba3e29", DeviceKeys { uplink: pair('a', 'b'), downlink: None }, None, 0).unwrap();
+        assert!(store.check_device_id("a0ba3e29").is_ok());
+        store.revoke("a0ba3e29", 1).unwrap();
+        // a request without (or with another) devEui resolves to the defaults, the frame's ID still matches
+        assert_eq!(store.resolve("", 1).unwrap()[0].source, KeySource::Default);
+        assert!(matches!(store.check_device_id("A0BA3E29"), Err(KeyError::Revoked(_))));
+        store.revoke("00956900B0BA3E29", 2).unwrap();
+        assert!(matches!(store.check_device_id("b0ba3e29"), Err(KeyError::Revoked(_))));
+        assert!(store.check_device_id("c0ba3e29").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn admin_endpoints_need_the_token() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        // no header, wrong token, right token without the `Bearer` scheme
+        for auth in [None, Some("Bearer wrong"), Some(ADMIN_TOKEN)] {
+            let req = TestRequest::get().uri("/keys");
+            let req = match auth { Some(a) => req.insert_header(("authorization", a)), None => req };
+            assert_eq!(call_service(&app, req.to_request()).await.status(), StatusCode::UNAUTHORIZED, "{auth:?}");
+        }
+        server.state.keys.set_secrets(&Secrets { admin_token: None, ..test_support::secrets() });
+        let res = call_service(&app, TestRequest::get().uri("/keys").insert_header(("authorization", "Bearer ")).to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn admin_endpoints_answer_404_and_400() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let bearer = format!("Bearer {ADMIN_TOKEN}");
+        let missing = TestRequest::get().uri("/keys/a0ba3e29").insert_header(("authorization", bearer.as_str())).to_request();
+        assert_eq!(call_service(&app, missing).await.status(), StatusCode::NOT_FOUND);
+        let delete = TestRequest::delete().uri("/keys/a0ba3e29").insert_header(("authorization", bearer.as_str())).to_request();
+        assert_eq!(call_service(&app, delete).await.status(), StatusCode::NOT_FOUND);
+        let bad = TestRequest::put().uri("/keys/xyz").insert_header(("authorization", bearer.as_str()))
+            .set_json(json!({ "uplink": { "secretKey": "A".repeat(32), "signToken": "B".repeat(16) } })).to_request();
+        let bad: Value = call_and_read_body_json(&app, bad).await;
+        assert_eq!(bad["code"], "invalid_device");
+    }
+
+    #[actix_web::test]
+    async fn revoked_devices_are_refused_at_ingestion() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let revoke = TestRequest::post().uri(&format!("/keys/{}/revoke", DEVICE.hex())).insert_header(("authorization", format!("Bearer {ADMIN_TOKEN}"))).to_request();
+        assert_eq!(call_service(&app, revoke).await.status(), StatusCode::OK);
+        // the frame's device ID is checked whatever devEui the request carries
+        for dev_eui in [DEV_EUI, "", "0011223344556677"] {
+            let body = test_support::uplink_body(dev_eui, 1, test_support::location_report(DEVICE), now_ms());
+            let reply: Value = call_and_read_body_json(&app, TestRequest::post().uri("/v1/uwb").set_json(&body).to_request()).await;
+            assert_eq!((&reply["ok"], &reply["error"]["code"]), (&json!(false), &json!("device_key_revoked")), "{dev_eui:?}");
+        }
+    }
+}
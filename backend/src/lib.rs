@@ -0,0 +1,25 @@
+//! Library half of the backend: LoRaWAN codec and ingestion/SSE handlers.
+//!
+//! Shared by the server (`main.rs`) and the helper binaries under `src/bin/`.
+pub mod admin_auth;
+pub mod anchor_registry;
+pub mod capture;
+pub mod config;
+pub mod device_registry;
+pub mod device_state;
+pub mod device_status;
+pub mod events;
+pub mod history;
+pub mod key_store;
+pub mod lorawan_codec;
+pub mod lorawan_stream;
+pub mod outlier;
+pub mod positioning;
+pub mod replay;
+pub mod replay_guard;
+pub mod secrets;
+pub mod stream_filter;
+pub mod tracking;
+pub mod ws_stream;
+#[cfg(test)]
+mod test_support;
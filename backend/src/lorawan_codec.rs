@@ -1,29 +1,111 @@
-//! LoRaWAN codec utilities (uplink decode & downlink encode/sign).
+//! LoRaWAN codec utilities (uplink decode/encode & downlink encode/sign).
 //!
 //! Ported from original Node/TypeScript (`decode.ts`, `encode_client.ts`) implementation with
 //! adjustments for Rust's crypto crates. The codec handles:
 //! - AES-ECB (manual block mode) with PKCS7 padding for both decrypt (uplink) & encrypt (downlink).
 //! - HMAC-SHA256 signature verification/building (first 32 bytes of plaintext).
-//! - Frame parsing for message types 0x01 (registration), 0x05 (location report), 0x03 (status).
+//! - Frame parsing for message types 0x01 (registration), 0x05 (location report), 0x03 (status)
+//!   into the typed `Frame` enum; `DecodedFrame::explain` renders the legacy hex breakdown.
 //! - Construction of downlink registration response buffer + encryption routine.
+//! - Uplink encoder (`Uplink`, `encrypt_uplink`) producing the ciphertext a tag would send, for
+//!   simulators, fixtures and load tests.
 //! - Conversion of 0x05 frames to a frontend `uwb_update` JSON shape consumed by the React app.
 //!
 //! Security Notes:
 //! - AES-ECB is retained for parity with device firmware; consider migrating to an authenticated
 //!   mode (e.g. AES-GCM) in future revisions.
-//! - The current decode does NOT re-compute & validate HMAC signature; it preserves it for
-//!   structural alignment. Add verification when timestamp / signing inputs are available.
-//! - Input frames are assumed well-formed; error paths surface descriptive `String` messages.
+//! - Uplink HMACs are verified against the request timestamp when one is supplied (see
+//!   `HmacBinding`); payload-only signatures are still accepted unless `require_timestamp_hmac` is set.
+//! - Failures are reported as `CodecError`, whose `code()` is a stable identifier for metrics/dashboards.
 use aes::Aes128;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use hex::FromHex;
 use base64::Engine; // bring trait in scope for encode/decode
-use tracing::{debug, info, warn, error};
+use tracing::{debug, warn};
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Codec failure with a stable machine-readable `code()` (used in metrics labels, SSE and HTTP).
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    /// Uplink `data` is not valid base64.
+    BadBase64(base64::DecodeError),
+    /// AES key is not 32 hex chars (16 bytes).
+    BadKey(hex::FromHexError),
+    /// HMAC sign token is not valid hex.
+    BadSignToken(hex::FromHexError),
+    /// Ciphertext length is zero, too short for the IV, or not a multiple of the block size.
+    BadCiphertextLength(usize),
+    /// PKCS7 padding missing or inconsistent after decrypt.
+    BadPadding(&'static str),
+    /// No signature layout produced a matching HMAC.
+    HmacMismatch,
+    /// Payload shorter than header + trailer.
+    FrameTooShort(usize),
+    /// Message type outside 0x01/0x03/0x05.
+    UnknownMessageType(u8),
+    /// Trailer CRC does not match `checksum16` over type + content.
+    CrcMismatch { expected: u16, actual: u16 },
+    /// Frame header or frame-end bytes are not the `FFEE` / `EEFF` markers.
+    BadFrameMarker { header: [u8;2], frame_end: [u8;2] },
+    /// Downlink requested for a frame that is not a registration.
+    NotRegistration,
+    /// `Frame::Unparsed` has no message type, so it cannot be encoded.
+    UnparsedFrame,
+}
+
+impl CodecError {
+    /// Stable snake_case identifier; never change existing values (dashboards group on them).
+    pub fn code(&self) -> &'static str {
+        match self {
+            CodecError::BadBase64(_) => "bad_base64",
+            CodecError::BadKey(_) => "bad_key",
+            CodecError::BadSignToken(_) => "bad_sign_token",
+            CodecError::BadCiphertextLength(_) => "bad_ciphertext_length",
+            CodecError::BadPadding(_) => "bad_padding",
+            CodecError::HmacMismatch => "hmac_mismatch",
+            CodecError::FrameTooShort(_) => "frame_too_short",
+            CodecError::UnknownMessageType(_) => "unknown_message_type",
+            CodecError::CrcMismatch { .. } => "crc_mismatch",
+            CodecError::BadFrameMarker { .. } => "bad_frame_marker",
+            CodecError::NotRegistration => "not_registration",
+            CodecError::UnparsedFrame => "unparsed_frame",
+        }
+    }
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::BadBase64(e) => write!(f, "base64: {e}"),
+            CodecError::BadKey(e) => write!(f, "bad key hex: {e}"),
+            CodecError::BadSignToken(e) => write!(f, "bad sign token hex: {e}"),
+            CodecError::BadCiphertextLength(n) => write!(f, "bad ciphertext length: {n}"),
+            CodecError::BadPadding(why) => write!(f, "bad padding: {why}"),
+            CodecError::HmacMismatch => f.write_str("hmac mismatch"),
+            CodecError::FrameTooShort(n) => write!(f, "frame too short: {n} bytes"),
+            CodecError::UnknownMessageType(t) => write!(f, "unknown message type: 0x{t:02x}"),
+            CodecError::CrcMismatch { expected, actual } => write!(f, "crc mismatch: expected {expected:04x}, got {actual:04x}"),
+            CodecError::BadFrameMarker { header, frame_end } => write!(f, "bad frame markers: header {}, end {}", hex::encode(header), hex::encode(frame_end)),
+            CodecError::NotRegistration => f.write_str("not a registration frame"),
+            CodecError::UnparsedFrame => f.write_str("cannot encode an unparsed frame"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+fn parse_key(key_hex: &str) -> Result<[u8;16], CodecError> {
+    <[u8;16]>::from_hex(key_hex).map_err(CodecError::BadKey)
+}
+
+fn decode_b64(b64: &str) -> Result<Vec<u8>, CodecError> {
+    base64::engine::general_purpose::STANDARD.decode(b64.as_bytes()).map_err(CodecError::BadBase64)
+}
+
 /// Folded 16-bit checksum (same algorithm as Node version) used for CRC field.
 pub fn checksum16(data: &[u8]) -> u16 {
     let mut sum: u32 = 0;
@@ -34,20 +116,20 @@ pub fn checksum16(data: &[u8]) -> u16 {
 }
 
 /// Remove PKCS7 padding from a mutable buffer.
-fn pkcs7_unpad(data: &mut Vec<u8>) -> Result<(), String> {
+fn pkcs7_unpad(data: &mut Vec<u8>) -> Result<(), CodecError> {
     if data.is_empty() { 
         debug!("pkcs7_unpad: empty data");
-        return Err("empty data".into()); 
+        return Err(CodecError::BadPadding("empty data")); 
     }
     let pad = *data.last().unwrap() as usize;
     if pad==0 || pad>16 || pad>data.len() { 
         debug!(pad, len = data.len(), "pkcs7_unpad: invalid pad value");
-        return Err("bad padding".into()); 
+        return Err(CodecError::BadPadding("invalid pad value")); 
     }
     let len = data.len();
     if !data[len-pad..].iter().all(|&b| b as usize == pad) { 
         debug!(pad, len = data.len(), tail = %hex::encode(&data[len-std::cmp::min(pad, len)..]), "pkcs7_unpad: pad bytes mismatch");
-        return Err("bad padding bytes".into()); 
+        return Err(CodecError::BadPadding("pad bytes mismatch")); 
     }
     data.truncate(len - pad);
     Ok(())
@@ -56,7 +138,7 @@ fn pkcs7_unpad(data: &mut Vec<u8>) -> Result<(), String> {
 /// Apply PKCS7 padding producing a new Vec<u8> sized to multiple of 16.
 fn pkcs7_pad(mut data: Vec<u8>) -> Vec<u8> {
     let pad = 16 - (data.len() % 16);
-    data.extend(std::iter::repeat(pad as u8).take(pad));
+    data.resize(data.len() + pad, pad as u8);
     data
 }
 
@@ -64,7 +146,7 @@ fn pkcs7_pad(mut data: Vec<u8>) -> Vec<u8> {
 fn aes_ecb_block_encrypt(key: &[u8;16], block: &mut [u8;16]) {
     use aes::cipher::{BlockEncrypt, KeyInit};
     use aes::cipher::generic_array::GenericArray;
-    let cipher = Aes128::new(&GenericArray::from_slice(key));
+    let cipher = Aes128::new(GenericArray::from_slice(key));
     let mut ba = GenericArray::clone_from_slice(block);
     cipher.encrypt_block(&mut ba);
     block.copy_from_slice(&ba);
@@ -74,38 +156,42 @@ fn aes_ecb_block_encrypt(key: &[u8;16], block: &mut [u8;16]) {
 fn aes_ecb_block_decrypt(key: &[u8;16], block: &mut [u8;16]) {
     use aes::cipher::{BlockDecrypt, KeyInit};
     use aes::cipher::generic_array::GenericArray;
-    let cipher = Aes128::new(&GenericArray::from_slice(key));
+    let cipher = Aes128::new(GenericArray::from_slice(key));
     let mut ba = GenericArray::clone_from_slice(block);
     cipher.decrypt_block(&mut ba);
     block.copy_from_slice(&ba);
 }
 
-/// Decrypt ciphertext using AES-128-CBC. Supports two IV modes:
-/// - "prefix": first 16 bytes of ciphertext are the IV, remaining bytes are the actual ciphertext
-/// - "zero": IV is 16 zero bytes, entire ciphertext is treated as CBC blocks
+/// IV source for the AES-128-CBC fallback.
+#[derive(Debug, Clone, Copy)]
+enum CbcIv {
+    /// First 16 bytes of ciphertext are the IV, remaining bytes are the actual ciphertext.
+    Prefix,
+    /// IV is 16 zero bytes, entire ciphertext is treated as CBC blocks.
+    Zero,
+}
+
+/// Decrypt ciphertext using AES-128-CBC with the given IV mode.
 /// If `do_unpad` is true, PKCS7 unpadding is applied to the result.
-fn aes_cbc_decrypt(key_hex: &str, b64: &str, iv_mode: &str, do_unpad: bool) -> Result<Vec<u8>, String> {
-    debug!(key_hex_len = key_hex.len(), b64_len = b64.len(), iv_mode, do_unpad, "aes_cbc_decrypt: starting");
-    let key = <[u8;16]>::from_hex(key_hex).map_err(|e| format!("bad key hex: {e}"))?;
-    let ct_all = base64::engine::general_purpose::STANDARD
-        .decode(b64.as_bytes())
-        .map_err(|e| format!("base64: {e}"))?;
-    if ct_all.len() < 16 { return Err("ct too short".into()); }
+fn aes_cbc_decrypt(key_hex: &str, b64: &str, iv_mode: CbcIv, do_unpad: bool) -> Result<Vec<u8>, CodecError> {
+    debug!(key_hex_len = key_hex.len(), b64_len = b64.len(), ?iv_mode, do_unpad, "aes_cbc_decrypt: starting");
+    let key = parse_key(key_hex)?;
+    let ct_all = decode_b64(b64)?;
+    if ct_all.len() < 16 { return Err(CodecError::BadCiphertextLength(ct_all.len())); }
     let (iv, ct) = match iv_mode {
-        "prefix" => {
-            if ct_all.len() < 32 { return Err("ct too short for prefix iv".into()); }
+        CbcIv::Prefix => {
+            if ct_all.len() < 32 { return Err(CodecError::BadCiphertextLength(ct_all.len())); }
             let mut iv = [0u8;16];
             iv.copy_from_slice(&ct_all[0..16]);
             (iv, ct_all[16..].to_vec())
         },
-        "zero" => {
+        CbcIv::Zero => {
             let iv = [0u8;16];
             (iv, ct_all)
         },
-        other => { return Err(format!("unknown iv_mode: {}", other)); }
     };
     debug!(ct_len = ct.len(), iv_first8 = %hex::encode(&iv[0..8.min(iv.len())]), "aes_cbc_decrypt: ct prepared");
-    if ct.len() % 16 != 0 { return Err("ct not multiple of block size".into()); }
+    if ct.len() % 16 != 0 { return Err(CodecError::BadCiphertextLength(ct.len())); }
     let mut out = vec![0u8; ct.len()];
     let mut prev = iv;
     for (i, chunk) in ct.chunks(16).enumerate() {
@@ -120,11 +206,11 @@ fn aes_cbc_decrypt(key_hex: &str, b64: &str, iv_mode: &str, do_unpad: bool) -> R
         prev = new_prev;
     }
     let pad_val = *out.last().unwrap_or(&0);
-    debug!(pt_len = out.len(), pt_first32 = %hex::encode(&out.get(0..32).unwrap_or(&[])), pad_val, do_unpad, "aes_cbc_decrypt: decrypted before unpad");
+    debug!(pt_len = out.len(), pt_first32 = %hex::encode(out.get(0..32).unwrap_or(&[])), pad_val, do_unpad, "aes_cbc_decrypt: decrypted before unpad");
     if do_unpad {
         let mut tmp = out;
         pkcs7_unpad(&mut tmp)?;
-        debug!(pt_len = tmp.len(), pt_first32 = %hex::encode(&tmp.get(0..32).unwrap_or(&[])), "aes_cbc_decrypt: unpad ok");
+        debug!(pt_len = tmp.len(), pt_first32 = %hex::encode(tmp.get(0..32).unwrap_or(&[])), "aes_cbc_decrypt: unpad ok");
         Ok(tmp)
     } else {
         Ok(out)
@@ -132,16 +218,14 @@ fn aes_cbc_decrypt(key_hex: &str, b64: &str, iv_mode: &str, do_unpad: bool) -> R
 }
 
 /// Decrypt base64 ciphertext using hex key (AES-128-ECB + PKCS7). Returns plaintext bytes.
-fn aes_ecb_decrypt(key_hex: &str, b64: &str) -> Result<Vec<u8>, String> {
+fn aes_ecb_decrypt(key_hex: &str, b64: &str) -> Result<Vec<u8>, CodecError> {
     debug!(key_hex_len = key_hex.len(), b64_len = b64.len(), "aes_ecb_decrypt: starting");
-    let key = <[u8;16]>::from_hex(key_hex).map_err(|e| format!("bad key hex: {e}"))?;
-    let ct = base64::engine::general_purpose::STANDARD
-        .decode(b64.as_bytes())
-        .map_err(|e| format!("base64: {e}"))?;
-    debug!(ct_len = ct.len(), ct_first16 = %hex::encode(&ct.get(0..16).unwrap_or(&[])), "aes_ecb_decrypt: decoded base64");
+    let key = parse_key(key_hex)?;
+    let ct = decode_b64(b64)?;
+    debug!(ct_len = ct.len(), ct_first16 = %hex::encode(ct.get(0..16).unwrap_or(&[])), "aes_ecb_decrypt: decoded base64");
     if ct.len() % 16 != 0 { 
         warn!(ct_len = ct.len(), "aes_ecb_decrypt: ciphertext not multiple of 16");
-        return Err("ct not multiple of block size".into()); 
+        return Err(CodecError::BadCiphertextLength(ct.len())); 
     }
     let mut out = vec![0u8; ct.len()];
     for (i, chunk) in ct.chunks(16).enumerate() {
@@ -151,24 +235,22 @@ fn aes_ecb_decrypt(key_hex: &str, b64: &str) -> Result<Vec<u8>, String> {
         out[i*16..(i+1)*16].copy_from_slice(&block);
     }
     let pad_val = *out.last().unwrap_or(&0);
-    debug!(pt_len = out.len(), pt_first32 = %hex::encode(&out.get(0..32).unwrap_or(&[])), pad_val, "aes_ecb_decrypt: decrypted before unpad");
+    debug!(pt_len = out.len(), pt_first32 = %hex::encode(out.get(0..32).unwrap_or(&[])), pad_val, "aes_ecb_decrypt: decrypted before unpad");
     pkcs7_unpad(&mut out)?;
-    debug!(pt_len = out.len(), pt_first32 = %hex::encode(&out.get(0..32).unwrap_or(&[])), "aes_ecb_decrypt: unpad ok");
+    debug!(pt_len = out.len(), pt_first32 = %hex::encode(out.get(0..32).unwrap_or(&[])), "aes_ecb_decrypt: unpad ok");
     Ok(out)
 }
 
 /// Decrypt base64 ciphertext using hex key (AES-128-ECB) without PKCS7 unpadding.
 /// Used as a fallback when devices do not apply padding and plaintext is already a multiple of 16 bytes.
-fn aes_ecb_decrypt_no_unpad(key_hex: &str, b64: &str) -> Result<Vec<u8>, String> {
+fn aes_ecb_decrypt_no_unpad(key_hex: &str, b64: &str) -> Result<Vec<u8>, CodecError> {
     debug!(key_hex_len = key_hex.len(), b64_len = b64.len(), "aes_ecb_decrypt_no_unpad: starting");
-    let key = <[u8;16]>::from_hex(key_hex).map_err(|e| format!("bad key hex: {e}"))?;
-    let ct = base64::engine::general_purpose::STANDARD
-        .decode(b64.as_bytes())
-        .map_err(|e| format!("base64: {e}"))?;
-    debug!(ct_len = ct.len(), ct_first16 = %hex::encode(&ct.get(0..16).unwrap_or(&[])), "aes_ecb_decrypt_no_unpad: decoded base64");
+    let key = parse_key(key_hex)?;
+    let ct = decode_b64(b64)?;
+    debug!(ct_len = ct.len(), ct_first16 = %hex::encode(ct.get(0..16).unwrap_or(&[])), "aes_ecb_decrypt_no_unpad: decoded base64");
     if ct.len() % 16 != 0 {
         warn!(ct_len = ct.len(), "aes_ecb_decrypt_no_unpad: ciphertext not multiple of 16");
-        return Err("ct not multiple of block size".into());
+        return Err(CodecError::BadCiphertextLength(ct.len()));
     }
     let mut out = vec![0u8; ct.len()];
     for (i, chunk) in ct.chunks(16).enumerate() {
@@ -177,13 +259,13 @@ fn aes_ecb_decrypt_no_unpad(key_hex: &str, b64: &str) -> Result<Vec<u8>, String>
         aes_ecb_block_decrypt(&key, &mut block);
         out[i*16..(i+1)*16].copy_from_slice(&block);
     }
-    debug!(pt_len = out.len(), pt_first32 = %hex::encode(&out.get(0..32).unwrap_or(&[])), "aes_ecb_decrypt_no_unpad: done");
+    debug!(pt_len = out.len(), pt_first32 = %hex::encode(out.get(0..32).unwrap_or(&[])), "aes_ecb_decrypt_no_unpad: done");
     Ok(out)
 }
 
 /// Encrypt plaintext bytes using hex key (AES-128-ECB + PKCS7) -> base64 ciphertext.
-fn aes_ecb_encrypt(key_hex: &str, pt: &[u8]) -> Result<String, String> {
-    let key = <[u8;16]>::from_hex(key_hex).map_err(|e| format!("bad key hex: {e}"))?;
+fn aes_ecb_encrypt(key_hex: &str, pt: &[u8]) -> Result<String, CodecError> {
+    let key = parse_key(key_hex)?;
     let mut data = pkcs7_pad(pt.to_vec());
     for chunk in data.chunks_mut(16) {
         let mut block = [0u8;16];
@@ -195,10 +277,11 @@ fn aes_ecb_encrypt(key_hex: &str, pt: &[u8]) -> Result<String, String> {
 }
 
 /// Compute HMAC-SHA256 over hex input using hex key; returns raw 32-byte digest.
-fn hmac_sha256_hex(data_hex: &str, token_hex: &str) -> Result<Vec<u8>, String> {
-    let data = Vec::from_hex(data_hex).map_err(|e| format!("data hex: {e}"))?;
-    let key = Vec::from_hex(token_hex).map_err(|e| format!("token hex: {e}"))?;
-    let mut mac = HmacSha256::new_from_slice(&key).map_err(|e| format!("hmac init: {e}"))?;
+/// `data_hex` is always produced by this module, so only the token can be malformed.
+fn hmac_sha256_hex(data_hex: &str, token_hex: &str) -> Result<Vec<u8>, CodecError> {
+    let data = Vec::from_hex(data_hex).expect("data_hex built with hex::encode");
+    let key = Vec::from_hex(token_hex).map_err(CodecError::BadSignToken)?;
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length");
     mac.update(&data);
     Ok(mac.finalize().into_bytes().to_vec())
 }
@@ -213,157 +296,566 @@ fn timestamp_be8(ts_ms: u128) -> [u8;8] {
     out
 }
 
-/// Parsed uplink frame metadata and helper representation.
-#[derive(Debug)]
+/// 4-byte device identifier carried in registration (0x01) and location (0x05) frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub u32);
+
+impl DeviceId {
+    /// Lower-case 8 hex chars, the `deviceIdHex` form used by the frontend.
+    pub fn hex(&self) -> String { format!("{:08x}", self.0) }
+}
+
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(&self.hex()) }
+}
+
+impl Serialize for DeviceId {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> { s.serialize_str(&self.hex()) }
+}
+
+/// Fixed 7-byte prefix shared by every frame: magic, equipment code, message number, ACK flag, type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameHeader {
+    pub magic: [u8;2],
+    pub equipment: u8,
+    pub message_number: u16,
+    pub ack_flag: u8,
+    pub message_type: u8,
+}
+
+/// 4-byte suffix: big-endian `checksum16` followed by the frame-end marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameTrailer {
+    pub crc: u16,
+    pub frame_end: [u8;2],
+}
+
+/// Magic bytes opening every frame.
+pub const FRAME_HEADER: [u8;2] = [0xFF, 0xEE];
+/// Magic bytes closing every frame.
+pub const FRAME_END: [u8;2] = [0xEE, 0xFF];
+
+/// How `decode_frame` treats frames whose CRC or header/end markers are wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegrityMode {
+    /// Reject the frame (`crc_mismatch` / `bad_frame_marker`).
+    #[default]
+    Strict,
+    /// Accept the frame and report the failure in `DecodedFrame::integrity`.
+    Lenient,
+}
+
+
+/// Input the uplink HMAC was found to cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HmacBinding {
+    /// `hex(payload) || hex(timestampBE8)` with the request's `content.timestamp` (same scheme as downlinks).
+    Timestamp(u128),
+    /// Legacy `hex(payload)` only; replayable, rejected when `require_timestamp_hmac` is set.
+    Payload,
+    /// No layout matched; accepted only because `allow_hmac_mismatch` is set.
+    Unverified,
+}
+
+/// Result of checking the trailer CRC and the header/end markers of an uplink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameIntegrity {
+    /// `checksum16(message_type || data_content)` as it should appear in the trailer.
+    pub expected_crc: u16,
+    pub crc_ok: bool,
+    pub header_ok: bool,
+    pub frame_end_ok: bool,
+}
+
+impl FrameIntegrity {
+    fn check(header: &FrameHeader, content: &[u8], trailer: &FrameTrailer) -> Self {
+        let mut crc_input = Vec::with_capacity(content.len() + 1);
+        crc_input.push(header.message_type);
+        crc_input.extend_from_slice(content);
+        let expected_crc = checksum16(&crc_input);
+        FrameIntegrity {
+            expected_crc,
+            crc_ok: expected_crc == trailer.crc,
+            header_ok: header.magic == FRAME_HEADER,
+            frame_end_ok: trailer.frame_end == FRAME_END,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool { self.crc_ok && self.header_ok && self.frame_end_ok }
+}
+
+/// Battery level as reported by tags and beacons (percent, 0..=100).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Battery(pub u8);
+
+/// Physical activity flag of a location report (`0x01` = moving, anything else = still).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Motion { Still, Moving }
+
+impl Motion {
+    pub fn from_flag(flag: u8) -> Self { if flag == 1 { Motion::Moving } else { Motion::Still } }
+    pub fn flag(self) -> u8 { match self { Motion::Moving => 1, Motion::Still => 0 } }
+    /// Text used in the `motion` field of `uwb_update`.
+    pub fn label(self) -> &'static str { match self { Motion::Moving => "Movement Detected", Motion::Still => "No Movement" } }
+}
+
+/// One ranged beacon: `major(2) minor(2) distance_cm(2 BE) battery(1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Beacon {
+    pub major: u16,
+    pub minor: u16,
+    pub distance_cm: u16,
+    pub battery: Battery,
+}
+
+impl Beacon {
+    pub const LEN: usize = 7;
+
+    fn parse(b: &[u8]) -> Self {
+        Beacon {
+            major: u16::from_be_bytes([b[0], b[1]]),
+            minor: u16::from_be_bytes([b[2], b[3]]),
+            distance_cm: u16::from_be_bytes([b[4], b[5]]),
+            battery: Battery(b[6]),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.major.to_be_bytes());
+        out.extend_from_slice(&self.minor.to_be_bytes());
+        out.extend_from_slice(&self.distance_cm.to_be_bytes());
+        out.push(self.battery.0);
+    }
+
+    /// `major || minor` as 8 lower-case hex chars (anchor key used by the frontend).
+    pub fn beacon_id(&self) -> String { format!("{:04x}{:04x}", self.major, self.minor) }
+}
+
+/// 0x01 registration data content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Registration {
+    pub device_id: DeviceId,
+    pub version_type: u16,
+    pub min_tx_period: u8,
+    pub motion_assist: u8,
+    pub beacon_search_timeout: u8,
+    pub beacon_search_quantity: u8,
+}
+
+impl Registration {
+    pub const LEN: usize = 10;
+
+    fn parse(c: &[u8]) -> Option<Self> {
+        if c.len() < Self::LEN { return None; }
+        Some(Registration {
+            device_id: DeviceId(u32::from_be_bytes([c[0], c[1], c[2], c[3]])),
+            version_type: u16::from_be_bytes([c[4], c[5]]),
+            min_tx_period: c[6],
+            motion_assist: c[7],
+            beacon_search_timeout: c[8],
+            beacon_search_quantity: c[9],
+        })
+    }
+
+    /// Data content as a tag sends it: the 10 parsed bytes plus the trailing reserved byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::LEN + 1);
+        out.extend_from_slice(&self.device_id.0.to_be_bytes());
+        out.extend_from_slice(&self.version_type.to_be_bytes());
+        out.extend_from_slice(&[self.min_tx_period, self.motion_assist, self.beacon_search_timeout, self.beacon_search_quantity, 0x00]);
+        out
+    }
+
+    /// Data content of the 0x02 registration response (Node `newBufferResponse`):
+    /// device ID | 0x01 | version/type | 0x00 0x01 0x01 | reserved(2).
+    pub fn response_body(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(12);
+        out.extend_from_slice(&self.device_id.0.to_be_bytes());
+        out.push(0x01);
+        out.extend_from_slice(&self.version_type.to_be_bytes());
+        out.extend_from_slice(&[0x00, 0x01, 0x01, 0x00, 0x00]);
+        out
+    }
+}
+
+/// 0x05 location report: device, motion and the list of ranged beacons.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationReport {
+    pub device_id: DeviceId,
+    /// Count declared by the device; `beacons` holds at most this many (but always the first).
+    pub beacon_count: u8,
+    pub motion: Motion,
+    pub beacons: Vec<Beacon>,
+}
+
+impl LocationReport {
+    /// Device ID, count, motion flag and the first (named) beacon.
+    pub const MIN_LEN: usize = 6 + Beacon::LEN;
+
+    fn parse(c: &[u8]) -> Option<Self> {
+        if c.len() < Self::MIN_LEN { return None; }
+        let beacon_count = c[4];
+        let mut beacons = vec![Beacon::parse(&c[6..13])];
+        for chunk in c[13..].chunks_exact(Beacon::LEN) {
+            if beacons.len() >= beacon_count as usize { break; }
+            beacons.push(Beacon::parse(chunk));
+        }
+        Some(LocationReport {
+            device_id: DeviceId(u32::from_be_bytes([c[0], c[1], c[2], c[3]])),
+            beacon_count,
+            motion: Motion::from_flag(c[5]),
+            beacons,
+        })
+    }
+
+    /// Report whose declared count matches `beacons` (which must not be empty to be parseable).
+    pub fn new(device_id: DeviceId, motion: Motion, beacons: Vec<Beacon>) -> Self {
+        LocationReport { device_id, beacon_count: beacons.len() as u8, motion, beacons }
+    }
+
+    /// Data content as a tag sends it; the declared `beacon_count` is written as is.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(6 + self.beacons.len() * Beacon::LEN);
+        out.extend_from_slice(&self.device_id.0.to_be_bytes());
+        out.push(self.beacon_count);
+        out.push(self.motion.flag());
+        for b in &self.beacons {
+            b.write(&mut out);
+        }
+        out
+    }
+}
+
+/// 0x03 status report: RFID UID, fault flag, battery and configuration version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusReport {
+    pub rfid_uid: [u8;4],
+    pub abnormal: u8,
+    pub battery: Battery,
+    pub config_version: u8,
+    /// Spec says 2 bytes; current firmware sends 1.
+    pub reserved: Vec<u8>,
+}
+
+impl StatusReport {
+    pub const MIN_LEN: usize = 7;
+
+    fn parse(c: &[u8]) -> Option<Self> {
+        if c.len() < Self::MIN_LEN { return None; }
+        Some(StatusReport {
+            rfid_uid: [c[0], c[1], c[2], c[3]],
+            abnormal: c[4],
+            battery: Battery(c[5]),
+            config_version: c[6],
+            reserved: c[Self::MIN_LEN..].to_vec(),
+        })
+    }
+
+    /// Tags fill the UID slot with their own device ID (same value as in 0x01 / 0x05).
+    pub fn device_id(&self) -> DeviceId { DeviceId(u32::from_be_bytes(self.rfid_uid)) }
+
+    /// Any non-zero "Device Abnormal" byte is a fault.
+    pub fn is_abnormal(&self) -> bool { self.abnormal != 0 }
+
+    /// Data content as a tag sends it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::MIN_LEN + self.reserved.len());
+        out.extend_from_slice(&self.rfid_uid);
+        out.extend_from_slice(&[self.abnormal, self.battery.0, self.config_version]);
+        out.extend_from_slice(&self.reserved);
+        out
+    }
+}
+
+/// Typed data content of an uplink frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Frame {
+    Registration(Registration),
+    LocationReport(LocationReport),
+    Status(StatusReport),
+    /// Unknown message type, or a known type whose content was too short to parse.
+    Unparsed { content: Vec<u8> },
+}
+
+impl Frame {
+    /// Message type byte for this body; `None` for `Unparsed`.
+    pub fn message_type(&self) -> Option<u8> {
+        match self {
+            Frame::Registration(_) => Some(0x01),
+            Frame::Status(_) => Some(0x03),
+            Frame::LocationReport(_) => Some(0x05),
+            Frame::Unparsed { .. } => None,
+        }
+    }
+
+    /// Serialized data content (inverse of parsing).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Frame::Registration(r) => r.to_bytes(),
+            Frame::LocationReport(l) => l.to_bytes(),
+            Frame::Status(s) => s.to_bytes(),
+            Frame::Unparsed { content } => content.clone(),
+        }
+    }
+}
+
+/// A decrypt mode / signature layout combination `decode_frame_with` tried and did not pick.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedCandidate {
+    pub mode: &'static str,
+    /// `None` when the mode itself failed to decrypt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layout: Option<&'static str>,
+    /// `CodecError::code()` of the failure, or `hmac_mismatch` / `unknown_message_type` / `lower_score`
+    /// for candidates that parsed but lost to the winner.
+    pub reason: &'static str,
+    /// Score of candidates that parsed (see `DecodeDiagnostics::score`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<i32>,
+}
+
+/// Which decrypt mode and signature layout produced a `DecodedFrame`, and what else was tried.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodeDiagnostics {
+    /// `ecb-pkcs7`, `ecb-raw`, `cbc-pkcs7:prefix`, `cbc-pkcs7:zero`, `cbc-raw:prefix` or `cbc-raw:zero`.
+    pub mode: &'static str,
+    /// `sig32_first`, `sig32_last`, `sig16_first` or `sig16_last`.
+    pub layout: &'static str,
+    /// `2 * trust + integrity`, 0..=5: trust 2 = HMAC match and known type, 1 = one of the two;
+    /// integrity 1 = CRC and markers ok. 5 stops the search.
+    pub score: i32,
+    /// Combinations tried before the search stopped, in order, excluding the winner.
+    pub rejected: Vec<RejectedCandidate>,
+}
+
+/// `decode_frame_traced` failure: the error plus every combination that was tried.
+#[derive(Debug, Clone)]
+pub struct DecodeFailure {
+    pub error: CodecError,
+    pub rejected: Vec<RejectedCandidate>,
+}
+
+impl From<CodecError> for DecodeFailure {
+    fn from(error: CodecError) -> Self { DecodeFailure { error, rejected: Vec::new() } }
+}
+
+/// Parsed uplink frame: header, typed body and trailer plus the raw payload they came from.
+#[derive(Debug, Clone)]
 pub struct DecodedFrame {
+    /// Decrypted payload bytes (HMAC segment removed).
     pub raw_payload: Vec<u8>,
-    pub message_type: u8,
-    pub buffer_explained: Value,
-    pub new_buffer_response: Option<Vec<u8>>, // for type 0x01
-}
-
-/// Decode an uplink frame (base64) with given AES key + sign token.
-/// Returns a `DecodedFrame` containing raw payload, message type and an explanatory JSON tree.
-pub fn decode_frame(b64: &str, secret_key_hex: &str, sign_token_hex: &str) -> Result<DecodedFrame, String> {
-    debug!(b64_len = b64.len(), key_hex_len = secret_key_hex.len(), sign_token_hex_len = sign_token_hex.len(), "decode_frame: begin");
-    let allow_fallback = std::env::var("LORA_DECODE_FALLBACK").ok().map(|s| s=="1" || s.eq_ignore_ascii_case("true")).unwrap_or(false);
-    let try_cbc = std::env::var("LORA_TRY_CBC").ok().map(|s| s=="1" || s.eq_ignore_ascii_case("true")).unwrap_or(false);
-    let allow_hmac_mismatch = std::env::var("LORA_ALLOW_HMAC_MISMATCH").ok().map(|s| s=="1" || s.eq_ignore_ascii_case("true")).unwrap_or(false);
-
-    // Helper to parse a payload buffer into DecodedFrame (reusing existing logic)
-    fn parse_payload_into_df(payload: Vec<u8>, require_valid_msg: bool) -> Result<DecodedFrame, String> {
-        if payload.len() < 11 { return Err("frame too short".into()); }
-        let frame_header = &payload[0..2];
-        let equip = &payload[2..3];
-        let msg_number = &payload[3..5];
-        let ack_flag = &payload[5..6];
-        let msg_type = payload[6];
-        if require_valid_msg {
-            let valid_msg = matches!(msg_type, 0x01 | 0x03 | 0x05);
-            if !valid_msg { return Err(format!("msg_type_invalid: 0x{:02x}", msg_type)); }
+    pub header: FrameHeader,
+    pub frame: Frame,
+    pub trailer: FrameTrailer,
+    /// Always populated; only ever `!is_ok()` in `IntegrityMode::Lenient`.
+    pub integrity: FrameIntegrity,
+    /// What the HMAC signature was verified against (`Unverified` only with `allow_hmac_mismatch`).
+    pub hmac: HmacBinding,
+    /// Set by `decode_frame_with`; empty for frames built by `parse_payload` alone.
+    pub diagnostics: DecodeDiagnostics,
+}
+
+impl DecodedFrame {
+    pub fn message_type(&self) -> u8 { self.header.message_type }
+
+    /// Device ID carried in the body (0x01 / 0x05, UID slot of 0x03); `None` for unparsed frames.
+    pub fn device_id(&self) -> Option<DeviceId> {
+        match &self.frame {
+            Frame::Registration(r) => Some(r.device_id),
+            Frame::LocationReport(l) => Some(l.device_id),
+            Frame::Status(s) => Some(s.device_id()),
+            Frame::Unparsed { .. } => None,
         }
-        debug!(msg_type = format!("0x{:02x}", msg_type), payload_total = payload.len(), "decode_frame: header parsed");
-        if payload.len() < 4 { return Err("frame too short".into()); }
-        let crc = &payload[payload.len()-4..payload.len()-2];
-        let frame_end = &payload[payload.len()-2..];
-        let data_content = &payload[7..payload.len()-4];
-
-        let mut buffer_obj = json!({
-            "Full Buffer": base64::engine::general_purpose::STANDARD.encode(&payload),
-            "Frame Header": hex::encode(frame_header),
-            "Equipment cluster coding": hex::encode(equip),
-            "Message Number": hex::encode(msg_number),
-            "ACK Flag": hex::encode(ack_flag),
-            "Message Type": format!("{:02x}", msg_type),
-            "CRC Check": hex::encode(crc),
-            "Frame End": hex::encode(frame_end)
-        });
+    }
 
-        match msg_type {
-            0x01 => {
-                if data_content.len() < 10 {
-                    warn!(len = data_content.len(), need = 10, "mt01 content too short; returning minimal parse");
-                    buffer_obj["Data Content"] = Value::String(hex::encode(data_content));
-                } else {
-                    let obj = json!({
-                        "Full Byte": hex::encode(data_content),
-                        "Device ID": hex::encode(&data_content[0..4]),
-                        "Device version and type": hex::encode(&data_content[4..6]),
-                        "Position the shortest transmission period": hex::encode(&data_content[6..7]),
-                        "Sports assistance function swtich": hex::encode(&data_content[7..8]),
-                        "Beacon search timeout": hex::encode(&data_content[8..9]),
-                        "Beacon search quantity": hex::encode(&data_content[9..10])
-                    });
-                    buffer_obj["Data Content"] = obj;
-                }
-            },
-            0x05 => {
-                if data_content.len() < 13 {
-                    warn!(len = data_content.len(), need = 13, "mt05 content too short; returning minimal parse");
-                    buffer_obj["Data Content"] = Value::String(hex::encode(data_content));
-                } else {
-                    let obj = json!({
-                        "Full Byte": hex::encode(data_content),
-                        "Device ID": hex::encode(&data_content[0..4]),
-                        "Number of Beacons": hex::encode(&data_content[4..5]),
-                        "Physical Activity Flag": hex::encode(&data_content[5..6]),
-                        "Major": hex::encode(&data_content[6..8]),
-                        "Minor": hex::encode(&data_content[8..10]),
-                        "Distance": hex::encode(&data_content[10..12]),
-                        "Battery Level": hex::encode(&data_content[12..13]),
-                        "Remaining Beacon Info": hex::encode(&data_content[13..])
-                    });
-                    buffer_obj["Data Content"] = obj;
-                }
-            },
-            0x03 => {
-                if data_content.len() < 9 {
-                    warn!(len = data_content.len(), need = 9, "mt03 content too short; returning minimal parse");
-                    buffer_obj["Data Content"] = Value::String(hex::encode(data_content));
-                } else {
-                    let obj = json!({
-                        "Full Byte": hex::encode(data_content),
-                        "UID of RFID": hex::encode(&data_content[0..4]),
-                        "Device Abnormal": hex::encode(&data_content[4..5]),
-                        "Battery Level": hex::encode(&data_content[5..6]),
-                        "Configuration File Version": hex::encode(&data_content[6..7]),
-                        "Reservation": hex::encode(&data_content[7..9])
-                    });
-                    buffer_obj["Data Content"] = obj;
-                }
+    /// Bytes between the header and the trailer.
+    pub fn data_content(&self) -> &[u8] { &self.raw_payload[7..self.raw_payload.len()-4] }
+
+    /// Field-by-field hex breakdown (same keys as the Node `bufferExplained` object) for debugging.
+    pub fn explain(&self) -> Value {
+        let h = &self.header;
+        let content = self.data_content();
+        let mut out = json!({
+            "Full Buffer": base64::engine::general_purpose::STANDARD.encode(&self.raw_payload),
+            "Frame Header": hex::encode(h.magic),
+            "Equipment cluster coding": format!("{:02x}", h.equipment),
+            "Message Number": format!("{:04x}", h.message_number),
+            "ACK Flag": format!("{:02x}", h.ack_flag),
+            "Message Type": format!("{:02x}", h.message_type),
+            "CRC Check": format!("{:04x}", self.trailer.crc),
+            "Frame End": hex::encode(self.trailer.frame_end),
+            "Integrity": self.integrity
+        });
+        out["Data Content"] = match &self.frame {
+            Frame::Registration(r) => json!({
+                "Full Byte": hex::encode(content),
+                "Device ID": r.device_id.hex(),
+                "Device version and type": format!("{:04x}", r.version_type),
+                "Position the shortest transmission period": format!("{:02x}", r.min_tx_period),
+                "Sports assistance function swtich": format!("{:02x}", r.motion_assist),
+                "Beacon search timeout": format!("{:02x}", r.beacon_search_timeout),
+                "Beacon search quantity": format!("{:02x}", r.beacon_search_quantity)
+            }),
+            Frame::LocationReport(r) => {
+                let first = &r.beacons[0];
+                json!({
+                    "Full Byte": hex::encode(content),
+                    "Device ID": r.device_id.hex(),
+                    "Number of Beacons": format!("{:02x}", r.beacon_count),
+                    "Physical Activity Flag": format!("{:02x}", r.motion.flag()),
+                    "Major": format!("{:04x}", first.major),
+                    "Minor": format!("{:04x}", first.minor),
+                    "Distance": format!("{:04x}", first.distance_cm),
+                    "Battery Level": format!("{:02x}", first.battery.0),
+                    "Remaining Beacon Info": hex::encode(&content[LocationReport::MIN_LEN..])
+                })
             },
-            _ => {
-                buffer_obj["Data Content"] = Value::String(hex::encode(data_content));
-            }
+            Frame::Status(s) => json!({
+                "Full Byte": hex::encode(content),
+                "UID of RFID": hex::encode(s.rfid_uid),
+                "Device Abnormal": format!("{:02x}", s.abnormal),
+                "Battery Level": format!("{:02x}", s.battery.0),
+                "Configuration File Version": format!("{:02x}", s.config_version),
+                "Reservation": hex::encode(&s.reserved)
+            }),
+            Frame::Unparsed { content } => Value::String(hex::encode(content)),
+        };
+        out
+    }
+}
+
+/// Split a signature-stripped payload into header, typed body and trailer.
+/// With `require_valid_msg` set, message types outside 0x01/0x03/0x05 are rejected;
+/// in `IntegrityMode::Strict` so are frames with a bad CRC or bad markers.
+fn parse_payload(payload: Vec<u8>, require_valid_msg: bool, mode: IntegrityMode) -> Result<DecodedFrame, CodecError> {
+    if payload.len() < 11 { return Err(CodecError::FrameTooShort(payload.len())); }
+    let header = FrameHeader {
+        magic: [payload[0], payload[1]],
+        equipment: payload[2],
+        message_number: u16::from_be_bytes([payload[3], payload[4]]),
+        ack_flag: payload[5],
+        message_type: payload[6],
+    };
+    let msg_type = header.message_type;
+    if require_valid_msg && !matches!(msg_type, 0x01 | 0x03 | 0x05) {
+        return Err(CodecError::UnknownMessageType(msg_type));
+    }
+    debug!(msg_type = format!("0x{:02x}", msg_type), payload_total = payload.len(), "decode_frame: header parsed");
+    let n = payload.len();
+    let trailer = FrameTrailer {
+        crc: u16::from_be_bytes([payload[n-4], payload[n-3]]),
+        frame_end: [payload[n-2], payload[n-1]],
+    };
+    let content = &payload[7..n-4];
+    let integrity = FrameIntegrity::check(&header, content, &trailer);
+    if mode == IntegrityMode::Strict {
+        if !integrity.header_ok || !integrity.frame_end_ok {
+            return Err(CodecError::BadFrameMarker { header: header.magic, frame_end: trailer.frame_end });
+        }
+        if !integrity.crc_ok {
+            return Err(CodecError::CrcMismatch { expected: integrity.expected_crc, actual: trailer.crc });
         }
+    }
+    let (parsed, need) = match msg_type {
+        0x01 => (Registration::parse(content).map(Frame::Registration), Registration::LEN),
+        0x05 => (LocationReport::parse(content).map(Frame::LocationReport), LocationReport::MIN_LEN),
+        0x03 => (StatusReport::parse(content).map(Frame::Status), StatusReport::MIN_LEN),
+        _ => (Some(Frame::Unparsed { content: content.to_vec() }), 0),
+    };
+    let frame = parsed.unwrap_or_else(|| {
+        warn!(msg_type = format!("0x{:02x}", msg_type), len = content.len(), need, "content too short; returning minimal parse");
+        Frame::Unparsed { content: content.to_vec() }
+    });
+    Ok(DecodedFrame { raw_payload: payload, header, frame, trailer, integrity, hmac: HmacBinding::Unverified, diagnostics: DecodeDiagnostics::default() })
+}
+
+/// Knobs controlling how hard `decode_frame_with` tries and how strict it is (`[decode]` in `Config`).
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodeOptions {
+    /// Also try ECB without PKCS7 unpadding (`LORA_DECODE_FALLBACK`).
+    pub allow_fallback: bool,
+    /// Also try the four CBC variants (`LORA_TRY_CBC`).
+    pub try_cbc: bool,
+    /// Accept frames whose HMAC does not verify (`LORA_ALLOW_HMAC_MISMATCH`).
+    pub allow_hmac_mismatch: bool,
+    /// CRC / marker handling (`LORA_FRAME_CHECK=strict|lenient`, default strict).
+    pub integrity: IntegrityMode,
+    /// Only accept HMACs bound to the request timestamp (`LORA_REQUIRE_TS_HMAC`).
+    pub require_timestamp_hmac: bool,
+}
+
+/// Decode an uplink frame (base64) with given AES key + sign token and the default `DecodeOptions`.
+/// Returns a `DecodedFrame` with the typed header, body and trailer.
+pub fn decode_frame(b64: &str, secret_key_hex: &str, sign_token_hex: &str) -> Result<DecodedFrame, CodecError> {
+    decode_frame_with(b64, secret_key_hex, sign_token_hex, None, &DecodeOptions::default())
+}
 
-        let new_buffer_response = if msg_type == 0x01 {
-            if let Some(dc) = buffer_obj.get("Data Content").and_then(|v| v.get("Full Byte")).and_then(|v| v.as_str()) {
-                let dc_bytes = Vec::from_hex(dc).unwrap_or_default();
-                if dc_bytes.len() >= 10 {
-                    let mut out = Vec::new();
-                    out.extend_from_slice(&dc_bytes[0..4]);
-                    out.push(0x01);
-                    out.extend_from_slice(&dc_bytes[4..6]);
-                    out.push(0x00);
-                    out.push(0x01);
-                    out.push(0x01);
-                    out.push(0x00);
-                    out.push(0x00);
-                    Some(out)
-                } else { None }
-            } else { None }
-        } else { None };
-
-        Ok(DecodedFrame { raw_payload: payload, message_type: msg_type, buffer_explained: buffer_obj, new_buffer_response })
-    }
-
-    // Build plaintext candidates across modes
+/// `decode_frame` with explicit options. `timestamp_ms` is the uplink's `content.timestamp`; when given,
+/// the HMAC is first checked against `hex(payload) || hex(timestampBE8)` like `encrypt_downlink` signs.
+pub fn decode_frame_with(b64: &str, secret_key_hex: &str, sign_token_hex: &str, timestamp_ms: Option<u128>, opts: &DecodeOptions) -> Result<DecodedFrame, CodecError> {
+    decode_frame_traced(b64, secret_key_hex, sign_token_hex, timestamp_ms, opts).map_err(|f| f.error)
+}
+
+/// `decode_frame_with` that keeps the rejected candidates on failure too (they are in
+/// `DecodedFrame::diagnostics` on success).
+pub fn decode_frame_traced(b64: &str, secret_key_hex: &str, sign_token_hex: &str, timestamp_ms: Option<u128>, opts: &DecodeOptions) -> Result<DecodedFrame, DecodeFailure> {
+    let fail = |error: CodecError, rejected: Vec<RejectedCandidate>| DecodeFailure { error, rejected };
+    debug!(b64_len = b64.len(), key_hex_len = secret_key_hex.len(), sign_token_hex_len = sign_token_hex.len(), ?timestamp_ms, ?opts, "decode_frame: begin");
+    let DecodeOptions { allow_fallback, try_cbc, allow_hmac_mismatch, integrity: integrity_mode, require_timestamp_hmac } = *opts;
+
+    // Fail fast on a malformed sign token rather than reporting every layout as an HMAC mismatch
+    if !sign_token_hex.is_empty() { Vec::from_hex(sign_token_hex).map_err(CodecError::BadSignToken)?; }
+
+    // Build plaintext candidates across modes; modes that fail to decrypt are recorded as rejected
     let mut candidates: Vec<(&'static str, Vec<u8>)> = Vec::new();
+    let mut rejected: Vec<RejectedCandidate> = Vec::new();
+    let mut add = |mode: &'static str, r: Result<Vec<u8>, CodecError>, candidates: &mut Vec<(&'static str, Vec<u8>)>| match r {
+        Ok(pt) => candidates.push((mode, pt)),
+        Err(e) => rejected.push(RejectedCandidate { mode, layout: None, reason: e.code(), score: None }),
+    };
+    let mut primary_err: Option<CodecError> = None;
     match aes_ecb_decrypt(secret_key_hex, b64) {
         Ok(pt) => { debug!(mode = "ecb-pkcs7", "decode_frame: primary decrypt ok"); candidates.push(("ecb-pkcs7", pt)); },
         Err(e) => {
-            warn!(error = %e, fallback = allow_fallback, "decode_frame: primary decrypt failed");
+            warn!(error = %e, code = e.code(), fallback = allow_fallback, "decode_frame: primary decrypt failed");
+            // Key / base64 problems affect every mode; no point trying fallbacks
+            if matches!(e, CodecError::BadKey(_) | CodecError::BadBase64(_)) { return Err(e.into()); }
+            add("ecb-pkcs7", Err(e.clone()), &mut candidates);
+            primary_err = Some(e);
             if allow_fallback {
-                if let Ok(pt2) = aes_ecb_decrypt_no_unpad(secret_key_hex, b64) {
-                    debug!(mode = "ecb-raw", "decode_frame: fallback decrypt ok");
-                    candidates.push(("ecb-raw", pt2));
-                }
+                add("ecb-raw", aes_ecb_decrypt_no_unpad(secret_key_hex, b64), &mut candidates);
             }
         }
     }
     if try_cbc {
-        if let Ok(pt) = aes_cbc_decrypt(secret_key_hex, b64, "prefix", true) { candidates.push(("cbc-pkcs7:prefix", pt)); }
-        if let Ok(pt) = aes_cbc_decrypt(secret_key_hex, b64, "zero", true) { candidates.push(("cbc-pkcs7:zero", pt)); }
-        if let Ok(pt) = aes_cbc_decrypt(secret_key_hex, b64, "prefix", false) { candidates.push(("cbc-raw:prefix", pt)); }
-        if let Ok(pt) = aes_cbc_decrypt(secret_key_hex, b64, "zero", false) { candidates.push(("cbc-raw:zero", pt)); }
+        add("cbc-pkcs7:prefix", aes_cbc_decrypt(secret_key_hex, b64, CbcIv::Prefix, true), &mut candidates);
+        add("cbc-pkcs7:zero", aes_cbc_decrypt(secret_key_hex, b64, CbcIv::Zero, true), &mut candidates);
+        add("cbc-raw:prefix", aes_cbc_decrypt(secret_key_hex, b64, CbcIv::Prefix, false), &mut candidates);
+        add("cbc-raw:zero", aes_cbc_decrypt(secret_key_hex, b64, CbcIv::Zero, false), &mut candidates);
     }
 
-    if candidates.is_empty() { return Err("no decrypt candidates".into()); }
+    if candidates.is_empty() {
+        return Err(fail(primary_err.unwrap_or(CodecError::BadPadding("no decrypt candidates")), rejected));
+    }
 
     // Try signature layouts for each plaintext candidate
     // Layouts: (name, sig_len, sig_first)
-    let layouts: [(&str, usize, bool); 4] = [
+    let layouts: [(&'static str, usize, bool); 4] = [
         ("sig32_first", 32, true),
         ("sig32_last", 32, false),
         ("sig16_first", 16, true),
@@ -372,11 +864,21 @@ pub fn decode_frame(b64: &str, secret_key_hex: &str, sign_token_hex: &str) -> Re
 
     // Evaluate candidates and pick the best per HMAC + message type validity
     let mut best_df: Option<DecodedFrame> = None;
-    let mut best_score = -1i32; // 2 = hmac match + valid msg, 1 = valid msg (if mismatch allowed), 0 = parse ok but unknown msg
+    // Every parsed candidate is pushed to `rejected` with the reason it would lose; the winner's entry is removed at the end
+    let mut best_idx = 0usize;
+    let mut parse_err: Option<CodecError> = None;
+    // Score = 2*trust + integrity, where trust is 2 = hmac match + valid msg, 1 = hmac only or valid msg
+    // (if mismatch allowed), 0 = parse ok but unknown msg; integrity (CRC + markers) breaks ties in lenient mode.
+    const TOP_SCORE: i32 = 5;
+    let mut best_score = -1i32;
     for (mode, pt) in candidates.into_iter() {
-        debug!(mode, pt_len = pt.len(), pt_first16 = %hex::encode(&pt.get(0..16).unwrap_or(&[])), "decode_frame: trying mode");
+        debug!(mode, pt_len = pt.len(), pt_first16 = %hex::encode(pt.get(0..16).unwrap_or(&[])), "decode_frame: trying mode");
         for (layout_name, sig_len, sig_first) in layouts.iter() {
-            if pt.len() < sig_len + 11 { continue; }
+            if pt.len() < sig_len + 11 {
+                let e = parse_err.get_or_insert(CodecError::FrameTooShort(pt.len().saturating_sub(*sig_len)));
+                rejected.push(RejectedCandidate { mode, layout: Some(layout_name), reason: e.code(), score: None });
+                continue;
+            }
             let (sig, payload) = if *sig_first {
                 (&pt[0..*sig_len], pt[*sig_len..].to_vec())
             } else {
@@ -384,177 +886,201 @@ pub fn decode_frame(b64: &str, secret_key_hex: &str, sign_token_hex: &str) -> Re
             };
             debug!(mode, layout = *layout_name, payload_len = payload.len(), sig_len = *sig_len, sig_prefix8 = %hex::encode(&sig[0..sig.len().min(8)]), "decode_frame: split plaintext");
 
-            // Compute HMAC over hex(payload).
-            let mut hmac_ok = false;
+            // HMAC over hex(payload) || hex(timestampBE8) (downlink scheme), then legacy hex(payload) only.
+            let mut binding = HmacBinding::Unverified;
             if !sign_token_hex.is_empty() {
-                if let Ok(mac) = hmac_sha256_hex(&hex::encode(&payload), sign_token_hex) {
-                    if *sig_len == 32 {
-                        hmac_ok = mac.len() >= 32 && &mac[0..32] == sig;
-                    } else { // 16
-                        hmac_ok = mac.len() >= 16 && &mac[0..16] == sig;
-                    }
-                    if !hmac_ok {
-                        debug!(mode, layout = *layout_name, computed_prefix8 = %hex::encode(&mac[0..8]), sig_prefix8 = %hex::encode(&sig[0..sig.len().min(8)]), "decode_frame: hmac mismatch");
-                    } else {
-                        debug!(mode, layout = *layout_name, "decode_frame: hmac match");
-                    }
+                let payload_hex = hex::encode(&payload);
+                if let Some(ts) = timestamp_ms {
+                    let mac = hmac_sha256_hex(&format!("{}{}", payload_hex, hex::encode(timestamp_be8(ts))), sign_token_hex)?;
+                    if &mac[..*sig_len] == sig { binding = HmacBinding::Timestamp(ts); }
+                }
+                if binding == HmacBinding::Unverified && !require_timestamp_hmac {
+                    let mac = hmac_sha256_hex(&payload_hex, sign_token_hex)?;
+                    if &mac[..*sig_len] == sig { binding = HmacBinding::Payload; }
+                }
+                if binding == HmacBinding::Unverified {
+                    debug!(mode, layout = *layout_name, sig_prefix8 = %hex::encode(&sig[0..sig.len().min(8)]), "decode_frame: hmac mismatch");
+                } else {
+                    debug!(mode, layout = *layout_name, ?binding, "decode_frame: hmac match");
                 }
             }
+            let hmac_ok = binding != HmacBinding::Unverified;
 
             // In fallback/deep mode, require known message type to filter bogus decrypts
             let require_valid_msg = allow_fallback || try_cbc;
-            match parse_payload_into_df(payload.clone(), require_valid_msg) {
-                Ok(df) => {
-                    let valid_msg = matches!(df.message_type, 0x01 | 0x03 | 0x05);
-                    let mut score = 0;
-                    if hmac_ok && valid_msg { score = 2; }
-                    else if valid_msg && allow_hmac_mismatch { score = 1; }
-                    else if hmac_ok { score = 1; } // accept if HMAC ok even if msg type not in set
-                    // else score remains 0
+            match parse_payload(payload.clone(), require_valid_msg, integrity_mode) {
+                Ok(mut df) => {
+                    df.hmac = binding;
+                    df.diagnostics = DecodeDiagnostics { mode, layout: layout_name, ..Default::default() };
+                    let valid_msg = matches!(df.message_type(), 0x01 | 0x03 | 0x05);
+                    let trust = if hmac_ok && valid_msg { 2 }
+                        else if hmac_ok || (valid_msg && allow_hmac_mismatch) { 1 }
+                        else { 0 };
+                    let score = trust * 2 + i32::from(df.integrity.is_ok());
+                    let reason = if !hmac_ok { "hmac_mismatch" } else if !valid_msg { "unknown_message_type" } else { "lower_score" };
+                    rejected.push(RejectedCandidate { mode, layout: Some(layout_name), reason, score: Some(score) });
                     if score > best_score {
-                        debug!(mode, layout = *layout_name, score, msg_type = format!("0x{:02x}", df.message_type), "decode_frame: candidate selected");
+                        best_idx = rejected.len() - 1;
+                        debug!(mode, layout = *layout_name, score, msg_type = format!("0x{:02x}", df.message_type()), "decode_frame: candidate selected");
                         best_score = score;
                         best_df = Some(df);
-                        if score == 2 { break; } // best possible for this mode/layout
+                        if score == TOP_SCORE { break; } // best possible for this mode/layout
                     }
                 },
                 Err(e) => {
                     debug!(mode, layout = *layout_name, error = %e, "decode_frame: parse failed");
+                    rejected.push(RejectedCandidate { mode, layout: Some(layout_name), reason: e.code(), score: None });
+                    parse_err.get_or_insert(e);
                 }
             }
         }
-        if best_score == 2 { break; }
+        if best_score == TOP_SCORE { break; }
     }
 
     match best_df {
-        Some(df) => {
-            if best_score >= 1 { Ok(df) }
-            else if allow_hmac_mismatch { Ok(df) }
-            else { Err("hmac_mismatch".into()) }
+        Some(mut df) => {
+            if best_score >= 2 || allow_hmac_mismatch {
+                rejected.remove(best_idx);
+                df.diagnostics.score = best_score;
+                df.diagnostics.rejected = rejected;
+                Ok(df)
+            } else {
+                Err(fail(CodecError::HmacMismatch, rejected))
+            }
         }
-        None => Err("no valid decode candidates".into())
+        // Every layout was rejected by the parser; report the first (canonical ecb / sig32_first) reason
+        None => Err(fail(parse_err.unwrap_or(CodecError::FrameTooShort(0)), rejected)),
     }
 }
 
 /// Construct downlink registration response (for message type 0x01) replicating Node logic.
-pub fn build_downlink_hex(df: &DecodedFrame) -> Result<Vec<u8>, String> {
+pub fn build_downlink_hex(df: &DecodedFrame) -> Result<Vec<u8>, CodecError> {
     // Only for type 0x01 registration
-    let new_resp = df.new_buffer_response.as_ref().ok_or("no new_buffer_response")?;
+    let Frame::Registration(reg) = &df.frame else { return Err(CodecError::NotRegistration) };
+    let new_resp = reg.response_body();
     // Assemble finalRequestBuffer per Node logic:
     // FrameHeader | EquipmentCoding | MessageNumber | 0x00 | 0x02 | newResp | CRC(0x02+newResp) | FrameEnd
-    if df.raw_payload.len() < 11 { return Err("raw frame too short".into()); }
-    let frame_header = &df.raw_payload[0..2];
-    let equip = &df.raw_payload[2..3];
-    let msg_number = &df.raw_payload[3..5];
-    let frame_end = &df.raw_payload[df.raw_payload.len()-2..];
-
     let mut checksum_data = Vec::new();
     checksum_data.push(0x02u8);
-    checksum_data.extend_from_slice(new_resp);
+    checksum_data.extend_from_slice(&new_resp);
     let crc_u16 = checksum16(&checksum_data);
-    let mut crc_buf = [0u8;2];
-    crc_buf[0] = (crc_u16 >> 8) as u8; // big-endian
-    crc_buf[1] = (crc_u16 & 0xFF) as u8;
 
     let mut final_buf = Vec::new();
-    final_buf.extend_from_slice(frame_header);
-    final_buf.extend_from_slice(equip);
-    final_buf.extend_from_slice(msg_number);
+    final_buf.extend_from_slice(&df.header.magic);
+    final_buf.push(df.header.equipment);
+    final_buf.extend_from_slice(&df.header.message_number.to_be_bytes());
     final_buf.push(0x00); // ACK Number
     final_buf.push(0x02); // Message Type (downlink registration response?)
-    final_buf.extend_from_slice(new_resp);
-    final_buf.extend_from_slice(&crc_buf);
-    final_buf.extend_from_slice(frame_end);
+    final_buf.extend_from_slice(&new_resp);
+    final_buf.extend_from_slice(&crc_u16.to_be_bytes());
+    final_buf.extend_from_slice(&df.trailer.frame_end);
     Ok(final_buf)
 }
 
 /// Encrypt downlink buffer into base64 LoRaWAN payload.
 /// Algorithm: HMAC-SHA256(hex(downlink)||timestampBE8) || downlinkBytes -> AES-ECB encrypt.
-pub fn encrypt_downlink(timestamp_ms: u128, downlink_hex: &[u8], sign_token_hex: &str, secret_key_hex: &str) -> Result<String, String> {
+pub fn encrypt_downlink(timestamp_ms: u128, downlink_hex: &[u8], sign_token_hex: &str, secret_key_hex: &str) -> Result<String, CodecError> {
     // Node logic: signData = payloadHex + timestampHexBE8; HMAC-SHA256 over that, then (HMAC || payloadHex) encrypted with AES-ECB.
-    let ts_be8 = timestamp_be8(timestamp_ms);
-    let payload_hex = hex::encode(downlink_hex);
-    let ts_hex = hex::encode(ts_be8);
-    let sign_input_hex = format!("{}{}", payload_hex, ts_hex);
-    let hmac_bytes = hmac_sha256_hex(&sign_input_hex, sign_token_hex)?; // 32 bytes
-    let mut plain = Vec::new();
-    plain.extend_from_slice(&hmac_bytes);
-    plain.extend_from_slice(&downlink_hex);
-    let b64 = aes_ecb_encrypt(secret_key_hex, &plain)?;
-    Ok(b64)
+    encrypt_uplink(downlink_hex, HmacBinding::Timestamp(timestamp_ms), sign_token_hex, secret_key_hex)
+}
+
+/// Sign and encrypt a plaintext frame the way tags do: `HMAC(32) || payload` (layout `sig32_first`),
+/// AES-128-ECB + PKCS7, base64. `binding` selects the HMAC input; `Unverified` writes a zeroed
+/// signature (for negative tests).
+pub fn encrypt_uplink(payload: &[u8], binding: HmacBinding, sign_token_hex: &str, secret_key_hex: &str) -> Result<String, CodecError> {
+    let payload_hex = hex::encode(payload);
+    let mac = match binding {
+        HmacBinding::Timestamp(ts) => hmac_sha256_hex(&format!("{}{}", payload_hex, hex::encode(timestamp_be8(ts))), sign_token_hex)?,
+        HmacBinding::Payload => hmac_sha256_hex(&payload_hex, sign_token_hex)?,
+        HmacBinding::Unverified => vec![0u8; 32],
+    };
+    let mut plain = Vec::with_capacity(mac.len() + payload.len());
+    plain.extend_from_slice(&mac);
+    plain.extend_from_slice(payload);
+    aes_ecb_encrypt(secret_key_hex, &plain)
+}
+
+/// Equipment cluster code seen on every captured tag uplink.
+pub const DEFAULT_EQUIPMENT: u8 = 0x51;
+
+/// Typed uplink ready to be serialized: header fields plus body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uplink {
+    pub equipment: u8,
+    pub message_number: u16,
+    pub ack_flag: u8,
+    pub frame: Frame,
+}
+
+impl Uplink {
+    /// Uplink with the default equipment code; registrations request an ACK like real tags do.
+    pub fn new(message_number: u16, frame: Frame) -> Self {
+        let ack_flag = u8::from(matches!(frame, Frame::Registration(_)));
+        Uplink { equipment: DEFAULT_EQUIPMENT, message_number, ack_flag, frame }
+    }
+
+    /// Plaintext frame: `FFEE | equipment | message number | ack | type | content | CRC | EEFF`.
+    pub fn payload(&self) -> Result<Vec<u8>, CodecError> {
+        let message_type = self.frame.message_type().ok_or(CodecError::UnparsedFrame)?;
+        let content = self.frame.to_bytes();
+        let mut out = Vec::with_capacity(11 + content.len());
+        out.extend_from_slice(&FRAME_HEADER);
+        out.push(self.equipment);
+        out.extend_from_slice(&self.message_number.to_be_bytes());
+        out.push(self.ack_flag);
+        out.push(message_type);
+        out.extend_from_slice(&content);
+        let crc = checksum16(&out[6..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+        out.extend_from_slice(&FRAME_END);
+        Ok(out)
+    }
+
+    /// Base64 ciphertext a tag would put in `content.data` (see `encrypt_uplink`).
+    pub fn encrypt(&self, binding: HmacBinding, sign_token_hex: &str, secret_key_hex: &str) -> Result<String, CodecError> {
+        encrypt_uplink(&self.payload()?, binding, sign_token_hex, secret_key_hex)
+    }
 }
 
 /// Convert a 0x05 location report frame into `uwb_update` JSON consumed by the frontend.
 pub fn as_uwb_update(df: &DecodedFrame, ts_field: u128) -> Option<Value> {
-    if df.message_type != 0x05 { return None; }
-    // Extract fields from buffer_explained
-    let dc = df.buffer_explained.get("Data Content")?;
-    let device_id_hex = dc.get("Device ID")?.as_str()?;
-    let num_beacons_hex = dc.get("Number of Beacons")?.as_str()?;
-    let motion_flag_hex = dc.get("Physical Activity Flag")?.as_str()?;
-    let device_id_bytes = Vec::from_hex(device_id_hex).ok()?;
-    if device_id_bytes.len() < 4 { return None; }
-    let device_id_decimal = ((device_id_bytes[0] as u32) << 24)
-        | ((device_id_bytes[1] as u32) << 16)
-        | ((device_id_bytes[2] as u32) << 8)
-        | (device_id_bytes[3] as u32);
-    let num_beacons = u8::from_str_radix(num_beacons_hex, 16).ok()?;
-    let motion_flag = u8::from_str_radix(motion_flag_hex, 16).ok()?;
-    let motion_text = if motion_flag == 1 { "Movement Detected" } else { "No Movement" };
-
-    // Build full beacons array. First beacon is named; remaining are in Remaining Beacon Info as 7-byte chunks.
-    let mut beacons_vec: Vec<Value> = Vec::new();
-    // First beacon
-    if let (Some(major_hex), Some(minor_hex), Some(distance_hex), Some(battery_hex)) = (
-        dc.get("Major").and_then(|v| v.as_str()),
-        dc.get("Minor").and_then(|v| v.as_str()),
-        dc.get("Distance").and_then(|v| v.as_str()),
-        dc.get("Battery Level").and_then(|v| v.as_str()),
-    ) {
-        let distance_bytes = Vec::from_hex(distance_hex).ok()?;
-        if distance_bytes.len() < 2 { return None; }
-        let distance_cm = ((distance_bytes[0] as u16) << 8) | (distance_bytes[1] as u16);
-        let battery_bytes = Vec::from_hex(battery_hex).ok()?;
-        let battery = if battery_bytes.is_empty() { 0 } else { battery_bytes[0] };
-        beacons_vec.push(json!({
-            "major": major_hex,
-            "minor": minor_hex,
-            "beaconId": format!("{}{}", major_hex, minor_hex),
-            "distance": distance_cm,
-            "battery": battery
-        }));
-    }
-
-    // Remaining beacons: parse 7-byte entries [major(2) minor(2) distance(2 BE) battery(1)]
-    if let Some(rem_hex) = dc.get("Remaining Beacon Info").and_then(|v| v.as_str()) {
-        if let Ok(rem_bytes) = Vec::from_hex(rem_hex) {
-            let per = 7usize;
-            let mut i = 0usize;
-            while i + per <= rem_bytes.len() && (beacons_vec.len() as u8) < num_beacons {
-                let major = &rem_bytes[i..i+2];
-                let minor = &rem_bytes[i+2..i+4];
-                let dist = ((rem_bytes[i+4] as u16) << 8) | (rem_bytes[i+5] as u16);
-                let batt = rem_bytes[i+6];
-                beacons_vec.push(json!({
-                    "major": hex::encode(major),
-                    "minor": hex::encode(minor),
-                    "beaconId": format!("{}{}", hex::encode(major), hex::encode(minor)),
-                    "distance": dist,
-                    "battery": batt
-                }));
-                i += per;
-            }
-        }
-    }
+    let Frame::LocationReport(report) = &df.frame else { return None };
+    let beacons: Vec<Value> = report.beacons.iter().map(|b| json!({
+        "major": format!("{:04x}", b.major),
+        "minor": format!("{:04x}", b.minor),
+        "beaconId": b.beacon_id(),
+        "distance": b.distance_cm,
+        "battery": b.battery.0
+    })).collect();
 
     Some(json!({
         "type": "uwb_update",
         "payload": {
-            "deviceIdHex": device_id_hex,
-            "deviceIdDecimal": device_id_decimal,
-            "numberOfBeacons": num_beacons,
-            "motion": motion_text,
-            "beacons": beacons_vec,
+            "deviceIdHex": report.device_id.hex(),
+            "deviceIdDecimal": report.device_id.0,
+            "numberOfBeacons": report.beacon_count,
+            "motion": report.motion.label(),
+            "beacons": beacons,
+            "integrity": df.integrity,
+            "requestTimestamp": ts_field
+        },
+        "ts": ts_field
+    }))
+}
+
+/// Convert a 0x03 frame into the `device_status` event broadcast to SSE clients.
+pub fn as_device_status(df: &DecodedFrame, ts_field: u128) -> Option<Value> {
+    let Frame::Status(status) = &df.frame else { return None };
+    Some(json!({
+        "type": "device_status",
+        "payload": {
+            "deviceIdHex": status.device_id().hex(),
+            "messageNumber": df.header.message_number,
+            "abnormal": status.is_abnormal(),
+            "abnormalCode": status.abnormal,
+            "battery": status.battery.0,
+            "configVersion": status.config_version,
+            "integrity": df.integrity,
             "requestTimestamp": ts_field
         },
         "ts": ts_field
@@ -565,14 +1091,53 @@ pub fn as_uwb_update(df: &DecodedFrame, ts_field: u128) -> Option<Value> {
 mod tests {
     use super::*;
 
+    /// Fill the CRC slot (4th/3rd byte from the end) with checksum16 over type + content.
+    fn seal_crc(payload: &mut [u8]) {
+        let n = payload.len();
+        let crc = checksum16(&payload[6..n-4]);
+        payload[n-4..n-2].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    fn strict_opts() -> DecodeOptions {
+        DecodeOptions { allow_fallback: false, try_cbc: false, allow_hmac_mismatch: false, integrity: IntegrityMode::Strict, require_timestamp_hmac: false }
+    }
+
     fn build_uplink_cipher_b64(secret_key: &str, sign_token: &str, payload_bytes: &[u8]) -> String {
-        // HMAC over hex(payload) as per test-only simplified check; prepend first 32 bytes
-        let payload_hex = hex::encode(payload_bytes);
-        let mac = hmac_sha256_hex(&payload_hex, sign_token).expect("hmac");
-        let mut plain = Vec::new();
-        plain.extend_from_slice(&mac[..32]);
-        plain.extend_from_slice(payload_bytes);
-        aes_ecb_encrypt(secret_key, &plain).expect("encrypt to b64")
+        encrypt_uplink(payload_bytes, HmacBinding::Payload, sign_token, secret_key).expect("encrypt to b64")
+    }
+
+    #[test]
+    fn uplink_encoder_round_trips_every_type() {
+        let secret = "3BA16CA4D2BE9EB96147779B32182750";
+        let token = "7AE4AF8AAD3BD554";
+        let dev = DeviceId(0xA0BA3E29);
+        let frames = [
+            Frame::Registration(Registration { device_id: dev, version_type: 0x0201, min_tx_period: 5, motion_assist: 1, beacon_search_timeout: 1, beacon_search_quantity: 4 }),
+            Frame::Status(StatusReport { rfid_uid: dev.0.to_be_bytes(), abnormal: 0, battery: Battery(42), config_version: 0, reserved: vec![0] }),
+            Frame::LocationReport(LocationReport::new(dev, Motion::Moving, vec![
+                Beacon { major: 0x0001, minor: 0x0002, distance_cm: 150, battery: Battery(90) },
+                Beacon { major: 0x0001, minor: 0x0003, distance_cm: 420, battery: Battery(80) },
+            ])),
+        ];
+        let mut opts = strict_opts();
+        opts.require_timestamp_hmac = true;
+        let ts = 1_763_307_421_341u128;
+        for (i, frame) in frames.into_iter().enumerate() {
+            let up = Uplink::new(0x0100 + i as u16, frame);
+            let b64 = up.encrypt(HmacBinding::Timestamp(ts), token, secret).unwrap();
+            let df = decode_frame_with(&b64, secret, token, Some(ts), &opts).expect("round trip");
+            assert_eq!(df.frame, up.frame);
+            assert_eq!(df.header.message_number, up.message_number);
+            assert_eq!(df.header.ack_flag, up.ack_flag);
+            assert!(df.integrity.is_ok());
+            assert_eq!(df.hmac, HmacBinding::Timestamp(ts));
+            assert_eq!(df.raw_payload, up.payload().unwrap());
+        }
+        // Zeroed signature never verifies
+        let up = Uplink::new(1, Frame::Status(StatusReport { rfid_uid: [0;4], abnormal: 1, battery: Battery(5), config_version: 2, reserved: vec![] }));
+        let b64 = up.encrypt(HmacBinding::Unverified, token, secret).unwrap();
+        assert_eq!(decode_frame_with(&b64, secret, token, Some(ts), &opts).unwrap_err(), CodecError::HmacMismatch);
+        assert_eq!(Uplink::new(1, Frame::Unparsed { content: vec![] }).payload().unwrap_err(), CodecError::UnparsedFrame);
     }
 
     #[test]
@@ -605,16 +1170,24 @@ mod tests {
         payload.extend_from_slice(&[0x00, 0x53]); // minor
         payload.extend_from_slice(&[0x00, 0xC8]); // distance 200 cm
         payload.push(0x5A); // battery 90
-        // CRC (dummy) + frame end
+        // CRC + frame end
         payload.extend_from_slice(&[0x00, 0x00]);
         payload.extend_from_slice(&[0xEE, 0xFF]);
+        seal_crc(&mut payload);
 
         let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
         let token = "3E3D4BEE7FE182D8";
         let ct_b64 = build_uplink_cipher_b64(secret, token, &payload);
 
         let df = decode_frame(&ct_b64, secret, token).expect("decode ok");
-        assert_eq!(df.message_type, 0x05);
+        assert_eq!(df.message_type(), 0x05);
+        assert_eq!(df.header.message_number, 0x0030);
+        assert_eq!(df.diagnostics, DecodeDiagnostics { mode: "ecb-pkcs7", layout: "sig32_first", score: 5, rejected: Vec::new() });
+        let Frame::LocationReport(report) = &df.frame else { panic!("expected location report") };
+        assert_eq!(report.device_id, DeviceId(0xA0BA3E29));
+        assert_eq!(report.motion, Motion::Moving);
+        assert_eq!(report.beacons[1], Beacon { major: 0x0200, minor: 0x0053, distance_cm: 200, battery: Battery(90) });
+        assert_eq!(df.explain()["Data Content"]["Remaining Beacon Info"], "0200005300c85a");
         let update = as_uwb_update(&df, 0);
         assert!(update.is_some());
         let u = update.unwrap();
@@ -626,10 +1199,107 @@ mod tests {
         assert_eq!(beacons[1]["distance"].as_u64().unwrap(), 200);
     }
 
+    #[test]
+    fn registration_frame_builds_downlink() {
+        let mut payload: Vec<u8> = vec![0xFF,0xEE,0x51,0x00,0x07,0x00,0x01];
+        payload.extend_from_slice(&[0xA0,0xBA,0x3E,0x29]); // device id
+        payload.extend_from_slice(&[0x01,0x02]); // version/type
+        payload.extend_from_slice(&[0x0A,0x01,0x05,0x04]); // period, motion assist, search timeout/qty
+        payload.extend_from_slice(&[0x00,0x00,0xEE,0xFF]);
+        seal_crc(&mut payload);
+        let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
+        let token = "3E3D4BEE7FE182D8";
+        let df = decode_frame(&build_uplink_cipher_b64(secret, token, &payload), secret, token).expect("decode ok");
+        let Frame::Registration(reg) = &df.frame else { panic!("expected registration") };
+        assert_eq!(reg.version_type, 0x0102);
+        assert_eq!(reg.beacon_search_quantity, 4);
+        assert!(as_uwb_update(&df, 0).is_none());
+        let down = build_downlink_hex(&df).expect("downlink");
+        let body = [0xA0,0xBA,0x3E,0x29,0x01,0x01,0x02,0x00,0x01,0x01,0x00,0x00];
+        let mut crc_input = vec![0x02];
+        crc_input.extend_from_slice(&body);
+        let mut expected = vec![0xFF,0xEE,0x51,0x00,0x07,0x00,0x02];
+        expected.extend_from_slice(&body);
+        expected.extend_from_slice(&checksum16(&crc_input).to_be_bytes());
+        expected.extend_from_slice(&[0xEE,0xFF]);
+        assert_eq!(down, expected);
+    }
+
+    #[test]
+    fn crc_and_markers_strict_rejects_lenient_flags() {
+        let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
+        let token = "3E3D4BEE7FE182D8";
+        let mut payload: Vec<u8> = vec![0xFF,0xEE,0x51,0x00,0x24,0x00,0x03];
+        payload.extend_from_slice(&[0xA0,0xBA,0x3E,0x29,0x00,0x28,0x00,0x00,0x00]);
+        payload.extend_from_slice(&[0x00,0x00,0xEE,0xFF]);
+        seal_crc(&mut payload);
+        let mut opts = strict_opts();
+        let df = decode_frame_with(&build_uplink_cipher_b64(secret, token, &payload), secret, token, None, &opts).expect("valid frame");
+        assert!(df.integrity.is_ok());
+
+        let n = payload.len();
+        let mut bad_crc = payload.clone();
+        bad_crc[n-3] ^= 0x01;
+        let b64 = build_uplink_cipher_b64(secret, token, &bad_crc);
+        let err = decode_frame_with(&b64, secret, token, None, &opts).unwrap_err();
+        assert_eq!(err, CodecError::CrcMismatch { expected: df.trailer.crc, actual: df.trailer.crc ^ 0x0001 });
+
+        let mut bad_end = payload.clone();
+        bad_end[n-1] = 0x00;
+        let b64_end = build_uplink_cipher_b64(secret, token, &bad_end);
+        assert_eq!(decode_frame_with(&b64_end, secret, token, None, &opts).unwrap_err().code(), "bad_frame_marker");
+
+        opts.integrity = IntegrityMode::Lenient;
+        let df = decode_frame_with(&b64, secret, token, None, &opts).expect("lenient accepts");
+        assert!(!df.integrity.crc_ok && df.integrity.header_ok && df.integrity.frame_end_ok);
+        assert_eq!(df.explain()["Integrity"]["crcOk"], false);
+    }
+
+    #[test]
+    fn timestamp_bound_hmac_verifies_and_rejects_other_timestamps() {
+        let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
+        let token = "3E3D4BEE7FE182D8";
+        let mut payload: Vec<u8> = vec![0xFF,0xEE,0x51,0x00,0x24,0x00,0x03];
+        payload.extend_from_slice(&[0xA0,0xBA,0x3E,0x29,0x00,0x28,0x00,0x00,0x00]);
+        payload.extend_from_slice(&[0x00,0x00,0xEE,0xFF]);
+        seal_crc(&mut payload);
+        // Uplink signed exactly like a downlink
+        let ts = 1_763_311_182_208u128;
+        let b64 = encrypt_downlink(ts, &payload, token, secret).unwrap();
+        let mut opts = strict_opts();
+        opts.require_timestamp_hmac = true;
+        let df = decode_frame_with(&b64, secret, token, Some(ts), &opts).expect("bound hmac");
+        assert_eq!(df.hmac, HmacBinding::Timestamp(ts));
+        assert_eq!(decode_frame_with(&b64, secret, token, Some(ts + 1), &opts).unwrap_err(), CodecError::HmacMismatch);
+        // Legacy payload-only signature is refused once binding is required
+        let legacy = build_uplink_cipher_b64(secret, token, &payload);
+        assert_eq!(decode_frame_with(&legacy, secret, token, Some(ts), &opts).unwrap_err(), CodecError::HmacMismatch);
+        opts.require_timestamp_hmac = false;
+        assert_eq!(decode_frame_with(&legacy, secret, token, Some(ts), &opts).unwrap().hmac, HmacBinding::Payload);
+    }
+
+    #[test]
+    fn real_status_frame_parses_with_one_reserved_byte() {
+        // 0x03 from devEui 009569000004C21E: content a0ba3e29 00 2a 00 00 (8 bytes, spec says 9)
+        let b64 = "WYKWYizwvxNwJWVe8/UJe1SYr1yCiqrXG8lUKZXZWZIe6eWjzWsOVESvobLDRMP7VcN5PLKlPv+FCQJ45YrL7Q==";
+        let mut opts = strict_opts();
+        opts.allow_hmac_mismatch = true;
+        let df = decode_frame_with(b64, "3BA16CA4D2BE9EB96147779B32182750", "7AE4AF8AAD3BD554", None, &opts).expect("status frame");
+        let Frame::Status(st) = &df.frame else { panic!("expected status, got {:?}", df.frame) };
+        assert_eq!(st.device_id(), DeviceId(0xA0BA3E29));
+        assert_eq!(st.battery, Battery(0x2A));
+        assert!(!st.is_abnormal());
+        assert_eq!(st.reserved, vec![0x00]);
+        assert_eq!(df.device_id(), Some(DeviceId(0xA0BA3E29)));
+        let ev = as_device_status(&df, 7).unwrap();
+        assert_eq!(ev["type"], "device_status");
+        assert_eq!(ev["payload"]["deviceIdHex"], "a0ba3e29");
+        assert_eq!(ev["payload"]["battery"], 42);
+        assert_eq!(ev["payload"]["abnormal"], false);
+    }
+
     #[test]
     fn decode_frame_hmac_mismatch_errors() {
-        // Ensure strict HMAC checking for this test
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "0");
         // Build payload as before
         let mut payload: Vec<u8> = vec![0xFF,0xEE,0x51,0x00,0x30,0x00,0x05];
         payload.extend_from_slice(&[0xDE,0xAD,0xBE,0xEF]); // device id
@@ -638,12 +1308,48 @@ mod tests {
         payload.extend_from_slice(&[0x01,0x02,0x03,0x04,0x00,0x0A]); // beacon (10cm)
         payload.push(0x32);
         payload.extend_from_slice(&[0x00,0x00,0xEE,0xFF]);
+        seal_crc(&mut payload);
         let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
         let token = "3E3D4BEE7FE182D8";
         let b64 = build_uplink_cipher_b64(secret, token, &payload);
         // Use wrong token for verification
         let err = decode_frame(&b64, secret, "0000000000000000").unwrap_err();
-        assert_eq!(err, "hmac_mismatch");
+        assert_eq!(err, CodecError::HmacMismatch);
+        assert_eq!(err.code(), "hmac_mismatch");
+    }
+
+    #[test]
+    fn traced_decode_reports_winner_and_rejected_candidates() {
+        let mut payload: Vec<u8> = vec![0xFF,0xEE,0x51,0x00,0x31,0x00,0x05];
+        payload.extend_from_slice(&[0xDE,0xAD,0xBE,0xEF, 0x01, 0x00, 0x01,0x02,0x03,0x04,0x00,0x0A, 0x32]);
+        payload.extend_from_slice(&[0x00,0x00,0xEE,0xFF]);
+        seal_crc(&mut payload);
+        let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
+        let token = "3E3D4BEE7FE182D8";
+        let b64 = build_uplink_cipher_b64(secret, token, &payload);
+
+        let df = decode_frame_traced(&b64, secret, token, None, &strict_opts()).expect("decode ok");
+        assert_eq!((df.diagnostics.mode, df.diagnostics.layout, df.diagnostics.score), ("ecb-pkcs7", "sig32_first", 5));
+        assert!(df.diagnostics.rejected.is_empty(), "top score stops the search");
+
+        let failure = decode_frame_traced(&b64, secret, "0000000000000000", None, &strict_opts()).unwrap_err();
+        assert_eq!(failure.error, CodecError::HmacMismatch);
+        assert_eq!(failure.rejected.len(), 4);
+        assert_eq!(failure.rejected[0], RejectedCandidate { mode: "ecb-pkcs7", layout: Some("sig32_first"), reason: "hmac_mismatch", score: Some(1) });
+        assert!(failure.rejected[1..].iter().all(|c| c.reason == "bad_frame_marker" && c.score.is_none()));
+    }
+
+    #[test]
+    fn decode_frame_error_codes() {
+        let secret = "A60C3263B832E551EEBDDDB93D8B05EA";
+        let token = "3E3D4BEE7FE182D8";
+        assert_eq!(decode_frame("not base64!", secret, token).unwrap_err().code(), "bad_base64");
+        assert_eq!(decode_frame("AAAA", "A60C", token).unwrap_err().code(), "bad_key");
+        assert_eq!(decode_frame("AAAA", secret, "zz").unwrap_err().code(), "bad_sign_token");
+        assert_eq!(decode_frame("AAAA", secret, token).unwrap_err().code(), "bad_ciphertext_length");
+        // Valid ciphertext whose plaintext is too short to hold signature + frame
+        let short = aes_ecb_encrypt(secret, &[0u8; 20]).unwrap();
+        assert_eq!(decode_frame(&short, secret, token).unwrap_err(), CodecError::FrameTooShort(0));
     }
 
     #[test]
@@ -659,12 +1365,12 @@ mod tests {
         let token = "7AE4AF8AAD3BD554";
 
     // The device HMAC may include a timestamp in signing; allow mismatch for this sample
-    std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "1");
-    let df = decode_frame(b64, secret, token).expect("decode ok");
-        println!("message_type=0x{:02x}", df.message_type);
+    let opts = DecodeOptions { allow_hmac_mismatch: true, ..DecodeOptions::default() };
+    let df = decode_frame_with(b64, secret, token, None, &opts).expect("decode ok");
+        println!("message_type=0x{:02x}", df.message_type());
         println!(
             "buffer_explained={}",
-            serde_json::to_string_pretty(&df.buffer_explained).unwrap()
+            serde_json::to_string_pretty(&df.explain()).unwrap()
         );
         // Produce uwb_update JSON if it's a 0x05; otherwise print None
         let ts = 1_731_734_400_000u128; // fixed timestamp for reproducibility
@@ -672,8 +1378,6 @@ mod tests {
             Some(js) => println!("uwb_update={}", serde_json::to_string_pretty(&js).unwrap()),
             None => println!("uwb_update=None"),
         }
-        // restore strict default for other tests
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "0");
     }
 
     #[test]
@@ -688,19 +1392,18 @@ mod tests {
         // Use uplink keys (Node defaults), allow HMAC mismatch due to timestamp in signing
         let secret = "3BA16CA4D2BE9EB96147779B32182750";
         let token = "7AE4AF8AAD3BD554";
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "1");
-        let df = decode_frame(b64, secret, token).expect("decode ok");
-        println!("message_type=0x{:02x}", df.message_type);
+        let opts = DecodeOptions { allow_hmac_mismatch: true, ..DecodeOptions::default() };
+        let df = decode_frame_with(b64, secret, token, None, &opts).expect("decode ok");
+        println!("message_type=0x{:02x}", df.message_type());
         println!(
             "buffer_explained={}",
-            serde_json::to_string_pretty(&df.buffer_explained).unwrap()
+            serde_json::to_string_pretty(&df.explain()).unwrap()
         );
         let ts = 1_763_311_182_208u128; // taken from log for reproducibility
         match as_uwb_update(&df, ts) {
             Some(js) => println!("uwb_update={}", serde_json::to_string_pretty(&js).unwrap()),
             None => println!("uwb_update=None"),
         }
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "0");
     }
 
     #[test]
@@ -807,14 +1510,14 @@ mod tests {
             ("jqrJ4oh9scFyZhtpgxOiJ89lt7//qyJqirTTFsvQEgfC8g6myWh+tJ2+SLWHxb6X+eejjKOEuR/z/pwGZ7KD2g==", "009569000004C21E", 1763311802361u128),
         ];
 
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "1");
+        let opts = DecodeOptions { allow_hmac_mismatch: true, ..DecodeOptions::default() };
         let mut count_ok = 0usize;
         let mut count_mt05 = 0usize;
         for (idx, (b64, dev_eui, ts)) in samples.iter().enumerate() {
-            match decode_frame(b64, secret, token) {
+            match decode_frame_with(b64, secret, token, None, &opts) {
                 Ok(df) => {
                     count_ok += 1;
-                    let mt = df.message_type;
+                    let mt = df.message_type();
                     if mt == 0x05 {
                         count_mt05 += 1;
                         println!("[{}] devEui={} ts={} type=0x{:02x} LOCATION", idx, dev_eui, ts, mt);
@@ -831,7 +1534,6 @@ mod tests {
             }
         }
         println!("decoded_ok={} mt05_count={}", count_ok, count_mt05);
-        std::env::set_var("LORA_ALLOW_HMAC_MISMATCH", "0");
         assert_eq!(count_ok, samples.len());
     }
 }
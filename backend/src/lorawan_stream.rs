@@ -2,36 +2,321 @@
 //!
 //! Endpoints registered when NOT using `USE_REMOTE_UWB` (i.e. local ingestion mode):
 //! - `POST /v1/uwb`: Accepts an encrypted uplink frame `{ content: { data, devEui, fPort, timestamp? } }`.
-//!     * Decrypt & parse via `decode_frame`.
-//!     * If message type == 0x05 (location report) -> convert to `uwb_update` JSON and broadcast.
-//!     * If message type == 0x01 (registration) -> build downlink response, encrypt, optionally POST to `DOWNLINK_URL`.
-//! - `GET /proxy/uwbStream`: Local SSE emitting broadcast updates (mirrors legacy naming for frontend compatibility).
+//!     * With `capture.file` set, write the raw request (body, headers, peer) to the capture file (see `capture`).
+//!     * Reject a missing `content.timestamp`, or one outside `ingest.ts_skew_ms` of the server clock.
+//!     * Resolve the device's keys (see `key_store`): its own, the pre-rotation ones during the overlap
+//!       window, or the defaults; revoked devices are rejected, by devEui before and by the in-frame
+//!       device ID after decoding.
+//!     * Decrypt & parse via `decode_frame_traced`, binding the HMAC to `content.timestamp` when present;
+//!       the response's `decode` reports the winning mode / layout / score and the rejected candidates.
+//!     * Drop repeats of an already accepted `(devEui, message number, timestamp)` as duplicates (the
+//!       usual multi-gateway copy); with a payload-only HMAC the payload digest stands in for the unsigned
+//!       timestamp (see `replay_guard`).
+//!     * Check the device registry (see `device_registry`): a 0x01 registers the device; frames from
+//!       disabled devices, and other frames from unregistered ones, are rejected.
+//!     * Track per-device message numbers: drop duplicates, count gaps / reorders, emit `sequence_reset`.
+//!     * If message type == 0x05 (location report) -> convert to `uwb_update` JSON, attach the solved
+//!       `payload.position` (see `positioning`), the ranges dropped as outliers in `payload.rejectedBeacons`
+//!       (see `outlier`) and the smoothed `payload.track` (see `tracking`), and broadcast.
+//!     * If message type == 0x01 (registration) -> build downlink response, encrypt, optionally POST to `ingest.downlink_url`.
+//!     * If message type == 0x03 (status) -> record in the status store and broadcast `device_status`.
+//! - `GET /proxy/uwbStream`: Local SSE emitting broadcast updates (mirrors legacy naming for frontend compatibility),
+//!   with event ids and `Last-Event-ID` resume (see `events`); `?devices=&types=&floor=&zone=&maxHz=`
+//!   narrow it per client (see `stream_filter`).
+//! - `GET /ws`: the same events over a WebSocket, with filter changes, snapshots and acks (see `ws_stream`).
+//! - `GET /devices/status`, `GET /devices/{device}/status`: latest 0x03 status (see `device_status`).
+//! - `/devices`, `/devices/{device}`: device registry with names, assignment and enable switch (see `device_registry`).
+//! - `GET /devices/state`, `GET /devices/{device}/state`: last known state per device, also sent to new
+//!   stream clients as a `snapshot` event (see `device_state`).
+//! - `GET /history`: stored frames, positions and events (see `history`); every accepted, non-duplicate
+//!   frame is appended by `POST /v1/uwb`.
+//! - `GET /replay/stream`, `POST /replay/{session}`: play stored events back over SSE (see `replay`).
+//! - `/keys`, `/keys/{device}`, `/keys/{device}/revoke`: key admin API (see `key_store`).
 //!
 //! Broadcasting strategy:
-//! A `tokio::sync::broadcast::Sender<String>` fan-out distributes JSON strings to all SSE clients.
-//! This avoids per-connection mutex contention and offers backpressure: lagging receivers get a
-//! `Lagged` error which we translate into a comment frame.
+//! `EventBus` numbers each event and fans it out over a `tokio::sync::broadcast` channel to all SSE
+//! clients, keeping the most recent ones in a ring. This avoids per-connection mutex contention and
+//! offers backpressure: a lagging receiver gets a `Lagged` error and is caught up from the ring, or sent
+//! a `resync` event when the ring has moved past it.
+//!
+//! Sequence tracking:
+//! `SequenceTracker` keeps the last message number and a short window of recently seen numbers per device
+//! (devEui, else the in-frame device ID). Multi-gateway duplicates (caught by the replay cache, or by the
+//! recent numbers when the timestamp differs) are dropped before broadcast and before any downlink is built; gaps, late (reordered) frames and counter resets feed the `uwb.seq.*` metrics,
+//! labelled by device, and `uwb.seq.loss_ratio` gives the running frame loss per tag.
 //!
 //! Downlink Posting (0x01):
-//! If `DOWNLINK_URL` env var is present, the encrypted downlink frame (base64) is POST'ed to that URL
+//! If `ingest.downlink_url` (`DOWNLINK_URL`) is configured, the encrypted downlink frame (base64) is POST'ed to that URL
 //! with body: `{ data, devEui, fPort, modeEnum, priority, timestamp, useClassA }` mirroring the Node implementation.
 //! Errors in downstream HTTP are captured and returned in the `downlink` field but do not prevent 0x05 broadcasts.
 use actix_web::{get, post, web, HttpResponse, Error, HttpRequest};
 use serde_json::{json, Value};
 use bytes::Bytes;
 use async_stream::stream;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::broadcast::Sender;
-use crate::lorawan_codec::{decode_frame, as_uwb_update, build_downlink_hex, encrypt_downlink};
-use std::env;
-use metrics::{counter, histogram};
-use tracing::{error, warn, info, debug};
+use crate::capture::{CaptureRecord, CaptureWriter};
+use crate::config::Config;
+use crate::device_registry::{self, DeviceRegistry, RegistryError};
+use crate::device_state::{self, DeviceStateStore};
+use crate::device_status::{self, StatusStore};
+use crate::events::{Event, EventBus};
+use crate::history::{self, HistoryRecord, HistoryStore};
+use crate::replay::{self, ReplaySessions};
+use crate::key_store::{self, KeyError, KeySource, KeyStore, ResolvedKeys};
+use crate::lorawan_codec::{decode_frame_traced, as_device_status, as_uwb_update, build_downlink_hex, encrypt_downlink, CodecError, DecodeFailure, DecodeOptions, DecodedFrame, Frame, HmacBinding};
+use crate::positioning::Positioner;
+use crate::replay_guard::{Freshness, ReplayGuard, ReplayRejection};
+use crate::secrets::Secrets;
+use crate::stream_filter::FilterSpec;
+use crate::ws_stream;
+use crate::tracking::Tracker;
+use metrics::{counter, gauge, histogram};
+use tracing::{debug, error, warn, info};
+
+/// Reason a `/v1/uwb` request produced no usable frame.
+/// `code()` values are stable and shared with `CodecError` so dashboards can group on them.
+#[derive(Debug)]
+pub enum IngestError {
+    /// Body has no `content.data` string.
+    MissingData,
+    /// Ciphertext could not be decrypted / verified / parsed.
+    Decode(CodecError),
+    /// `content.timestamp` missing or outside the skew window. A replay inside the window is not an
+    /// error: it is dropped as a duplicate.
+    Rejected(ReplayRejection),
+    /// The device's keys are revoked (see `key_store`).
+    Key(KeyError),
+    /// The device is disabled or has not registered (see `device_registry`).
+    Device(RegistryError),
+}
+
+impl IngestError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            IngestError::MissingData => "missing_data",
+            IngestError::Decode(e) => e.code(),
+            IngestError::Rejected(ReplayRejection::MissingTimestamp) => "timestamp_missing",
+            IngestError::Rejected(ReplayRejection::OutOfWindow { .. }) => "timestamp_out_of_window",
+            IngestError::Key(e) => e.code(),
+            IngestError::Device(e) => e.code(),
+        }
+    }
+
+    /// `{ code, message }` object embedded in the HTTP response and the `decode_error` event.
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::MissingData => f.write_str("missing content.data"),
+            IngestError::Decode(e) => e.fmt(f),
+            IngestError::Rejected(ReplayRejection::MissingTimestamp) =>
+                f.write_str("missing content.timestamp (required while ingest.ts_skew_ms > 0)"),
+            IngestError::Rejected(ReplayRejection::OutOfWindow { timestamp_ms, now_ms, skew_ms }) =>
+                write!(f, "timestamp {} is {} ms from server time (window {} ms)", timestamp_ms, timestamp_ms.abs_diff(*now_ms), skew_ms),
+            IngestError::Key(e) => e.fmt(f),
+            IngestError::Device(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+impl From<CodecError> for IngestError {
+    fn from(e: CodecError) -> Self { IngestError::Decode(e) }
+}
+
+impl From<ReplayRejection> for IngestError {
+    fn from(e: ReplayRejection) -> Self { IngestError::Rejected(e) }
+}
 
-fn sse_block_from_value(v: &Value) -> String {
+impl From<KeyError> for IngestError {
+    fn from(e: KeyError) -> Self { IngestError::Key(e) }
+}
+
+impl From<RegistryError> for IngestError {
+    fn from(e: RegistryError) -> Self { IngestError::Device(e) }
+}
+
+/// `content.timestamp` as epoch ms; accepts a JSON number or a numeric string.
+pub fn content_timestamp_ms(content: &Value) -> Option<u128> {
+    match content.get("timestamp")? {
+        Value::Number(n) => n.as_u64().map(u128::from),
+        Value::String(s) => s.trim().parse::<u128>().ok(),
+        _ => None,
+    }
+}
+
+/// How far back (in message numbers) a frame counts as late/duplicate rather than a counter reset.
+pub const REORDER_WINDOW: u16 = 64;
+
+/// Where a frame's message number falls relative to what the device sent before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqOutcome {
+    /// First frame seen from this device.
+    First,
+    /// Exactly `last + 1`.
+    InOrder,
+    /// Ahead of `last + 1`; `missing` frames were skipped.
+    Gap { missing: u16 },
+    /// Within `REORDER_WINDOW` behind `last` and not seen yet (fills an earlier gap).
+    Reordered,
+    /// Already seen recently; dropped.
+    Duplicate,
+    /// Jumped back further than `REORDER_WINDOW`: the tag restarted its counter.
+    Reset { previous: u16 },
+}
+
+/// Running per-device counters behind the `uwb.seq.*` metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeqStats {
+    pub received: u64,
+    pub duplicates: u64,
+    pub missing: u64,
+    pub reordered: u64,
+    pub resets: u64,
+}
+
+impl SeqStats {
+    /// Frames never received / frames the device sent (late arrivals are not lost).
+    pub fn loss_ratio(&self) -> f64 {
+        let lost = self.missing.saturating_sub(self.reordered);
+        let sent = self.received + lost;
+        if sent == 0 { 0.0 } else { lost as f64 / sent as f64 }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SeqState {
+    last: u16,
+    recent: VecDeque<u16>,
+    stats: SeqStats,
+}
+
+impl SeqState {
+    fn remember(&mut self, n: u16) {
+        if self.recent.len() == REORDER_WINDOW as usize {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(n);
+    }
+}
+
+/// Per-device message-number state, shared by all workers.
+#[derive(Default)]
+pub struct SequenceTracker {
+    devices: Mutex<HashMap<String, SeqState>>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self { Self::default() }
+
+    /// Classify `message_number` for `device` and update its state and counters.
+    pub fn observe(&self, device: &str, message_number: u16) -> SeqOutcome {
+        let mut devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        let Some(st) = devices.get_mut(device) else {
+            let mut st = SeqState { last: message_number, ..Default::default() };
+            st.remember(message_number);
+            st.stats.received = 1;
+            devices.insert(device.to_string(), st);
+            return SeqOutcome::First;
+        };
+        if st.recent.contains(&message_number) {
+            st.stats.duplicates += 1;
+            return SeqOutcome::Duplicate;
+        }
+        let ahead = message_number.wrapping_sub(st.last);
+        let behind = st.last.wrapping_sub(message_number);
+        let outcome = if ahead == 1 {
+            SeqOutcome::InOrder
+        } else if ahead < 0x8000 {
+            st.stats.missing += u64::from(ahead - 1);
+            SeqOutcome::Gap { missing: ahead - 1 }
+        } else if behind <= REORDER_WINDOW {
+            st.stats.reordered += 1;
+            SeqOutcome::Reordered
+        } else {
+            st.stats.resets += 1;
+            st.recent.clear();
+            SeqOutcome::Reset { previous: st.last }
+        };
+        if outcome != SeqOutcome::Reordered {
+            st.last = message_number;
+        }
+        st.remember(message_number);
+        st.stats.received += 1;
+        outcome
+    }
+
+    /// Count a frame known to be a copy of one already observed, however far back its number is.
+    pub fn repeat(&self, device: &str) -> SeqOutcome {
+        let mut devices = self.devices.lock().unwrap_or_else(|p| p.into_inner());
+        if let Some(st) = devices.get_mut(device) {
+            st.stats.duplicates += 1;
+        }
+        SeqOutcome::Duplicate
+    }
+
+    pub fn stats(&self, device: &str) -> Option<SeqStats> {
+        self.devices.lock().unwrap_or_else(|p| p.into_inner()).get(device).map(|s| s.stats)
+    }
+}
+
+/// State shared across workers by the ingestion handlers; created once in `main`.
+pub struct IngestState {
+    pub replay: ReplayGuard,
+    pub sequences: SequenceTracker,
+    pub statuses: StatusStore,
+    pub devices: DeviceStateStore,
+    pub positioner: Positioner,
+    pub tracker: Tracker,
+    pub history: HistoryStore,
+    pub replays: ReplaySessions,
+    pub capture: CaptureWriter,
+    pub keys: KeyStore,
+    pub registry: DeviceRegistry,
+}
+
+impl IngestState {
+    /// Everything from `config`; key material from the already loaded `secrets`.
+    pub fn new(config: &Config, secrets: &Secrets) -> Self {
+        IngestState {
+            replay: ReplayGuard::new(u128::from(config.ingest.ts_skew_ms)),
+            sequences: SequenceTracker::new(),
+            statuses: StatusStore::new(),
+            devices: DeviceStateStore::new(u128::from(config.devices.stale_after_ms)),
+            positioner: Positioner::from_config(config),
+            tracker: Tracker::new(config.tracking),
+            history: HistoryStore::from_config(&config.history),
+            replays: ReplaySessions::new(),
+            capture: CaptureWriter::from_config(&config.capture),
+            keys: KeyStore::from_config(&config.keys, secrets),
+            registry: DeviceRegistry::from_config(&config.devices),
+        }
+    }
+}
+
+/// Key used for per-device state: devEui identifies the radio; fall back to the in-frame device ID
+/// when a forwarder omits it.
+fn device_key(dev_eui: &str, df: &DecodedFrame) -> Option<String> {
+    if !dev_eui.is_empty() {
+        return Some(dev_eui.to_string());
+    }
+    df.device_id().map(|d| d.hex())
+}
+
+pub(crate) fn sse_block_from_value(v: &Value) -> String {
     let data = v.to_string();
-    // default event name is uwb_update for compatibility with frontend
+    // event name follows the JSON `type` (uwb_update, decode_error, ...); default uwb_update for compatibility
+    let event = v.get("type").and_then(|t| t.as_str()).unwrap_or("uwb_update");
     format!(
-        "event: uwb_update\n{}\n\n",
+        "event: {}\n{}\n\n",
+        event,
         data
             .split('\n')
             .map(|l| format!("data: {}", l))
@@ -42,124 +327,321 @@ fn sse_block_from_value(v: &Value) -> String {
 
 /// Ingest encrypted uplink frame, decode, broadcast (0x05) and optionally produce + send downlink (0x01).
 #[post("/v1/uwb")]
-pub async fn post_uwb(req: HttpRequest, body: web::Json<Value>, tx: web::Data<Sender<String>>) -> Result<HttpResponse, Error> {
+pub async fn post_uwb(req: HttpRequest, body: web::Json<Value>, events: web::Data<EventBus>, state: web::Data<IngestState>, config: web::Data<Config>) -> Result<HttpResponse, Error> {
     let req_start = std::time::Instant::now();
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let now = now_ms();
     // Expect { content: { data: <base64>, devEui, fPort, timestamp? } } similar to server.ts
     let raw_body = body.into_inner();
     let content = raw_body.get("content").cloned().unwrap_or(Value::Null);
     let data_b64 = content.get("data").and_then(|v| v.as_str()).unwrap_or("");
-    // Uplink (decode) keys: prefer explicit uplink vars, then legacy names, with Node defaults last
-    let uplink_secret = env::var("LORA_UPLINK_SECRET_KEY")
-        .or_else(|_| env::var("LORA_SECRET_KEY"))
-        .unwrap_or_else(|_| "3BA16CA4D2BE9EB96147779B32182750".to_string());
-    let uplink_token = env::var("LORA_UPLINK_SIGN_TOKEN")
-        .or_else(|_| env::var("LORA_SIGN_TOKEN"))
-        .unwrap_or_else(|_| "7AE4AF8AAD3BD554".to_string());
-    // Downlink (encrypt) keys: prefer explicit downlink vars, then legacy names, with Node defaults last
-    let downlink_secret = env::var("LORA_DOWNLINK_SECRET_KEY")
-        .or_else(|_| env::var("LORA_SECRET_KEY"))
-        .unwrap_or_else(|_| "A60C3263B832E551EEBDDDB93D8B05EA".to_string());
-    let downlink_token = env::var("LORA_DOWNLINK_SIGN_TOKEN")
-        .or_else(|_| env::var("LORA_SIGN_TOKEN"))
-        .unwrap_or_else(|_| "3E3D4BEE7FE182D8".to_string());
-    let log_keys_full = env::var("LOG_KEYS_FULL").ok().map(|s| s=="1" || s.to_lowercase()=="true").unwrap_or(false);
-    let log_raw = env::var("LORA_LOG_RAW").ok().map(|s| s=="1" || s.eq_ignore_ascii_case("true")).unwrap_or(false);
+    let uplink_ts = content_timestamp_ms(&content);
+    let dev_eui = content.get("devEui").and_then(|v| v.as_str()).unwrap_or("");
+    // Key sets to try for this device: its own (current, then previous during a rotation) or the defaults
+    let key_sets = state.keys.resolve(dev_eui, now);
+    let primary = key_sets.as_ref().ok().and_then(|sets| sets.first());
+    // Keys are only ever logged as fingerprints
+    let (upl_sk, upl_tk, dnl_sk, dnl_tk) = primary
+        .map(|k| (k.uplink.secret_key.fingerprint(), k.uplink.sign_token.fingerprint(), k.downlink.secret_key.fingerprint(), k.downlink.sign_token.fingerprint()))
+        .unwrap_or_default();
     let peer = req
         .connection_info()
         .peer_addr()
         .map(|s| s.to_string())
         .unwrap_or_else(|| "unknown".to_string());
-    let upl_sk_masked = if log_keys_full { uplink_secret.clone() } else { format!("{}..{}", &uplink_secret[..4.min(uplink_secret.len())], &uplink_secret[uplink_secret.len().saturating_sub(4)..]) };
-    let upl_tk_masked = if log_keys_full { uplink_token.clone() } else { format!("{}..{}", &uplink_token[..4.min(uplink_token.len())], &uplink_token[uplink_token.len().saturating_sub(4)..]) };
-    let dnl_sk_masked = if log_keys_full { downlink_secret.clone() } else { format!("{}..{}", &downlink_secret[..4.min(downlink_secret.len())], &downlink_secret[downlink_secret.len().saturating_sub(4)..]) };
-    let dnl_tk_masked = if log_keys_full { downlink_token.clone() } else { format!("{}..{}", &downlink_token[..4.min(downlink_token.len())], &downlink_token[downlink_token.len().saturating_sub(4)..]) };
-    info!(peer = %peer, data_b64_len = data_b64.len(), dev_eui = content.get("devEui").and_then(|v| v.as_str()).unwrap_or(""), f_port = content.get("fPort").and_then(|v| v.as_i64()).unwrap_or(-1), uplink_sk = %upl_sk_masked, uplink_tk = %upl_tk_masked, downlink_sk = %dnl_sk_masked, downlink_tk = %dnl_tk_masked, full_keys = log_keys_full, "POST /v1/uwb received");
+    if state.capture.is_enabled() {
+        let headers = req.headers().iter().map(|(k, v)| (k.as_str(), v.as_bytes()));
+        state.capture.record(&CaptureRecord::new(now, &peer, headers, raw_body.clone()));
+    }
+    info!(peer = %peer, data_b64_len = data_b64.len(), dev_eui, f_port = content.get("fPort").and_then(|v| v.as_i64()).unwrap_or(-1), uplink_sk = %upl_sk, uplink_tk = %upl_tk, downlink_sk = %dnl_sk, downlink_tk = %dnl_tk, key_source = primary.map_or("none", |k| k.source.as_str()), "POST /v1/uwb received");
 
     // Always log the raw body and base64 (preview) for visibility during vendor debugging
     let raw_json_str = raw_body.to_string();
     let b64_preview = if data_b64.len() > 64 { &data_b64[0..64] } else { data_b64 };
     info!(raw_json_len = raw_json_str.len(), raw_json = %raw_json_str, data_b64_preview = %b64_preview, data_b64_len = data_b64.len(), "uwb request body");
 
-    if log_raw {
+    if config.ingest.log_raw {
         let raw_json = raw_body.to_string();
         // Log full raw JSON body and the base64 payload to enable offline replay.
         info!(raw_json_len = raw_json.len(), raw_json = %raw_json, data_b64 = data_b64, "lorawan raw body");
     }
     let mut downlink_response: Option<Value> = None; // JSON detail about constructed/sent downlink
-    if !data_b64.is_empty() {
-    match decode_frame(data_b64, &uplink_secret, &uplink_token) {
-            Ok(df) => {
-                info!(msg_type = format!("0x{:02x}", df.message_type), "decode ok");
-                // If message type 0x01: build and encrypt a downlink and (optionally) send it to external server via reqwest
-                if df.message_type == 0x01 {
-                    if let Ok(down_hex) = build_downlink_hex(&df) {
-                        if let Ok(encrypted_b64) = encrypt_downlink(now, &down_hex, &downlink_token, &downlink_secret) {
-                            // Attempt optional external POST if DOWNLINK_URL is configured.
-                            let downlink_url = env::var("DOWNLINK_URL").ok();
-                            let mut sent_obj = json!({ "sentData": encrypted_b64 });
-                            if let Some(url) = downlink_url {
-                                info!(url = %url, "posting downlink");
-                                // Fire-and-await; failures captured but do not abort response.
-                                match reqwest::Client::new().post(&url)
-                                    .json(&json!({
-                                        "data": encrypted_b64,
-                                        "devEui": content.get("devEui").and_then(|v| v.as_str()).unwrap_or(""),
-                                        "fPort": content.get("fPort").and_then(|v| v.as_i64()).unwrap_or(0),
-                                        "modeEnum": "DEFAULT_MODE",
-                                        "priority": false,
-                                        "timestamp": now,
-                                        "useClassA": true
-                                    }))
-                                    .send().await {
-                                    Ok(resp) => {
-                                        let status = resp.status().as_u16();
-                                        let body_json = resp.json::<Value>().await.unwrap_or(json!({"error":"invalid-json"}));
-                                        sent_obj["downlinkHttp"] = json!({ "status": status, "body": body_json });
-                                        counter!("uwb.downlink.http.ok", "status" => status.to_string()).increment(1);
-                                        info!(status, "downlink http ok");
-                                    },
-                                    Err(e) => {
-                                        sent_obj["downlinkHttpError"] = json!(e.to_string());
-                                        counter!("uwb.downlink.http.err").increment(1);
-                                        warn!(error = %e, "downlink http failed");
+    let mut ingest_err: Option<IngestError> = None;
+    let mut duplicate = false;
+    // Winning decrypt mode / signature layout and the rejected candidates, echoed in the response
+    let mut decode_report: Option<Value> = None;
+    if uplink_ts.is_none() {
+        counter!("uwb.ingest.no_timestamp").increment(1);
+    }
+    if data_b64.is_empty() {
+        ingest_err = Some(IngestError::MissingData);
+    } else if let Err(e) = state.replay.check_window(uplink_ts, now) {
+        ingest_err = Some(e.into());
+    } else {
+    let decoded = key_sets.map_err(IngestError::from)
+        .and_then(|sets| decode_with_keys(data_b64, uplink_ts, sets, &config.decode).map_err(|f| {
+            decode_report = Some(json!({ "rejected": f.rejected }));
+            IngestError::from(f.error)
+        }))
+        .and_then(|(df, keys)| {
+            // The request's devEui chose the keys; a revoked in-frame device ID still rejects the frame
+            if let Some(id) = df.device_id() {
+                state.keys.check_device_id(&id.hex())?;
+            }
+            // The same frame again is normally another gateway's copy: dropped below as a duplicate
+            let device = device_key(dev_eui, &df).unwrap_or_default();
+            let freshness = match df.hmac {
+                HmacBinding::Timestamp(signed) => Freshness::Signed(signed),
+                HmacBinding::Payload | HmacBinding::Unverified => Freshness::payload(&df.raw_payload),
+            };
+            let mut repeated = uplink_ts.is_some() && state.replay.contains(&device, df.header.message_number, freshness, now);
+            if !repeated {
+                state.registry.admit(dev_eui, &df, now).inspect_err(|e| {
+                    counter!("uwb.registry.rejected", "code" => e.code()).increment(1);
+                })?;
+                // Recorded only once admitted, so a refused frame sent again is refused again
+                if let Some(ts) = uplink_ts {
+                    // `false`: another copy was recorded since `contains`
+                    repeated = !state.replay.check(&device, df.header.message_number, ts, freshness, now)?;
+                }
+            }
+            Ok((df, keys, repeated))
+        });
+    match decoded {
+            Ok((df, keys, repeated)) => {
+                let diag = &df.diagnostics;
+                info!(msg_type = format!("0x{:02x}", df.message_type()), hmac = ?df.hmac, mode = diag.mode, layout = diag.layout, score = diag.score, rejected = diag.rejected.len(), "decode ok");
+                counter!("uwb.decode.path", "mode" => diag.mode, "layout" => diag.layout).increment(1);
+                counter!("uwb.keys.used", "source" => keys.source.as_str()).increment(1);
+                let mut report = json!(diag);
+                report["keySource"] = json!(keys.source);
+                decode_report = Some(report);
+                if !df.integrity.is_ok() {
+                    // Only reachable with decode.integrity = lenient; strict mode rejects in decode_frame
+                    counter!("uwb.decode.integrity_flagged").increment(1);
+                    warn!(integrity = ?df.integrity, "frame accepted with integrity failure");
+                }
+                if let Some(device) = device_key(dev_eui, &df) {
+                    duplicate = track_sequence(&state.sequences, &device, df.header.message_number, repeated, &events, now);
+                }
+                if duplicate {
+                    info!(msg_number = df.header.message_number, "duplicate frame dropped");
+                } else {
+                    // Event broadcast for this frame, kept for the history record
+                    let mut broadcast: Option<Value> = None;
+                    let device = device_key(dev_eui, &df).unwrap_or_default();
+                    state.devices.seen(&device, &df.device_id().map(|d| d.hex()).unwrap_or_default(), dev_eui, now);
+                    // If message type 0x01: build and encrypt a downlink and (optionally) send it to external server via reqwest
+                    if df.message_type() == 0x01 {
+                        if let Ok(down_hex) = build_downlink_hex(&df) {
+                            if let Ok(encrypted_b64) = encrypt_downlink(now, &down_hex, keys.downlink.sign_token.expose(), keys.downlink.secret_key.expose()) {
+                                // Attempt optional external POST if a downlink URL is configured.
+                                let mut sent_obj = json!({ "sentData": encrypted_b64 });
+                                if let Some(url) = &config.ingest.downlink_url {
+                                    info!(url = %url, "posting downlink");
+                                    // Fire-and-await; failures captured but do not abort response.
+                                    match reqwest::Client::new().post(url)
+                                        .json(&json!({
+                                            "data": encrypted_b64,
+                                            "devEui": content.get("devEui").and_then(|v| v.as_str()).unwrap_or(""),
+                                            "fPort": content.get("fPort").and_then(|v| v.as_i64()).unwrap_or(0),
+                                            "modeEnum": "DEFAULT_MODE",
+                                            "priority": false,
+                                            "timestamp": now,
+                                            "useClassA": true
+                                        }))
+                                        .send().await {
+                                        Ok(resp) => {
+                                            let status = resp.status().as_u16();
+                                            let body_json = resp.json::<Value>().await.unwrap_or(json!({"error":"invalid-json"}));
+                                            sent_obj["downlinkHttp"] = json!({ "status": status, "body": body_json });
+                                            counter!("uwb.downlink.http.ok", "status" => status.to_string()).increment(1);
+                                            info!(status, "downlink http ok");
+                                        },
+                                        Err(e) => {
+                                            sent_obj["downlinkHttpError"] = json!(e.to_string());
+                                            counter!("uwb.downlink.http.err").increment(1);
+                                            warn!(error = %e, "downlink http failed");
+                                        }
                                     }
                                 }
+                                downlink_response = Some(sent_obj);
                             }
-                            downlink_response = Some(sent_obj);
                         }
                     }
-                }
-                // If message type 0x05: convert to uwb_update and broadcast
-                if let Some(update) = as_uwb_update(&df, now) {
-                    match tx.send(update.to_string()) {
-                        Ok(subs) => { info!(subs, "broadcast sent uwb_update"); },
-                        Err(e) => { warn!(error = %e, "broadcast send failed"); }
+                    // If message type 0x03: keep latest status per device and broadcast device_status
+                    if let (Frame::Status(report), Some(mut event)) = (&df.frame, as_device_status(&df, now)) {
+                        if !dev_eui.is_empty() { event["payload"]["devEui"] = json!(dev_eui); }
+                        let status = state.statuses.record(&device, report, df.header.message_number, now);
+                        state.devices.record_status(&status);
+                        gauge!("uwb.device.battery", "device" => device.clone()).set(f64::from(status.battery));
+                        if status.abnormal {
+                            warn!(device = %device, code = status.abnormal_code, "device reports abnormal status");
+                        }
+                        events.publish(event.clone());
+                        counter!("uwb.broadcast.sent").increment(1);
+                        broadcast = Some(event);
+                    }
+                    // If message type 0x05: convert to uwb_update and broadcast
+                    if let (Frame::LocationReport(report), Some(mut update)) = (&df.frame, as_uwb_update(&df, now)) {
+                        let device_hex = report.device_id.hex();
+                        let taken_at = uplink_ts.unwrap_or(now);
+                        if !dev_eui.is_empty() { update["payload"]["devEui"] = json!(dev_eui); }
+                        update["payload"]["floor"] = json!(state.positioner.floor(report));
+                        let located = state.positioner.locate(&device_hex, report, taken_at);
+                        for r in &located.rejected {
+                            counter!("uwb.position.rejected", "reason" => r.reason.as_str()).increment(1);
+                        }
+                        if !located.rejected.is_empty() {
+                            debug!(device = %device_hex, rejected = located.rejected.len(), "ranges rejected before solve");
+                        }
+                        update["payload"]["rejectedBeacons"] = json!(located.rejected);
+                        match located.position {
+                            Ok(pos) => {
+                                histogram!("uwb.position.residual_m").record(pos.residual);
+                                counter!("uwb.position.solved", "mode" => pos.mode.as_str()).increment(1);
+                                let track = state.tracker.update(&device_hex, &pos, report.motion, taken_at);
+                                if track.updates == 1 { counter!("uwb.track.started").increment(1); }
+                                update["payload"]["position"] = json!(pos);
+                                update["payload"]["track"] = json!(track);
+                            }
+                            Err(e) => {
+                                counter!("uwb.position.failed", "code" => e.code()).increment(1);
+                                debug!(error = %e, device = %report.device_id, "no position");
+                            }
+                        }
+                        let id = events.publish(update.clone());
+                        state.devices.record_update(&device, &update, id, now);
+                        info!(id, subs = events.subscribers(), "broadcast sent uwb_update");
+                        counter!("uwb.broadcast.sent").increment(1);
+                        broadcast = Some(update);
+                    }
+                    if state.history.is_enabled() {
+                        // File IO off the async worker
+                        let (record, st) = (HistoryRecord::new(now, dev_eui, &df, broadcast), state.clone());
+                        let appended = web::block(move || st.history.append(&record)).await
+                            .map_err(std::io::Error::other).and_then(|r| r);
+                        if let Err(e) = appended {
+                            counter!("uwb.history.write_err").increment(1);
+                            error!(error = %e, "history append failed");
+                        }
                     }
-                    counter!("uwb.broadcast.sent").increment(1);
                 }
             },
-            Err(e) => {
-                counter!("uwb.decode.err").increment(1);
-                error!(error = %e, "decode failed");
-                let _ = tx.send(json!({"type":"decode_error","error":e,"ts":now}).to_string());
-            }
+            Err(e) => { ingest_err = Some(e); }
         }
     }
+    if let Some(e) = &ingest_err {
+        counter!("uwb.decode.err", "code" => e.code()).increment(1);
+        error!(error = %e, code = e.code(), "decode failed");
+        events.publish(json!({"type":"decode_error","code":e.code(),"error":e.to_string(),"device":dev_eui,"ts":now}));
+    }
     histogram!("uwb.ingest.latency_ms").record(req_start.elapsed().as_secs_f64()*1000.0);
-    let resp_json = json!({"ok": true, "downlink": downlink_response });
+    // Failures still answer 200 (see README "Error Handling"): forwarders retry non-2xx, and a frame
+    // that failed here fails the same way on every retry.
+    let mut resp_json = json!({"ok": ingest_err.is_none(), "downlink": downlink_response });
+    if duplicate { resp_json["duplicate"] = json!(true); }
+    if let Some(report) = decode_report { resp_json["decode"] = report; }
+    if let Some(e) = &ingest_err { resp_json["error"] = e.to_json(); }
     info!(response = %resp_json, "POST /v1/uwb response");
     Ok(HttpResponse::Ok().json(resp_json))
 }
 
-/// Local SSE stream of decoded location updates plus occasional comment heartbeats.
+/// Decode with each key set in order; the first that verifies wins. When none does, the failure of
+/// the first (most specific) set is reported.
+pub fn decode_with_keys(data_b64: &str, uplink_ts: Option<u128>, key_sets: Vec<ResolvedKeys>, opts: &DecodeOptions) -> Result<(DecodedFrame, ResolvedKeys), DecodeFailure> {
+    let mut first_failure: Option<DecodeFailure> = None;
+    for keys in key_sets {
+        match decode_frame_traced(data_b64, keys.uplink.secret_key.expose(), keys.uplink.sign_token.expose(), uplink_ts, opts) {
+            Ok(df) => {
+                if keys.source == KeySource::Previous {
+                    info!(msg_number = df.header.message_number, "frame verified with pre-rotation keys");
+                }
+                return Ok((df, keys));
+            }
+            Err(f) => { first_failure.get_or_insert(f); }
+        }
+    }
+    Err(first_failure.expect("KeyStore::resolve returns at least one key set"))
+}
+
+/// Feed one frame into the tracker, update `uwb.seq.*` metrics and announce counter resets. `repeated`
+/// frames (already accepted per the replay cache) are counted as duplicates without being classified.
+/// Returns `true` when the frame is a duplicate and must not be processed further.
+fn track_sequence(seq: &SequenceTracker, device: &str, message_number: u16, repeated: bool, events: &EventBus, now: u128) -> bool {
+    let outcome = if repeated { seq.repeat(device) } else { seq.observe(device, message_number) };
+    let label = device.to_string();
+    match outcome {
+        SeqOutcome::First | SeqOutcome::InOrder => {}
+        SeqOutcome::Duplicate => {
+            counter!("uwb.seq.duplicate", "device" => label.clone()).increment(1);
+        }
+        SeqOutcome::Gap { missing } => {
+            counter!("uwb.seq.missing", "device" => label.clone()).increment(u64::from(missing));
+            warn!(device, message_number, missing, "message number gap");
+        }
+        SeqOutcome::Reordered => {
+            counter!("uwb.seq.reordered", "device" => label.clone()).increment(1);
+        }
+        SeqOutcome::Reset { previous } => {
+            counter!("uwb.seq.reset", "device" => label.clone()).increment(1);
+            warn!(device, previous, message_number, "message number reset");
+            events.publish(json!({"type":"sequence_reset","device":device,"previous":previous,"messageNumber":message_number,"ts":now}));
+        }
+    }
+    if outcome != SeqOutcome::Duplicate {
+        counter!("uwb.seq.received", "device" => label.clone()).increment(1);
+    }
+    if let Some(stats) = seq.stats(device) {
+        gauge!("uwb.seq.loss_ratio", "device" => label).set(stats.loss_ratio());
+    }
+    outcome == SeqOutcome::Duplicate
+}
+
+/// Server time, epoch ms; the one clock the handlers and stores share.
+pub(crate) fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// `Last-Event-ID` header as an event id; anything unparsable counts as absent.
+pub(crate) fn last_event_id(req: &HttpRequest) -> Option<u64> {
+    req.headers().get("Last-Event-ID")?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Local SSE stream of decoded location updates plus occasional comment heartbeats. Starts with a
+/// `snapshot` of every device (see `device_state`), or resumes after `Last-Event-ID` from the event
+/// ring, sending `resync` and the snapshot when it cannot (see `events`); the query string narrows what
+/// is sent (see `stream_filter`, 400 `invalid_filter`).
 #[get("/proxy/uwbStream")]
-pub async fn local_stream(tx: web::Data<Sender<String>>) -> Result<HttpResponse, Error> {
+pub async fn local_stream(req: HttpRequest, events: web::Data<EventBus>, state: web::Data<IngestState>) -> Result<HttpResponse, Error> {
+    let mut filter = match FilterSpec::from_query(req.query_string()).and_then(FilterSpec::build) {
+        Ok(f) => f,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(e.to_json())),
+    };
+    if !filter.spec().is_empty() {
+        debug!(filter = ?filter.spec(), "sse client filter");
+    }
     // Subscribe to broadcast; each client gets its own receiver
-    let mut rx = tx.subscribe();
+    let resume_from = last_event_id(&req);
+    let sub = events.subscribe(resume_from);
+    let (mut rx, mut cursor) = (sub.rx, sub.cursor);
     let s = stream! {
         // send hello
         yield Ok::<Bytes, Error>(Bytes::from_static(b": hello\n\n"));
+        if !sub.backlog.is_empty() {
+            info!(last_event_id = resume_from, missed = sub.backlog.len(), "sse client resumed");
+            counter!("uwb.sse.resumed").increment(sub.backlog.len() as u64);
+        }
+        for event in sub.backlog.iter().filter(|e| filter.admit(&e.value)) {
+            yield Ok(event.sse.clone());
+        }
+        if let Some(resync) = &sub.resync {
+            info!(last_event_id = resync.last_event_id, reason = resync.reason.as_str(), "sse client cannot resume; resync");
+            counter!("uwb.sse.resync", "reason" => resync.reason.as_str()).increment(1);
+            yield Ok(resync.event(now_ms()).sse);
+        }
+        if resume_from.is_none() || sub.resync.is_some() {
+            yield Ok(Event::new(sub.cursor, state.devices.snapshot(&filter, sub.cursor, now_ms())).sse);
+        }
         // heartbeat ticker
         let mut hb = tokio::time::interval(Duration::from_secs(15));
         loop {
@@ -170,17 +652,35 @@ pub async fn local_stream(tx: web::Data<Sender<String>>) -> Result<HttpResponse,
                 }
                 recv = rx.recv() => {
                     match recv {
-                        Ok(s) => {
-                            // s is a JSON string; wrap into SSE block
-                            let v: Value = serde_json::from_str(&s).unwrap_or(json!({"type":"uwb_update","payload":null}));
-                            let block = sse_block_from_value(&v);
-                            yield Ok(Bytes::from(block));
-                            counter!("uwb.sse.sent").increment(1);
+                        // already sent from the ring
+                        Ok(event) if event.id <= cursor => {}
+                        Ok(event) => {
+                            cursor = event.id;
+                            if filter.admit(&event.value) {
+                                yield Ok(event.sse.clone());
+                                counter!("uwb.sse.sent").increment(1);
+                            } else {
+                                counter!("uwb.sse.filtered").increment(1);
+                            }
                         },
-                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
-                            // If lagged, send a note and continue
-                            yield Ok(Bytes::from_static(b": lagged\n\n"));
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                            // Fill the gap from the ring; events still queued in rx are then skipped by id
                             counter!("uwb.sse.lagged").increment(1);
+                            match events.since(cursor) {
+                                Ok(missed) => {
+                                    debug!(skipped, refilled = missed.len(), "sse client lagged; caught up from ring");
+                                    for event in missed {
+                                        cursor = event.id;
+                                        if filter.admit(&event.value) { yield Ok(event.sse.clone()); }
+                                    }
+                                }
+                                Err(resync) => {
+                                    warn!(skipped, "sse client lagged past the ring; resync");
+                                    counter!("uwb.sse.resync", "reason" => resync.reason.as_str()).increment(1);
+                                    cursor = resync.latest_id;
+                                    yield Ok(resync.event(now_ms()).sse);
+                                }
+                            }
                         },
                         Err(tokio::sync::broadcast::error::RecvError::Closed) => {
                             break;
@@ -199,9 +699,164 @@ pub async fn local_stream(tx: web::Data<Sender<String>>) -> Result<HttpResponse,
         .streaming(s))
 }
 
-/// Register ingestion + SSE endpoints and attach broadcast sender to app data.
-pub fn config(cfg: &mut web::ServiceConfig, tx: Sender<String>) {
-    cfg.app_data(web::Data::new(tx));
+/// Register ingestion + SSE endpoints and attach the event bus, ingest state and configuration to
+/// app data. `events` and `state` are created once in `main` so all workers share them.
+pub fn config(cfg: &mut web::ServiceConfig, events: web::Data<EventBus>, state: web::Data<IngestState>, config: web::Data<Config>) {
+    cfg.app_data(events);
+    cfg.app_data(state);
+    cfg.app_data(config);
     cfg.service(post_uwb);
     cfg.service(local_stream);
+    cfg.service(ws_stream::ws);
+    cfg.service(device_status::list_status);
+    cfg.service(device_status::get_status);
+    cfg.service(device_state::list_state);
+    cfg.service(device_state::get_state);
+    // After the fixed `/devices/status` and `/devices/state` paths
+    cfg.service(device_registry::list_devices);
+    cfg.service(device_registry::get_device);
+    cfg.service(device_registry::put_device);
+    cfg.service(device_registry::delete_device);
+    cfg.service(history::get_history);
+    cfg.service(replay::replay_stream);
+    cfg.service(replay::control_replay);
+    cfg.service(key_store::list_keys);
+    cfg.service(key_store::get_keys);
+    cfg.service(key_store::put_keys);
+    cfg.service(key_store::revoke_keys);
+    cfg.service(key_store::delete_keys);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::test_support::{self, sse_events, Server, DEVICE, DEV_EUI};
+    use crate::device_registry::DeviceEdit;
+
+    #[test]
+    fn sequence_tracker_classifies_frames() {
+        let t = SequenceTracker::new();
+        assert_eq!(t.observe("a", 1000), SeqOutcome::First);
+        assert_eq!(t.observe("a", 1001), SeqOutcome::InOrder);
+        assert_eq!(t.observe("a", 1001), SeqOutcome::Duplicate);
+        assert_eq!(t.observe("a", 1004), SeqOutcome::Gap { missing: 2 });
+        assert_eq!(t.observe("a", 1002), SeqOutcome::Reordered);
+        assert_eq!(t.observe("a", 1002), SeqOutcome::Duplicate);
+        assert_eq!(t.observe("a", 1005), SeqOutcome::InOrder);
+        // 16-bit wrap is in order
+        assert_eq!(t.observe("b", 0xFFFF), SeqOutcome::First);
+        assert_eq!(t.observe("b", 0x0000), SeqOutcome::InOrder);
+        // large backwards jump is a reset, and the counter restarts from there
+        assert_eq!(t.observe("a", 1), SeqOutcome::Reset { previous: 1005 });
+        assert_eq!(t.observe("a", 2), SeqOutcome::InOrder);
+
+        let st = t.stats("a").unwrap();
+        assert_eq!(st, SeqStats { received: 7, duplicates: 2, missing: 2, reordered: 1, resets: 1 });
+        // one of the two missing frames arrived late: 1 lost out of 8 sent
+        assert!((st.loss_ratio() - 1.0 / 8.0).abs() < 1e-9);
+    }
+
+    #[actix_web::test]
+    async fn multi_gateway_copies_are_dropped_as_duplicates() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let start = server.events.subscribe(None).cursor;
+        let body = test_support::uplink_body(DEV_EUI, 42, test_support::location_report(DEVICE), now_ms());
+
+        let post = || TestRequest::post().uri("/v1/uwb").set_json(&body).to_request();
+        let first: Value = call_and_read_body_json(&app, post()).await;
+        assert_eq!((&first["ok"], first.get("duplicate"), first.get("error")), (&json!(true), None, None));
+        let copy: Value = call_and_read_body_json(&app, post()).await;
+        assert_eq!((&copy["ok"], &copy["duplicate"], copy.get("error")), (&json!(true), &json!(true), None));
+
+        let published: Vec<_> = server.events.since(start).unwrap().iter().map(|e| e.value["type"].clone()).collect();
+        assert_eq!(published, [json!("uwb_update")]);
+        assert_eq!(server.state.sequences.stats(DEV_EUI).map(|s| (s.received, s.duplicates)), Some((1, 1)));
+    }
+
+    #[actix_web::test]
+    async fn refused_frames_stay_refused_when_sent_again() {
+        let server = Server::new(|_| {});
+        let disable = DeviceEdit { enabled: Some(false), ..DeviceEdit::default() };
+        server.state.registry.edit(DEV_EUI, disable, now_ms()).unwrap();
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let body = test_support::uplink_body(DEV_EUI, 42, test_support::location_report(DEVICE), now_ms());
+
+        for _ in 0..2 {
+            let reply: Value = call_and_read_body_json(&app, TestRequest::post().uri("/v1/uwb").set_json(&body).to_request()).await;
+            assert_eq!((&reply["ok"], reply.get("duplicate"), &reply["error"]["code"]), (&json!(false), None, &json!("device_disabled")));
+        }
+        assert!(server.state.replay.is_empty());
+    }
+
+    fn update(device: &str, n: u64) -> Value {
+        json!({ "type": "uwb_update", "ts": n, "payload": { "deviceIdHex": device, "n": n } })
+    }
+
+    #[actix_web::test]
+    async fn stream_starts_with_a_snapshot() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, TestRequest::get().uri("/proxy/uwbStream").to_request()).await;
+        let mut body = res.into_body();
+        let first = sse_events(&mut body, 1).await;
+        assert_eq!(first[0].1["type"], "snapshot");
+
+        let id = server.events.publish(update("a0ba3e29", 1));
+        assert_eq!(sse_events(&mut body, 1).await, [(Some(id), update("a0ba3e29", 1))]);
+    }
+
+    #[actix_web::test]
+    async fn stream_resumes_after_last_event_id() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let first = server.events.publish(update("a0ba3e29", 1));
+        let ids: Vec<_> = (2..=3).map(|n| server.events.publish(update("a0ba3e29", n))).collect();
+
+        let req = TestRequest::get().uri("/proxy/uwbStream").insert_header(("Last-Event-ID", first.to_string())).to_request();
+        let mut body = call_service(&app, req).await.into_body();
+        // the missed events, then live ones; no snapshot
+        let live = server.events.publish(update("a0ba3e29", 4));
+        let events = sse_events(&mut body, 3).await;
+        assert_eq!(events.iter().map(|(id, _)| id.unwrap()).collect::<Vec<_>>(), [ids[0], ids[1], live]);
+    }
+
+    #[actix_web::test]
+    async fn unknown_last_event_id_gets_resync_and_snapshot() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let latest = server.events.publish(update("a0ba3e29", 1));
+        let req = TestRequest::get().uri("/proxy/uwbStream").insert_header(("Last-Event-ID", (latest + 100).to_string())).to_request();
+        let mut body = call_service(&app, req).await.into_body();
+        let events = sse_events(&mut body, 2).await;
+        assert_eq!((&events[0].1["type"], &events[1].1["type"]), (&json!("resync"), &json!("snapshot")));
+    }
+
+    #[actix_web::test]
+    async fn stream_sends_only_what_the_filter_selects() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let req = TestRequest::get().uri("/proxy/uwbStream?devices=A0BA3E29&types=uwb_update").to_request();
+        let mut body = call_service(&app, req).await.into_body();
+        assert_eq!(sse_events(&mut body, 1).await[0].1["type"], "snapshot");
+
+        server.events.publish(update("b0ba3e29", 1));
+        server.events.publish(json!({ "type": "device_status", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        let wanted = server.events.publish(update("a0ba3e29", 2));
+        assert_eq!(sse_events(&mut body, 1).await, [(Some(wanted), update("a0ba3e29", 2))]);
+    }
+
+    #[actix_web::test]
+    async fn stream_rejects_an_invalid_filter() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        for uri in ["/proxy/uwbStream?types=position", "/proxy/uwbStream?maxHz=0", "/proxy/uwbStream?floor=one"] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+            let body: Value = read_body_json(res).await;
+            assert_eq!(body["code"], "invalid_filter", "{uri}");
+        }
+    }
 }
@@ -12,14 +12,16 @@
 //!   for development & testing without real hardware.
 //! - Conditionally expose either the legacy remote proxy stream (`/proxy/uwbStream`) OR
 //!   the local LoRaWAN ingestion endpoints (`POST /v1/uwb` + local SSE `/proxy/uwbStream`) based on
-//!   the `USE_REMOTE_UWB` environment variable.
+//!   `server.use_remote_uwb` (`USE_REMOTE_UWB`).
 //! - Provide a migration bridge so the Node `server.ts` functionality can be retired.
 //!
-//! Key Environment Variables (documented again in `README.md`):
+//! Settings come from `pinpoint.toml` / `CONFIG_FILE` with environment overrides (see `config` and
+//! `config.example.toml`); the configuration is validated and logged before anything starts. Key ones:
 //! - `BACKEND_PORT` (default 8080) : TCP port for this server.
 //! - `USE_REMOTE_UWB` ("1"/"true") : If set, use legacy remote upstream and proxy its SSE stream.
-//! - `LORA_SECRET_KEY` : Hex AES key for decrypting uplink (and encrypting downlink) frames.
-//! - `LORA_SIGN_TOKEN` : Hex HMAC key used when signing (HMAC-SHA256) uplink/downlink frames.
+//! - `LORA_UPLINK_*` / `LORA_DOWNLINK_*` (legacy `LORA_SECRET_KEY` / `LORA_SIGN_TOKEN`): default keys, also
+//!   loadable from `SECRETS_FILE` / `SECRETS_DIR` and reloaded on change or SIGHUP (see `secrets`).
+//! - `APP_ENV=production` : refuse to start without real keys (the demo keys are rejected).
 //! - `DOWNLINK_URL` : Optional full URL for posting registration/downlink responses (0x01 messages).
 //!
 //! High-Level Data Flow (local ingestion mode):
@@ -41,13 +43,17 @@ use std::collections::HashMap;
 use async_stream::stream;
 use bytes::Bytes;
 use reqwest::Client as ReqwestClient;
-use std::env;
-mod lorawan_stream;
-mod lorawan_codec;
+use pinpoint_backend::anchor_registry::{self, AnchorRegistry};
+use pinpoint_backend::config::Config;
+use pinpoint_backend::events::EventBus;
+use pinpoint_backend::lorawan_stream::{self, IngestState};
+use pinpoint_backend::positioning::Anchor;
+use pinpoint_backend::secrets;
 
 #[derive(Deserialize)]
 struct QueryApiKey {
     // optional api key in query for demo
+    #[allow(dead_code)]
     api_key: Option<String>
 }
 
@@ -64,6 +70,23 @@ fn corner_anchors(width: f64, height: f64) -> Vec<(&'static str, f64, f64)> {
     ]
 }
 
+/// Anchors the mock endpoints range against: the registry when it has any, so the mock stream
+/// agrees with what operators configured; otherwise the corners of the `width` x `height` rectangle.
+fn mock_anchors(registry: &AnchorRegistry, width: f64, height: f64, anchor_z: f64) -> Vec<Anchor> {
+    let registered = registry.anchors();
+    if !registered.is_empty() {
+        return registered;
+    }
+    corner_anchors_at(width, height, anchor_z)
+}
+
+/// `corner_anchors` as solver anchors, all at `anchor_z`.
+fn corner_anchors_at(width: f64, height: f64, anchor_z: f64) -> Vec<Anchor> {
+    corner_anchors(width, height).into_iter()
+        .map(|(id, x, y)| Anchor { beacon_id: id.to_string(), x, y, z: anchor_z })
+        .collect()
+}
+
 // Deterministic path waypoints based on rectangle size.
 // Middle -> left edge -> right edge -> middle -> bottom edge -> top edge -> middle
 // -> left edge to bottom-left anchor -> along bottom to bottom-right (virtual)
@@ -101,14 +124,19 @@ fn path_waypoints(width: f64, height: f64) -> Vec<(f64, f64)> {
 /// Distances are computed in 3D (shared anchor Z + tag Z) to allow solver testing
 /// where Z differences exist yet anchors share a common altitude (cancels out in 2D math).
 fn generate_uwb_update_for_pos(x: f64, y: f64, width: f64, height: f64, anchor_z: f64, tag_z: f64) -> serde_json::Value {
+    generate_uwb_update_with(x, y, &corner_anchors_at(width, height, anchor_z), anchor_z, tag_z)
+}
+
+/// Same as `generate_uwb_update_for_pos`, ranging against an explicit anchor list (per-anchor Z).
+fn generate_uwb_update_with(x: f64, y: f64, anchors: &[Anchor], anchor_z: f64, tag_z: f64) -> serde_json::Value {
     let mut beacons = vec![];
-    for (id, ax, ay) in corner_anchors(width, height) {
-        let dz = tag_z - anchor_z;
-        let dist = ((ax - x).powi(2) + (ay - y).powi(2) + dz.powi(2)).sqrt();
+    for a in anchors {
+        let dz = tag_z - a.z;
+        let dist = ((a.x - x).powi(2) + (a.y - y).powi(2) + dz.powi(2)).sqrt();
         beacons.push(json!({
-            "major": "0200",
-            "minor": id.get(2..).unwrap_or("0000"),
-            "beaconId": id,
+            "major": a.beacon_id.get(..4).unwrap_or("0000"),
+            "minor": a.beacon_id.get(4..).unwrap_or("0000"),
+            "beaconId": a.beacon_id,
             // distance in meters; conversion to cm is handled by endpoints
             "distance": dist,
             "battery": 100
@@ -144,7 +172,7 @@ fn sse_event_block(payload: &serde_json::Value) -> String {
 /// Mock streaming endpoint producing a synthetic trajectory as SSE (`event: uwb_update`).
 /// Query parameters offer perturbations (noise/outliers/dropouts/zeros) to stress-test the solver.
 #[get("/mock/stream")]
-async fn mock_stream(query: web::Query<HashMap<String, String>>) -> Result<HttpResponse, Error> {
+async fn mock_stream(query: web::Query<HashMap<String, String>>, registry: web::Data<AnchorRegistry>) -> Result<HttpResponse, Error> {
     let width = query.get("w").and_then(|s| s.parse::<f64>().ok()).unwrap_or(20.0);
     let height = query.get("h").and_then(|s| s.parse::<f64>().ok()).unwrap_or(10.0);
     // Anchors share a single Z; choose randomly unless provided
@@ -161,6 +189,8 @@ async fn mock_stream(query: web::Query<HashMap<String, String>>) -> Result<HttpR
     let outlier_scale = query.get("outlierScale").and_then(|s| s.parse::<f64>().ok()).unwrap_or(1.8);
     let drop_rate = query.get("dropRate").and_then(|s| s.parse::<f64>().ok()).unwrap_or(0.0);
     let zero_rate = query.get("zeroRate").and_then(|s| s.parse::<f64>().ok()).unwrap_or(0.0);
+    // Snapshot the anchors once so the whole stream uses one geometry
+    let anchors = mock_anchors(&registry, width, height, anchor_z);
     // Use a stable mock device ID so the frontend can draw a continuous path
     let stable_hex = String::from("a0ba3e29");
     let stable_dec: u64 = 2696560169;
@@ -181,7 +211,7 @@ async fn mock_stream(query: web::Query<HashMap<String, String>>) -> Result<HttpR
             if t >= 1.0 { t = 0.0; seg_idx = (seg_idx + 1) % waypoints.len(); }
 
             let tag_z = if tz_amp > 0.0 && tz_hz > 0.0 { tz_base + tz_amp * (std::f64::consts::TAU * tz_hz * (tick as f64) * 0.6).sin() } else { tz_base };
-            let mut p2 = generate_uwb_update_for_pos(x, y, width, height, anchor_z, tag_z);
+            let mut p2 = generate_uwb_update_with(x, y, &anchors, anchor_z, tag_z);
             // Apply perturbations and convert to centimeters
             if let Some(payload) = p2.get_mut("payload") {
                 if let Some(arr) = payload.get_mut("beacons").and_then(|b| b.as_array_mut()) {
@@ -232,7 +262,7 @@ async fn mock_stream(query: web::Query<HashMap<String, String>>) -> Result<HttpR
 // Single-shot mock endpoint: emits one `uwb_update` payload (distances in cm)
 /// Single-shot mock endpoint returning one synthetic `uwb_update` (or an SSE block if `?sse=1`).
 #[get("/mock/once")]
-async fn mock_once(query: web::Query<HashMap<String, String>>) -> Result<HttpResponse, Error> {
+async fn mock_once(query: web::Query<HashMap<String, String>>, registry: web::Data<AnchorRegistry>) -> Result<HttpResponse, Error> {
     let width = query.get("w").and_then(|s| s.parse::<f64>().ok()).unwrap_or(20.0);
     let height = query.get("h").and_then(|s| s.parse::<f64>().ok()).unwrap_or(10.0);
     let cx = width/2.0; let cy = height/2.0;
@@ -249,7 +279,7 @@ async fn mock_once(query: web::Query<HashMap<String, String>>) -> Result<HttpRes
     let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as f64;
     let t_sec = now_ms / 1000.0;
     let tag_z = if tz_amp > 0.0 && tz_hz > 0.0 { tz_base + tz_amp * (std::f64::consts::TAU * tz_hz * t_sec).sin() } else { tz_base };
-    let mut p = generate_uwb_update_for_pos(cx, cy, width, height, anchor_z, tag_z);
+    let p = generate_uwb_update_with(cx, cy, &mock_anchors(&registry, width, height, anchor_z), anchor_z, tag_z);
     // Convert distances to centimeters to match the live stream format
     let mut p2 = p.clone();
     if let Some(payload) = p2.get_mut("payload") {
@@ -320,7 +350,7 @@ async fn proxy_uwb_stream() -> Result<HttpResponse, Error> {
     let upstream = resp.bytes_stream();
     let s = upstream.map(|chunk_res| {
         match chunk_res {
-            Ok(bytes) => Ok::<Bytes, Error>(Bytes::from(bytes)),
+            Ok(bytes) => Ok::<Bytes, Error>(bytes),
             Err(e) => { log::error!("upstream chunk error: {:?}", e); Err(actix_web::error::ErrorBadGateway("upstream error")) }
         }
     });
@@ -361,7 +391,7 @@ mod tests {
         assert_eq!(v.get("type").and_then(|t| t.as_str()), Some("uwb_update"));
         // payload must contain beacons array
         let beacons = v.get("payload").and_then(|p| p.get("beacons")).and_then(|b| b.as_array()).expect("beacons array expected");
-        assert!(beacons.len() >= 1);
+        assert!(!beacons.is_empty());
         // each beacon must have beaconId and distance
         for b in beacons {
             assert!(b.get("beaconId").is_some());
@@ -392,7 +422,7 @@ mod tests {
             let dist_cm_opt = if d.is_i64() { d.as_i64() } else if d.is_f64() { Some(d.as_f64().unwrap().round() as i64) } else { None };
             let dist_cm = dist_cm_opt.expect("distance numeric") ;
             // reasonable bounds for factory distances in centimeters
-            assert!(dist_cm >= 0 && dist_cm <= 10000, "distance out of range: {}", dist_cm);
+            assert!((0..=10000).contains(&dist_cm), "distance out of range: {}", dist_cm);
         }
     }
 }
@@ -403,12 +433,18 @@ async fn main() -> std::io::Result<()> {
     use tracing_subscriber::{EnvFilter, fmt};
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     fmt().with_env_filter(filter).init();
+    // Every setting, validated up front: a bad value stops the server here rather than at first use
+    let config = Config::load().unwrap_or_else(|e| {
+        tracing::error!(code = e.code(), error = %e, "configuration invalid; refusing to start");
+        std::process::exit(1)
+    });
+    config.log_summary();
     // Prometheus exporter binding (on separate port) can be optionally started; environment toggle could be added.
     use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
     use once_cell::sync::Lazy;
     static PROM_HANDLE: Lazy<PrometheusHandle> = Lazy::new(|| PrometheusBuilder::new().install_recorder().expect("prometheus recorder install"));
-    // Optionally expose metrics endpoint if METRICS_PORT provided.
-    if let Ok(mp) = std::env::var("METRICS_PORT").and_then(|s| s.parse::<u16>().map_err(|_| std::env::VarError::NotPresent)) {
+    // Optionally expose metrics endpoint if server.metrics_port is set.
+    if let Some(mp) = config.server.metrics_port {
         let handle_clone = PROM_HANDLE.clone();
         std::thread::spawn(move || {
             let addr = format!("0.0.0.0:{}", mp);
@@ -425,15 +461,22 @@ async fn main() -> std::io::Result<()> {
             }
         });
     }
-    // Read runtime configuration from environment
-    let backend_port: u16 = env::var("BACKEND_PORT").ok()
-        .and_then(|s| s.parse::<u16>().ok())
-        .unwrap_or(8080);
-    let frontend_port_str = env::var("FRONTEND_PORT").unwrap_or_else(|_| "3000".to_string());
-    let use_remote_uwb = env::var("USE_REMOTE_UWB").map(|s| s=="1" || s.eq_ignore_ascii_case("true")).unwrap_or(false);
+    let (backend_port, use_remote_uwb) = (config.server.port, config.server.use_remote_uwb);
 
-    // Broadcast channel for local UWB ingestion -> SSE
-    let (tx, _rx) = tokio::sync::broadcast::channel::<String>(256);
+    // Event bus (ids + replay ring) for local UWB ingestion -> SSE
+    let events = web::Data::new(EventBus::new(&config.stream));
+    // Key material: no keys are compiled in, so there is nothing to decode with when this fails
+    let secrets = config.load_secrets().unwrap_or_else(|e| {
+        tracing::error!(code = e.code(), error = %e, "secrets not loaded; refusing to start");
+        std::process::exit(1)
+    });
+    secrets.log_summary("secrets loaded");
+    // Uplink freshness / replay cache + per-device sequence state, shared by all workers
+    let ingest_state = web::Data::new(IngestState::new(&config, &secrets));
+    let reload_state = ingest_state.clone();
+    let sources = config.secrets.sources(config.profile);
+    actix_web::rt::spawn(secrets::watch(secrets, sources, config.secrets.poll_ms, move |s| reload_state.keys.set_secrets(s)));
+    let config = web::Data::new(config);
 
     HttpServer::new(move || {
         // For demos, allow origins dynamically to avoid accidental 400 CORS errors
@@ -457,13 +500,14 @@ async fn main() -> std::io::Result<()> {
             .service(positions)
             .service(mock_stream)
             .service(mock_once)
+            .configure(|cfg| anchor_registry::config(cfg, ingest_state.clone()))
             ;
 
         // Conditional registration: remote proxy OR local ingestion (LoRaWAN decode + SSE)
         if use_remote_uwb {
             app.service(proxy_uwb_stream)
         } else {
-            app.configure(|cfg| lorawan_stream::config(cfg, tx.clone()))
+            app.configure(|cfg| lorawan_stream::config(cfg, events.clone(), ingest_state.clone(), config.clone()))
         }
     })
     .bind(("0.0.0.0", backend_port))?
@@ -0,0 +1,237 @@
+//! NLOS / outlier screening of ranges before and during the solve.
+//!
+//! Metal racks cause multipath: a beacon's range comes back too long (occasionally near zero) while
+//! the others are fine. `RangeScreen::locate` removes such ranges in four steps and reports each one:
+//!
+//! 1. `unknown_anchor`: the beacon is not in the anchor registry.
+//! 2. `too_short`: below `POSITION_MIN_RANGE_M` (default 0.1) or, in 2D, shorter than the height
+//!    difference between anchor and tag allows.
+//! 3. `range_rate`: differs from the range predicted from the device's previous fix by more than the tag
+//!    could have moved (`POSITION_MAX_SPEED_MPS` × dt + `POSITION_RATE_MARGIN_M`). Skipped when the
+//!    previous fix is older than `POSITION_RATE_WINDOW_MS` or gating would leave too few anchors.
+//! 4. `residual`: while the fix residual is above `POSITION_RESIDUAL_GATE_M` (default 0.5), leave each
+//!    range out in turn and drop the one whose removal lowers the residual most, as long as enough
+//!    anchors remain and the improvement is clear.
+//!
+//! A slowly learned per-anchor bias (EWMA of `range - fitted range` over clean fixes, weight
+//! `POSITION_BIAS_ALPHA`, clamped to `POSITION_MAX_BIAS_M`) is subtracted from every range first.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::positioning::{solve, Anchor, Position, PositionError, Range, SolverMode, SolverOptions, MIN_ANCHORS_2D};
+
+/// `[outlier]` in `Config`; environment names in the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierOptions {
+    pub min_range_m: f64,
+    pub residual_gate_m: f64,
+    pub max_speed_mps: f64,
+    pub rate_margin_m: f64,
+    pub rate_window_ms: u64,
+    /// EWMA weight of the per-anchor bias; 0 disables bias correction.
+    pub bias_alpha: f64,
+    pub max_bias_m: f64,
+}
+
+impl Default for OutlierOptions {
+    fn default() -> Self {
+        OutlierOptions { min_range_m: 0.1, residual_gate_m: 0.5, max_speed_mps: 3.0, rate_margin_m: 1.0, rate_window_ms: 10_000, bias_alpha: 0.02, max_bias_m: 1.0 }
+    }
+}
+
+/// Why a range was left out of the solve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    UnknownAnchor,
+    TooShort,
+    RangeRate,
+    Residual,
+}
+
+impl RejectReason {
+    /// Stable identifier used as metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RejectReason::UnknownAnchor => "unknown_anchor",
+            RejectReason::TooShort => "too_short",
+            RejectReason::RangeRate => "range_rate",
+            RejectReason::Residual => "residual",
+        }
+    }
+}
+
+/// One rejected range, attached to `uwb_update` as `payload.rejectedBeacons[]`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedRange {
+    pub beacon_id: String,
+    /// Measured range (m), before bias correction.
+    pub distance_m: f64,
+    pub reason: RejectReason,
+    /// Range the rest of the data implies (previous fix for `range_rate`, final fix for `residual`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_m: Option<f64>,
+}
+
+/// Result of `RangeScreen::locate`: the fix (if any) and every range that was dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Located {
+    pub position: Result<Position, PositionError>,
+    pub rejected: Vec<RejectedRange>,
+}
+
+/// Per-anchor bias and per-device last fix, shared by all workers.
+pub struct RangeScreen {
+    opts: OutlierOptions,
+    biases: Mutex<HashMap<String, f64>>,
+    last_fix: Mutex<HashMap<String, ([f64; 3], u128)>>,
+}
+
+impl RangeScreen {
+    pub fn new(opts: OutlierOptions) -> Self {
+        RangeScreen { opts, biases: Mutex::new(HashMap::new()), last_fix: Mutex::new(HashMap::new()) }
+    }
+
+    /// Current bias estimate per anchor (m, positive = ranges read long).
+    pub fn biases(&self) -> HashMap<String, f64> {
+        self.biases.lock().unwrap_or_else(|p| p.into_inner()).clone()
+    }
+
+    /// Screen `ranges` of `device` measured at `timestamp_ms`, then solve.
+    pub fn locate(&self, device: &str, anchors: &[Anchor], ranges: &[Range], solver: &SolverOptions, timestamp_ms: u128) -> Located {
+        let o = &self.opts;
+        let mut rejected = Vec::new();
+        let reject = |rejected: &mut Vec<RejectedRange>, r: &Range, reason, expected_m| {
+            rejected.push(RejectedRange { beacon_id: r.beacon_id.clone(), distance_m: r.distance_m, reason, expected_m });
+        };
+        let biases = self.biases();
+        let find = |id: &str| anchors.iter().find(|a| a.beacon_id.eq_ignore_ascii_case(id));
+
+        // 1 + 2: unknown anchors and impossible short ranges; bias-correct the rest
+        let mut kept: Vec<(Range, &Anchor)> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            let Some(a) = find(&r.beacon_id) else {
+                reject(&mut rejected, r, RejectReason::UnknownAnchor, None);
+                continue;
+            };
+            let floor = if solver.mode == SolverMode::ThreeD { o.min_range_m } else { o.min_range_m.max((a.z - solver.tag_height_m).abs() - o.rate_margin_m) };
+            if r.distance_m < floor {
+                reject(&mut rejected, r, RejectReason::TooShort, None);
+                continue;
+            }
+            let bias = biases.get(&a.beacon_id).copied().unwrap_or(0.0);
+            kept.push((Range { beacon_id: r.beacon_id.clone(), distance_m: (r.distance_m - bias).max(0.0) }, a));
+        }
+
+        // 3: range-rate gate against the previous fix
+        let previous = self.last_fix.lock().unwrap_or_else(|p| p.into_inner()).get(device).copied();
+        if let Some((p, at)) = previous.filter(|(_, at)| timestamp_ms >= *at && timestamp_ms - at <= u128::from(o.rate_window_ms)) {
+            let allowed = o.max_speed_mps * (timestamp_ms - at) as f64 / 1000.0 + o.rate_margin_m;
+            let expected = |a: &Anchor| ((p[0] - a.x).powi(2) + (p[1] - a.y).powi(2) + (p[2] - a.z).powi(2)).sqrt();
+            let passing = kept.iter().filter(|(r, a)| (r.distance_m - expected(a)).abs() <= allowed).count();
+            if passing >= MIN_ANCHORS_2D && passing < kept.len() {
+                kept.retain(|(r, a)| {
+                    let e = expected(a);
+                    let ok = (r.distance_m - e).abs() <= allowed;
+                    if !ok { reject(&mut rejected, &original(ranges, &r.beacon_id), RejectReason::RangeRate, Some(e)); }
+                    ok
+                });
+            }
+        }
+
+        // 4: leave-one-out on the residual
+        let mut used: Vec<Range> = kept.iter().map(|(r, _)| r.clone()).collect();
+        let mut position = solve(anchors, &used, solver);
+        while let Ok(pos) = &position {
+            if pos.residual <= o.residual_gate_m || used.len() <= MIN_ANCHORS_2D { break; }
+            let best = (0..used.len())
+                .filter_map(|i| {
+                    let mut subset = used.clone();
+                    subset.remove(i);
+                    solve(anchors, &subset, solver).ok().map(|p| (i, p))
+                })
+                .min_by(|a, b| a.1.residual.total_cmp(&b.1.residual));
+            // Only drop a range when it clearly carries the error, not when all are noisy
+            let Some((i, fix)) = best.filter(|(_, fix)| fix.residual < pos.residual * 0.5) else { break };
+            let dropped = used.remove(i);
+            let expected = find(&dropped.beacon_id).map(|a| ((fix.x - a.x).powi(2) + (fix.y - a.y).powi(2) + (fix.z - a.z).powi(2)).sqrt());
+            reject(&mut rejected, &original(ranges, &dropped.beacon_id), RejectReason::Residual, expected);
+            position = Ok(fix);
+        }
+
+        if let Ok(pos) = &position {
+            if pos.residual <= o.residual_gate_m {
+                self.learn(pos, anchors, ranges, &used);
+                self.last_fix.lock().unwrap_or_else(|p| p.into_inner()).insert(device.to_string(), ([pos.x, pos.y, pos.z], timestamp_ms));
+            }
+        }
+        Located { position, rejected }
+    }
+
+    /// Move each used anchor's bias towards its raw range error at the accepted fix.
+    fn learn(&self, pos: &Position, anchors: &[Anchor], raw: &[Range], used: &[Range]) {
+        if self.opts.bias_alpha <= 0.0 { return; }
+        let mut biases = self.biases.lock().unwrap_or_else(|p| p.into_inner());
+        for r in used {
+            let Some(a) = anchors.iter().find(|a| a.beacon_id.eq_ignore_ascii_case(&r.beacon_id)) else { continue };
+            let fitted = ((pos.x - a.x).powi(2) + (pos.y - a.y).powi(2) + (pos.z - a.z).powi(2)).sqrt();
+            let error = original(raw, &r.beacon_id).distance_m - fitted;
+            let b = biases.entry(a.beacon_id.clone()).or_insert(0.0);
+            *b = (*b + self.opts.bias_alpha * (error - *b)).clamp(-self.opts.max_bias_m, self.opts.max_bias_m);
+        }
+    }
+}
+
+/// The measured (uncorrected) range for `beacon_id`.
+fn original(ranges: &[Range], beacon_id: &str) -> Range {
+    ranges.iter().find(|r| r.beacon_id == beacon_id).cloned()
+        .unwrap_or_else(|| Range { beacon_id: beacon_id.to_string(), distance_m: f64::NAN })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchors() -> Vec<Anchor> {
+        [("020000b3", 0.0, 0.0), ("02000053", 20.0, 0.0), ("020000e6", 0.0, 10.0), ("020000f1", 20.0, 10.0), ("020000a0", 10.0, 0.0)]
+            .iter().map(|&(id, x, y)| Anchor { beacon_id: id.into(), x, y, z: 1.5 }).collect()
+    }
+
+    fn ranges_to(anchors: &[Anchor], p: [f64; 3]) -> Vec<Range> {
+        anchors.iter().map(|a| Range { beacon_id: a.beacon_id.clone(), distance_m: ((p[0] - a.x).powi(2) + (p[1] - a.y).powi(2) + (p[2] - a.z).powi(2)).sqrt() }).collect()
+    }
+
+    fn solver() -> SolverOptions { SolverOptions { mode: SolverMode::TwoD, tag_height_m: 1.0 } }
+
+    #[test]
+    fn drops_multipath_spike_and_reports_it() {
+        let screen = RangeScreen::new(OutlierOptions::default());
+        let a = anchors();
+        let mut ranges = ranges_to(&a, [7.0, 4.0, 1.0]);
+        ranges[1].distance_m *= 1.8;
+        ranges.push(Range { beacon_id: "0200ffff".into(), distance_m: 3.0 });
+        let out = screen.locate("tag", &a, &ranges, &solver(), 1_000);
+        let pos = out.position.unwrap();
+        assert!((pos.x - 7.0).abs() < 0.05 && (pos.y - 4.0).abs() < 0.05, "{pos:?}");
+        let reasons: Vec<_> = out.rejected.iter().map(|r| (r.beacon_id.as_str(), r.reason)).collect();
+        assert_eq!(reasons, vec![("0200ffff", RejectReason::UnknownAnchor), ("02000053", RejectReason::Residual)]);
+        assert!((out.rejected[1].expected_m.unwrap() - ranges[1].distance_m / 1.8).abs() < 0.05);
+    }
+
+    #[test]
+    fn gates_near_zero_and_range_jumps() {
+        let screen = RangeScreen::new(OutlierOptions::default());
+        let a = anchors();
+        assert!(screen.locate("tag", &a, &ranges_to(&a, [7.0, 4.0, 1.0]), &solver(), 1_000).rejected.is_empty());
+        let mut ranges = ranges_to(&a, [7.2, 4.0, 1.0]);
+        ranges[0].distance_m = 0.03;
+        ranges[2].distance_m += 6.0;
+        let out = screen.locate("tag", &a, &ranges, &solver(), 2_000);
+        let reasons: Vec<_> = out.rejected.iter().map(|r| (r.beacon_id.as_str(), r.reason)).collect();
+        assert_eq!(reasons, vec![("020000b3", RejectReason::TooShort), ("020000e6", RejectReason::RangeRate)]);
+        let pos = out.position.unwrap();
+        assert!((pos.x - 7.2).abs() < 0.05, "{pos:?}");
+    }
+}
@@ -0,0 +1,383 @@
+//! Server-side trilateration: beacon ranges of a 0x05 report -> tag position in meters.
+//!
+//! Solver:
+//! 1. Match each ranged beacon (`major||minor` hex, the `beaconId` of `uwb_update`) to a known anchor.
+//! 2. Linear least squares initial guess (subtract the first anchor's sphere equation from the others,
+//!    same formulation as `frontend/src/triangulation.js`).
+//! 3. Levenberg-Marquardt refinement of `sum(|p - a_i| - d_i)^2`, which uses the true slant ranges and
+//!    per-anchor heights instead of the linearized system.
+//!
+//! 2D mode solves `(x, y)` with the tag height fixed (`TAG_HEIGHT_M`); 3D mode also solves `z` and needs
+//! at least 4 anchors that are not all at the same height. `Auto` picks 3D only when that holds.
+//!
+//! Ingestion goes through `Positioner::locate`, which screens out NLOS / outlier ranges first (see `outlier`).
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::anchor_registry::AnchorRegistry;
+use crate::config::Config;
+use crate::lorawan_codec::LocationReport;
+use crate::outlier::{Located, RangeScreen};
+
+/// Fewest anchors for a 2D / 3D fix.
+pub const MIN_ANCHORS_2D: usize = 3;
+pub const MIN_ANCHORS_3D: usize = 4;
+/// Height spread (m) below which anchors are treated as coplanar and `Auto` stays in 2D.
+pub const MIN_Z_SPREAD_M: f64 = 0.5;
+
+/// Anchor (fixed beacon) position in meters.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Anchor {
+    /// `major||minor` as 8 lower-case hex chars.
+    pub beacon_id: String,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Anchor {
+    /// Parse `beaconId:x:y[:z]` (z defaults to `default_z`).
+    pub fn parse_spec(spec: &str, default_z: f64) -> Option<Anchor> {
+        let parts: Vec<&str> = spec.trim().split(':').collect();
+        if parts.len() < 3 || parts[0].len() != 8 || u32::from_str_radix(parts[0], 16).is_err() { return None; }
+        Some(Anchor {
+            beacon_id: parts[0].to_ascii_lowercase(),
+            x: parts[1].parse().ok()?,
+            y: parts[2].parse().ok()?,
+            z: parts.get(3).map_or(Some(default_z), |z| z.parse().ok())?,
+        })
+    }
+
+    /// Comma-separated `parse_spec` entries; the first bad entry is returned as the error.
+    pub fn parse_list(list: &str, default_z: f64) -> Result<Vec<Anchor>, String> {
+        list.split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| Anchor::parse_spec(s, default_z).ok_or_else(|| s.trim().to_string()))
+            .collect()
+    }
+}
+
+/// One measured range, meters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub beacon_id: String,
+    pub distance_m: f64,
+}
+
+/// Ranges of a location report (distances are reported in centimeters).
+pub fn ranges_from_report(report: &LocationReport) -> Vec<Range> {
+    report.beacons.iter()
+        .map(|b| Range { beacon_id: b.beacon_id(), distance_m: f64::from(b.distance_cm) / 100.0 })
+        .collect()
+}
+
+/// Which unknowns the solver estimates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SolverMode {
+    /// 3D when the anchors allow it, else 2D.
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "2d")]
+    TwoD,
+    #[serde(rename = "3d")]
+    ThreeD,
+}
+
+impl SolverMode {
+    pub fn as_str(self) -> &'static str {
+        match self { SolverMode::Auto => "auto", SolverMode::TwoD => "2d", SolverMode::ThreeD => "3d" }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SolverOptions {
+    pub mode: SolverMode,
+    /// Tag height (m) assumed in 2D mode.
+    pub tag_height_m: f64,
+}
+
+impl Default for SolverOptions {
+    fn default() -> Self { SolverOptions { mode: SolverMode::Auto, tag_height_m: 1.0 } }
+}
+
+/// Solved position attached to `uwb_update` as `payload.position`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+    /// Solved in 3D mode, the configured tag height in 2D mode.
+    pub z: f64,
+    /// RMS of `|p - anchor| - range` over the anchors used, meters.
+    pub residual: f64,
+    pub anchors_used: usize,
+    /// `2d` or `3d` (never `auto`).
+    pub mode: SolverMode,
+}
+
+/// Why no position could be produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PositionError {
+    /// Fewer ranged beacons with a known anchor than a 2D fix needs.
+    TooFewAnchors { used: usize },
+    /// Solver did not produce a finite position.
+    Degenerate,
+}
+
+impl PositionError {
+    /// Stable identifier used as metric label.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PositionError::TooFewAnchors { .. } => "too_few_anchors",
+            PositionError::Degenerate => "degenerate",
+        }
+    }
+}
+
+impl std::fmt::Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionError::TooFewAnchors { used } => write!(f, "only {used} ranged beacon(s) with a known anchor"),
+            PositionError::Degenerate => f.write_str("solver did not converge to a finite position"),
+        }
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+/// Anchor registry + outlier screening + solver options used by ingestion.
+pub struct Positioner {
+    pub anchors: Arc<AnchorRegistry>,
+    pub screen: RangeScreen,
+    pub opts: SolverOptions,
+}
+
+impl Positioner {
+    /// Anchors from the registry (`positioning.anchors_file`, `ANCHORS_FILE`), seeded on first start from
+    /// `positioning.anchors` (`ANCHORS`: `beaconId:x:y[:z],...`, z defaulting to `ANCHOR_HEIGHT_M`, 1.5);
+    /// solver options from `[positioning]`, screening from `[outlier]`.
+    pub fn from_config(config: &Config) -> Self {
+        let registry = AnchorRegistry::open_or_in_memory(&config.positioning.anchors_file, &config.positioning.seed());
+        Positioner { anchors: Arc::new(registry), screen: RangeScreen::new(config.outlier), opts: config.positioning.solver() }
+    }
+
+    /// Screen and solve against the registry as it is right now, so anchor edits apply to the next report.
+    /// `device` keys the range-rate gate (`deviceIdHex`); `timestamp_ms` is when the ranges were taken.
+    pub fn locate(&self, device: &str, report: &LocationReport, timestamp_ms: u128) -> Located {
+        let anchors = self.anchors.anchors();
+        // Positioning not configured: nothing to screen, and every beacon would read as unknown
+        if anchors.is_empty() {
+            return Located { position: Err(PositionError::TooFewAnchors { used: 0 }), rejected: Vec::new() };
+        }
+        self.screen.locate(device, &anchors, &ranges_from_report(report), &self.opts, timestamp_ms)
+    }
+
+    /// Registry floor of the closest ranged beacon that is a known anchor: where the tag is, for
+    /// floor-filtered stream clients, even when no position can be solved.
+    pub fn floor(&self, report: &LocationReport) -> Option<i32> {
+        let mut beacons: Vec<_> = report.beacons.iter().filter(|b| b.distance_cm > 0).collect();
+        beacons.sort_by_key(|b| b.distance_cm);
+        beacons.iter().find_map(|b| self.anchors.get(&b.beacon_id()).ok()).map(|a| a.floor)
+    }
+}
+
+/// Solve the tag position from `ranges` against `anchors`.
+pub fn solve(anchors: &[Anchor], ranges: &[Range], opts: &SolverOptions) -> Result<Position, PositionError> {
+    let mut used: Vec<(&Anchor, f64)> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        if !r.distance_m.is_finite() || r.distance_m < 0.0 { continue; }
+        if let Some(a) = anchors.iter().find(|a| a.beacon_id.eq_ignore_ascii_case(&r.beacon_id)) {
+            if !used.iter().any(|(u, _)| u.beacon_id == a.beacon_id) {
+                used.push((a, r.distance_m));
+            }
+        }
+    }
+    if used.len() < MIN_ANCHORS_2D {
+        return Err(PositionError::TooFewAnchors { used: used.len() });
+    }
+    let z_spread = used.iter().map(|(a, _)| a.z).fold(f64::NEG_INFINITY, f64::max)
+        - used.iter().map(|(a, _)| a.z).fold(f64::INFINITY, f64::min);
+    let three_d = used.len() >= MIN_ANCHORS_3D && match opts.mode {
+        SolverMode::TwoD => false,
+        SolverMode::ThreeD => true,
+        SolverMode::Auto => z_spread >= MIN_Z_SPREAD_M,
+    };
+    let tz = opts.tag_height_m;
+    let (x, y, z) = if three_d {
+        let init = linear_3d(&used).unwrap_or_else(|| { let (x, y) = centroid(&used); [x, y, tz] });
+        let p = levenberg_marquardt(init, &used, |p| [p[0], p[1], p[2]]);
+        (p[0], p[1], p[2])
+    } else {
+        let init = linear_2d(&used, tz).unwrap_or_else(|| { let (x, y) = centroid(&used); [x, y] });
+        let p = levenberg_marquardt(init, &used, |p| [p[0], p[1], tz]);
+        (p[0], p[1], tz)
+    };
+    if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+        return Err(PositionError::Degenerate);
+    }
+    let residual = rms(&used, [x, y, z]);
+    Ok(Position { x, y, z, residual, anchors_used: used.len(), mode: if three_d { SolverMode::ThreeD } else { SolverMode::TwoD } })
+}
+
+/// Anchors matched to a range, paired with the measured distance.
+type Used<'a> = [(&'a Anchor, f64)];
+
+fn centroid(used: &Used) -> (f64, f64) {
+    let n = used.len() as f64;
+    (used.iter().map(|(a, _)| a.x).sum::<f64>() / n, used.iter().map(|(a, _)| a.y).sum::<f64>() / n)
+}
+
+fn dist(p: [f64; 3], a: &Anchor) -> f64 {
+    ((p[0] - a.x).powi(2) + (p[1] - a.y).powi(2) + (p[2] - a.z).powi(2)).sqrt()
+}
+
+fn rms(used: &Used, p: [f64; 3]) -> f64 {
+    (used.iter().map(|(a, d)| (dist(p, a) - d).powi(2)).sum::<f64>() / used.len() as f64).sqrt()
+}
+
+/// Linearized 2D system on horizontal ranges (slant range with the height difference removed).
+fn linear_2d(used: &Used, tz: f64) -> Option<[f64; 2]> {
+    let h = |a: &Anchor, d: f64| (d * d - (tz - a.z).powi(2)).max(0.0);
+    let (a0, d0) = used[0];
+    let h0 = h(a0, d0);
+    let mut ata = [[0.0; 2]; 2];
+    let mut atb = [0.0; 2];
+    for &(ai, di) in &used[1..] {
+        let row = [2.0 * (ai.x - a0.x), 2.0 * (ai.y - a0.y)];
+        let b = (ai.x * ai.x - a0.x * a0.x) + (ai.y * ai.y - a0.y * a0.y) + h0 - h(ai, di);
+        accumulate(&mut ata, &mut atb, row, b);
+    }
+    solve_linear(ata, atb)
+}
+
+fn linear_3d(used: &Used) -> Option<[f64; 3]> {
+    let (a0, d0) = used[0];
+    let mut ata = [[0.0; 3]; 3];
+    let mut atb = [0.0; 3];
+    for &(ai, di) in &used[1..] {
+        let row = [2.0 * (ai.x - a0.x), 2.0 * (ai.y - a0.y), 2.0 * (ai.z - a0.z)];
+        let b = (ai.x * ai.x - a0.x * a0.x) + (ai.y * ai.y - a0.y * a0.y) + (ai.z * ai.z - a0.z * a0.z) + d0 * d0 - di * di;
+        accumulate(&mut ata, &mut atb, row, b);
+    }
+    solve_linear(ata, atb)
+}
+
+fn accumulate<const N: usize>(ata: &mut [[f64; N]; N], atb: &mut [f64; N], row: [f64; N], b: f64) {
+    for i in 0..N {
+        for j in 0..N { ata[i][j] += row[i] * row[j]; }
+        atb[i] += row[i] * b;
+    }
+}
+
+/// Gaussian elimination with partial pivoting; `None` when (near) singular.
+fn solve_linear<const N: usize>(mut m: [[f64; N]; N], mut v: [f64; N]) -> Option<[f64; N]> {
+    let scale = m.iter().flatten().fold(0.0f64, |acc, x| acc.max(x.abs())).max(1.0);
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-9 * scale { return None; }
+        m.swap(col, pivot);
+        v.swap(col, pivot);
+        let (top, bottom) = m.split_at_mut(col + 1);
+        let pivot_row = &top[col];
+        for (offset, r) in bottom.iter_mut().enumerate() {
+            let f = r[col] / pivot_row[col];
+            for (x, p) in r[col..].iter_mut().zip(&pivot_row[col..]) { *x -= f * p; }
+            v[col + 1 + offset] -= f * v[col];
+        }
+    }
+    let mut x = [0.0; N];
+    for i in (0..N).rev() {
+        let s: f64 = (i + 1..N).map(|k| m[i][k] * x[k]).sum();
+        x[i] = (v[i] - s) / m[i][i];
+    }
+    Some(x)
+}
+
+/// Levenberg-Marquardt on the range residuals; `point` maps the N unknowns to a 3D point.
+fn levenberg_marquardt<const N: usize>(init: [f64; N], used: &Used, point: impl Fn(&[f64; N]) -> [f64; 3]) -> [f64; N] {
+    let cost = |p: &[f64; N]| used.iter().map(|(a, d)| (dist(point(p), a) - d).powi(2)).sum::<f64>();
+    let mut p = init;
+    let mut c = cost(&p);
+    let mut lambda = 1e-3;
+    for _ in 0..100 {
+        let mut jtj = [[0.0; N]; N];
+        let mut jtr = [0.0; N];
+        for (a, d) in used {
+            let q = point(&p);
+            let r = dist(q, a);
+            // gradient of |q - a| w.r.t. the free coordinates; zero at the anchor itself
+            let mut row = [0.0; N];
+            if r > 1e-9 {
+                let g = [(q[0] - a.x) / r, (q[1] - a.y) / r, (q[2] - a.z) / r];
+                row.copy_from_slice(&g[..N]);
+            }
+            accumulate(&mut jtj, &mut jtr, row, r - d);
+        }
+        let mut damped = jtj;
+        for (i, row) in damped.iter_mut().enumerate() { row[i] += lambda * jtj[i][i].max(1e-9); }
+        let Some(step) = solve_linear(damped, jtr) else { break };
+        let mut next = p;
+        for i in 0..N { next[i] -= step[i]; }
+        let nc = cost(&next);
+        if nc < c {
+            p = next;
+            c = nc;
+            lambda = (lambda / 10.0).max(1e-12);
+            if step.iter().map(|s| s * s).sum::<f64>().sqrt() < 1e-7 { break; }
+        } else {
+            lambda *= 10.0;
+            if lambda > 1e12 { break; }
+        }
+    }
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchor(id: &str, x: f64, y: f64, z: f64) -> Anchor { Anchor { beacon_id: id.into(), x, y, z } }
+
+    fn ranges_to(anchors: &[Anchor], p: [f64; 3]) -> Vec<Range> {
+        anchors.iter().map(|a| Range { beacon_id: a.beacon_id.clone(), distance_m: dist(p, a) }).collect()
+    }
+
+    #[test]
+    fn solves_2d_with_slant_ranges() {
+        let anchors = vec![anchor("020000b3", 0.0, 0.0, 1.5), anchor("02000053", 20.0, 0.0, 1.5), anchor("020000e6", 0.0, 10.0, 1.5)];
+        let opts = SolverOptions { mode: SolverMode::Auto, tag_height_m: 1.0 };
+        let pos = solve(&anchors, &ranges_to(&anchors, [7.0, 3.0, 1.0]), &opts).unwrap();
+        assert_eq!((pos.mode, pos.anchors_used), (SolverMode::TwoD, 3));
+        assert!((pos.x - 7.0).abs() < 1e-6 && (pos.y - 3.0).abs() < 1e-6, "{pos:?}");
+        assert!(pos.residual < 1e-6);
+    }
+
+    #[test]
+    fn solves_3d_when_anchor_heights_differ() {
+        let anchors = vec![
+            anchor("00010001", 0.0, 0.0, 3.0), anchor("00010002", 10.0, 0.0, 0.5),
+            anchor("00010003", 0.0, 8.0, 0.5), anchor("00010004", 10.0, 8.0, 3.0),
+        ];
+        let mut ranges = ranges_to(&anchors, [4.0, 5.0, 1.2]);
+        ranges[0].distance_m += 0.05; // a little noise: LM still lands close, with a non-zero residual
+        let pos = solve(&anchors, &ranges, &SolverOptions::default()).unwrap();
+        assert_eq!(pos.mode, SolverMode::ThreeD);
+        assert!((pos.x - 4.0).abs() < 0.2 && (pos.y - 5.0).abs() < 0.2 && (pos.z - 1.2).abs() < 0.3, "{pos:?}");
+        assert!(pos.residual > 0.0 && pos.residual < 0.05);
+    }
+
+    #[test]
+    fn unknown_beacons_and_too_few_anchors() {
+        let anchors = vec![anchor("020000b3", 0.0, 0.0, 1.5), anchor("02000053", 20.0, 0.0, 1.5)];
+        let mut ranges = ranges_to(&anchors, [5.0, 5.0, 1.0]);
+        ranges.push(Range { beacon_id: "ffffffff".into(), distance_m: 3.0 });
+        assert_eq!(solve(&anchors, &ranges, &SolverOptions::default()).unwrap_err(), PositionError::TooFewAnchors { used: 2 });
+    }
+
+    #[test]
+    fn parses_anchor_specs() {
+        let list = Anchor::parse_list("020000B3:0:0, 02000053:20:0:2.5", 1.5).unwrap();
+        assert_eq!(list, vec![anchor("020000b3", 0.0, 0.0, 1.5), anchor("02000053", 20.0, 0.0, 2.5)]);
+        assert_eq!(Anchor::parse_list("020000b3:0", 1.5).unwrap_err(), "020000b3:0");
+    }
+}
@@ -0,0 +1,359 @@
+//! Playback of stored history over SSE, for incident review and realistic demos.
+//!
+//! - `GET /replay/stream?from=&to=&speed=&device=`: replays the `event` of every history record in
+//!   `[from, to]` (epoch ms) with the same SSE framing as `/proxy/uwbStream`, spaced by their original
+//!   receive times divided by `speed` (`1x`, `10x`, any positive factor, or `max` for no waiting).
+//!   Gaps in the data are shortened to at most `MAX_IDLE_MS` of real time.
+//! - The stream opens with a `replay_status` event carrying the `session` id (also returned in the
+//!   `X-Replay-Session` header); `POST /replay/{session}` with `{ "paused": bool, "seek": ms, "speed": "10x" }`
+//!   (all optional) controls it. Every state change is echoed as another `replay_status` event, and the
+//!   stream ends with `state: "ended"`.
+use actix_web::{get, post, web, Error, HttpResponse, Responder};
+use async_stream::stream;
+use bytes::Bytes;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use crate::history::{DEFAULT_WINDOW_MS, MAX_QUERY_LIMIT};
+use crate::lorawan_stream::{now_ms, sse_block_from_value, IngestState};
+
+/// Longest real-time wait between two replayed events, whatever the gap in the data.
+pub const MAX_IDLE_MS: f64 = 5_000.0;
+/// History is loaded in windows of this many ms.
+const CHUNK_MS: u128 = 10 * 60 * 1000;
+
+/// Playback rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Data time advances `n` times faster than real time.
+    Factor(f64),
+    /// No waiting between events.
+    Max,
+}
+
+impl ReplaySpeed {
+    /// `max`, or a positive factor with optional `x` suffix (`1`, `10x`, `0.5x`).
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("max") { return Some(ReplaySpeed::Max); }
+        let f: f64 = s.strip_suffix(['x', 'X']).unwrap_or(s).parse().ok()?;
+        (f.is_finite() && f > 0.0).then_some(ReplaySpeed::Factor(f))
+    }
+
+    /// Real time to wait for `data_ms` of recorded time.
+    fn wait_ms(self, data_ms: u128) -> f64 {
+        match self {
+            ReplaySpeed::Max => 0.0,
+            ReplaySpeed::Factor(f) => (data_ms as f64 / f).min(MAX_IDLE_MS),
+        }
+    }
+}
+
+impl Serialize for ReplaySpeed {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ReplaySpeed::Max => s.serialize_str("max"),
+            ReplaySpeed::Factor(f) => s.serialize_str(&format!("{f}x")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PlayState { Playing, Paused, Ended }
+
+/// Shared between the stream and the control endpoint.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayStatus {
+    pub session: String,
+    pub state: PlayState,
+    pub speed: ReplaySpeed,
+    /// Recorded time reached so far, epoch ms.
+    pub position: u128,
+    pub from: u128,
+    pub to: u128,
+    #[serde(skip)]
+    seek: Option<u128>,
+}
+
+impl ReplayStatus {
+    fn event(&self) -> Value {
+        json!({ "type": "replay_status", "payload": self })
+    }
+}
+
+struct Session {
+    status: Mutex<ReplayStatus>,
+    changed: Notify,
+}
+
+impl Session {
+    fn status(&self) -> ReplayStatus { self.status.lock().unwrap_or_else(|p| p.into_inner()).clone() }
+
+    fn update(&self, f: impl FnOnce(&mut ReplayStatus)) -> ReplayStatus {
+        let mut s = self.status.lock().unwrap_or_else(|p| p.into_inner());
+        f(&mut s);
+        s.clone()
+    }
+}
+
+/// Open replay sessions by id.
+#[derive(Default)]
+pub struct ReplaySessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+}
+
+impl ReplaySessions {
+    pub fn new() -> Self { Self::default() }
+
+    fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap_or_else(|p| p.into_inner()).get(id).cloned()
+    }
+
+    pub fn len(&self) -> usize { self.sessions.lock().unwrap_or_else(|p| p.into_inner()).len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+/// Removes the session when the SSE stream is dropped (client gone or playback ended).
+struct SessionGuard {
+    state: web::Data<IngestState>,
+    id: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.replays.sessions.lock().unwrap_or_else(|p| p.into_inner()).remove(&self.id);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub speed: Option<String>,
+    pub device: Option<String>,
+}
+
+fn bad_request(code: &str, message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "code": code, "message": message }))
+}
+
+/// Stream a stored time window as SSE.
+#[get("/replay/stream")]
+pub async fn replay_stream(state: web::Data<IngestState>, q: web::Query<ReplayQuery>) -> Result<HttpResponse, Error> {
+    if !state.history.is_enabled() {
+        return Ok(HttpResponse::ServiceUnavailable().json(json!({ "code": "history_disabled", "message": "history.dir is empty (HISTORY_DIR)" })));
+    }
+    let now = now_ms();
+    let to = q.to.map_or(now, u128::from);
+    let from = q.from.map_or_else(|| to.saturating_sub(DEFAULT_WINDOW_MS), u128::from);
+    if from > to {
+        return Ok(bad_request("bad_range", format!("from ({from}) is after to ({to})")));
+    }
+    let speed = match q.speed.as_deref().map(ReplaySpeed::parse) {
+        None => ReplaySpeed::Factor(1.0),
+        Some(Some(s)) => s,
+        Some(None) => return Ok(bad_request("bad_speed", "speed must be max or a positive factor like 10x".into())),
+    };
+    let device = q.device.clone().filter(|d| !d.is_empty());
+    let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let session = Arc::new(Session {
+        status: Mutex::new(ReplayStatus { session: id.clone(), state: PlayState::Playing, speed, position: from, from, to, seek: None }),
+        changed: Notify::new(),
+    });
+    state.replays.sessions.lock().unwrap_or_else(|p| p.into_inner()).insert(id.clone(), session.clone());
+    let guard = SessionGuard { state: state.clone(), id: id.clone() };
+
+    let s = stream! {
+        let _guard = guard;
+        yield Ok::<Bytes, Error>(Bytes::from(sse_block_from_value(&session.status().event())));
+        let mut cursor = from;
+        let mut clock = from;
+        'chunks: while cursor <= to {
+            let chunk_end = cursor.saturating_add(CHUNK_MS - 1).min(to);
+            let st = state.clone();
+            let dev = device.clone();
+            let (batch, truncated) = match web::block(move || st.history.query(dev.as_deref(), cursor, chunk_end, MAX_QUERY_LIMIT)).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) => { yield Ok(Bytes::from(sse_block_from_value(&json!({"type":"replay_error","code":"storage_error","error":e.to_string()})))); break; }
+                Err(e) => { yield Ok(Bytes::from(sse_block_from_value(&json!({"type":"replay_error","code":"storage_error","error":e.to_string()})))); break; }
+            };
+            let next_cursor = if truncated { batch.last().map_or(chunk_end, |r| r.ts) + 1 } else { chunk_end + 1 };
+            for record in batch {
+                // Wait until the replay clock reaches this record, reacting to pause / seek / speed changes
+                let mut announced_pause = false;
+                loop {
+                    let status = session.status();
+                    if let Some(target) = status.seek {
+                        let status = session.update(|s| { s.seek = None; s.position = target; });
+                        yield Ok(Bytes::from(sse_block_from_value(&status.event())));
+                        cursor = target;
+                        clock = target;
+                        continue 'chunks;
+                    }
+                    if status.state == PlayState::Paused {
+                        if !announced_pause {
+                            yield Ok(Bytes::from(sse_block_from_value(&status.event())));
+                            announced_pause = true;
+                        }
+                        tokio::select! {
+                            _ = session.changed.notified() => {}
+                            _ = tokio::time::sleep(Duration::from_secs(15)) => { yield Ok(Bytes::from_static(b": ping\n\n")); }
+                        }
+                        continue;
+                    }
+                    if announced_pause {
+                        yield Ok(Bytes::from(sse_block_from_value(&status.event())));
+                        announced_pause = false;
+                    }
+                    let wait = status.speed.wait_ms(record.ts.saturating_sub(clock));
+                    if wait <= 0.0 { break; }
+                    let started = Instant::now();
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs_f64(wait / 1000.0)) => break,
+                        _ = session.changed.notified() => {
+                            // Control changed mid-wait: credit the recorded time already played at the old speed
+                            if let ReplaySpeed::Factor(f) = status.speed {
+                                clock = clock.saturating_add((started.elapsed().as_secs_f64() * 1000.0 * f) as u128).min(record.ts);
+                            }
+                        }
+                    }
+                }
+                clock = record.ts;
+                session.update(|s| s.position = record.ts);
+                if let Some(event) = &record.event {
+                    yield Ok(Bytes::from(sse_block_from_value(event)));
+                }
+            }
+            cursor = next_cursor;
+            clock = clock.max(cursor.min(to));
+        }
+        let status = session.update(|s| { s.state = PlayState::Ended; s.position = to; });
+        yield Ok(Bytes::from(sse_block_from_value(&status.event())));
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("Connection", "keep-alive"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .insert_header(("X-Replay-Session", id))
+        .streaming(s))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayControl {
+    pub paused: Option<bool>,
+    /// Jump to this recorded time (epoch ms, clamped to the session window).
+    pub seek: Option<u64>,
+    pub speed: Option<String>,
+}
+
+/// Pause / resume / seek / change speed of a running replay.
+#[post("/replay/{session}")]
+pub async fn control_replay(state: web::Data<IngestState>, path: web::Path<String>, body: web::Json<ReplayControl>) -> impl Responder {
+    let id = path.into_inner();
+    let Some(session) = state.replays.get(&id) else {
+        return HttpResponse::NotFound().json(json!({ "code": "unknown_session", "message": format!("no replay session {id}") }));
+    };
+    let speed = match body.speed.as_deref().map(ReplaySpeed::parse) {
+        None => None,
+        Some(Some(s)) => Some(s),
+        Some(None) => return bad_request("bad_speed", "speed must be max or a positive factor like 10x".into()),
+    };
+    let status = session.update(|s| {
+        if s.state == PlayState::Ended { return; }
+        if let Some(p) = body.paused { s.state = if p { PlayState::Paused } else { PlayState::Playing }; }
+        if let Some(sp) = speed { s.speed = sp; }
+        if let Some(t) = body.seek { s.seek = Some(u128::from(t).clamp(s.from, s.to)); }
+    });
+    session.changed.notify_one();
+    HttpResponse::Ok().json(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::test_support::{self, Server, DEVICE, DEV_EUI};
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(ReplaySpeed::parse("10x"), Some(ReplaySpeed::Factor(10.0)));
+        assert_eq!(ReplaySpeed::parse("1"), Some(ReplaySpeed::Factor(1.0)));
+        assert_eq!(ReplaySpeed::parse("MAX"), Some(ReplaySpeed::Max));
+        assert_eq!(ReplaySpeed::parse("0x"), None);
+        assert_eq!(ReplaySpeed::parse("fast"), None);
+        assert_eq!(ReplaySpeed::Factor(10.0).wait_ms(2_000), 200.0);
+        assert_eq!(ReplaySpeed::Factor(1.0).wait_ms(3_600_000), MAX_IDLE_MS);
+        assert_eq!(ReplaySpeed::Max.wait_ms(3_600_000), 0.0);
+    }
+
+    /// `type` of every event in an SSE body.
+    fn event_types(body: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(body).lines()
+            .filter_map(|l| l.strip_prefix("data: "))
+            .filter_map(|d| serde_json::from_str::<Value>(d).ok())
+            .map(|v| v["type"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn stream_plays_stored_events_and_ends() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let now = now_ms();
+        let body = test_support::uplink_body(DEV_EUI, 3, test_support::location_report(DEVICE), now);
+        call_service(&app, TestRequest::post().uri("/v1/uwb").set_json(&body).to_request()).await;
+
+        let uri = format!("/replay/stream?speed=max&from={}&to={}", now - 1_000, now + 60_000);
+        let res = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(event_types(&read_body(res).await), ["replay_status", "uwb_update", "replay_status"]);
+        assert!(server.state.replays.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn control_changes_an_open_session() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, TestRequest::get().uri("/replay/stream").to_request()).await;
+        let session = res.headers().get("x-replay-session").unwrap().to_str().unwrap().to_string();
+
+        let control = |body: Value| TestRequest::post().uri(&format!("/replay/{session}")).set_json(body).to_request();
+        let status: Value = call_and_read_body_json(&app, control(json!({ "paused": true, "speed": "10x" }))).await;
+        assert_eq!((&status["state"], &status["speed"]), (&json!("paused"), &json!("10x")));
+        let bad: Value = call_and_read_body_json(&app, control(json!({ "speed": "fast" }))).await;
+        assert_eq!(bad["code"], "bad_speed");
+        drop(res);
+        assert!(server.state.replays.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_queries_and_unknown_sessions() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        for (uri, code) in [("/replay/stream?from=2000&to=1000", "bad_range"), ("/replay/stream?speed=0x", "bad_speed")] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(read_body_json::<Value, _>(res).await["code"], code);
+        }
+        let unknown = TestRequest::post().uri("/replay/0123456789abcdef").set_json(json!({ "paused": true })).to_request();
+        assert_eq!(call_service(&app, unknown).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn stream_is_unavailable_without_history() {
+        let server = Server::new(|c| c.history.dir = String::new());
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, TestRequest::get().uri("/replay/stream").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
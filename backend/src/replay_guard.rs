@@ -0,0 +1,194 @@
+//! Freshness + replay protection for `/v1/uwb` uplinks.
+//!
+//! Each uplink carries `content.timestamp` (epoch ms). A frame is accepted only when that timestamp is
+//! within `ingest.ts_skew_ms` (`LORA_TS_SKEW_MS`) of the server clock (a missing timestamp is rejected),
+//! and only the first time a given `(device, message number, freshness)` tuple is seen. Freshness is the
+//! timestamp when the HMAC covers it; for payload-only signatures, where the timestamp can be swapped
+//! freely, it is a digest of the decrypted payload instead. Tuples are remembered for one window (from
+//! their timestamp, or from when a payload-only frame was first seen), so memory is bounded by uplink
+//! rate × window. A payload-only frame can therefore be replayed once its window has passed; only
+//! `decode.require_timestamp_hmac` closes that. A tuple seen again inside the window is not an error:
+//! `post_uwb` drops it as a duplicate (usually another gateway's copy).
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Default accepted clock difference between the uplink timestamp and the server (5 minutes).
+pub const DEFAULT_SKEW_MS: u128 = 5 * 60 * 1000;
+
+/// Why `ReplayGuard::check` refused an uplink.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayRejection {
+    /// No `content.timestamp` while the window is enabled.
+    MissingTimestamp,
+    /// `|now - timestamp| > skew_ms`.
+    OutOfWindow { timestamp_ms: u128, now_ms: u128, skew_ms: u128 },
+}
+
+/// What, besides device and message number, tells two accepted uplinks apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Freshness {
+    /// The HMAC covers `content.timestamp`: the timestamp.
+    Signed(u128),
+    /// Payload-only (or unverified) HMAC: SHA-256 of the decrypted payload.
+    Unsigned([u8; 32]),
+}
+
+impl Freshness {
+    pub fn payload(raw_payload: &[u8]) -> Self {
+        Freshness::Unsigned(Sha256::digest(raw_payload).into())
+    }
+}
+
+type Key = (String, u16, Freshness);
+
+#[derive(Default)]
+struct Seen {
+    keys: HashSet<Key>,
+    // insertion order with the time each key starts to expire from
+    order: VecDeque<(u128, Key)>,
+}
+
+/// Shared (via `web::Data`) set of recently accepted uplink tuples.
+pub struct ReplayGuard {
+    skew_ms: u128,
+    seen: Mutex<Seen>,
+}
+
+impl ReplayGuard {
+    /// `skew_ms == 0` disables both the window and the replay cache.
+    pub fn new(skew_ms: u128) -> Self {
+        ReplayGuard { skew_ms, seen: Mutex::new(Seen::default()) }
+    }
+
+    pub fn skew_ms(&self) -> u128 { self.skew_ms }
+
+    /// Reject missing timestamps and timestamps outside the window before spending time on decryption.
+    pub fn check_window(&self, timestamp_ms: Option<u128>, now_ms: u128) -> Result<(), ReplayRejection> {
+        if self.skew_ms == 0 {
+            return Ok(());
+        }
+        let Some(timestamp_ms) = timestamp_ms else { return Err(ReplayRejection::MissingTimestamp) };
+        if timestamp_ms.abs_diff(now_ms) > self.skew_ms {
+            return Err(ReplayRejection::OutOfWindow { timestamp_ms, now_ms, skew_ms: self.skew_ms });
+        }
+        Ok(())
+    }
+
+    /// Whether the tuple was already accepted (within the window); records nothing.
+    pub fn contains(&self, device: &str, message_number: u16, freshness: Freshness, now_ms: u128) -> bool {
+        if self.skew_ms == 0 {
+            return false;
+        }
+        let seen = self.seen_at(now_ms);
+        seen.keys.contains(&(device.to_string(), message_number, freshness))
+    }
+
+    /// Window check plus replay check; records the tuple when accepted. `Ok(false)` when it was already
+    /// accepted: a repeat, dropped as a duplicate.
+    pub fn check(&self, device: &str, message_number: u16, timestamp_ms: u128, freshness: Freshness, now_ms: u128) -> Result<bool, ReplayRejection> {
+        self.check_window(Some(timestamp_ms), now_ms)?;
+        if self.skew_ms == 0 {
+            return Ok(true);
+        }
+        let mut seen = self.seen_at(now_ms);
+        let key = (device.to_string(), message_number, freshness);
+        if seen.keys.contains(&key) {
+            return Ok(false);
+        }
+        let at = match freshness {
+            Freshness::Signed(ts) => ts,
+            Freshness::Unsigned(_) => now_ms,
+        };
+        seen.keys.insert(key.clone());
+        seen.order.push_back((at, key));
+        Ok(true)
+    }
+
+    /// The cache with the tuples expired at `now_ms` dropped.
+    fn seen_at(&self, now_ms: u128) -> std::sync::MutexGuard<'_, Seen> {
+        let mut seen = self.seen.lock().unwrap_or_else(|p| p.into_inner());
+        // A signed timestamp older than the window can no longer pass check_window, so it need not be
+        // remembered; payload digests are kept for one window from when they were first seen.
+        let horizon = now_ms.saturating_sub(self.skew_ms);
+        while seen.order.front().is_some_and(|(at, _)| *at < horizon) {
+            if let Some((_, k)) = seen.order.pop_front() {
+                seen.keys.remove(&k);
+            }
+        }
+        seen
+    }
+
+    /// Number of tuples currently remembered.
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap_or_else(|p| p.into_inner()).keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(g: &ReplayGuard, device: &str, n: u16, ts: u128, now: u128) -> Result<bool, ReplayRejection> {
+        g.check(device, n, ts, Freshness::Signed(ts), now)
+    }
+
+    #[test]
+    fn rejects_outside_window_and_replays() {
+        let g = ReplayGuard::new(1_000);
+        let now = 1_000_000;
+        assert!(matches!(signed(&g, "dev", 1, now - 1_001, now), Err(ReplayRejection::OutOfWindow { .. })));
+        assert!(matches!(signed(&g, "dev", 1, now + 1_001, now), Err(ReplayRejection::OutOfWindow { .. })));
+        assert_eq!(signed(&g, "dev", 1, now - 500, now), Ok(true));
+        assert_eq!(signed(&g, "dev", 1, now - 500, now + 10), Ok(false));
+        // Same message number with a new timestamp, or another device, is fresh
+        assert_eq!(signed(&g, "dev", 1, now, now), Ok(true));
+        assert_eq!(signed(&g, "other", 1, now - 500, now), Ok(true));
+        // Expired tuples are forgotten once the clock moves past the window
+        assert_eq!(signed(&g, "dev", 2, now + 2_000, now + 2_000), Ok(true));
+        assert_eq!(g.len(), 1);
+    }
+
+    #[test]
+    fn contains_only_looks() {
+        let g = ReplayGuard::new(1_000);
+        let now = 1_000_000;
+        assert!(!g.contains("dev", 1, Freshness::Signed(now), now));
+        assert!(g.is_empty());
+        assert_eq!(signed(&g, "dev", 1, now, now), Ok(true));
+        assert!(g.contains("dev", 1, Freshness::Signed(now), now + 10));
+        assert!(!g.contains("dev", 1, Freshness::Signed(now), now + 1_001));
+        assert!(!ReplayGuard::new(0).contains("dev", 1, Freshness::Signed(now), now));
+    }
+
+    #[test]
+    fn requires_timestamp_while_window_is_on() {
+        assert_eq!(ReplayGuard::new(1_000).check_window(None, 5_000), Err(ReplayRejection::MissingTimestamp));
+        assert_eq!(ReplayGuard::new(1_000).check_window(Some(4_500), 5_000), Ok(()));
+        assert_eq!(ReplayGuard::new(0).check_window(None, 5_000), Ok(()));
+    }
+
+    #[test]
+    fn payload_only_frames_are_keyed_on_the_payload() {
+        let g = ReplayGuard::new(1_000);
+        let now = 1_000_000;
+        let frame = Freshness::payload(b"ffee..0105..eeff");
+        assert_eq!(g.check("dev", 7, now - 100, frame, now), Ok(true));
+        // a fresh, unsigned timestamp does not make the same payload new
+        assert_eq!(g.check("dev", 7, now, frame, now + 10), Ok(false));
+        assert_eq!(g.check("dev", 7, now, Freshness::payload(b"ffee..0105..eefe"), now + 10), Ok(true));
+        // remembered for a window from first sight, whatever timestamp came with it
+        assert_eq!(g.check("dev", 7, now + 900, frame, now + 900), Ok(false));
+        assert_eq!(g.check("dev", 7, now + 1_100, frame, now + 1_100), Ok(true));
+    }
+
+    #[test]
+    fn zero_skew_disables_checks() {
+        let g = ReplayGuard::new(0);
+        assert_eq!(signed(&g, "dev", 1, 0, 10_000_000), Ok(true));
+        assert_eq!(signed(&g, "dev", 1, 0, 10_000_000), Ok(true));
+        assert!(g.is_empty());
+    }
+}
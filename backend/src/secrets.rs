@@ -0,0 +1,459 @@
+//! Key material: where it is loaded from, how it is held, and reloading it at run time.
+//!
+//! Sources (`[secrets]` in the configuration, see `config`), later ones winning per name:
+//! 1. `SECRETS_FILE`: `NAME=value` lines (`#` comments, optional `export ` and quotes), e.g. a Docker secret.
+//! 2. `SECRETS_DIR`: one file per name (`LORA_UPLINK_SECRET_KEY` or `lora_uplink_secret_key`), e.g. a
+//!    Kubernetes secret volume or `/run/secrets`.
+//! 3. The environment variable of the same name (empty counts as unset).
+//!
+//! Names: `LORA_UPLINK_SECRET_KEY` / `LORA_UPLINK_SIGN_TOKEN`, `LORA_DOWNLINK_SECRET_KEY` /
+//! `LORA_DOWNLINK_SIGN_TOKEN` (legacy `LORA_SECRET_KEY` / `LORA_SIGN_TOKEN` for either direction) and
+//! `KEYS_ADMIN_TOKEN`.
+//!
+//! No keys are compiled in. Outside the production profile, default keys found in none of the sources
+//! come from `secrets/demo.env` (the Node demo keys; in the working directory, else in the crate
+//! directory so `cargo run --manifest-path backend/Cargo.toml` works from the repository root). With
+//! `APP_ENV=production` that file is never read, and loading fails when a default key is missing or is
+//! one of the demo keys (recognised by SHA-256), so the server refuses to start.
+//!
+//! Values are held in `Secret`, which zeroes its buffer on drop and formats as a fingerprint
+//! (`sha256:1a2b3c4d`) for `Debug` / `Display`; only `expose()` yields the value. `watch` reloads on
+//! SIGHUP and, every `secrets.poll_ms` (`SECRETS_POLL_MS`, default 5000, 0 = SIGHUP only), when the
+//! loaded keys changed.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+use crate::key_store::{KeyError, KeyPair};
+
+/// Read in development only, for default keys no other source provides.
+pub const DEMO_FILE: &str = "secrets/demo.env";
+pub const DEFAULT_POLL_MS: u64 = 5000;
+
+const NAMES: &[&str] = &[
+    "LORA_UPLINK_SECRET_KEY", "LORA_UPLINK_SIGN_TOKEN", "LORA_DOWNLINK_SECRET_KEY", "LORA_DOWNLINK_SIGN_TOKEN",
+    "LORA_SECRET_KEY", "LORA_SIGN_TOKEN", "KEYS_ADMIN_TOKEN",
+];
+
+/// SHA-256 of the Node demo keys and tokens (upper-case hex), rejected in production.
+const DEMO_SHA256: &[&str] = &[
+    "b9f6f468037cd75f58d2defeb42a754b86aa21d635818440782e6786636c2bde",
+    "f7f1e789e2138cca222dcfc4859b95dfa65645a29aa34521ede44642e13d5664",
+    "c021ca5e61eb4cfccf2612360295e0fbd49614785aab416120f3c7571f9f3704",
+    "e0f5374091e8ea157b1f46c267247ff4e895cc4ee7b57db3006ed54ed91044f0",
+];
+
+/// Key material; zeroed on drop and never formatted in full.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self { Secret(Zeroizing::new(value.into())) }
+
+    /// The value itself, for the codec and for writing `KEYS_FILE`.
+    pub fn expose(&self) -> &str { &self.0 }
+
+    /// `sha256:` and the first 8 hex digits of the value's SHA-256: enough to tell keys apart in logs
+    /// and API responses without revealing any of them.
+    pub fn fingerprint(&self) -> String {
+        format!("sha256:{}", &hex::encode(Sha256::digest(self.expose().as_bytes()))[..8])
+    }
+
+    /// Compared in the form `KeyPair::validated` stores, so a lower-case copy of a demo key still matches.
+    fn is_demo(&self) -> bool {
+        let canonical = self.expose().trim().to_ascii_uppercase();
+        DEMO_SHA256.contains(&hex::encode(Sha256::digest(canonical.as_bytes())).as_str())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self { Secret::new(value) }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self { Secret::new(value) }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "Secret({})", self.fingerprint()) }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.fingerprint()) }
+}
+
+/// Plain string on the wire: `KEYS_FILE` and `PUT /keys` bodies carry the keys themselves.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> { s.serialize_str(self.expose()) }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> { String::deserialize(d).map(Secret::new) }
+}
+
+/// `profile` in the configuration (`APP_ENV`): `development` (default) or `production` (`prod`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    #[serde(alias = "dev")]
+    Development,
+    #[serde(alias = "prod")]
+    Production,
+}
+
+impl Profile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Development => "development",
+            Profile::Production => "production",
+        }
+    }
+}
+
+/// Where a value was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Demo,
+    File(PathBuf),
+    Env,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Demo => write!(f, "demo ({DEMO_FILE})"),
+            Origin::File(p) => write!(f, "file {}", p.display()),
+            Origin::Env => f.write_str("env"),
+        }
+    }
+}
+
+/// Why secrets could not be loaded.
+#[derive(Debug)]
+pub enum SecretsError {
+    Io { path: PathBuf, error: io::Error },
+    /// A `SECRETS_FILE` line that is not `NAME=value`.
+    Parse { path: PathBuf, line: usize },
+    /// A default key or token found in no source.
+    Missing(&'static str),
+    /// Production profile with a demo key as a default key.
+    DemoKeys(&'static str),
+    InvalidKey(KeyError),
+}
+
+impl SecretsError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SecretsError::Io { .. } => "secrets_unreadable",
+            SecretsError::Parse { .. } => "secrets_parse_error",
+            SecretsError::Missing(_) => "default_keys_missing",
+            SecretsError::DemoKeys(_) => "demo_keys_in_production",
+            SecretsError::InvalidKey(_) => "invalid_key",
+        }
+    }
+}
+
+impl fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretsError::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            SecretsError::Parse { path, line } => write!(f, "{} line {line}: expected NAME=value", path.display()),
+            SecretsError::Missing(name) => write!(f, "{name} not set (environment, SECRETS_FILE or SECRETS_DIR)"),
+            SecretsError::DemoKeys(name) => write!(f, "{name} is a demo key; refusing it with APP_ENV=production"),
+            SecretsError::InvalidKey(e) => write!(f, "default keys: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SecretsError {}
+
+/// Where to look; built from the configuration by `Config::load_secrets`.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    pub demo: Option<PathBuf>,
+    pub file: Option<PathBuf>,
+    pub dir: Option<PathBuf>,
+}
+
+impl Sources {
+    /// `file` and `dir` (empty paths ignored), plus `DEMO_FILE` outside production.
+    pub fn new(profile: Profile, file: Option<PathBuf>, dir: Option<PathBuf>) -> Self {
+        let demo = [PathBuf::from(DEMO_FILE), Path::new(env!("CARGO_MANIFEST_DIR")).join(DEMO_FILE)]
+            .into_iter().find(|p| p.is_file());
+        let set = |p: Option<PathBuf>| p.filter(|p| !p.as_os_str().is_empty());
+        Sources { demo: demo.filter(|_| profile == Profile::Development), file: set(file), dir: set(dir) }
+    }
+}
+
+/// Default keys and admin token, validated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secrets {
+    pub profile: Profile,
+    pub uplink: KeyPair,
+    pub downlink: KeyPair,
+    pub admin_token: Option<Secret>,
+    /// Where each value used came from, for the start-up / reload log line.
+    pub origins: BTreeMap<&'static str, Origin>,
+}
+
+type Found = BTreeMap<&'static str, (Secret, Origin)>;
+
+impl Secrets {
+    /// Load from `sources`, with the process environment on top.
+    pub fn load(profile: Profile, sources: &Sources) -> Result<Self, SecretsError> {
+        Self::load_from(profile, sources, |name| env::var(name).ok())
+    }
+
+    pub fn load_from(profile: Profile, sources: &Sources, env_var: impl Fn(&str) -> Option<String>) -> Result<Self, SecretsError> {
+        let mut found = Found::new();
+        if let Some(path) = &sources.file {
+            read_env_file(path, Origin::File(path.clone()), &mut found)?;
+        }
+        if let Some(dir) = &sources.dir {
+            read_dir(dir, &mut found)?;
+        }
+        for &name in NAMES {
+            if let Some(v) = env_var(name).filter(|v| !v.trim().is_empty()) {
+                found.insert(name, (Secret::new(v.trim()), Origin::Env));
+            }
+        }
+        // The demo file only fills gaps, so a legacy name set anywhere beats a demo value
+        let mut demo = Found::new();
+        if let Some(path) = sources.demo.as_ref().filter(|p| profile == Profile::Development && p.is_file()) {
+            read_env_file(path, Origin::Demo, &mut demo)?;
+        }
+
+        let mut origins = BTreeMap::new();
+        let mut pick = |names: [&'static str; 2]| -> Result<Secret, SecretsError> {
+            let (name, (value, origin)) = names.iter().find_map(|n| found.get_key_value(n))
+                .or_else(|| names.iter().find_map(|n| demo.get_key_value(n)))
+                .ok_or(SecretsError::Missing(names[0]))?;
+            if profile == Profile::Production && value.is_demo() {
+                return Err(SecretsError::DemoKeys(name));
+            }
+            origins.insert(*name, origin.clone());
+            Ok(value.clone())
+        };
+        let uplink = KeyPair { secret_key: pick(["LORA_UPLINK_SECRET_KEY", "LORA_SECRET_KEY"])?, sign_token: pick(["LORA_UPLINK_SIGN_TOKEN", "LORA_SIGN_TOKEN"])? }
+            .validated("uplink").map_err(SecretsError::InvalidKey)?;
+        let downlink = KeyPair { secret_key: pick(["LORA_DOWNLINK_SECRET_KEY", "LORA_SECRET_KEY"])?, sign_token: pick(["LORA_DOWNLINK_SIGN_TOKEN", "LORA_SIGN_TOKEN"])? }
+            .validated("downlink").map_err(SecretsError::InvalidKey)?;
+        let admin_token = found.get("KEYS_ADMIN_TOKEN").map(|(v, origin)| {
+            origins.insert("KEYS_ADMIN_TOKEN", origin.clone());
+            v.clone()
+        });
+        Ok(Secrets { profile, uplink, downlink, admin_token, origins })
+    }
+
+    /// Whether any default key came from the demo file.
+    pub fn uses_demo_keys(&self) -> bool {
+        self.origins.values().any(|o| *o == Origin::Demo)
+    }
+
+    /// One line per value: name, origin and fingerprint (never the value).
+    pub fn log_summary(&self, what: &str) {
+        let values = [
+            ("LORA_UPLINK_SECRET_KEY", &self.uplink.secret_key), ("LORA_UPLINK_SIGN_TOKEN", &self.uplink.sign_token),
+            ("LORA_DOWNLINK_SECRET_KEY", &self.downlink.secret_key), ("LORA_DOWNLINK_SIGN_TOKEN", &self.downlink.sign_token),
+        ];
+        let summary = values.iter().map(|(name, v)| {
+            let origin = self.origins.get(name).or_else(|| self.origins.get(legacy_name(name)));
+            format!("{name}={v} ({})", origin.map_or_else(|| "?".to_string(), Origin::to_string))
+        }).collect::<Vec<_>>().join(", ");
+        info!(profile = self.profile.as_str(), admin_api = self.admin_token.is_some(), keys = %summary, "{what}");
+        if self.uses_demo_keys() {
+            warn!("using the demo keys from {DEMO_FILE}; set real keys before going to production");
+        }
+    }
+}
+
+fn legacy_name(name: &str) -> &'static str {
+    if name.ends_with("SECRET_KEY") { "LORA_SECRET_KEY" } else { "LORA_SIGN_TOKEN" }
+}
+
+fn known_name(name: &str) -> Option<&'static str> {
+    NAMES.iter().copied().find(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn read_secret_file(path: &Path) -> Result<Option<Zeroizing<String>>, SecretsError> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(Zeroizing::new(s))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(SecretsError::Io { path: path.to_path_buf(), error }),
+    }
+}
+
+/// `NAME=value` lines; unknown names are skipped with a warning.
+fn read_env_file(path: &Path, origin: Origin, found: &mut Found) -> Result<(), SecretsError> {
+    let text = read_secret_file(path)?
+        .ok_or_else(|| SecretsError::Io { path: path.to_path_buf(), error: io::ErrorKind::NotFound.into() })?;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line.split_once('=').ok_or(SecretsError::Parse { path: path.to_path_buf(), line: i + 1 })?;
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')].iter()
+            .find_map(|&(a, b)| value.strip_prefix(a).and_then(|v| v.strip_suffix(b)))
+            .unwrap_or(value);
+        match known_name(name.trim()) {
+            Some(name) if !value.is_empty() => { found.insert(name, (Secret::new(value), origin.clone())); }
+            Some(_) => {}
+            None => warn!(path = %path.display(), name = name.trim(), "unknown name in secrets file ignored"),
+        }
+    }
+    Ok(())
+}
+
+/// One file per known name, upper or lower case; missing files are skipped.
+fn read_dir(dir: &Path, found: &mut Found) -> Result<(), SecretsError> {
+    if !dir.is_dir() {
+        return Err(SecretsError::Io { path: dir.to_path_buf(), error: io::ErrorKind::NotFound.into() });
+    }
+    for &name in NAMES {
+        for file in [dir.join(name), dir.join(name.to_ascii_lowercase())] {
+            let Some(text) = read_secret_file(&file)? else { continue };
+            if !text.trim().is_empty() {
+                found.insert(name, (Secret::new(text.trim()), Origin::File(file)));
+            }
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup() -> Option<Hangup> {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| warn!(error = %e, "SIGHUP handler not installed; secrets reload on change only"))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn hangup() -> Option<Hangup> { None }
+
+/// Resolves on SIGHUP; never when no handler is installed.
+async fn next_hangup(hup: &mut Option<Hangup>) {
+    #[cfg(unix)]
+    if let Some(h) = hup.as_mut() {
+        h.recv().await;
+        return;
+    }
+    let _ = hup;
+    std::future::pending::<()>().await
+}
+
+/// Reload on SIGHUP, and poll every `poll_ms` for changed values; `apply` receives each new set.
+/// A failed reload (unreadable file, invalid keys, demo keys in production) is logged and the keys in
+/// use stay in place.
+pub async fn watch(mut current: Secrets, sources: Sources, poll_ms: u64, apply: impl Fn(&Secrets)) {
+    let mut hup = hangup();
+    let mut last_error: Option<String> = None;
+    loop {
+        let forced = tokio::select! {
+            _ = next_hangup(&mut hup) => true,
+            _ = tokio::time::sleep(Duration::from_millis(poll_ms)), if poll_ms > 0 => false,
+        };
+        match Secrets::load(current.profile, &sources) {
+            Ok(next) if forced || next != current => {
+                last_error = None;
+                next.log_summary(if forced { "secrets reloaded (SIGHUP)" } else { "secrets reloaded (changed)" });
+                apply(&next);
+                metrics::counter!("secrets.reload", "result" => "ok").increment(1);
+                current = next;
+            }
+            Ok(_) => last_error = None,
+            Err(e) => {
+                // Polling retries every few seconds: report each distinct failure once
+                let msg = e.to_string();
+                if forced || last_error.as_deref() != Some(msg.as_str()) {
+                    error!(code = e.code(), error = %msg, "secrets reload failed; keeping the current keys");
+                    metrics::counter!("secrets.reload", "result" => "err").increment(1);
+                }
+                last_error = Some(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Demo file with all four keys, a secrets file with the uplink key and a directory with the uplink token.
+    fn sources(dir: &TempDir) -> Sources {
+        let dir = dir.path();
+        fs::create_dir_all(dir.join("mount")).unwrap();
+        let demo = dir.join("demo.env");
+        fs::write(&demo, "# demo\nLORA_UPLINK_SECRET_KEY=3BA16CA4D2BE9EB96147779B32182750\nLORA_UPLINK_SIGN_TOKEN=7AE4AF8AAD3BD554\n\
+            LORA_DOWNLINK_SECRET_KEY=A60C3263B832E551EEBDDDB93D8B05EA\nLORA_DOWNLINK_SIGN_TOKEN=3E3D4BEE7FE182D8\n").unwrap();
+        let file = dir.join("lora.env");
+        fs::write(&file, "export LORA_UPLINK_SECRET_KEY=\"11111111111111111111111111111111\"\nOTHER=x\n").unwrap();
+        fs::write(dir.join("mount/lora_uplink_sign_token"), "2222\n").unwrap();
+        Sources { demo: Some(demo), file: Some(file), dir: Some(dir.join("mount")) }
+    }
+
+    fn no_env(_: &str) -> Option<String> { None }
+
+    /// `LORA_SECRET_KEY` / `LORA_SIGN_TOKEN` from the environment.
+    fn legacy_env(secret_key: &'static str, sign_token: &'static str) -> impl Fn(&str) -> Option<String> {
+        move |n| match n {
+            "LORA_SECRET_KEY" => Some(secret_key.to_string()),
+            "LORA_SIGN_TOKEN" => Some(sign_token.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = sources(&dir);
+        let s = Secrets::load_from(Profile::Development, &sources, no_env).unwrap();
+        assert_eq!((s.uplink.secret_key.expose(), s.uplink.sign_token.expose()), ("11111111111111111111111111111111", "2222"));
+        assert_eq!(s.origins["LORA_UPLINK_SECRET_KEY"], Origin::File(sources.file.clone().unwrap()));
+        assert_eq!(s.origins["LORA_DOWNLINK_SECRET_KEY"], Origin::Demo);
+        assert!(s.uses_demo_keys() && s.admin_token.is_none());
+    }
+
+    #[test]
+    fn legacy_names_from_a_real_source_win_over_the_demo_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Secrets::load_from(Profile::Development, &sources(&dir), |n: &str| (n == "LORA_SIGN_TOKEN").then(|| "abcd".to_string())).unwrap();
+        assert_eq!(s.downlink.sign_token.expose(), "ABCD");
+    }
+
+    #[test]
+    fn key_material_is_never_formatted() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Secrets::load_from(Profile::Development, &sources(&dir), no_env).unwrap();
+        let printed = format!("{s:?} {}", s.uplink.secret_key);
+        assert!(!printed.contains("1111111111") && printed.contains("sha256:"));
+    }
+
+    #[test]
+    fn production_skips_the_demo_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(Secrets::load_from(Profile::Production, &sources(&dir), no_env), Err(SecretsError::Missing("LORA_DOWNLINK_SECRET_KEY"))));
+    }
+
+    #[test]
+    fn production_refuses_demo_keys_in_any_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = sources(&dir);
+        for key in ["A60C3263B832E551EEBDDDB93D8B05EA", "3ba16ca4d2be9eb96147779b32182750", " 3BA16CA4D2BE9EB96147779B32182750 "] {
+            let loaded = Secrets::load_from(Profile::Production, &sources, legacy_env(key, "33"));
+            assert!(matches!(loaded, Err(SecretsError::DemoKeys("LORA_SECRET_KEY"))), "{key:?}");
+        }
+    }
+}
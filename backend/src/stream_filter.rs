@@ -0,0 +1,262 @@
+//! Per-client filtering of the live stream: which devices, event types and floor / zone a client wants,
+//! and how often it wants position updates.
+//!
+//! A `FilterSpec` comes from the `/proxy/uwbStream` query string, lists comma separated:
+//! `?devices=a0ba3e29,009569000004C21E&types=uwb_update,device_status&floor=1&zone=0,0,20,10&maxHz=2`.
+//! The same fields are accepted as JSON (lists as arrays or comma strings).
+//!
+//! - `devices`: devEui or device ID hex, case-insensitive; events without a device are dropped.
+//! - `types`: any of `TYPES`. Control events (`resync`, `snapshot`) are always sent; a `snapshot` only
+//!   lists the devices that pass `devices` and floor / zone (see `wants_state`).
+//! - `floor` / `zone` (`x0,y0,x1,y1`, meters on the floor plan): a `uwb_update` matches when its
+//!   `payload.floor` (the floor of the closest ranged anchor) equals `floor` and its `payload.track`
+//!   (else `payload.position`) lies inside `zone`. Other events for a device are sent while the device's
+//!   latest update matched; events that cannot be placed are dropped.
+//! - `maxHz`: at most this many `uwb_update` per device per second, by event time (`ts`), so a resumed
+//!   backlog is thinned the same way as the live stream.
+use actix_web::web;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Event types a client can select.
+pub const TYPES: &[&str] = &["uwb_update", "device_status", "decode_error", "sequence_reset"];
+
+/// Floor-plan rectangle, meters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Zone {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+impl Zone {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        (self.x0.min(self.x1)..=self.x0.max(self.x1)).contains(&x) && (self.y0.min(self.y1)..=self.y0.max(self.y1)).contains(&y)
+    }
+}
+
+/// What a client subscribes to; empty / absent fields do not filter.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct FilterSpec {
+    #[serde(deserialize_with = "list")]
+    pub devices: Vec<String>,
+    #[serde(deserialize_with = "list")]
+    pub types: Vec<String>,
+    pub floor: Option<i32>,
+    #[serde(deserialize_with = "zone")]
+    pub zone: Option<Zone>,
+    pub max_hz: Option<f64>,
+}
+
+/// `"a,b"` or `["a", "b"]`.
+fn list<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List { Joined(String), Items(Vec<String>) }
+    let items = match List::deserialize(d)? {
+        List::Joined(s) => s.split(',').map(str::to_string).collect(),
+        List::Items(v) => v,
+    };
+    Ok(items.into_iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+}
+
+/// `"x0,y0,x1,y1"` or `[x0, y0, x1, y1]`.
+fn zone<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Zone>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw { Joined(String), Items([f64; 4]) }
+    let [x0, y0, x1, y1] = match Raw::deserialize(d)? {
+        Raw::Items(v) => v,
+        Raw::Joined(s) if s.trim().is_empty() => return Ok(None),
+        Raw::Joined(s) => {
+            let v: Vec<f64> = s.split(',').map(|p| p.trim().parse::<f64>()).collect::<Result<_, _>>()
+                .map_err(|_| de::Error::custom(format!("zone {s:?}: expected x0,y0,x1,y1")))?;
+            v.try_into().map_err(|_| de::Error::custom(format!("zone {s:?}: expected x0,y0,x1,y1")))?
+        }
+    };
+    Ok(Some(Zone { x0, y0, x1, y1 }))
+}
+
+/// Why a filter was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterError {
+    /// Query string / JSON that does not parse.
+    Malformed(String),
+    UnknownType(String),
+    /// Non-finite zone corner.
+    BadZone,
+    /// `maxHz` not a positive number.
+    BadRate(f64),
+}
+
+impl FilterError {
+    pub fn code(&self) -> &'static str { "invalid_filter" }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::Malformed(e) => write!(f, "{e}"),
+            FilterError::UnknownType(t) => write!(f, "unknown event type {t:?} (expected one of {})", TYPES.join(", ")),
+            FilterError::BadZone => f.write_str("zone corners must be numbers"),
+            FilterError::BadRate(hz) => write!(f, "maxHz must be > 0, got {hz}"),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+impl FilterSpec {
+    /// Parse a `/proxy/uwbStream` query string.
+    pub fn from_query(query: &str) -> Result<Self, FilterError> {
+        web::Query::<FilterSpec>::from_query(query).map(web::Query::into_inner).map_err(|e| FilterError::Malformed(e.to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool { *self == FilterSpec::default() }
+
+    /// Check the values and start filtering.
+    pub fn build(self) -> Result<StreamFilter, FilterError> {
+        if let Some(t) = self.types.iter().find(|t| !TYPES.contains(&t.as_str())) {
+            return Err(FilterError::UnknownType(t.clone()));
+        }
+        if self.zone.is_some_and(|z| ![z.x0, z.y0, z.x1, z.y1].iter().all(|v| v.is_finite())) {
+            return Err(FilterError::BadZone);
+        }
+        if let Some(hz) = self.max_hz.filter(|hz| !(hz.is_finite() && *hz > 0.0)) {
+            return Err(FilterError::BadRate(hz));
+        }
+        let devices = self.devices.iter().map(|d| d.to_ascii_lowercase()).collect();
+        Ok(StreamFilter { spec: self, devices, placed: HashMap::new(), last_update: HashMap::new() })
+    }
+}
+
+/// Event types that are always sent, whatever the filter.
+fn is_control(kind: &str) -> bool {
+    !TYPES.contains(&kind)
+}
+
+/// devEui / device ID hex the event is about, lower-case.
+fn identities(event: &Value) -> Vec<String> {
+    [&event["payload"]["deviceIdHex"], &event["payload"]["devEui"], &event["device"]].into_iter()
+        .filter_map(Value::as_str).filter(|s| !s.is_empty()).map(str::to_ascii_lowercase).collect()
+}
+
+/// A client's filter plus what it needs to remember between events.
+#[derive(Debug)]
+pub struct StreamFilter {
+    spec: FilterSpec,
+    devices: HashSet<String>,
+    /// Whether each device's latest `uwb_update` matched floor / zone.
+    placed: HashMap<String, bool>,
+    /// `ts` of the last `uwb_update` sent per device, for `maxHz`.
+    last_update: HashMap<String, u128>,
+}
+
+impl StreamFilter {
+    pub fn spec(&self) -> &FilterSpec { &self.spec }
+
+    /// Whether a device (any of its identities) passes `devices`.
+    fn wants_device<'a>(&self, mut ids: impl Iterator<Item = &'a str>) -> bool {
+        self.devices.is_empty() || ids.any(|id| self.devices.contains(&id.to_ascii_lowercase()))
+    }
+
+    /// Whether a `snapshot` entry (a device state with `device` / `deviceIdHex` / `devEui`, `floor`,
+    /// `track` / `position`) passes `devices` and floor / zone.
+    pub fn wants_state(&self, state: &Value) -> bool {
+        let ids = [&state["device"], &state["deviceIdHex"], &state["devEui"]];
+        self.wants_device(ids.into_iter().filter_map(Value::as_str))
+            && ((self.spec.floor.is_none() && self.spec.zone.is_none()) || self.place(state) == Some(true))
+    }
+
+    /// Whether to send `event` to this client.
+    pub fn admit(&mut self, event: &Value) -> bool {
+        let kind = event["type"].as_str().unwrap_or("uwb_update");
+        if is_control(kind) { return true; }
+        if !self.spec.types.is_empty() && !self.spec.types.iter().any(|t| t == kind) { return false; }
+        let ids = identities(event);
+        if !self.wants_device(ids.iter().map(String::as_str)) { return false; }
+        let Some(key) = ids.first() else {
+            // nothing to place or rate-limit by
+            return self.spec.floor.is_none() && self.spec.zone.is_none();
+        };
+        if kind == "uwb_update" && (self.spec.floor.is_some() || self.spec.zone.is_some()) {
+            if let Some(inside) = self.place(&event["payload"]) {
+                for id in &ids { self.placed.insert(id.clone(), inside); }
+            }
+        }
+        if (self.spec.floor.is_some() || self.spec.zone.is_some()) && self.placed.get(key) != Some(&true) {
+            return false;
+        }
+        if let (Some(hz), "uwb_update") = (self.spec.max_hz, kind) {
+            let ts = event["ts"].as_u64().map(u128::from).unwrap_or_default();
+            let min_gap = (1000.0 / hz) as u128;
+            if self.last_update.get(key).is_some_and(|last| ts < last + min_gap) { return false; }
+            self.last_update.insert(key.clone(), ts);
+        }
+        true
+    }
+
+    /// Floor / zone match of an update payload; `None` when it carries no floor or location to judge by.
+    fn place(&self, payload: &Value) -> Option<bool> {
+        let floor_ok = match self.spec.floor {
+            Some(floor) => payload["floor"].as_i64()? == i64::from(floor),
+            None => true,
+        };
+        let zone_ok = match self.spec.zone {
+            Some(zone) => {
+                let p = if payload["track"].is_object() { &payload["track"] } else { &payload["position"] };
+                zone.contains(p["x"].as_f64()?, p["y"].as_f64()?)
+            }
+            None => true,
+        };
+        Some(floor_ok && zone_ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(device: &str, ts: u64, floor: i32, x: f64) -> Value {
+        json!({ "type": "uwb_update", "ts": ts, "payload": { "deviceIdHex": device, "floor": floor, "position": { "x": x, "y": 1.0 } } })
+    }
+
+    #[test]
+    fn filters_by_device_type_place_and_rate() {
+        let spec = FilterSpec::from_query("devices=A0BA3E29,beef0001&types=uwb_update,device_status&floor=1&zone=0,0,10,5&maxHz=2").unwrap();
+        assert_eq!((spec.devices.len(), spec.floor, spec.zone.map(|z| z.x1)), (2, Some(1), Some(10.0)));
+        let mut f = spec.build().unwrap();
+
+        assert!(!f.admit(&update("ffff0000", 0, 1, 1.0)), "other device");
+        assert!(!f.admit(&json!({ "type": "decode_error", "code": "bad_padding" })), "type not selected");
+        assert!(f.admit(&json!({ "type": "resync" })), "control events always pass");
+        // status before the device was placed: dropped; after an in-zone update: sent
+        let status = json!({ "type": "device_status", "payload": { "deviceIdHex": "a0ba3e29" } });
+        assert!(!f.admit(&status));
+        assert!(f.admit(&update("a0ba3e29", 1_000, 1, 2.0)));
+        assert!(f.admit(&status));
+        // 2 Hz: 400 ms later is too soon, 500 ms is fine
+        assert!(!f.admit(&update("a0ba3e29", 1_400, 1, 2.0)));
+        assert!(f.admit(&update("a0ba3e29", 1_500, 1, 2.0)));
+        // leaving the zone / floor drops the device's events until it comes back
+        assert!(!f.admit(&update("a0ba3e29", 3_000, 1, 12.0)));
+        assert!(!f.admit(&status));
+        assert!(!f.admit(&update("a0ba3e29", 4_000, 2, 2.0)));
+
+        assert!(matches!(FilterSpec::from_query("types=uwb_updat").unwrap().build(), Err(FilterError::UnknownType(_))));
+        assert!(matches!(FilterSpec::from_query("zone=1,2,3"), Err(FilterError::Malformed(_))));
+        assert!(matches!(FilterSpec::from_query("maxHz=0").unwrap().build(), Err(FilterError::BadRate(_))));
+        let json: FilterSpec = serde_json::from_value(json!({ "devices": ["a0ba3e29"], "zone": [0, 0, 1, 1] })).unwrap();
+        assert_eq!(json.zone, Some(Zone { x0: 0.0, y0: 0.0, x1: 1.0, y1: 1.0 }));
+        assert!(FilterSpec::from_query("").unwrap().is_empty());
+    }
+}
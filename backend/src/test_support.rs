@@ -0,0 +1,99 @@
+//! Fixtures shared by the handler tests: a configuration whose files all live in a temporary directory,
+//! the demo default keys, and the app data `lorawan_stream::config` registers.
+use actix_web::body::MessageBody;
+use actix_web::web;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::lorawan_codec::{Beacon, Battery, DeviceId, Frame, HmacBinding, LocationReport, Motion, Uplink};
+use crate::lorawan_stream::IngestState;
+use crate::secrets::{Profile, Secrets, Sources};
+
+/// Demo uplink keys (`secrets/demo.env`).
+pub const SECRET_KEY: &str = "3BA16CA4D2BE9EB96147779B32182750";
+pub const SIGN_TOKEN: &str = "7AE4AF8AAD3BD554";
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// Default configuration with every file inside `dir` (history, registries, key store).
+pub fn config(dir: &TempDir) -> Config {
+    let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+    let mut config = Config::default();
+    config.history.dir = path("history");
+    config.positioning.anchors_file = path("anchors.json");
+    config.devices.registry_file = path("devices.json");
+    config.keys.file = path("keys.json");
+    config
+}
+
+/// Demo default keys plus `ADMIN_TOKEN`, without reading the environment.
+pub fn secrets() -> Secrets {
+    let sources = Sources { demo: None, file: None, dir: None };
+    Secrets::load_from(Profile::Development, &sources, |name| match name {
+        "LORA_SECRET_KEY" => Some(SECRET_KEY.to_string()),
+        "LORA_SIGN_TOKEN" => Some(SIGN_TOKEN.to_string()),
+        "KEYS_ADMIN_TOKEN" => Some(ADMIN_TOKEN.to_string()),
+        _ => None,
+    }).expect("test secrets")
+}
+
+/// What `lorawan_stream::config` takes, built from `config`.
+pub struct Server {
+    pub events: web::Data<EventBus>,
+    pub state: web::Data<IngestState>,
+    pub config: web::Data<Config>,
+    /// Keeps the files alive as long as the server.
+    _dir: TempDir,
+}
+
+impl Server {
+    pub fn new(edit: impl FnOnce(&mut Config)) -> Self {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let mut config = config(&dir);
+        edit(&mut config);
+        Server {
+            events: web::Data::new(EventBus::new(&config.stream)),
+            state: web::Data::new(IngestState::new(&config, &secrets())),
+            config: web::Data::new(config),
+            _dir: dir,
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        crate::lorawan_stream::config(cfg, self.events.clone(), self.state.clone(), self.config.clone());
+    }
+}
+
+pub const DEVICE: DeviceId = DeviceId(0xa0ba3e29);
+pub const DEV_EUI: &str = "00956900A0BA3E29";
+
+pub fn location_report(device_id: DeviceId) -> Frame {
+    Frame::LocationReport(LocationReport::new(device_id, Motion::Moving, vec![Beacon { major: 0x0200, minor: 0x00b3, distance_cm: 150, battery: Battery(90) }]))
+}
+
+/// `/v1/uwb` body for `frame`, HMAC bound to `ts`.
+pub fn uplink_body(dev_eui: &str, message_number: u16, frame: Frame, ts: u128) -> Value {
+    let data = Uplink::new(message_number, frame).encrypt(HmacBinding::Timestamp(ts), SIGN_TOKEN, SECRET_KEY).expect("encrypt uplink");
+    json!({ "content": { "data": data, "devEui": dev_eui, "fPort": 10, "timestamp": ts } })
+}
+
+/// The next `n` events of an SSE body as `(id, JSON)`, skipping comments; panics after 5 s without one.
+pub async fn sse_events<B: MessageBody + Unpin>(body: &mut B, n: usize) -> Vec<(Option<u64>, Value)> {
+    let mut buf = String::new();
+    let mut events = Vec::new();
+    while events.len() < n {
+        let next = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx));
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), next).await.expect("sse event in time").expect("sse stream open");
+        let Ok(chunk) = chunk else { panic!("sse body error") };
+        buf.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(end) = buf.find("\n\n") {
+            let block: String = buf.drain(..end + 2).collect();
+            let id = block.lines().find_map(|l| l.strip_prefix("id: ")).and_then(|id| id.parse().ok());
+            let data: Vec<_> = block.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+            if !data.is_empty() {
+                events.push((id, serde_json::from_str(&data.join("\n")).expect("sse data is JSON")));
+            }
+        }
+    }
+    events
+}
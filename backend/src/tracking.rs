@@ -0,0 +1,196 @@
+//! Per-device constant-velocity Kalman tracker run on the solver output.
+//!
+//! Each device (`deviceIdHex`) keeps one filter per axis with state `[position, velocity]`. Every solved
+//! 0x05 report predicts the state forward by the real time since the previous report (uplink
+//! `content.timestamp`, else server time) and then corrects it with the fix.
+//!
+//! - Process noise is white acceleration: `TRACK_ACCEL_STILL` (default 0.05 m/s²) when the frame says
+//!   "No Movement", `TRACK_ACCEL_MOVING` (default 1.5 m/s²) when it says "Movement Detected".
+//! - Measurement noise is `TRACK_MEAS_STD_M²` (default 0.3 m) plus the solver residual².
+//! - A track restarts from the fix after `TRACK_RESET_MS` (default 30000; 0 never) without reports.
+//!   Older (reordered) frames correct the track without moving its clock.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::lorawan_codec::Motion;
+use crate::positioning::Position;
+
+/// Initial velocity variance of a new track, (m/s)² (about 2 m/s one sigma).
+const INITIAL_VELOCITY_VAR: f64 = 4.0;
+
+/// `[tracking]` in `Config`; environment names in the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerOptions {
+    pub meas_std_m: f64,
+    pub accel_still: f64,
+    pub accel_moving: f64,
+    pub reset_after_ms: u64,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        TrackerOptions { meas_std_m: 0.3, accel_still: 0.05, accel_moving: 1.5, reset_after_ms: 30_000 }
+    }
+}
+
+/// `[position, velocity]` filter for one axis.
+#[derive(Debug, Clone, Copy)]
+struct Axis {
+    p: f64,
+    v: f64,
+    cov: [[f64; 2]; 2],
+}
+
+impl Axis {
+    fn new(p: f64, var: f64) -> Self {
+        Axis { p, v: 0.0, cov: [[var, 0.0], [0.0, INITIAL_VELOCITY_VAR]] }
+    }
+
+    /// `x = F x`, `P = F P Fᵀ + Q` with `F = [[1, dt], [0, 1]]` and white-acceleration `Q`.
+    fn predict(&mut self, dt: f64, accel: f64) {
+        let q = accel * accel;
+        let [[p00, p01], [p10, p11]] = self.cov;
+        self.p += self.v * dt;
+        self.cov = [
+            [p00 + dt * (p10 + p01) + dt * dt * p11 + q * dt.powi(4) / 4.0, p01 + dt * p11 + q * dt.powi(3) / 2.0],
+            [p10 + dt * p11 + q * dt.powi(3) / 2.0, p11 + q * dt * dt],
+        ];
+    }
+
+    /// Position measurement `z` with variance `r` (`H = [1, 0]`).
+    fn update(&mut self, z: f64, r: f64) {
+        let [[p00, p01], [p10, p11]] = self.cov;
+        let s = p00 + r;
+        let (k0, k1) = (p00 / s, p10 / s);
+        let innovation = z - self.p;
+        self.p += k0 * innovation;
+        self.v += k1 * innovation;
+        self.cov = [[(1.0 - k0) * p00, (1.0 - k0) * p01], [p10 - k1 * p00, p11 - k1 * p01]];
+    }
+}
+
+struct Track {
+    axes: [Axis; 3],
+    last_ms: u128,
+    updates: u64,
+}
+
+/// Per-axis `[[var(p), cov(p, v)], [cov(v, p), var(v)]]`; axes are filtered independently.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Covariance {
+    pub x: [[f64; 2]; 2],
+    pub y: [[f64; 2]; 2],
+    pub z: [[f64; 2]; 2],
+}
+
+/// Smoothed state attached to `uwb_update` as `payload.track`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub vz: f64,
+    /// Horizontal speed, m/s.
+    pub speed: f64,
+    pub covariance: Covariance,
+    /// Time predicted over for this update (0 for a new track or a reordered frame).
+    pub dt_ms: u64,
+    /// Fixes merged into this track since it (re)started; 1 means the track was just created.
+    pub updates: u64,
+}
+
+/// Tracks for every device, shared by all workers.
+pub struct Tracker {
+    opts: TrackerOptions,
+    tracks: Mutex<HashMap<String, Track>>,
+}
+
+impl Tracker {
+    pub fn new(opts: TrackerOptions) -> Self {
+        Tracker { opts, tracks: Mutex::new(HashMap::new()) }
+    }
+
+    /// Merge one solver fix for `device`, taken at `timestamp_ms`.
+    pub fn update(&self, device: &str, pos: &Position, motion: Motion, timestamp_ms: u128) -> TrackedPosition {
+        let r = self.opts.meas_std_m.powi(2) + pos.residual.powi(2);
+        let z = [pos.x, pos.y, pos.z];
+        let accel = match motion { Motion::Moving => self.opts.accel_moving, Motion::Still => self.opts.accel_still };
+        let mut tracks = self.tracks.lock().unwrap_or_else(|p| p.into_inner());
+        let stale = |t: &Track| self.opts.reset_after_ms > 0 && timestamp_ms.saturating_sub(t.last_ms) > u128::from(self.opts.reset_after_ms);
+        let mut dt_ms = 0;
+        match tracks.get_mut(device) {
+            Some(t) if !stale(t) => {
+                dt_ms = timestamp_ms.saturating_sub(t.last_ms);
+                let dt = dt_ms as f64 / 1000.0;
+                for (axis, &zi) in t.axes.iter_mut().zip(&z) {
+                    axis.predict(dt, accel);
+                    axis.update(zi, r);
+                }
+                t.last_ms = t.last_ms.max(timestamp_ms);
+                t.updates += 1;
+            }
+            _ => {
+                tracks.insert(device.to_string(), Track { axes: z.map(|zi| Axis::new(zi, r)), last_ms: timestamp_ms, updates: 1 });
+            }
+        }
+        let t = &tracks[device];
+        let [ax, ay, az] = t.axes;
+        TrackedPosition {
+            x: ax.p,
+            y: ay.p,
+            z: az.p,
+            vx: ax.v,
+            vy: ay.v,
+            vz: az.v,
+            speed: ax.v.hypot(ay.v),
+            covariance: Covariance { x: ax.cov, y: ay.cov, z: az.cov },
+            dt_ms: u64::try_from(dt_ms).unwrap_or(u64::MAX),
+            updates: t.updates,
+        }
+    }
+
+    pub fn len(&self) -> usize { self.tracks.lock().unwrap_or_else(|p| p.into_inner()).len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::positioning::SolverMode;
+
+    fn fix(x: f64, y: f64) -> Position {
+        Position { x, y, z: 1.0, residual: 0.0, anchors_used: 3, mode: SolverMode::TwoD }
+    }
+
+    #[test]
+    fn estimates_velocity_and_resets_after_gap() {
+        let t = Tracker::new(TrackerOptions::default());
+        let mut last = None;
+        for i in 0..30u32 {
+            last = Some(t.update("a0ba3e29", &fix(f64::from(i), 2.0), Motion::Moving, u128::from(i) * 1000));
+        }
+        let last = last.unwrap();
+        assert!((last.vx - 1.0).abs() < 0.05 && last.vy.abs() < 0.05, "{last:?}");
+        assert!((last.x - 29.0).abs() < 0.1 && (last.speed - 1.0).abs() < 0.05);
+        assert!(last.covariance.x[0][0] < 0.09, "fix variance should shrink below the measurement variance");
+
+        let restarted = t.update("a0ba3e29", &fix(5.0, 5.0), Motion::Still, 29_000 + 31_000);
+        assert_eq!((restarted.updates, restarted.dt_ms, restarted.x, restarted.vx), (1, 0, 5.0, 0.0));
+    }
+
+    #[test]
+    fn movement_flag_follows_jumps_faster() {
+        let step = |motion| {
+            let t = Tracker::new(TrackerOptions::default());
+            for i in 0..10u32 { t.update("d", &fix(0.0, 0.0), Motion::Still, u128::from(i) * 1000); }
+            t.update("d", &fix(3.0, 0.0), motion, 10_000).x
+        };
+        let (still, moving) = (step(Motion::Still), step(Motion::Moving));
+        assert!(moving - still > 1.0, "still {still} moving {moving}");
+    }
+}
@@ -0,0 +1,408 @@
+//! WebSocket transport for the live stream: `GET /ws`, for clients where SSE through proxies is unreliable.
+//!
+//! Carries the same events as `/proxy/uwbStream`, one JSON text message each, with the event id added as
+//! `id` (`{ "id": 42, "type": "uwb_update", "payload": .. }`). The connection takes the same filter query
+//! (see `stream_filter`) plus `?lastEventId=<id>` (or a `Last-Event-ID` header) to resume from the event
+//! ring, with `resync` when it cannot (see `events`). The first message is
+//! `{ type: "hello", latestId, filter }`, followed on a fresh connection or after `resync` by a `snapshot`
+//! of every device, as on the SSE stream (see `device_state`).
+//!
+//! Client messages are JSON objects with an `op`:
+//! - `{ op: "subscribe", filter: { devices, types, floor, zone, maxHz } }`: replace the filter (fields as in
+//!   the query string, lists as arrays or comma strings); answered with `{ type: "subscribed", filter }`.
+//! - `{ op: "snapshot" }`: answered with a fresh `snapshot` for the current filter.
+//! - `{ op: "ack", id }`: the client has processed everything up to `id`; not answered. Reconnecting with
+//!   `lastEventId` set to the last acked id gets the rest again.
+//!
+//! A message that cannot be used is answered with `{ type: "error", code, message }` (`bad_message`,
+//! `invalid_filter`) and the connection stays open. The server pings every 15 s and closes connections it
+//! has not heard from (pongs included) for three intervals.
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Closed, Message, MessageStream, Session};
+use metrics::counter;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+use crate::events::{Event, EventBus, Subscription};
+use crate::lorawan_stream::{last_event_id, now_ms, IngestState};
+use crate::stream_filter::{FilterError, FilterSpec, StreamFilter};
+
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// A client message.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase", deny_unknown_fields)]
+pub enum Control {
+    Subscribe { filter: FilterSpec },
+    Snapshot,
+    Ack { id: u64 },
+}
+
+impl Control {
+    pub fn parse(text: &str) -> Result<Self, ControlError> {
+        serde_json::from_str(text).map_err(|e| ControlError::BadMessage(e.to_string()))
+    }
+
+    fn op(&self) -> &'static str {
+        match self {
+            Control::Subscribe { .. } => "subscribe",
+            Control::Snapshot => "snapshot",
+            Control::Ack { .. } => "ack",
+        }
+    }
+}
+
+/// Why a client message was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlError {
+    /// Not JSON, unknown `op` or missing / unknown fields; binary messages.
+    BadMessage(String),
+    Filter(FilterError),
+}
+
+impl ControlError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ControlError::BadMessage(_) => "bad_message",
+            ControlError::Filter(e) => e.code(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "type": "error", "code": self.code(), "message": self.to_string() })
+    }
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::BadMessage(e) => write!(f, "{e}"),
+            ControlError::Filter(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Event JSON with its id added.
+fn with_id(event: &Event) -> Value {
+    let mut value = event.value.clone();
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert("id".into(), json!(event.id));
+    }
+    value
+}
+
+/// Event as a WebSocket message.
+fn frame(event: &Event) -> String {
+    with_id(event).to_string()
+}
+
+/// `lastEventId` taken out of the query string, and the rest for the filter.
+fn split_resume(query: &str) -> (Option<u64>, String) {
+    let mut last = None;
+    let rest: Vec<&str> = query.split('&').filter(|kv| match kv.strip_prefix("lastEventId=") {
+        Some(v) => { last = v.parse().ok(); false }
+        None => true,
+    }).collect();
+    (last, rest.join("&"))
+}
+
+/// Per-connection state.
+struct Client {
+    filter: StreamFilter,
+    /// Newest event id handled (sent or filtered out).
+    cursor: u64,
+    acked: Option<u64>,
+}
+
+impl Client {
+    async fn send(&mut self, session: &mut Session, event: &Event) -> Result<(), Closed> {
+        self.cursor = self.cursor.max(event.id);
+        if self.filter.admit(&event.value) {
+            session.text(frame(event)).await?;
+            counter!("uwb.ws.sent").increment(1);
+        } else {
+            counter!("uwb.ws.filtered").increment(1);
+        }
+        Ok(())
+    }
+
+    /// Reply to a client message, if it gets one.
+    fn control(&mut self, text: &str, state: &IngestState) -> Option<Value> {
+        let control = match Control::parse(text) {
+            Ok(c) => c,
+            Err(e) => return Some(e.to_json()),
+        };
+        counter!("uwb.ws.control", "op" => control.op()).increment(1);
+        match control {
+            Control::Subscribe { filter } => match filter.build() {
+                Ok(filter) => {
+                    debug!(filter = ?filter.spec(), "ws client filter");
+                    let reply = json!({ "type": "subscribed", "filter": filter.spec() });
+                    self.filter = filter;
+                    Some(reply)
+                }
+                Err(e) => Some(ControlError::Filter(e).to_json()),
+            },
+            Control::Snapshot => Some(state.devices.snapshot(&self.filter, self.cursor, now_ms())),
+            Control::Ack { id } => {
+                self.acked = self.acked.max(Some(id));
+                None
+            }
+        }
+    }
+}
+
+/// Live WebSocket stream; see the module docs for the protocol.
+#[get("/ws")]
+pub async fn ws(req: HttpRequest, body: web::Payload, events: web::Data<EventBus>, state: web::Data<IngestState>) -> Result<HttpResponse, Error> {
+    let (resume_from, query) = split_resume(req.query_string());
+    let filter = match FilterSpec::from_query(&query).and_then(FilterSpec::build) {
+        Ok(f) => f,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(e.to_json())),
+    };
+    let resume_from = resume_from.or_else(|| last_event_id(&req));
+    let (response, mut session, mut msgs) = actix_ws::handle(&req, body)?;
+    let sub = events.subscribe(resume_from);
+    let mut client = Client { filter, cursor: sub.cursor, acked: None };
+    actix_web::rt::spawn(async move {
+        let reason = match serve(&mut session, &mut msgs, &mut client, sub, resume_from, &events, &state).await {
+            Ok(reason) => reason,
+            Err(Closed) => return,
+        };
+        debug!(cursor = client.cursor, acked = client.acked, "ws client closed");
+        let _ = session.close(reason).await;
+    });
+    Ok(response)
+}
+
+/// Run one connection until either side closes; `Err` when the session is already gone.
+async fn serve(session: &mut Session, msgs: &mut MessageStream, client: &mut Client, sub: Subscription, resume_from: Option<u64>, events: &EventBus, state: &IngestState) -> Result<Option<CloseReason>, Closed> {
+    let mut rx = sub.rx;
+    session.text(json!({ "type": "hello", "latestId": client.cursor, "filter": client.filter.spec() }).to_string()).await?;
+    if !sub.backlog.is_empty() {
+        info!(missed = sub.backlog.len(), "ws client resumed");
+        counter!("uwb.ws.resumed").increment(sub.backlog.len() as u64);
+    }
+    for event in &sub.backlog {
+        client.send(session, event).await?;
+    }
+    if let Some(resync) = &sub.resync {
+        info!(last_event_id = resync.last_event_id, reason = resync.reason.as_str(), "ws client cannot resume; resync");
+        counter!("uwb.ws.resync", "reason" => resync.reason.as_str()).increment(1);
+        session.text(frame(&resync.event(now_ms()))).await?;
+    }
+    if resume_from.is_none() || sub.resync.is_some() {
+        session.text(state.devices.snapshot(&client.filter, client.cursor, now_ms()).to_string()).await?;
+    }
+    let mut hb = tokio::time::interval(HEARTBEAT);
+    let mut heard = Instant::now();
+    loop {
+        tokio::select! {
+            _ = hb.tick() => {
+                if heard.elapsed() > HEARTBEAT * 3 {
+                    debug!("ws client timed out");
+                    return Ok(Some(CloseReason { code: CloseCode::Away, description: Some("heartbeat timeout".into()) }));
+                }
+                session.ping(b"").await?;
+            }
+            msg = msgs.recv() => {
+                heard = Instant::now();
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some(reply) = client.control(&text, state) {
+                            session.text(reply.to_string()).await?;
+                        }
+                    }
+                    Some(Ok(Message::Binary(_))) => {
+                        let e = ControlError::BadMessage("binary messages are not supported".into());
+                        session.text(e.to_json().to_string()).await?;
+                    }
+                    Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await?,
+                    Some(Ok(Message::Close(reason))) => return Ok(reason),
+                    // pongs only refresh `heard`; continuations are not aggregated
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        debug!(error = %e, "ws protocol error");
+                        return Ok(Some(CloseCode::Protocol.into()));
+                    }
+                    None => return Ok(None),
+                }
+            }
+            recv = rx.recv() => {
+                match recv {
+                    // already sent from the ring
+                    Ok(event) if event.id <= client.cursor => {}
+                    Ok(event) => client.send(session, &event).await?,
+                    Err(RecvError::Lagged(skipped)) => {
+                        counter!("uwb.ws.lagged").increment(1);
+                        match events.since(client.cursor) {
+                            Ok(missed) => {
+                                debug!(skipped, refilled = missed.len(), "ws client lagged; caught up from ring");
+                                for event in missed { client.send(session, &event).await?; }
+                            }
+                            Err(resync) => {
+                                warn!(skipped, "ws client lagged past the ring; resync");
+                                counter!("uwb.ws.resync", "reason" => resync.reason.as_str()).increment(1);
+                                client.cursor = resync.latest_id;
+                                session.text(frame(&resync.event(now_ms()))).await?;
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => return Ok(Some(CloseCode::Restart.into())),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::dev::Payload;
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use crate::config::StreamConfig;
+    use crate::test_support::Server;
+
+    #[test]
+    fn resume_id_is_split_from_the_filter_query() {
+        assert_eq!(split_resume("devices=a&lastEventId=42&maxHz=2"), (Some(42), "devices=a&maxHz=2".to_string()));
+        assert_eq!(split_resume(""), (None, String::new()));
+    }
+
+    #[test]
+    fn control_messages_parse() {
+        match Control::parse(r#"{"op":"subscribe","filter":{"devices":["A0BA3E29"],"maxHz":2}}"#).unwrap() {
+            Control::Subscribe { filter } => assert_eq!((filter.devices.len(), filter.max_hz), (1, Some(2.0))),
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(Control::parse(r#"{"op":"ack","id":7}"#), Ok(Control::Ack { id: 7 })));
+        assert!(matches!(Control::parse(r#"{"op":"snapshot"}"#), Ok(Control::Snapshot)));
+        let bad = Control::parse(r#"{"op":"unsubscribe"}"#).unwrap_err();
+        assert_eq!(bad.to_json()["code"], "bad_message");
+    }
+
+    #[test]
+    fn events_carry_their_id() {
+        let bus = EventBus::new(&StreamConfig::default());
+        let id = bus.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        let sub = bus.subscribe(Some(id - 1));
+        let sent: Value = serde_json::from_str(&frame(&sub.backlog[0])).unwrap();
+        assert_eq!((sent["id"].as_u64(), &sent["type"], &sent["payload"]["deviceIdHex"]), (Some(id), &json!("uwb_update"), &json!("a0ba3e29")));
+    }
+
+    fn upgrade(uri: &str) -> TestRequest {
+        TestRequest::get().uri(uri)
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    /// Client side that never sends or closes (an empty request body would read as a close).
+    fn silent_client() -> Payload {
+        client_sending(&[])
+    }
+
+    /// Client side that sends `messages` as masked text frames, then stays open.
+    fn client_sending(messages: &[Value]) -> Payload {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let frames: Vec<_> = messages.iter().map(|m| {
+            let text = m.to_string().into_bytes();
+            assert!(text.len() < 126, "short frames only");
+            let mut frame = vec![0x81, 0x80 | text.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(text.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            Ok(Bytes::from(frame))
+        }).collect();
+        Payload::Stream { payload: Box::pin(futures_util::stream::iter(frames).chain(futures_util::stream::pending())) }
+    }
+
+    /// The next `n` text messages the server writes into an upgraded response body (unmasked frames).
+    async fn text_messages<B: MessageBody + Unpin>(body: &mut B, n: usize) -> Vec<Value> {
+        let mut buf = Vec::new();
+        let mut messages = Vec::new();
+        while messages.len() < n {
+            let next = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx));
+            let chunk = tokio::time::timeout(Duration::from_secs(5), next).await.expect("ws message in time").expect("ws open");
+            let Ok(chunk) = chunk else { panic!("ws body error") };
+            buf.extend_from_slice(&chunk);
+            loop {
+                let (len, start) = match buf.get(1).map(|b| b & 0x7f) {
+                    Some(126) if buf.len() >= 4 => (usize::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+                    Some(l) if l < 126 => (usize::from(l), 2),
+                    _ => break,
+                };
+                if buf.len() < start + len { break; }
+                let frame: Vec<u8> = buf.drain(..start + len).collect();
+                // text frames only; pings and the like are skipped
+                if frame[0] & 0x0f == 0x1 {
+                    messages.push(serde_json::from_slice(&frame[start..]).expect("ws text is JSON"));
+                }
+            }
+        }
+        messages
+    }
+
+    #[actix_web::test]
+    async fn fresh_connections_get_hello_then_snapshot() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let (req, _) = upgrade("/ws?types=uwb_update").to_request().replace_payload(silent_client());
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let mut body = res.into_body();
+        let first = text_messages(&mut body, 2).await;
+        assert_eq!((&first[0]["type"], &first[0]["filter"]["types"], &first[1]["type"]), (&json!("hello"), &json!(["uwb_update"]), &json!("snapshot")));
+
+        let id = server.events.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        server.events.publish(json!({ "type": "device_status", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        assert_eq!(text_messages(&mut body, 1).await[0]["id"], id);
+    }
+
+    #[actix_web::test]
+    async fn reconnects_resume_after_last_event_id() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let acked = server.events.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        let missed = server.events.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "b0ba3e29" } }));
+        let (req, _) = upgrade(&format!("/ws?lastEventId={acked}")).to_request().replace_payload(silent_client());
+        let res = call_service(&app, req).await;
+        let mut body = res.into_body();
+        let messages = text_messages(&mut body, 2).await;
+        assert_eq!((&messages[0]["type"], &messages[1]["id"]), (&json!("hello"), &json!(missed)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_an_invalid_filter_before_upgrading() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let res = call_service(&app, upgrade("/ws?types=position").to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["code"], "invalid_filter");
+    }
+
+    #[actix_web::test]
+    async fn subscribe_replaces_the_filter() {
+        let server = Server::new(|_| {});
+        let app = init_service(App::new().configure(|cfg| server.configure(cfg))).await;
+        let subscribe = json!({ "op": "subscribe", "filter": { "devices": "b0ba3e29" } });
+        let (req, _) = upgrade("/ws").to_request().replace_payload(client_sending(&[subscribe]));
+        let mut body = call_service(&app, req).await.into_body();
+        let first = text_messages(&mut body, 3).await;
+        assert_eq!((&first[2]["type"], &first[2]["filter"]["devices"]), (&json!("subscribed"), &json!(["b0ba3e29"])));
+
+        server.events.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "a0ba3e29" } }));
+        let wanted = server.events.publish(json!({ "type": "uwb_update", "payload": { "deviceIdHex": "b0ba3e29" } }));
+        assert_eq!(text_messages(&mut body, 1).await[0]["id"], wanted);
+    }
+}
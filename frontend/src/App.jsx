@@ -35,6 +35,7 @@ import { useSvgPanZoom } from './hooks/useSvgPanZoom'
 import AnchorsCard from './components/AnchorsCard'
 import Admin from './components/Admin'
 import { Kalman2D } from './kalman'
+import { loadAdminToken, saveAdminToken, withAdminToken, writeError } from './adminToken'
 import { Dialog, DialogContent, DialogHeader, DialogTitle, DialogDescription } from "@/components/ui/dialog"
 import { IconDeviceAnalytics, IconListDetails, IconBuilding, IconSettings, IconRefresh, IconChevronDown, IconNavigation, IconLayersLinked, IconFocusCentered, IconCompass, IconArrowsMaximize, IconZoomIn, IconZoomOut, IconMaximize, IconTarget } from '@tabler/icons-react'
 
@@ -75,12 +76,18 @@ function App() {
   const planRef = useRef()
   const pollRef = useRef()
   const streamControllerRef = useRef(null)
+  // id of the last SSE event received; sent as Last-Event-ID on reconnect so the backend replays the gap
+  const lastEventIdRef = useRef(null)
   const [view, setView] = useState('home')
   const [adminOpen, setAdminOpen] = useState(false)
   const [paths, setPaths] = useState({})
   const [connStatus, setConnStatus] = useState('closed') // connecting | open | closed
   const [logs, setLogs] = useState([])
+  // Friendly device names by device ID; saved to the backend device registry in live mode,
+  // localStorage `deviceNames` is an offline cache
   const [deviceNames, setDeviceNames] = useState(() => { try { const s = localStorage.getItem('deviceNames'); return s ? JSON.parse(s) : {} } catch (e) { return {} } })
+  const [adminToken, setAdminToken] = useState(loadAdminToken)
+  useEffect(() => saveAdminToken(adminToken), [adminToken])
   const [anchorNames, setAnchorNames] = useState(() => { try { const s = localStorage.getItem('anchorNames'); return s ? JSON.parse(s) : {} } catch (e) { return {} } })
   // Debug overlay state
   const [showDebug, setShowDebug] = useState(false)
@@ -404,6 +411,132 @@ function App() {
     pushLog(`pollUrl set to ${url} (useLive=${useLive})`)
   }, [backendPort, useLive, factoryWidthMeters, factoryHeightMeters])
 
+  // Backend anchor registry (live mode only). `registryAnchors` mirrors what the backend holds
+  // (`null` until loaded); localStorage `anchors` / `anchorNames` remain an offline cache.
+  const [registryAnchors, setRegistryAnchors] = useState(null)
+  const anchorsUrl = (id) => `http://${window.location.hostname}:${backendPort}/anchors${id ? `/${encodeURIComponent(id)}` : ''}`
+
+  // Load anchors from the backend registry when going live. An empty registry keeps the local
+  // anchors, which the sync effect below then uploads.
+  useEffect(() => {
+    if (!useLive || !backendPort) { setRegistryAnchors(null); return }
+    let cancelled = false
+      ; (async function () {
+        try {
+          const r = await fetch(anchorsUrl(), { cache: 'no-store' })
+          if (!r.ok) throw new Error(`HTTP ${r.status}`)
+          const list = (await r.json()).anchors || []
+          if (cancelled) return
+          if (list.length > 0) {
+            setAnchors(list.map(a => ({ beaconId: a.beaconId, x: a.x, y: a.y, height: a.height, floor: a.floor })))
+            setAnchorNames(prev => {
+              const next = { ...prev }
+              list.forEach(a => { if (a.label) next[a.beaconId] = a.label })
+              return next
+            })
+          }
+          setRegistryAnchors(list)
+          pushLog(`Loaded ${list.length} anchor(s) from backend registry`)
+        } catch (e) {
+          if (!cancelled) pushLog(`Anchor registry unavailable (${e.message}); using local anchors`)
+        }
+      })()
+    return () => { cancelled = true }
+  }, [useLive, backendPort])
+
+  // Push local anchor edits (add / move / rename / remove) to the registry, debounced so dragging
+  // an anchor sends one PUT when it settles. Writes need the admin token; failed ones are retried when
+  // it changes.
+  useEffect(() => {
+    if (!useLive || !backendPort || !registryAnchors) return
+    const desired = new Map()
+    anchors.forEach(a => {
+      const beaconId = String(a.beaconId).toLowerCase()
+      desired.set(beaconId, { beaconId, x: a.x, y: a.y, height: a.height ?? anchorHeight, floor: a.floor ?? 0, label: anchorNames[a.beaconId] || '' })
+    })
+    const current = new Map(registryAnchors.map(a => [a.beaconId, a]))
+    const same = (a, b) => a.x === b.x && a.y === b.y && a.height === b.height && a.floor === b.floor && a.label === b.label
+    const upserts = [...desired.values()].filter(a => !current.has(a.beaconId) || !same(a, current.get(a.beaconId)))
+    const removals = [...current.keys()].filter(id => !desired.has(id))
+    if (upserts.length === 0 && removals.length === 0) return
+    const timer = setTimeout(async () => {
+      const next = new Map(current)
+      let changed = false
+      for (const a of upserts) {
+        try {
+          const r = await fetch(anchorsUrl(a.beaconId), { method: 'PUT', headers: withAdminToken(adminToken, { 'Content-Type': 'application/json' }), body: JSON.stringify(a) })
+          if (!r.ok) throw new Error(await writeError(r))
+          next.set(a.beaconId, await r.json())
+          changed = true
+        } catch (e) { pushLog(`Anchor ${a.beaconId} not saved: ${e.message}`) }
+      }
+      for (const id of removals) {
+        try {
+          const r = await fetch(anchorsUrl(id), { method: 'DELETE', headers: withAdminToken(adminToken) })
+          if (!r.ok && r.status !== 404) throw new Error(await writeError(r))
+          next.delete(id)
+          changed = true
+        } catch (e) { pushLog(`Anchor ${id} not deleted: ${e.message}`) }
+      }
+      // Only failures left: stop here rather than retrying until the next edit
+      if (changed) setRegistryAnchors([...next.values()])
+    }, 500)
+    return () => clearTimeout(timer)
+  }, [anchors, anchorNames, anchorHeight, registryAnchors, adminToken, useLive, backendPort])
+
+  // Backend device registry (live mode only): names for registered devices, keyed by device ID as in
+  // `uwb_update`. `registryDevices` mirrors the backend (`null` until loaded).
+  const [registryDevices, setRegistryDevices] = useState(null)
+  const devicesUrl = (id) => `http://${window.location.hostname}:${backendPort}/devices${id ? `/${encodeURIComponent(id)}` : ''}`
+  const deviceKey = (d) => d.deviceIdHex || d.device
+
+  useEffect(() => {
+    if (!useLive || !backendPort) { setRegistryDevices(null); return }
+    let cancelled = false
+      ; (async function () {
+        try {
+          const r = await fetch(devicesUrl(), { cache: 'no-store' })
+          if (!r.ok) throw new Error(`HTTP ${r.status}`)
+          const list = (await r.json()).devices || []
+          if (cancelled) return
+          // Registry names win; cached names of unnamed devices are saved by the effect below
+          setDeviceNames(prev => {
+            const next = { ...prev }
+            list.forEach(d => { if (d.name || !next[deviceKey(d)]) next[deviceKey(d)] = d.name || '' })
+            return next
+          })
+          setRegistryDevices(list)
+          pushLog(`Loaded ${list.length} device(s) from backend registry`)
+        } catch (e) {
+          if (!cancelled) pushLog(`Device registry unavailable (${e.message}); names are not saved`)
+        }
+      })()
+    return () => { cancelled = true }
+  }, [useLive, backendPort])
+
+  // Save renamed devices to the registry, debounced so typing sends one PUT per device. Writes need
+  // the admin token; failed ones are retried when it changes.
+  useEffect(() => {
+    if (!useLive || !backendPort || !registryDevices) return
+    const current = new Map(registryDevices.map(d => [deviceKey(d), d]))
+    const renamed = Object.entries(deviceNames).filter(([id, name]) => current.has(id) && (current.get(id).name || '') !== name.trim())
+    if (renamed.length === 0) return
+    const timer = setTimeout(async () => {
+      const next = new Map(current)
+      let changed = false
+      for (const [id, name] of renamed) {
+        try {
+          const r = await fetch(devicesUrl(id), { method: 'PUT', headers: withAdminToken(adminToken, { 'Content-Type': 'application/json' }), body: JSON.stringify({ name }) })
+          if (!r.ok) throw new Error(await writeError(r))
+          next.set(id, await r.json())
+          changed = true
+        } catch (e) { pushLog(`Device ${id} name not saved: ${e.message}`) }
+      }
+      if (changed) setRegistryDevices([...next.values()])
+    }, 500)
+    return () => clearTimeout(timer)
+  }, [deviceNames, registryDevices, adminToken, useLive, backendPort])
+
   // clear per-device Kalman filters when anchors change (recalibration)
   // Reset per-device Kalman filter instances whenever anchors change (geometry shift invalidates previous filter state).
   useEffect(() => {
@@ -424,6 +557,7 @@ function App() {
       try {
         setConnStatus('connecting')
         const headers = {}
+        if (useLive && lastEventIdRef.current) headers['Last-Event-ID'] = lastEventIdRef.current
         const ac = new AbortController()
         streamControllerRef.current = ac
         const res = await fetch(pollUrl, { headers, signal: ac.signal })
@@ -446,12 +580,20 @@ function App() {
             let dataLines = []
             for (const line of lines) {
               if (line.startsWith('data:')) dataLines.push(line.replace(/^data:\s*/, ''))
+              else if (line.startsWith('id:')) lastEventIdRef.current = line.replace(/^id:\s*/, '')
             }
             if (dataLines.length === 0) continue
             const dataText = dataLines.join('\n')
             try {
               const parsed = JSON.parse(dataText)
-              if (parsed && parsed.type === 'uwb_update' && parsed.payload) { handleUwbUpdate(parsed.payload); pushLog(`recv uwb_update ${parsed.payload.deviceIdHex || parsed.payload.deviceId || ''}`) }
+              // the backend could not replay what was missed: start tracks afresh
+              if (parsed && parsed.type === 'resync') { kalmanRef.current = {}; pushLog(`stream resync (${parsed.reason}); missed events dropped`) }
+              // last known state of every device, so idle tags show before their next frame
+              else if (parsed && parsed.type === 'snapshot' && Array.isArray(parsed.devices)) {
+                for (const d of parsed.devices) { if (d.beacons && d.beacons.length) handleUwbUpdate(d) }
+                pushLog(`recv snapshot (${parsed.devices.length} devices)`)
+              }
+              else if (parsed && parsed.type === 'uwb_update' && parsed.payload) { handleUwbUpdate(parsed.payload); pushLog(`recv uwb_update ${parsed.payload.deviceIdHex || parsed.payload.deviceId || ''}`) }
               else if (parsed && parsed.payload && parsed.payload.beacons) { handleUwbUpdate(parsed.payload); pushLog(`recv payload ${parsed.payload.deviceIdHex || parsed.payload.deviceId || ''}`) }
               else if (parsed && parsed.beacons) { handleUwbUpdate(parsed); pushLog(`recv beacons ${parsed.deviceIdHex || parsed.deviceId || ''}`) }
             } catch (err) { /* ignore non-JSON frames */ }
@@ -564,6 +706,19 @@ function App() {
     setLastPacketTimestamp(prev => ({ ...prev, [id]: ts }))
     setLastPacketAt(Date.now())
 
+    // Backend-tracked position (live ingestion): already solved and Kalman-smoothed server side, so
+    // every viewer draws the same track. Skip the local solver and smoothing.
+    const track = payload.track
+    if (track && Number.isFinite(track.x) && Number.isFinite(track.y)) {
+      const p = { x: Math.max(0, Math.min(factoryWidthMeters, track.x)), y: Math.max(0, Math.min(factoryHeightMeters, track.y)) }
+      setDebugInfo({ when: Date.now(), posMeters: payload.position || track, clamped: p, track, payload, smoothingMethod: 'backend' })
+      setFrameCount(c => c + 1)
+      setSmoothed(prev => ({ ...prev, [id]: p }))
+      setEmployees([{ id, label: deviceNames[id] || id, x: p.x, y: p.y, t: Date.now() }])
+      pushDevicePoint(id, p.x, p.y)
+      return
+    }
+
     // 2. Anchors are already in meters
     const anchorsInMeters = anchors
 
@@ -994,6 +1149,8 @@ function App() {
               onClose={() => setAdminOpen(false)}
               apiKey={apiKey}
               setApiKey={setApiKey}
+              adminToken={adminToken}
+              setAdminToken={setAdminToken}
               pollUrl={pollUrl}
               setPollUrl={setPollUrl}
               useLive={useLive}
@@ -0,0 +1,51 @@
+import React from 'react'
+import { screen } from '@testing-library/react'
+import userEvent from '@testing-library/user-event'
+import Admin from '../../components/Admin'
+import { renderWithProviders } from '../../test-utils'
+import { loadAdminToken, saveAdminToken, withAdminToken, writeError } from '../../adminToken'
+
+describe('Admin token', () => {
+  afterEach(() => {
+    sessionStorage.clear()
+  })
+
+  it('is entered in the Admin dialog', async () => {
+    const setAdminToken = vi.fn()
+    renderWithProviders(
+      <Admin
+        anchors={[]}
+        setAnchors={() => { }}
+        anchorNames={{}}
+        setAnchorNames={() => { }}
+        deviceNames={{ a0ba3e29: 'Forklift' }}
+        setDeviceNames={() => { }}
+        onClose={() => { }}
+        adminToken=""
+        setAdminToken={setAdminToken}
+        logs={[]}
+      />
+    )
+    const input = screen.getByPlaceholderText('Admin token (registry writes)')
+    expect(input).toHaveAttribute('type', 'password')
+    await userEvent.type(input, 's')
+    expect(setAdminToken).toHaveBeenCalledWith('s')
+  })
+
+  it('is kept for the session and sent as a bearer token', () => {
+    expect(loadAdminToken()).toBe('')
+    expect(withAdminToken('', { 'Content-Type': 'application/json' })).toEqual({ 'Content-Type': 'application/json' })
+    saveAdminToken('s3cret')
+    expect(loadAdminToken()).toBe('s3cret')
+    expect(withAdminToken(loadAdminToken(), { 'Content-Type': 'application/json' }))
+      .toEqual({ 'Content-Type': 'application/json', Authorization: 'Bearer s3cret' })
+    saveAdminToken('')
+    expect(sessionStorage.getItem('adminToken')).toBeNull()
+  })
+
+  it('points refused writes at the token', async () => {
+    const refused = { status: 401, json: async () => ({ code: 'unauthorized', message: 'missing or wrong bearer token' }) }
+    expect(await writeError(refused)).toBe('missing or wrong bearer token; set the admin token in Admin')
+    expect(await writeError({ status: 404, json: async () => { throw new Error() } })).toBe('HTTP 404')
+  })
+})
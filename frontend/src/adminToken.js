@@ -0,0 +1,22 @@
+// Admin token (the backend's KEYS_ADMIN_TOKEN) for registry writes: entered by the operator in the
+// Admin dialog and kept in sessionStorage, so it lasts for the browser session only.
+const KEY = 'adminToken'
+
+export function loadAdminToken() {
+  try { return sessionStorage.getItem(KEY) || '' } catch (e) { return '' }
+}
+
+export function saveAdminToken(token) {
+  try { token ? sessionStorage.setItem(KEY, token) : sessionStorage.removeItem(KEY) } catch (e) { /* ignore */ }
+}
+
+// `headers` plus `Authorization: Bearer <token>` when a token is set
+export function withAdminToken(token, headers = {}) {
+  return token ? { ...headers, Authorization: `Bearer ${token}` } : headers
+}
+
+// Error message for a failed registry write; 401/403 mean the token is missing or wrong
+export async function writeError(r) {
+  const message = (await r.json().catch(() => ({}))).message || `HTTP ${r.status}`
+  return r.status === 401 || r.status === 403 ? `${message}; set the admin token in Admin` : message
+}
@@ -7,7 +7,7 @@ import { Select, SelectContent, SelectItem, SelectTrigger, SelectValue } from "@
 import { ScrollArea } from "@/components/ui/scroll-area"
 import { Badge } from "@/components/ui/badge"
 
-export default function Admin({ anchors, setAnchors, anchorNames, setAnchorNames, deviceNames, setDeviceNames, factoryWidthMeters, factoryHeightMeters, setFactoryWidthMeters, setFactoryHeightMeters, anchorHeight, setAnchorHeight, tagHeight, setTagHeight, onClose, apiKey, setApiKey, pollUrl, setPollUrl, useLive, setUseLive, smoothingMethod, setSmoothingMethod, connStatus, logs, fetchNow, clearLines, clearAllLines }) {
+export default function Admin({ anchors, setAnchors, anchorNames, setAnchorNames, deviceNames, setDeviceNames, factoryWidthMeters, factoryHeightMeters, setFactoryWidthMeters, setFactoryHeightMeters, anchorHeight, setAnchorHeight, tagHeight, setTagHeight, onClose, apiKey, setApiKey, adminToken, setAdminToken, pollUrl, setPollUrl, useLive, setUseLive, smoothingMethod, setSmoothingMethod, connStatus, logs, fetchNow, clearLines, clearAllLines }) {
   const [localAnchors, setLocalAnchors] = useState(anchors)
   useEffect(() => setLocalAnchors(anchors), [anchors])
 
@@ -50,11 +50,12 @@ export default function Admin({ anchors, setAnchors, anchorNames, setAnchorNames
       <div className="space-y-4">
         <div>
           <h3 className="text-sm font-semibold">Connection & Stream</h3>
-          <p className="text-sm text-muted-foreground">Configure API key, poll URL, streaming mode and smoothing.</p>
+          <p className="text-sm text-muted-foreground">Configure API key, admin token, poll URL, streaming mode and smoothing.</p>
         </div>
 
         <div className="flex gap-4">
           <Input placeholder="API Key (optional)" value={apiKey || ''} onChange={e => setApiKey(e.target.value)} />
+          <Input type="password" placeholder="Admin token (registry writes)" value={adminToken || ''} onChange={e => setAdminToken(e.target.value)} />
           <Input placeholder="Poll URL" value={pollUrl || ''} onChange={e => setPollUrl(e.target.value)} className="flex-1" />
         </div>
 
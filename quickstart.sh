@@ -60,7 +60,7 @@ export FRONTEND_PORT
 
 # Minimum required versions
 REQUIRED_DOCKER="20.10.0"
-REQUIRED_RUST="1.83.0"
+REQUIRED_RUST="1.88.0"
 REQUIRED_NODE="18.0.0"
 REQUIRED_NPM="8.0.0"
 